@@ -0,0 +1,58 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+
+// Builds outside of a git repository, for example from the crates.io
+// tarball, don't know which commit they come from
+const UNKNOWN_HASH: &'static str = "unknown";
+
+
+fn git_hash() -> Option<String> {
+    let output = match Command::new("git").args(&["rev-parse", "HEAD"])
+                                          .output() {
+        Ok(output) => output,
+        Err(..) => return None,
+    };
+    if ! output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout).ok()
+        .map(|hash| hash.trim().to_string())
+        .and_then(|hash| if hash.is_empty() { None } else { Some(hash) })
+}
+
+
+fn main() {
+    println!("cargo:rustc-env=FISHER_GIT_HASH={}",
+        git_hash().unwrap_or_else(|| UNKNOWN_HASH.to_string()),
+    );
+
+    // Embed the new hash when the current commit changes
+    let git_dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap())
+        .join(".git");
+    if git_dir.is_dir() {
+        println!("cargo:rerun-if-changed={}",
+            git_dir.join("HEAD").display(),
+        );
+        println!("cargo:rerun-if-changed={}",
+            git_dir.join("refs").join("heads").display(),
+        );
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Helpers to authenticate incoming requests.
+
+
+/// The credentials provided in an HTTP `Authorization` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    /// A token provided with the `Bearer` scheme.
+    Bearer(String),
+
+    /// An username and a password provided with the `Basic` scheme.
+    Basic(String, String),
+}
+
+impl Authorization {
+
+    /// Parse the content of an `Authorization` header, returning None if the
+    /// header is malformed or its scheme is not supported.
+    pub fn parse(header: &str) -> Option<Authorization> {
+        let mut parts = header.trim().splitn(2, ' ');
+        let scheme = parts.next().unwrap_or("").to_lowercase();
+        let content = parts.next().unwrap_or("").trim();
+
+        if content.is_empty() {
+            return None;
+        }
+
+        match scheme.as_str() {
+            "bearer" => Some(Authorization::Bearer(content.to_string())),
+            "basic" => {
+                let decoded = match decode_base64(content) {
+                    Some(bytes) => match String::from_utf8(bytes) {
+                        Ok(string) => string,
+                        Err(..) => return None,
+                    },
+                    None => return None,
+                };
+
+                // The username and the password are separated by a colon
+                let mut split = decoded.splitn(2, ':');
+                let username = split.next().unwrap_or("");
+                if let Some(password) = split.next() {
+                    Some(Authorization::Basic(
+                        username.to_string(), password.to_string(),
+                    ))
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Return the secret contained in the credentials: the token for the
+    /// `Bearer` scheme and the password for the `Basic` one.
+    pub fn secret(&self) -> &str {
+        match *self {
+            Authorization::Bearer(ref token) => token,
+            Authorization::Basic(_, ref password) => password,
+        }
+    }
+
+    /// Check if the secret contained in the credentials is the expected one.
+    /// The comparison is done in constant time.
+    pub fn is_secret(&self, expected: &str) -> bool {
+        constant_time_eq(self.secret().as_bytes(), expected.as_bytes())
+    }
+}
+
+
+/// Compare two byte strings in constant time, to avoid leaking informations
+/// about a secret through timing attacks. Only the length of the strings is
+/// leaked.
+///
+/// Providers should use it every time they compare a secret or a signature
+/// sent with a request.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut result = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        result |= x ^ y;
+    }
+
+    result == 0
+}
+
+
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim_right_matches('=');
+
+    let mut result = Vec::with_capacity(input.len() * 3 / 4);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in input.bytes() {
+        let value = match byte {
+            b'A'...b'Z' => byte - b'A',
+            b'a'...b'z' => byte - b'a' + 26,
+            b'0'...b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    // A single leftover character can't encode a full byte
+    if bits >= 6 {
+        return None;
+    }
+
+    Some(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Authorization, constant_time_eq, decode_base64};
+
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64(""), Some(vec![]));
+        assert_eq!(decode_base64("Zg=="), Some(b"f".to_vec()));
+        assert_eq!(decode_base64("Zm8="), Some(b"fo".to_vec()));
+        assert_eq!(decode_base64("Zm9v"), Some(b"foo".to_vec()));
+        assert_eq!(decode_base64("Zm9vYmFy"), Some(b"foobar".to_vec()));
+
+        assert_eq!(decode_base64("Zm9v!"), None);
+        assert_eq!(decode_base64("Z"), None);
+    }
+
+
+    #[test]
+    fn test_parse_bearer() {
+        assert_eq!(
+            Authorization::parse("Bearer abcde"),
+            Some(Authorization::Bearer("abcde".into()))
+        );
+        assert_eq!(
+            Authorization::parse("bearer  abcde "),
+            Some(Authorization::Bearer("abcde".into()))
+        );
+        assert_eq!(Authorization::parse("Bearer abcde").unwrap().secret(),
+                   "abcde");
+    }
+
+
+    #[test]
+    fn test_parse_basic() {
+        // "user:abcde"
+        let auth = Authorization::parse("Basic dXNlcjphYmNkZQ==").unwrap();
+        assert_eq!(auth, Authorization::Basic("user".into(), "abcde".into()));
+        assert_eq!(auth.secret(), "abcde");
+
+        // ":abc:de", the password can contain colons
+        let auth = Authorization::parse("Basic OmFiYzpkZQ==").unwrap();
+        assert_eq!(auth, Authorization::Basic("".into(), "abc:de".into()));
+    }
+
+
+    #[test]
+    fn test_parse_malformed() {
+        for header in &[
+            "",
+            "Bearer",
+            "Bearer ",
+            "abcde",
+            "Digest abcde",
+            "Basic",
+            "Basic !!!",
+            // "user", without a password
+            "Basic dXNlcg==",
+        ] {
+            assert_eq!(Authorization::parse(header), None, "{}", header);
+        }
+    }
+
+
+    #[test]
+    fn test_is_secret() {
+        let auth = Authorization::parse("Bearer abcde").unwrap();
+        assert!(auth.is_secret("abcde"));
+        assert!(! auth.is_secret("abcdf"));
+        assert!(! auth.is_secret("abcd"));
+        assert!(! auth.is_secret(""));
+    }
+
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"abcde", b"abcde"));
+        assert!(! constant_time_eq(b"abcde", b"abcdf"));
+        assert!(! constant_time_eq(b"fbcde", b"abcde"));
+
+        // Strings with different lengths are never equal, even if one is
+        // the prefix of the other
+        assert!(! constant_time_eq(b"abcde", b"abcd"));
+        assert!(! constant_time_eq(b"abcd", b"abcde"));
+        assert!(! constant_time_eq(b"", b"abcde"));
+    }
+}
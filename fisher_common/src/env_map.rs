@@ -0,0 +1,147 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Extraction of environment variables from JSON documents.
+
+use std::collections::HashMap;
+
+use serde_json::{self, Value};
+
+use errors::{ErrorKind, Result};
+
+
+/// Environment variables to extract from a JSON document, mapping the name
+/// of each variable to the [JSON pointer](https://tools.ietf.org/html/rfc6901)
+/// of its value.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct EnvMap(HashMap<String, String>);
+
+impl EnvMap {
+
+    /// Check if all the JSON pointers in the map are valid.
+    pub fn validate(&self) -> Result<()> {
+        for (name, pointer) in &self.0 {
+            if ! (pointer.is_empty() || pointer.starts_with('/')) {
+                return Err(ErrorKind::InvalidInput(format!(
+                    r#""{}" is not a valid JSON pointer for {}"#,
+                    pointer, name,
+                )).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return true if there are no variables to extract.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Extract the variables from a JSON document. Strings are used as-is,
+    /// null is converted to an empty string, and the other values are
+    /// serialized back to JSON. If a pointer is missing from the document
+    /// the variable is not set, unless `strict` is true: in that case the
+    /// variable is set to an empty string.
+    pub fn extract(&self, document: &Value, strict: bool)
+                   -> HashMap<String, String> {
+        let mut result = HashMap::new();
+
+        for (name, pointer) in &self.0 {
+            let value = match document.pointer(pointer) {
+                Some(&Value::String(ref string)) => string.clone(),
+                Some(&Value::Null) => String::new(),
+                Some(other) => other.to_string(),
+                None if strict => String::new(),
+                None => continue,
+            };
+            result.insert(name.clone(), value);
+        }
+
+        result
+    }
+
+    /// Extract the variables from a serialized JSON document, like
+    /// [`extract`](#method.extract). If the document is not valid JSON all
+    /// the pointers are considered missing.
+    pub fn extract_str(&self, document: &str, strict: bool)
+                       -> HashMap<String, String> {
+        let document = serde_json::from_str(document).unwrap_or(Value::Null);
+        self.extract(&document, strict)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::EnvMap;
+
+
+    #[test]
+    fn test_validate() {
+        let map: EnvMap = serde_json::from_str(
+            r#"{"REF": "/ref", "ALL": ""}"#
+        ).unwrap();
+        assert!(map.validate().is_ok());
+
+        let map: EnvMap = serde_json::from_str(r#"{"REF": "ref"}"#).unwrap();
+        assert!(map.validate().is_err());
+    }
+
+
+    #[test]
+    fn test_extract() {
+        let map: EnvMap = serde_json::from_str(r#"{
+            "REF": "/ref",
+            "AUTHOR": "/commits/0/author/name",
+            "SIZE": "/size",
+            "FORCED": "/forced",
+            "BASE": "/base_ref",
+            "MISSING": "/repository/missing"
+        }"#).unwrap();
+
+        let document = json!({
+            "ref": "refs/heads/master",
+            "commits": [{"author": {"name": "Pietro"}}],
+            "size": 1,
+            "forced": false,
+            "base_ref": null,
+            "repository": {},
+        });
+
+        let env = map.extract(&document, false);
+        assert_eq!(env.len(), 5);
+        assert_eq!(env["REF"], "refs/heads/master");
+        assert_eq!(env["AUTHOR"], "Pietro");
+        assert_eq!(env["SIZE"], "1");
+        assert_eq!(env["FORCED"], "false");
+        assert_eq!(env["BASE"], "");
+        assert!(! env.contains_key("MISSING"));
+
+        // In strict mode missing pointers produce empty variables
+        let env = map.extract(&document, true);
+        assert_eq!(env.len(), 6);
+        assert_eq!(env["MISSING"], "");
+
+        // Invalid documents don't contain any pointer
+        assert!(map.extract_str("{", false).is_empty());
+        assert_eq!(map.extract_str("{", true).len(), 6);
+        assert_eq!(
+            map.extract_str(&document.to_string(), false),
+            map.extract(&document, false)
+        );
+    }
+}
@@ -27,18 +27,40 @@ use std::num;
 use std::error::Error as StdError;
 use std::sync::mpsc;
 use std::sync;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::result::Result as StdResult;
 
 use serde_json;
 use ansi_term::Colour;
 
 
+// Whether the errors are printed as JSON, along with the events of the jobs
+static JSON_OUTPUT: AtomicBool = ATOMIC_BOOL_INIT;
+
+
+/// Choose whether [`Error::pretty_print`](struct.Error.html) prints the
+/// errors as JSON objects, one per line, instead of in a human readable way.
+pub fn print_errors_as_json(enabled: bool) {
+    JSON_OUTPUT.store(enabled, Ordering::SeqCst);
+}
+
+
 /// Convenience type alias to easily use Result with
 /// [`Error`](struct.Error.html).
 
 pub type Result<T> = StdResult<T, Error>;
 
 
+/// Format the duration in seconds, keeping the fractional part so short
+/// durations aren't shown as zero seconds.
+fn seconds(duration: &Duration) -> String {
+    let secs = duration.as_secs() as f64 +
+               duration.subsec_nanos() as f64 / 1_000_000_000.0;
+    format!("{} seconds", secs)
+}
+
+
 /// This enum represents the kind of error that occured, with the details
 /// about it.
 
@@ -76,6 +98,58 @@ pub enum ErrorKind {
     /// An internal thread crashed.
     ThreadCrashed,
 
+    /// The interpreter requested by an hook doesn't exist. The interpreter
+    /// command is provided as the first parameter.
+    InterpreterNotFound(String),
+
+    /// A script required by the current one didn't succeed recently. The
+    /// name of the required script is provided as the first parameter.
+    PrerequisiteNotMet(String),
+
+    /// A job waited in the queue for longer than its script allows. The
+    /// maximum age of the job is provided as the first parameter.
+    JobExpired(Duration),
+
+    /// A job took longer than the timeout, so it was killed. The timeout is
+    /// provided as the first parameter.
+    JobTimedOut(Duration),
+
+    /// A job didn't exit successfully. The exit code, if there is one, is
+    /// provided as the first parameter, and the standard error of the job as
+    /// the second one.
+    JobFailed(Option<i32>, String),
+
+    /// A job panicked while it was being executed. The message of the panic
+    /// is provided as the first parameter.
+    JobPanicked(String),
+
+    /// The resource limits of a script couldn't be applied because cgroups
+    /// aren't available. The reason is provided as the first parameter.
+    CgroupsUnavailable(String),
+
+    /// The content of a script doesn't match its checksum in the manifest.
+    ChecksumMismatch,
+
+    /// A script isn't listed in the manifest, so it can't be trusted.
+    NotInManifest,
+
+    /// The processor is overloaded, and it's rejecting the low-priority jobs.
+    Overloaded,
+
+    /// The queue of the processor is full, and it's rejecting all the jobs.
+    QueueFull,
+
+    /// The body of a request is bigger than the maximum allowed size. The
+    /// maximum size in bytes is provided as the first parameter.
+    BodyTooLarge(u64),
+
+    /// The headers of a request are more or bigger than the maximum allowed.
+    HeadersTooLarge,
+
+    /// The failure of a job couldn't be sent to the failure webhook. The
+    /// reason is provided as the first parameter.
+    FailureNotNotified(String),
+
     /// An error occured while performing I/O operations. The underlying error
     /// is available as the first parameter.
     IoError(io::Error),
@@ -131,6 +205,64 @@ impl fmt::Display for ErrorKind {
             ErrorKind::ThreadCrashed =>
                 "an internal thread crashed".into(),
 
+            ErrorKind::InterpreterNotFound(ref command) =>
+                format!("interpreter {} not found", command),
+
+            ErrorKind::PrerequisiteNotMet(ref script) =>
+                format!("the required script {} didn't succeed", script),
+
+            ErrorKind::JobExpired(ref max_age) =>
+                format!(
+                    "the job waited in the queue for more than {}",
+                    seconds(max_age),
+                ),
+
+            ErrorKind::JobTimedOut(ref timeout) =>
+                format!("the hook timed out after {}", seconds(timeout)),
+
+            ErrorKind::JobFailed(code, ref stderr) => {
+                let mut message = match code {
+                    Some(code) => {
+                        format!("the hook exited with code {}", code)
+                    },
+                    None => "the hook was unsuccessful".into(),
+                };
+                if ! stderr.trim().is_empty() {
+                    message.push_str(", printing:\n");
+                    message.push_str(stderr.trim_right());
+                }
+                message
+            },
+
+            ErrorKind::JobPanicked(ref message) =>
+                format!("the job panicked: {}", message),
+
+            ErrorKind::CgroupsUnavailable(ref reason) =>
+                format!("cgroups are unavailable: {}", reason),
+
+            ErrorKind::ChecksumMismatch =>
+                "the checksum doesn't match the one in the manifest".into(),
+
+            ErrorKind::NotInManifest =>
+                "the file isn't listed in the manifest".into(),
+
+            ErrorKind::Overloaded =>
+                "the processor is overloaded".into(),
+
+            ErrorKind::QueueFull =>
+                "the queue of the processor is full".into(),
+
+            ErrorKind::BodyTooLarge(ref max_size) =>
+                format!(
+                    "the request body is bigger than {} bytes", max_size,
+                ),
+
+            ErrorKind::HeadersTooLarge =>
+                "the request headers are too many or too big".into(),
+
+            ErrorKind::FailureNotNotified(ref reason) =>
+                format!("the failure webhook wasn't notified: {}", reason),
+
             ErrorKind::IoError(ref error) =>
                 format!("{}", error),
 
@@ -271,6 +403,14 @@ impl Error {
     /// }
     /// ```
     pub fn pretty_print(&self) {
+        if JSON_OUTPUT.load(Ordering::SeqCst) {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0);
+            println!("{}", self.json_line(timestamp));
+            return;
+        }
+
         println!("{} {}",
             Colour::Red.bold().paint("Error:"),
             self
@@ -282,6 +422,22 @@ impl Error {
             );
         }
     }
+
+    // Render the error like the events of the jobs are rendered
+    fn json_line(&self, timestamp: u64) -> String {
+        let location = if self.location == ErrorLocation::Unknown {
+            None
+        } else {
+            Some(self.location.to_string())
+        };
+
+        json!({
+            "event": "error",
+            "error": self.to_string(),
+            "location": location,
+            "timestamp": timestamp,
+        }).to_string()
+    }
 }
 
 impl fmt::Display for Error {
@@ -313,6 +469,34 @@ impl StdError for Error {
                 "poisoned lock",
             ErrorKind::ThreadCrashed =>
                 "thread crashed",
+            ErrorKind::InterpreterNotFound(..) =>
+                "interpreter not found",
+            ErrorKind::PrerequisiteNotMet(..) =>
+                "prerequisite not met",
+            ErrorKind::JobExpired(..) =>
+                "job expired",
+            ErrorKind::JobTimedOut(..) =>
+                "hook timed out",
+            ErrorKind::JobFailed(..) =>
+                "hook failed",
+            ErrorKind::JobPanicked(..) =>
+                "job panicked",
+            ErrorKind::CgroupsUnavailable(..) =>
+                "cgroups unavailable",
+            ErrorKind::ChecksumMismatch =>
+                "checksum mismatch",
+            ErrorKind::NotInManifest =>
+                "not in manifest",
+            ErrorKind::Overloaded =>
+                "overloaded",
+            ErrorKind::QueueFull =>
+                "queue full",
+            ErrorKind::BodyTooLarge(..) =>
+                "request body too large",
+            ErrorKind::HeadersTooLarge =>
+                "request headers too large",
+            ErrorKind::FailureNotNotified(..) =>
+                "failure not notified",
             ErrorKind::IoError(ref error) =>
                 error.description(),
             ErrorKind::JsonError(ref error) =>
@@ -383,3 +567,52 @@ derive_error!(serde_json::Error, ErrorKind::JsonError);
 derive_error!(net::AddrParseError, ErrorKind::AddrParseError);
 derive_error!(num::ParseIntError, ErrorKind::ParseIntError);
 derive_error!(Box<StdError + Send + Sync>, ErrorKind::GenericError);
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{self, Value};
+
+    use super::{Error, ErrorKind, ErrorLocation};
+
+
+    #[test]
+    fn test_durations_in_messages() {
+        let message = |timeout| ErrorKind::JobTimedOut(timeout).to_string();
+
+        assert_eq!(
+            message(Duration::from_secs(30)),
+            "the hook timed out after 30 seconds"
+        );
+        // Sub-second durations aren't rounded down to zero
+        assert_eq!(
+            message(Duration::from_millis(200)),
+            "the hook timed out after 0.2 seconds"
+        );
+        assert_eq!(
+            message(Duration::from_millis(1500)),
+            "the hook timed out after 1.5 seconds"
+        );
+    }
+
+    #[test]
+    fn test_json_line() {
+        let parse = |error: &Error| -> Value {
+            serde_json::from_str(&error.json_line(42)).unwrap()
+        };
+
+        let mut error = Error::new(ErrorKind::Dummy);
+        assert_eq!(parse(&error), json!({
+            "event": "error", "error": error.to_string(), "location": null,
+            "timestamp": 42,
+        }));
+
+        error.set_location(ErrorLocation::HookProcessing("a.sh".into()));
+        assert_eq!(parse(&error), json!({
+            "event": "error", "error": error.to_string(),
+            "location": error.location().to_string(), "timestamp": 42,
+        }));
+    }
+}
@@ -22,8 +22,10 @@
 #[macro_use] extern crate serde_derive;
 extern crate ansi_term;
 extern crate serde;
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
 
+pub mod auth;
+pub mod env_map;
 pub mod errors;
 pub mod prelude;
 pub mod serial;
@@ -20,6 +20,7 @@
 //! [`State`](struct.State.html) struct is also marked as Sync and Send, so
 //! it can be used across threads without locking.
 
+use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::cmp::PartialOrd;
 use std::cmp::Ordering as CmpOrdering;
@@ -37,6 +38,9 @@ pub enum IdKind {
     /// This kind should be used to identify threads.
     ThreadId,
 
+    /// This kind should be used to identify jobs.
+    JobId,
+
     #[doc(hidden)]
     __NonExaustiveMatch,
 }
@@ -47,7 +51,8 @@ pub enum IdKind {
 /// The struct is intentionally opaque, so you won't be able to get the actual
 /// value of the ID, but you can compare multiple IDs to get which one is
 /// greater, and check if multiple IDs are equal. This is done to be able to
-/// swap the inner implementation without breaking any code.
+/// swap the inner implementation without breaking any code. The IDs can be
+/// displayed to the user, but you shouldn't rely on their format.
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct UniqueId {
@@ -55,6 +60,13 @@ pub struct UniqueId {
     kind: IdKind,
 }
 
+impl fmt::Display for UniqueId {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)
+    }
+}
+
 impl PartialOrd for UniqueId {
 
     fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
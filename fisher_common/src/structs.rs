@@ -15,10 +15,13 @@
 
 //! Structs used by Fisher.
 
+use std::collections::HashMap;
+use std::time::Duration;
+
 
 /// This struct contains some information about how the processor is feeling.
 
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct HealthDetails {
     /// The number of jobs in the queue, waiting to be processed.
     pub queued_jobs: usize,
@@ -28,4 +31,198 @@ pub struct HealthDetails {
 
     /// The total number of threads running, either waiting or working.
     pub max_threads: u16,
+
+    /// How many jobs were completed each second, on average over the last
+    /// minute.
+    pub throughput: f64,
+
+    /// Statistics about the jobs of each script, by the script name. Scripts
+    /// which never completed a job are not included.
+    pub hooks: HashMap<String, ScriptStats>,
+}
+
+impl HealthDetails {
+
+    /// Estimate how long it takes to process all the queued jobs, at the
+    /// current throughput. There is no estimate if no job was completed
+    /// recently.
+    pub fn drain_time(&self) -> Option<Duration> {
+        if self.throughput <= 0.0 {
+            return None;
+        }
+
+        let secs = self.queued_jobs as f64 / self.throughput;
+        Some(Duration::from_millis((secs * 1000.0).ceil() as u64))
+    }
+}
+
+
+/// This struct contains statistics about the jobs of a single script.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ScriptStats {
+    /// How long the jobs waited in the queue before being executed.
+    pub queue_time: Percentiles,
+
+    /// How long the jobs took to execute.
+    pub execution_time: Percentiles,
+
+    /// Counters about the jobs started by a request.
+    pub jobs: JobCounters,
+
+    /// Counters about the jobs started by a status event.
+    pub status_jobs: JobCounters,
+}
+
+
+/// This struct contains how many jobs were executed, and how many of them
+/// failed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct JobCounters {
+    /// The number of executions, including the retries.
+    pub executed: u64,
+
+    /// The number of executions which failed.
+    pub failed: u64,
+
+    /// The number of times a failed job was scheduled again.
+    pub retried: u64,
+
+    /// The number of jobs which failed and weren't retried anymore.
+    pub gave_up: u64,
+
+    /// The number of jobs which weren't executed because the scripts they
+    /// require didn't succeed.
+    pub skipped: u64,
+
+    /// The number of jobs which weren't executed because they waited in the
+    /// queue for too long.
+    pub expired: u64,
+}
+
+
+/// This struct contains the details of a single execution of a script.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    /// When the execution started, as a UNIX timestamp.
+    pub started_at: u64,
+
+    /// How long the execution took, in seconds.
+    pub duration: f64,
+
+    /// The exit code of the script, if it has one.
+    pub exit_code: Option<i32>,
+
+    /// If the execution was successful.
+    pub successful: bool,
+
+    /// If the execution was a retry of a failed one.
+    pub retry: bool,
+}
+
+
+/// This struct contains some percentiles of a set of durations, in seconds.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct Percentiles {
+    /// The median duration.
+    pub p50: f64,
+
+    /// The 95th percentile of the durations.
+    pub p95: f64,
+
+    /// The 99th percentile of the durations.
+    pub p99: f64,
+}
+
+
+/// The scripts whose last execution must have succeeded before a script is
+/// allowed to run. Scripts which never ran count as failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prerequisites {
+    /// The names of the required scripts.
+    pub scripts: Vec<String>,
+
+    /// How recent the successful executions must be, if there is a limit.
+    pub within: Option<Duration>,
+}
+
+
+/// This enum represents how many threads a single script is allowed to use
+/// at the same time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConcurrencyLimit {
+    /// An absolute number of threads.
+    Threads(u16),
+
+    /// A fraction of the threads of the processor, between 0 and 1.
+    Fraction(f64),
+}
+
+impl ConcurrencyLimit {
+
+    /// Resolve the limit against the total number of threads of the
+    /// processor. The result is never less than one and never more than the
+    /// total number of threads.
+    pub fn resolve(&self, max_threads: u16) -> u16 {
+        let max_threads = if max_threads == 0 { 1 } else { max_threads };
+
+        let limit = match *self {
+            ConcurrencyLimit::Threads(threads) => threads,
+            ConcurrencyLimit::Fraction(fraction) => {
+                (max_threads as f64 * fraction).floor() as u16
+            },
+        };
+
+        if limit < 1 {
+            1
+        } else if limit > max_threads {
+            max_threads
+        } else {
+            limit
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::{ConcurrencyLimit, HealthDetails};
+
+
+    #[test]
+    fn test_health_details_drain_time() {
+        let mut details = HealthDetails {
+            queued_jobs: 30,
+            busy_threads: 1,
+            max_threads: 1,
+            throughput: 0.5,
+            hooks: HashMap::new(),
+        };
+        assert_eq!(details.drain_time(), Some(Duration::from_secs(60)));
+
+        details.queued_jobs = 0;
+        assert_eq!(details.drain_time(), Some(Duration::from_secs(0)));
+
+        // Without recent jobs the throughput is unknown
+        details.throughput = 0.0;
+        assert_eq!(details.drain_time(), None);
+    }
+
+
+    #[test]
+    fn test_concurrency_limit_resolve() {
+        // Absolute limits are capped to the number of threads
+        assert_eq!(ConcurrencyLimit::Threads(2).resolve(4), 2);
+        assert_eq!(ConcurrencyLimit::Threads(10).resolve(4), 4);
+        assert_eq!(ConcurrencyLimit::Threads(0).resolve(4), 1);
+
+        // Fractions are rounded down, but at least one thread is allowed
+        assert_eq!(ConcurrencyLimit::Fraction(0.5).resolve(4), 2);
+        assert_eq!(ConcurrencyLimit::Fraction(0.5).resolve(5), 2);
+        assert_eq!(ConcurrencyLimit::Fraction(0.1).resolve(4), 1);
+        assert_eq!(ConcurrencyLimit::Fraction(1.0).resolve(4), 4);
+        assert_eq!(ConcurrencyLimit::Fraction(2.0).resolve(4), 4);
+    }
 }
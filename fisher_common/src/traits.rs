@@ -16,11 +16,12 @@
 //! Traits used by Fisher.
 
 use std::hash::Hash;
-use std::sync::Arc;
+use std::sync::{Arc, mpsc};
+use std::time::Duration;
 use std::fmt::Debug;
 
 use prelude::*;
-use structs::HealthDetails;
+use structs::{HealthDetails, HistoryEntry, ConcurrencyLimit, Prerequisites};
 
 
 /// This trait represents a script that can be run by Fisher.
@@ -35,6 +36,44 @@ pub trait ScriptTrait {
     /// This method returns if multiple instances of the script can be safely
     /// run in parallel.
     fn can_be_parallel(&self) -> bool;
+
+    /// This method returns the maximum number of instances of the script
+    /// which can be run at the same time, if there is a limit.
+    ///
+    /// The limit is ignored if the script can't be run in parallel, and it
+    /// can't raise the number of instances over the number of threads of the
+    /// processor. By default there is no limit.
+    fn max_parallel(&self) -> Option<ConcurrencyLimit> {
+        None
+    }
+
+    /// This method returns the scripts which must have succeeded before this
+    /// one is allowed to run. Jobs of scripts whose prerequisites aren't met
+    /// are skipped. By default there are no prerequisites.
+    fn prerequisites(&self) -> Option<Prerequisites> {
+        None
+    }
+
+    /// This method returns how long the jobs of the script can wait in the
+    /// queue. Jobs waiting for longer are discarded instead of being run. By
+    /// default there is no limit.
+    fn max_queue_age(&self) -> Option<Duration> {
+        None
+    }
+
+    /// This method returns the labels of the script, which allow to check
+    /// the health of a group of scripts. By default there are no labels.
+    fn labels(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// This method returns how much of the processor's capacity a job of the
+    /// script uses while it runs. Jobs are started only while the total cost
+    /// of the running jobs fits in the number of threads. The default cost
+    /// is 1.
+    fn cost(&self) -> u16 {
+        1
+    }
 }
 
 
@@ -86,18 +125,81 @@ pub trait JobTrait<S: ScriptTrait> {
 
     /// Get the name of the underlying script.
     fn script_name(&self) -> &str;
+
+    /// Check if the output of the job represents a successful execution. By
+    /// default every output is successful.
+    fn is_successful(&self, _output: &Self::Output) -> bool {
+        true
+    }
+
+    /// Get the exit code from the output of the job, if it has one. By
+    /// default there is no exit code.
+    fn exit_code(&self, _output: &Self::Output) -> Option<i32> {
+        None
+    }
+
+    /// Get the key identifying the job, if it has one. The job is not
+    /// queued if another job of the same script with the same key is
+    /// already queued or running. By default there is no key.
+    fn dedup_key(&self) -> Option<String> {
+        None
+    }
+
+    /// This method is called when the job failed and the processor gave up
+    /// retrying it. It receives the error of the last execution and how many
+    /// times the job was executed. By default it does nothing.
+    fn gave_up(&self, _ctx: &Self::Context, _error: &str, _attempts: u32) {}
+
+    /// This method is called once when the last attempt to execute the job
+    /// failed, either with the unsuccessful output of the job or with the
+    /// error it returned. By default it does nothing.
+    fn failed_permanently(&self, _ctx: &Self::Context,
+                          _output: Option<&Self::Output>,
+                          _error: Option<&str>) {}
+
+    /// This method is called when the job is added to the queue. By default
+    /// it does nothing.
+    fn queued(&self, _ctx: &Self::Context) {}
+
+    /// This method is called when the processor is done with the job, either
+    /// because it was executed successfully or because it's not going to be
+    /// executed anymore. Jobs still queued when the processor stops are not
+    /// finished. By default it does nothing.
+    fn finished(&self, _ctx: &Self::Context) {}
 }
 
 
+/// What the clients waiting for a job receive: its output, or the error
+/// which prevented it from returning one.
+pub type WaitResult<O> = ::std::result::Result<O, String>;
+
+
 /// This trait represents the API of the processor
 pub trait ProcessorApiTrait<S: ScriptsRepositoryTrait>: Send {
 
     /// Queue a new job into the processor.
     fn queue(&self, job: S::Job, priority: isize) -> Result<()>;
 
+    /// Queue a new job into the processor, returning a channel which will
+    /// receive the output of the job once it's executed, or the error it
+    /// returned. The channel is closed without receiving anything if the
+    /// job isn't executed.
+    fn queue_wait(&self, job: S::Job, priority: isize) -> Result<
+        mpsc::Receiver<WaitResult<<S::Job as JobTrait<S::Script>>::Output>>
+    >;
+
     /// Get some insights about the health of the processor.
     fn health_details(&self) -> Result<HealthDetails>;
 
+    /// Get some insights about the health of the processor, counting only
+    /// the queued and running jobs of the scripts with the label. The
+    /// throughput and the statistics of the scripts are not filtered.
+    fn labeled_health_details(&self, label: &str) -> Result<HealthDetails>;
+
+    /// Get the most recent executions of a script, from the oldest to the
+    /// newest one.
+    fn history(&self, script: &str) -> Result<Vec<HistoryEntry>>;
+
     /// Execute periodic cleanup tasks on the processor.
     fn cleanup(&self) -> Result<()>;
 
@@ -14,15 +14,54 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::sync::{Arc, mpsc};
+use std::time::Duration;
 
 use fisher_common::prelude::*;
 use fisher_common::state::State;
-use fisher_common::structs::HealthDetails;
+use fisher_common::structs::{HealthDetails, HistoryEntry};
 
-use scheduler::{Scheduler, SchedulerInput};
+use events::JobEvent;
+use scheduler::{Scheduler, SchedulerInput, SchedulerKind};
 #[cfg(test)] use scheduler::DebugDetails;
+use retry::RetryPolicy;
+use shedding::{LoadShedding, Shedder};
 use timer::Timer;
-use types::{Job, JobContext};
+use types::{Job, JobContext, JobOutput};
+
+
+/// The configuration of a [`Processor`](struct.Processor.html). The default
+/// one runs a job at a time, without retrying the failed ones and without
+/// limiting the queue.
+
+#[derive(Debug, Clone)]
+pub struct ProcessorConfig {
+    /// How many jobs can run at the same time.
+    pub max_threads: u16,
+    /// How the failed jobs are retried.
+    pub retry: RetryPolicy,
+    /// How the queued jobs are assigned to the threads.
+    pub scheduler: SchedulerKind,
+    /// When the jobs with a low priority are rejected, if ever.
+    pub shedding: Option<LoadShedding>,
+    /// How many executions of each script are kept in its history.
+    pub history_size: usize,
+    /// How many jobs can be queued at the same time, if they're limited.
+    pub max_queue_size: Option<usize>,
+}
+
+impl Default for ProcessorConfig {
+
+    fn default() -> Self {
+        ProcessorConfig {
+            max_threads: 1,
+            retry: RetryPolicy::default(),
+            scheduler: SchedulerKind::default(),
+            shedding: None,
+            history_size: 10,
+            max_queue_size: None,
+        }
+    }
+}
 
 
 /// This struct allows you to spawn a new processor, stop it and get its
@@ -31,6 +70,7 @@ use types::{Job, JobContext};
 #[derive(Debug)]
 pub struct Processor<S: ScriptsRepositoryTrait + 'static> {
     input: mpsc::Sender<SchedulerInput<S>>,
+    shedder: Arc<Shedder>,
     timer: Timer,
     wait: mpsc::Receiver<()>,
 }
@@ -39,26 +79,36 @@ impl<S: ScriptsRepositoryTrait> Processor<S> {
 
     /// Create a new processor with the provided configuration. The returned
     /// struct allows you to control it.
-    pub fn new(max_threads: u16, hooks: Arc<S>, ctx: Arc<JobContext<S>>,
-               state: Arc<State>) -> Result<Self> {
+    pub fn new(hooks: Arc<S>, ctx: Arc<JobContext<S>>, state: Arc<State>,
+               config: ProcessorConfig) -> Result<Self> {
+        let shedder = Arc::new(Shedder::new(
+            config.shedding, config.max_queue_size,
+        )?);
+
         // Retrieve wanted information from the spawned thread
         let (input_send, input_recv) = mpsc::sync_channel(0);
         let (wait_send, wait_recv) = mpsc::channel();
 
+        let scheduler_shedder = shedder.clone();
         ::std::thread::spawn(move || {
             let inner = Scheduler::new(
-                max_threads, hooks, ctx, state,
+                config.max_threads, hooks, ctx, state, config.retry,
+                config.scheduler, scheduler_shedder, config.history_size,
             );
-            input_send.send(inner.input()).unwrap();
+            // The processor could have been dropped in the meantime
+            if input_send.send(inner.input()).is_err() {
+                return;
+            }
 
             inner.run().unwrap();
 
             // Notify the main thread this exited
-            wait_send.send(()).unwrap();
+            let _ = wait_send.send(());
         });
 
         let processor = Processor {
             input: input_recv.recv()?,
+            shedder: shedder,
             timer: Timer::new(),
             wait: wait_recv,
         };
@@ -84,10 +134,28 @@ impl<S: ScriptsRepositoryTrait> Processor<S> {
         Ok(())
     }
 
+    /// Stop this processor, waiting at most `timeout` for the running jobs
+    /// to end. If some jobs are still running after that, their threads are
+    /// abandoned and their processes are left running, and `false` is
+    /// returned.
+    pub fn stop_timeout(self, timeout: Duration) -> Result<bool> {
+        self.timer.stop()?;
+
+        self.input.send(SchedulerInput::StopSignal)?;
+        match self.wait.recv_timeout(timeout) {
+            Ok(()) => Ok(true),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(false),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(ErrorKind::BrokenChannel.into())
+            },
+        }
+    }
+
     /// Get a struct allowing you to control the processor.
     pub fn api(&self) -> ProcessorApi<S> {
         ProcessorApi {
             input: self.input.clone(),
+            shedder: self.shedder.clone(),
         }
     }
 }
@@ -98,10 +166,18 @@ impl<S: ScriptsRepositoryTrait> Processor<S> {
 #[derive(Debug, Clone)]
 pub struct ProcessorApi<S: ScriptsRepositoryTrait> {
     input: mpsc::Sender<SchedulerInput<S>>,
+    shedder: Arc<Shedder>,
 }
 
 impl<S: ScriptsRepositoryTrait> ProcessorApi<S> {
 
+    /// Send to the provided channel the events of the jobs processed from
+    /// now on. The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self, events: mpsc::Sender<JobEvent>) -> Result<()> {
+        self.input.send(SchedulerInput::Subscribe(events))?;
+        Ok(())
+    }
+
     #[cfg(test)]
     pub fn debug_details(&self) -> Result<DebugDetails<S>> {
         let (res_send, res_recv) = mpsc::channel();
@@ -113,13 +189,52 @@ impl<S: ScriptsRepositoryTrait> ProcessorApi<S> {
 impl<S: ScriptsRepositoryTrait> ProcessorApiTrait<S> for ProcessorApi<S> {
 
     fn queue(&self, job: Job<S>, priority: isize) -> Result<()> {
-        self.input.send(SchedulerInput::Job(job, priority))?;
+        // This is checked here, so the caller knows right away the job
+        // wasn't accepted
+        if ! self.shedder.accepts(priority) {
+            return Err(ErrorKind::Overloaded.into());
+        }
+        if ! self.shedder.reserve() {
+            return Err(ErrorKind::QueueFull.into());
+        }
+
+        self.input.send(SchedulerInput::Job(job, priority, None))?;
         Ok(())
     }
 
+    fn queue_wait(&self, job: Job<S>, priority: isize)
+                  -> Result<mpsc::Receiver<WaitResult<JobOutput<S>>>> {
+        if ! self.shedder.accepts(priority) {
+            return Err(ErrorKind::Overloaded.into());
+        }
+        if ! self.shedder.reserve() {
+            return Err(ErrorKind::QueueFull.into());
+        }
+
+        let (output_send, output_recv) = mpsc::channel();
+        self.input.send(
+            SchedulerInput::Job(job, priority, Some(output_send))
+        )?;
+        Ok(output_recv)
+    }
+
     fn health_details(&self) -> Result<HealthDetails> {
         let (res_send, res_recv) = mpsc::channel();
-        self.input.send(SchedulerInput::HealthStatus(res_send))?;
+        self.input.send(SchedulerInput::HealthStatus(None, res_send))?;
+        Ok(res_recv.recv()?)
+    }
+
+    fn labeled_health_details(&self, label: &str) -> Result<HealthDetails> {
+        let (res_send, res_recv) = mpsc::channel();
+        self.input.send(
+            SchedulerInput::HealthStatus(Some(label.into()), res_send)
+        )?;
+        Ok(res_recv.recv()?)
+    }
+
+    fn history(&self, script: &str) -> Result<Vec<HistoryEntry>> {
+        let (res_send, res_recv) = mpsc::channel();
+        self.input.send(SchedulerInput::History(script.into(), res_send))?;
         Ok(res_recv.recv()?)
     }
 
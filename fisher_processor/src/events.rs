@@ -0,0 +1,103 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::mpsc;
+use std::time::Duration;
+
+use fisher_common::state::UniqueId;
+
+
+/// This enum contains the events sent to the subscribers of the processor
+/// as the jobs progress. Every event contains the name of the script the job
+/// belongs to.
+///
+/// A job is queued, started, and then it either succeeds or fails. Failed
+/// jobs which are retried are started again after a delay, and the jobs which
+/// aren't going to be executed anymore are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobEvent {
+    /// The job was added to the queue.
+    Queued {
+        /// The name of the script.
+        script: String,
+    },
+
+    /// A thread started executing the job.
+    Started {
+        /// The name of the script.
+        script: String,
+        /// The ID of the thread executing the job.
+        thread: UniqueId,
+    },
+
+    /// The job was executed successfully.
+    Succeeded {
+        /// The name of the script.
+        script: String,
+        /// The exit code of the job, if it has one.
+        code: Option<i32>,
+        /// How long the job took to execute.
+        duration: Duration,
+    },
+
+    /// The job returned an error, or its output wasn't successful.
+    Failed {
+        /// The name of the script.
+        script: String,
+        /// Why the job failed.
+        error: String,
+    },
+
+    /// The job failed, and it's going to be started again after a delay.
+    Retrying {
+        /// The name of the script.
+        script: String,
+        /// Which retry this is, starting from 1.
+        retry: u32,
+        /// How long the job waits before being started again.
+        delay: Duration,
+    },
+
+    /// The job was discarded, either without being executed or after the
+    /// processor gave up retrying it.
+    Dropped {
+        /// The name of the script.
+        script: String,
+    },
+}
+
+
+#[derive(Debug)]
+pub struct Subscribers {
+    senders: Vec<mpsc::Sender<JobEvent>>,
+}
+
+impl Subscribers {
+
+    pub fn new() -> Self {
+        Subscribers {
+            senders: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, sender: mpsc::Sender<JobEvent>) {
+        self.senders.push(sender);
+    }
+
+    pub fn emit(&mut self, event: JobEvent) {
+        // Subscribers which went away are forgotten
+        self.senders.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
@@ -21,11 +21,20 @@
 extern crate fisher_common;
 
 mod api;
+mod events;
+mod queue;
+mod retry;
 mod scheduled_job;
 mod scheduler;
+mod shedding;
+mod stats;
 mod thread;
 mod timer;
 mod types;
 #[cfg(test)] mod test_utils;
 
-pub use api::{Processor, ProcessorApi};
+pub use api::{Processor, ProcessorApi, ProcessorConfig};
+pub use events::JobEvent;
+pub use retry::RetryPolicy;
+pub use scheduler::SchedulerKind;
+pub use shedding::LoadShedding;
@@ -0,0 +1,209 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use fisher_common::prelude::*;
+
+use super::scheduled_job::ScheduledJob;
+use super::types::ScriptId;
+
+
+/// This struct contains the jobs ready to be executed, with a separate queue
+/// for each script.
+///
+/// The jobs with the highest priority are always dequeued first. Between the
+/// scripts with jobs of the same priority, the one with the fewest running
+/// jobs is picked, and then the one dequeued least recently. This way a
+/// burst of jobs of a single script can't take all the threads and starve
+/// the other scripts. The jobs of each script are still dequeued in the
+/// order they were queued.
+#[derive(Debug)]
+pub struct JobsQueue<S: ScriptsRepositoryTrait> {
+    queues: HashMap<ScriptId<S>, BinaryHeap<ScheduledJob<S>>>,
+    last_dequeued: HashMap<ScriptId<S>, u64>,
+    dequeued: u64,
+    len: usize,
+}
+
+impl<S: ScriptsRepositoryTrait> JobsQueue<S> {
+
+    pub fn new() -> Self {
+        JobsQueue {
+            queues: HashMap::new(),
+            last_dequeued: HashMap::new(),
+            dequeued: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn push(&mut self, job: ScheduledJob<S>) {
+        self.queues.entry(job.hook_id())
+            .or_insert_with(BinaryHeap::new)
+            .push(job);
+        self.len += 1;
+    }
+
+    /// Take the next job out of the queue. The map contains how many jobs of
+    /// each script are currently running.
+    pub fn pop(&mut self, running: &HashMap<ScriptId<S>, usize>)
+               -> Option<ScheduledJob<S>> {
+        let script = match self.next_script(running) {
+            Some(script) => script,
+            None => return None,
+        };
+
+        let (job, empty) = {
+            let queue = self.queues.get_mut(&script).unwrap();
+            (queue.pop(), queue.is_empty())
+        };
+
+        // Scripts without queued jobs are forgotten, so the removed scripts
+        // don't use memory forever
+        if empty {
+            self.queues.remove(&script);
+            self.last_dequeued.remove(&script);
+        } else {
+            self.dequeued += 1;
+            self.last_dequeued.insert(script, self.dequeued);
+        }
+
+        self.len -= 1;
+        job
+    }
+
+    /// Return the scripts with at least a job in the queue.
+    pub fn scripts(&self) -> Vec<ScriptId<S>> {
+        self.queues.keys().cloned().collect()
+    }
+
+    /// Return how many jobs of the script are in the queue.
+    pub fn script_len(&self, script: &ScriptId<S>) -> usize {
+        self.queues.get(script).map(|queue| queue.len()).unwrap_or(0)
+    }
+
+    /// Return if there is at least a status job in the queue.
+    pub fn has_status_jobs(&self) -> bool {
+        self.queues.values().any(|queue| {
+            queue.iter().any(|job| job.is_status_job())
+        })
+    }
+
+    fn next_script(&self, running: &HashMap<ScriptId<S>, usize>)
+                   -> Option<ScriptId<S>> {
+        let mut best: Option<(ScriptId<S>, &ScheduledJob<S>, usize, u64)> =
+            None;
+
+        for (script, queue) in &self.queues {
+            let job = match queue.peek() {
+                Some(job) => job,
+                None => continue,
+            };
+            let running = running.get(script).cloned().unwrap_or(0);
+            let last = self.last_dequeued.get(script).cloned().unwrap_or(0);
+
+            let better = match best {
+                Some((_, best_job, best_running, best_last)) => {
+                    match job.priority().cmp(&best_job.priority()) {
+                        Ordering::Greater => true,
+                        Ordering::Less => false,
+                        Ordering::Equal => {
+                            (running, last) < (best_running, best_last) || (
+                                (running, last) == (best_running, best_last)
+                                && job > best_job
+                            )
+                        },
+                    }
+                },
+                None => true,
+            };
+
+            if better {
+                best = Some((*script, job, running, last));
+            }
+        }
+
+        best.map(|(script, _, _, _)| script)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use fisher_common::serial::Serial;
+
+    use super::super::scheduled_job::ScheduledJob;
+    use super::super::test_utils::*;
+    use super::JobsQueue;
+
+
+    fn pop_all(queue: &mut JobsQueue<Repository<()>>,
+               running: &HashMap<usize, usize>) -> Vec<String> {
+        let mut result = Vec::new();
+        while let Some(job) = queue.pop(running) {
+            result.push(job.dedup_key().unwrap());
+        }
+        result
+    }
+
+
+    #[test]
+    fn test_fairness() {
+        let repo = Repository::<()>::new();
+        repo.add_script("a", true, |_| Ok(()));
+        repo.add_script("b", true, |_| Ok(()));
+        repo.add_script("c", true, |_| Ok(()));
+
+        // The jobs are identified by their key in the assertions
+        let mut serial = Serial::zero();
+        let mut queue = JobsQueue::new();
+        let mut push = |queue: &mut JobsQueue<_>, key: &str, priority| {
+            let job = repo.dedup_job(&key[..1], (), key).unwrap();
+            queue.push(ScheduledJob::new(job, priority, serial.incr()));
+        };
+
+        // A burst of jobs of a script doesn't delay the other scripts
+        for key in &["a1", "a2", "a3", "b1", "b2"] {
+            push(&mut queue, key, 0);
+        }
+        assert_eq!(
+            pop_all(&mut queue, &HashMap::new()),
+            vec!["a1", "b1", "a2", "b2", "a3"]
+        );
+
+        // The scripts with fewer running jobs are preferred
+        for key in &["a1", "b1", "c1"] {
+            push(&mut queue, key, 0);
+        }
+        let mut running = HashMap::new();
+        running.insert(repo.hook_id_of("a").unwrap(), 2);
+        running.insert(repo.hook_id_of("c").unwrap(), 1);
+        assert_eq!(pop_all(&mut queue, &running), vec!["b1", "c1", "a1"]);
+
+        // The priority still comes first
+        push(&mut queue, "a1", 0);
+        push(&mut queue, "a2", 10);
+        push(&mut queue, "b1", 0);
+        assert_eq!(pop_all(&mut queue, &running), vec!["a2", "b1", "a1"]);
+        assert_eq!(queue.len(), 0);
+    }
+}
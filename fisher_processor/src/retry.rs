@@ -0,0 +1,87 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::time::Duration;
+
+
+/// This struct contains how failed jobs should be retried.
+///
+/// A job fails when its execution returns an error, or when its output isn't
+/// successful (for example because of the exit code). Failed jobs are
+/// executed again after a delay, which doubles after each attempt, until the
+/// maximum number of retries is reached.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a failed job is executed again. Zero disables retries.
+    pub max_retries: u32,
+
+    /// The delay before the first retry.
+    pub base_delay: Duration,
+
+    /// The maximum delay between two retries.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+
+    /// Get how long to wait before retrying a job which already failed
+    /// `attempts + 1` times.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        // Don't overflow the shift
+        if attempts >= 32 {
+            return self.max_delay;
+        }
+
+        match self.base_delay.checked_mul(1 << attempts) {
+            Some(delay) if delay < self.max_delay => delay,
+            _ => self.max_delay,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(30),
+        };
+
+        assert_eq!(policy.delay(0), Duration::from_secs(2));
+        assert_eq!(policy.delay(1), Duration::from_secs(4));
+        assert_eq!(policy.delay(3), Duration::from_secs(16));
+        assert_eq!(policy.delay(4), Duration::from_secs(30));
+        assert_eq!(policy.delay(100), Duration::from_secs(30));
+    }
+}
@@ -14,6 +14,8 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::cmp::Ordering;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use fisher_common::prelude::*;
 use fisher_common::serial::Serial;
@@ -21,11 +23,18 @@ use fisher_common::serial::Serial;
 use super::types::{Job, JobContext, JobOutput, ScriptId};
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ScheduledJob<S: ScriptsRepositoryTrait> {
     job: Job<S>,
     priority: isize,
     serial: Serial,
+    queued_at: Instant,
+    status_job: bool,
+    attempts: u32,
+    last_attempt: bool,
+    cost: u16,
+    last_error: Option<String>,
+    waiter: Option<mpsc::Sender<WaitResult<JobOutput<S>>>>,
 }
 
 impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
@@ -35,13 +44,88 @@ impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
             job: job,
             priority: priority,
             serial: serial,
+            queued_at: Instant::now(),
+            status_job: false,
+            attempts: 0,
+            last_attempt: true,
+            cost: 1,
+            last_error: None,
+            waiter: None,
         }
     }
 
+    pub fn as_status_job(mut self) -> Self {
+        self.status_job = true;
+        self
+    }
+
+    /// Send the output of the job to the channel once it's executed.
+    pub fn with_waiter(mut self,
+                       waiter: mpsc::Sender<WaitResult<JobOutput<S>>>)
+                       -> Self {
+        self.waiter = Some(waiter);
+        self
+    }
+
+    pub fn notify_waiter(&self, output: &JobOutput<S>) {
+        if let Some(ref waiter) = self.waiter {
+            // The waiter could have given up in the meantime
+            let _ = waiter.send(Ok(output.clone()));
+        }
+    }
+
+    /// Tell the waiter the job returned an error instead of an output.
+    pub fn notify_waiter_error(&self, error: &Error) {
+        if let Some(ref waiter) = self.waiter {
+            let _ = waiter.send(Err(error.to_string()));
+        }
+    }
+
+    pub fn retry(&mut self, delay: Duration) {
+        // The job is considered queued again only after the delay
+        self.queued_at = Instant::now() + delay;
+        self.attempts += 1;
+    }
+
     pub fn execute(&self, ctx: &JobContext<S>) -> Result<JobOutput<S>> {
         self.job.execute(ctx)
     }
 
+    pub fn is_successful(&self, output: &JobOutput<S>) -> bool {
+        self.job.is_successful(output)
+    }
+
+    pub fn exit_code(&self, output: &JobOutput<S>) -> Option<i32> {
+        self.job.exit_code(output)
+    }
+
+    pub fn dedup_key(&self) -> Option<String> {
+        self.job.dedup_key()
+    }
+
+    pub fn failed(&mut self, error: &Error) {
+        self.last_error = Some(format!("{}", error));
+    }
+
+    pub fn gave_up(&self, ctx: &JobContext<S>) {
+        let error = self.last_error.as_ref().map(|e| e.as_str()).unwrap_or("");
+        self.job.gave_up(ctx, error, self.attempts + 1);
+    }
+
+    pub fn failed_permanently(&self, ctx: &JobContext<S>,
+                              output: Option<&JobOutput<S>>) {
+        let error = self.last_error.as_ref().map(|e| e.as_str());
+        self.job.failed_permanently(ctx, output, error);
+    }
+
+    pub fn queued(&self, ctx: &JobContext<S>) {
+        self.job.queued(ctx);
+    }
+
+    pub fn finished(&self, ctx: &JobContext<S>) {
+        self.job.finished(ctx);
+    }
+
     pub fn hook_id(&self) -> ScriptId<S> {
         self.job.script_id()
     }
@@ -49,6 +133,46 @@ impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
     pub fn hook_name(&self) -> &str {
         self.job.script_name()
     }
+
+    pub fn priority(&self) -> isize {
+        self.priority
+    }
+
+    pub fn queued_at(&self) -> Instant {
+        self.queued_at
+    }
+
+    pub fn is_ready(&self, now: Instant) -> bool {
+        self.queued_at <= now
+    }
+
+    pub fn is_status_job(&self) -> bool {
+        self.status_job
+    }
+
+    /// Set the capacity the job uses while it runs. This is recorded when
+    /// the job is started, so changing the cost of the script doesn't affect
+    /// the jobs already running.
+    pub fn set_cost(&mut self, cost: u16) {
+        self.cost = cost;
+    }
+
+    /// Set if the job won't be retried when this attempt fails.
+    pub fn set_last_attempt(&mut self, last_attempt: bool) {
+        self.last_attempt = last_attempt;
+    }
+
+    pub fn is_last_attempt(&self) -> bool {
+        self.last_attempt
+    }
+
+    pub fn cost(&self) -> u16 {
+        self.cost
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
 }
 
 impl<S: ScriptsRepositoryTrait> Ord for ScheduledJob<S> {
@@ -13,22 +13,121 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp::min;
 use std::collections::{BinaryHeap, HashMap, HashSet};
-use std::sync::{Arc, mpsc};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant, SystemTime};
 
 use fisher_common::prelude::*;
 use fisher_common::state::{State, UniqueId};
 use fisher_common::serial::Serial;
-use fisher_common::structs::HealthDetails;
+use fisher_common::structs::{HealthDetails, HistoryEntry, Prerequisites};
 
-use super::thread::Thread;
+use super::events::{JobEvent, Subscribers};
+use super::thread::{Thread, ThreadInput, SharedInput};
+use super::queue::JobsQueue;
 use super::scheduled_job::ScheduledJob;
+use super::stats::{StatsRecorder, ExecutionReport};
+use super::retry::RetryPolicy;
+use super::shedding::Shedder;
 use super::types::{ScriptId, Job, JobOutput, JobContext};
 
 
 const STATUS_EVENTS_PRIORITY: isize = 1000;
 
 
+/// This enum represents how the jobs are dispatched to the threads of the
+/// processor.
+///
+/// With both kinds the scheduler is fair across the hooks: between jobs with
+/// the same priority, it picks the hook with the fewest running jobs, and it
+/// rotates between the hooks with the same number of running jobs. The jobs
+/// of each hook are still executed in the order they were queued.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SchedulerKind {
+    /// Each thread has its own input, and the scheduler looks for an idle
+    /// thread every time a job needs to be run.
+    PerThread,
+
+    /// All the threads pull the jobs from a single shared queue, so
+    /// dispatching a job doesn't depend on the number of threads.
+    Shared,
+}
+
+impl Default for SchedulerKind {
+
+    fn default() -> Self {
+        SchedulerKind::PerThread
+    }
+}
+
+
+/// Return how many instances of a script can be run at the same time, or None
+/// if the only limit is the number of threads of the processor.
+///
+/// Scripts which can't be run in parallel are always limited to one instance,
+/// regardless of their own limit. Otherwise the script's own limit is used,
+/// resolved against the number of threads and never going over it.
+fn concurrency_limit<T: ScriptTrait>(script: &T, max_threads: u16)
+                                     -> Option<u16> {
+    if ! script.can_be_parallel() {
+        Some(1)
+    } else if let Some(limit) = script.max_parallel() {
+        Some(limit.resolve(max_threads))
+    } else {
+        None
+    }
+}
+
+
+fn collect_prerequisites<S: ScriptsRepositoryTrait>(hooks: &S)
+    -> HashMap<ScriptId<S>, Prerequisites>
+{
+    hooks.iter().filter_map(|hook| {
+        hook.prerequisites().map(|needed| (hook.id(), needed))
+    }).collect()
+}
+
+
+fn collect_max_queue_ages<S: ScriptsRepositoryTrait>(hooks: &S)
+    -> HashMap<ScriptId<S>, Duration>
+{
+    hooks.iter().filter_map(|hook| {
+        hook.max_queue_age().map(|age| (hook.id(), age))
+    }).collect()
+}
+
+
+fn collect_labels<S: ScriptsRepositoryTrait>(hooks: &S)
+    -> HashMap<ScriptId<S>, Vec<String>>
+{
+    hooks.iter().map(|hook| (hook.id(), hook.labels()))
+        .filter(|&(_, ref labels)| ! labels.is_empty())
+        .collect()
+}
+
+
+// Jobs costing more than the whole capacity would never be started, so they
+// run alone instead
+fn collect_costs<S: ScriptsRepositoryTrait>(hooks: &S, max_threads: u16)
+    -> HashMap<ScriptId<S>, u16>
+{
+    hooks.iter().map(|hook| (hook.id(), min(hook.cost(), max_threads)))
+        .filter(|&(_, cost)| cost != 1)
+        .collect()
+}
+
+
+fn report_not_executed<S>(job: &ScheduledJob<S>, mut error: Error)
+    where S: ScriptsRepositoryTrait
+{
+    error.set_location(ErrorLocation::HookProcessing(
+        job.hook_name().to_string()
+    ));
+    error.pretty_print();
+}
+
+
 #[cfg(test)]
 #[derive(Debug)]
 pub struct DebugDetails<S: ScriptsRepositoryTrait> {
@@ -52,8 +151,9 @@ impl<S: ScriptsRepositoryTrait> DebugDetails<S> {
 
 #[derive(Clone)]
 pub enum SchedulerInput<S: ScriptsRepositoryTrait> {
-    Job(Job<S>, isize),
-    HealthStatus(mpsc::Sender<HealthDetails>),
+    Job(Job<S>, isize, Option<mpsc::Sender<WaitResult<JobOutput<S>>>>),
+    HealthStatus(Option<String>, mpsc::Sender<HealthDetails>),
+    History(String, mpsc::Sender<Vec<HistoryEntry>>),
     ProcessOutput(JobOutput<S>),
 
     Cleanup,
@@ -64,7 +164,10 @@ pub enum SchedulerInput<S: ScriptsRepositoryTrait> {
     Unlock,
 
     StopSignal,
-    JobEnded(UniqueId, ScriptId<S>),
+    JobStarted(UniqueId, String),
+    JobEnded(UniqueId, ScheduledJob<S>, ExecutionReport, JobEvent),
+    ThreadCrashed(UniqueId, Option<ScheduledJob<S>>),
+    Subscribe(mpsc::Sender<JobEvent>),
 }
 
 
@@ -75,14 +178,53 @@ pub struct SchedulerInternalApi<S: ScriptsRepositoryTrait> {
 
 impl<S: ScriptsRepositoryTrait> SchedulerInternalApi<S> {
 
+    #[cfg(test)]
+    pub fn new(input: mpsc::Sender<SchedulerInput<S>>) -> Self {
+        SchedulerInternalApi {
+            input: input,
+        }
+    }
+
     pub fn record_output(&self, output: JobOutput<S>) -> Result<()> {
         self.input.send(SchedulerInput::ProcessOutput(output))?;
         Ok(())
     }
 
-    pub fn job_ended(&self, thread: UniqueId, job: &ScheduledJob<S>)
-                     -> Result<()> {
-        self.input.send(SchedulerInput::JobEnded(thread, job.hook_id()))?;
+    pub fn job_started(&self, thread: UniqueId, job: &ScheduledJob<S>)
+                       -> Result<()> {
+        self.input.send(SchedulerInput::JobStarted(
+            thread, job.hook_name().to_string(),
+        ))?;
+        Ok(())
+    }
+
+    pub fn job_ended(&self, thread: UniqueId, job: ScheduledJob<S>,
+                     started_at: Instant, failed: bool, successful: bool,
+                     exit_code: Option<i32>, event: JobEvent) -> Result<()> {
+        let execution_time = started_at.elapsed();
+        let report = ExecutionReport {
+            script_name: job.hook_name().to_string(),
+            queue_time: started_at.duration_since(job.queued_at()),
+            execution_time: execution_time,
+            status_job: job.is_status_job(),
+            failed: failed,
+            successful: successful,
+            started_at: SystemTime::now() - execution_time,
+            exit_code: exit_code,
+            retry: job.attempts() > 0,
+        };
+
+        self.input.send(
+            SchedulerInput::JobEnded(thread, job, report, event)
+        )?;
+        Ok(())
+    }
+
+    /// Report the thread crashed, with the job it was running if there was
+    /// one.
+    pub fn thread_crashed(&self, thread: UniqueId,
+                          running: Option<ScheduledJob<S>>) -> Result<()> {
+        self.input.send(SchedulerInput::ThreadCrashed(thread, running))?;
         Ok(())
     }
 }
@@ -94,12 +236,32 @@ pub struct Scheduler<S: ScriptsRepositoryTrait + 'static> {
     hooks: Arc<S>,
     jobs_context: Arc<JobContext<S>>,
     state: Arc<State>,
+    retry: RetryPolicy,
+    kind: SchedulerKind,
 
     locked: bool,
     should_stop: bool,
-    queue: BinaryHeap<ScheduledJob<S>>,
+    queue: JobsQueue<S>,
+    delayed: Vec<ScheduledJob<S>>,
     waiting: HashMap<ScriptId<S>, BinaryHeap<ScheduledJob<S>>>,
+    limits: HashMap<ScriptId<S>, u16>,
+    prerequisites: HashMap<ScriptId<S>, Prerequisites>,
+    max_queue_ages: HashMap<ScriptId<S>, Duration>,
+    labels: HashMap<ScriptId<S>, Vec<String>>,
+    costs: HashMap<ScriptId<S>, u16>,
+    used_capacity: u16,
     threads: HashMap<UniqueId, Thread<S>>,
+    stats: StatsRecorder,
+    shedder: Arc<Shedder>,
+    subscribers: Subscribers,
+    dedup_keys: HashSet<(ScriptId<S>, String)>,
+
+    // Only used with the shared queue, where the scheduler doesn't know which
+    // thread is running which job
+    shared_send: mpsc::Sender<ThreadInput<S>>,
+    shared_recv: SharedInput<S>,
+    busy: u16,
+    running: HashMap<ScriptId<S>, u16>,
 
     input_send: mpsc::Sender<SchedulerInput<S>>,
     input_recv: mpsc::Receiver<SchedulerInput<S>>,
@@ -108,28 +270,55 @@ pub struct Scheduler<S: ScriptsRepositoryTrait + 'static> {
 impl<S: ScriptsRepositoryTrait> Scheduler<S> {
 
     pub fn new(max_threads: u16, hooks: Arc<S>, ctx: Arc<JobContext<S>>,
-               state: Arc<State>) -> Self {
+               state: Arc<State>, retry: RetryPolicy, kind: SchedulerKind,
+               shedder: Arc<Shedder>, history_size: usize) -> Self {
         let (input_send, input_recv) = mpsc::channel();
+        let (shared_send, shared_recv) = mpsc::channel();
 
-        // Populate the waiting HashMap with non-parallel hooks
+        // Populate the waiting HashMap with hooks with a concurrency limit
         let mut waiting = HashMap::new();
+        let mut limits = HashMap::new();
         for hook in hooks.iter() {
-            if ! hook.can_be_parallel() {
+            if let Some(limit) = concurrency_limit(&*hook, max_threads) {
                 waiting.insert(hook.id(), BinaryHeap::new());
+                limits.insert(hook.id(), limit);
             }
         }
 
+        let prerequisites = collect_prerequisites(&*hooks);
+        let max_queue_ages = collect_max_queue_ages(&*hooks);
+        let labels = collect_labels(&*hooks);
+        let costs = collect_costs(&*hooks, max_threads);
+
         Scheduler {
             max_threads: max_threads,
             hooks: hooks,
             jobs_context: ctx,
             state: state,
+            retry: retry,
+            kind: kind,
 
             locked: false,
             should_stop: false,
-            queue: BinaryHeap::new(),
+            queue: JobsQueue::new(),
+            delayed: Vec::new(),
             waiting: waiting,
+            limits: limits,
+            prerequisites: prerequisites,
+            max_queue_ages: max_queue_ages,
+            labels: labels,
+            costs: costs,
+            used_capacity: 0,
             threads: HashMap::with_capacity(max_threads as usize),
+            stats: StatsRecorder::new(history_size),
+            shedder: shedder,
+            subscribers: Subscribers::new(),
+            dedup_keys: HashSet::new(),
+
+            shared_send: shared_send,
+            shared_recv: Arc::new(Mutex::new(shared_recv)),
+            busy: 0,
+            running: HashMap::new(),
 
             input_send: input_send,
             input_recv: input_recv,
@@ -147,45 +336,114 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
 
         let mut serial = Serial::zero();
         let mut to_schedule = Vec::new();
-        while let Ok(input) = self.input_recv.recv() {
+        loop {
+            // Put back in the queue the failed jobs ready to be retried
+            if self.requeue_delayed() {
+                self.run_jobs();
+
+                // The requeued jobs might have been the last ones the
+                // processor was waiting for before stopping
+                if self.drained() {
+                    break;
+                }
+            }
+
+            // Start or stop rejecting jobs before receiving the next ones
+            self.shedder.update(self.queued_jobs());
+
+            let input = if let Some(timeout) = self.next_retry() {
+                // Wake up when the next failed job should be retried
+                match self.input_recv.recv_timeout(timeout) {
+                    Ok(input) => input,
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            } else {
+                match self.input_recv.recv() {
+                    Ok(input) => input,
+                    Err(..) => break,
+                }
+            };
+
             match input {
 
-                SchedulerInput::Job(job, priority) => {
-                    self.queue_job(ScheduledJob::new(
+                SchedulerInput::Job(job, priority, waiter) => {
+                    let mut job = ScheduledJob::new(
                         job, priority, serial.incr(),
-                    ));
+                    );
+                    if let Some(waiter) = waiter {
+                        job = job.with_waiter(waiter);
+                    }
+
+                    // Skip duplicates of jobs already queued or running
+                    if ! self.track_dedup_key(&job) {
+                        self.shedder.received();
+                        continue;
+                    }
+
+                    job.queued(&*self.jobs_context);
+                    self.subscribers.emit(JobEvent::Queued {
+                        script: job.hook_name().to_string(),
+                    });
+                    self.queue_job(job);
                     self.run_jobs();
+
+                    // The job is released only after it's counted among the
+                    // queued ones, so the queue never looks emptier than it is
+                    self.shedder.update(self.queued_jobs());
+                    self.shedder.received();
                 },
 
-                SchedulerInput::HealthStatus(return_to) => {
+                SchedulerInput::HealthStatus(label, return_to) => {
                     // Count the busy threads
-                    let busy_threads = self.threads.values()
-                        .filter(|thread| thread.busy())
-                        .count();
-
-                    let mut queued_jobs = self.queue.len();
-                    for waiting in self.waiting.values() {
-                        queued_jobs += waiting.len();
-                    }
+                    let busy_threads = match self.kind {
+                        SchedulerKind::PerThread => {
+                            self.threads.values()
+                                .filter(|thread| thread.busy())
+                                .count()
+                        },
+                        SchedulerKind::Shared => self.busy as usize,
+                    };
+
+                    // Only the jobs of the scripts with the label are
+                    // counted, if one was requested
+                    let (queued_jobs, busy_threads) = match label {
+                        Some(ref label) => self.labeled_jobs(label),
+                        None => (self.queued_jobs(), busy_threads),
+                    };
 
                     return_to.send(HealthDetails {
                         queued_jobs: queued_jobs,
                         busy_threads: busy_threads as u16,
                         max_threads: self.max_threads,
+                        throughput: self.stats.throughput(),
+                        hooks: self.stats.summary(),
                     })?;
                 },
 
+                SchedulerInput::History(script, return_to) => {
+                    return_to.send(self.stats.history(&script))?;
+                },
+
                 SchedulerInput::ProcessOutput(output) => {
                     if let Some(jobs) = self.hooks.jobs_after_output(output) {
                         for job in jobs {
                             to_schedule.push(ScheduledJob::new(
                                 job, STATUS_EVENTS_PRIORITY, serial.incr(),
-                            ));
+                            ).as_status_job());
                         }
                     }
 
                     // This is a separated step due to mutable borrows
                     for job in to_schedule.drain(..) {
+                        if ! self.track_dedup_key(&job) {
+                            continue;
+                        }
+
+                        job.queued(&*self.jobs_context);
+                        self.subscribers.emit(JobEvent::Queued {
+                            script: job.hook_name().to_string(),
+                        });
                         self.queue_job(job);
                     }
 
@@ -210,40 +468,73 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                 SchedulerInput::Unlock => {
                     self.locked = false;
                     self.run_jobs();
+
+                    if self.drained() {
+                        break;
+                    }
                 },
 
-                SchedulerInput::JobEnded(thread_id, hook_id) => {
-                    // Mark the thread as idle
-                    if let Some(mut thread) = self.threads.get_mut(&thread_id) {
-                        thread.mark_idle();
+                SchedulerInput::JobStarted(thread_id, script) => {
+                    self.subscribers.emit(JobEvent::Started {
+                        script: script,
+                        thread: thread_id,
+                    });
+                },
+
+                SchedulerInput::JobEnded(thread_id, job, report, event) => {
+                    let hook_id = job.hook_id();
+                    self.mark_idle(thread_id, hook_id);
+                    self.used_capacity =
+                        self.used_capacity.saturating_sub(job.cost());
+
+                    self.stats.record(&report);
+                    self.subscribers.emit(event);
+
+                    if ! report.successful {
+                        self.retry_job(job);
+                    } else {
+                        self.job_finished(&job);
                     }
 
-                    // Put the highest-priority waiting job for this hook
-                    // back in the queue
-                    let mut push_back = None;
-                    if let Some(mut waiting) = self.waiting.get_mut(&hook_id) {
-                        push_back = waiting.pop();
+                    self.requeue_waiting(hook_id);
+                    self.run_jobs();
+
+                    if self.drained() {
+                        break;
                     }
-                    if let Some(job) = push_back {
-                        self.queue_job(job);
+                },
+
+                SchedulerInput::ThreadCrashed(thread_id, running) => {
+                    // The job isn't retried, but everything it held is
+                    // released
+                    if let Some(job) = running {
+                        let hook_id = job.hook_id();
+                        self.mark_idle(thread_id, hook_id);
+                        self.used_capacity =
+                            self.used_capacity.saturating_sub(job.cost());
+                        self.job_finished(&job);
+                        self.requeue_waiting(hook_id);
+                    }
+                    if let Some(thread) = self.threads.get_mut(&thread_id) {
+                        thread.mark_disconnected();
                     }
 
+                    self.replace_disconnected_threads();
                     self.run_jobs();
 
-                    if self.should_stop {
-                        self.cleanup_threads();
-
-                        if self.threads.is_empty() {
-                            break;
-                        }
+                    if self.drained() {
+                        break;
                     }
                 },
 
+                SchedulerInput::Subscribe(sender) => {
+                    self.subscribers.add(sender);
+                },
+
                 SchedulerInput::StopSignal => {
                     self.should_stop = true;
-                    self.cleanup_threads();
 
-                    if self.threads.is_empty() {
+                    if self.drained() {
                         break;
                     }
                 },
@@ -253,28 +544,197 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         Ok(())
     }
 
+    // Stop the idle threads if the processor is stopping, and return if all
+    // of them exited
+    fn drained(&mut self) -> bool {
+        if ! self.should_stop {
+            return false;
+        }
+
+        self.cleanup_threads();
+        self.threads.is_empty()
+    }
+
+    // Status jobs are spawned by the jobs that just completed, and they need
+    // to run even if the processor is stopping, so their notifications are
+    // not lost
+    fn pending_status_jobs(&self) -> bool {
+        self.queue.has_status_jobs()
+            || self.delayed.iter().any(|job| job.is_status_job())
+            || self.waiting.values().any(|waiting| {
+                waiting.iter().any(|job| job.is_status_job())
+            })
+    }
+
+    fn queued_jobs(&self) -> usize {
+        // Failed jobs waiting to be retried are also queued
+        let mut queued_jobs = self.queue.len() + self.delayed.len();
+        for waiting in self.waiting.values() {
+            queued_jobs += waiting.len();
+        }
+        queued_jobs
+    }
+
+    // Return how many jobs of the hooks with the label are queued, and how
+    // many of them are running
+    fn labeled_jobs(&self, label: &str) -> (usize, usize) {
+        let labeled = |hook: &ScriptId<S>| {
+            self.labels.get(hook).map_or(false, |labels| {
+                labels.iter().any(|existing| existing == label)
+            })
+        };
+
+        let mut queued = 0;
+        for hook in self.queue.scripts() {
+            if labeled(&hook) {
+                queued += self.queue.script_len(&hook);
+            }
+        }
+        queued += self.delayed.iter()
+            .filter(|job| labeled(&job.hook_id()))
+            .count();
+        for (hook, waiting) in &self.waiting {
+            if labeled(hook) {
+                queued += waiting.len();
+            }
+        }
+
+        let running = self.running_jobs().iter()
+            .filter(|&(hook, _)| labeled(hook))
+            .map(|(_, count)| *count)
+            .sum();
+
+        (queued, running)
+    }
+
+    // Returns false if a job with the same key is already queued or running
+    fn track_dedup_key(&mut self, job: &ScheduledJob<S>) -> bool {
+        match job.dedup_key() {
+            Some(key) => self.dedup_keys.insert((job.hook_id(), key)),
+            None => true,
+        }
+    }
+
+    fn job_finished(&mut self, job: &ScheduledJob<S>) {
+        if let Some(key) = job.dedup_key() {
+            self.dedup_keys.remove(&(job.hook_id(), key));
+        }
+        job.finished(&self.jobs_context);
+    }
+
+    // Put the highest-priority waiting job for this hook back in the queue
+    fn requeue_waiting(&mut self, hook_id: ScriptId<S>) {
+        let mut push_back = None;
+        if let Some(mut waiting) = self.waiting.get_mut(&hook_id) {
+            push_back = waiting.pop();
+        }
+        if let Some(job) = push_back {
+            self.queue_job(job);
+        }
+    }
+
+    fn retry_job(&mut self, mut job: ScheduledJob<S>) {
+        if job.attempts() < self.retry.max_retries {
+            self.stats.record_retry(job.hook_name(), job.is_status_job());
+
+            let delay = self.retry.delay(job.attempts());
+            job.retry(delay);
+            self.subscribers.emit(JobEvent::Retrying {
+                script: job.hook_name().to_string(),
+                retry: job.attempts(),
+                delay: delay,
+            });
+            self.delayed.push(job);
+        } else {
+            self.stats.record_gave_up(job.hook_name(), job.is_status_job());
+            job.gave_up(&self.jobs_context);
+            self.job_finished(&job);
+            self.subscribers.emit(JobEvent::Dropped {
+                script: job.hook_name().to_string(),
+            });
+        }
+    }
+
+    fn requeue_delayed(&mut self) -> bool {
+        let now = Instant::now();
+        let (ready, delayed): (Vec<_>, Vec<_>) = self.delayed.drain(..)
+            .partition(|job| job.is_ready(now));
+        self.delayed = delayed;
+
+        let requeued = ! ready.is_empty();
+        for job in ready {
+            self.queue_job(job);
+        }
+        requeued
+    }
+
+    fn next_retry(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.delayed.iter().map(|job| job.queued_at()).min().map(|at| {
+            if at > now {
+                at - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
+
     #[inline]
     fn spawn_thread(&mut self) {
         let api = SchedulerInternalApi {
             input: self.input_send.clone(),
         };
 
-        let thread = Thread::new(api, self.jobs_context.clone(), &self.state);
+        let ctx = self.jobs_context.clone();
+        let thread = match self.kind {
+            SchedulerKind::PerThread => Thread::new(api, ctx, &self.state),
+            SchedulerKind::Shared => Thread::new_shared(
+                api, ctx, &self.state,
+                self.shared_send.clone(), self.shared_recv.clone(),
+            ),
+        };
         self.threads.insert(thread.id(), thread);
     }
 
+    fn mark_idle(&mut self, thread_id: UniqueId, hook_id: ScriptId<S>) {
+        match self.kind {
+            SchedulerKind::PerThread => {
+                if let Some(mut thread) = self.threads.get_mut(&thread_id) {
+                    thread.mark_idle();
+                }
+            },
+            SchedulerKind::Shared => {
+                self.busy -= 1;
+
+                let mut finished = false;
+                if let Some(running) = self.running.get_mut(&hook_id) {
+                    *running -= 1;
+                    finished = *running == 0;
+                }
+                if finished {
+                    let _ = self.running.remove(&hook_id);
+                }
+            },
+        }
+    }
+
     fn cleanup_threads(&mut self) {
+        if self.kind == SchedulerKind::Shared {
+            return self.cleanup_shared_threads();
+        }
+
         // This is done in two steps: the list of threads to remove is
         // computed, and then each marked thread is stopped
         let mut to_remove = Vec::with_capacity(self.threads.len());
 
+        let stopping = self.should_stop && ! self.pending_status_jobs();
         let mut remaining = self.threads.len();
         for (id, thread) in self.threads.iter() {
             if thread.busy() {
                 continue;
             }
 
-            if self.should_stop || remaining > self.max_threads as usize {
+            if stopping || remaining > self.max_threads as usize {
                 to_remove.push(*id);
                 remaining -= 1;
             }
@@ -287,10 +747,42 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         }
     }
 
+    fn replace_disconnected_threads(&mut self) -> bool {
+        let disconnected = self.threads.iter()
+            .filter(|&(_, thread)| thread.disconnected())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in &disconnected {
+            if let Some(thread) = self.threads.remove(id) {
+                thread.join();
+            }
+            self.spawn_thread();
+        }
+
+        ! disconnected.is_empty()
+    }
+
+    fn cleanup_shared_threads(&mut self) {
+        // The stop signals can't be sent to a specific thread, so the threads
+        // are stopped only when all of them are idle
+        if ! self.should_stop || self.busy != 0 || self.pending_status_jobs() {
+            return;
+        }
+
+        for _ in 0..self.threads.len() {
+            let _ = self.shared_send.send(ThreadInput::StopSignal);
+        }
+        for (_, thread) in self.threads.drain() {
+            thread.join();
+        }
+    }
+
     fn cleanup_hooks(&mut self) {
         // Get a set of all the queued hooks
-        let mut queued = HashSet::with_capacity(self.queue.len());;
-        for job in self.queue.iter() {
+        let mut queued = self.queue.scripts().into_iter()
+            .collect::<HashSet<_>>();
+        for job in &self.delayed {
             queued.insert(job.hook_id());
         }
 
@@ -316,19 +808,25 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         }
         for hook_id in &to_remove {
             let _ = self.waiting.remove(&hook_id);
+            let _ = self.limits.remove(&hook_id);
         }
 
         // Add new hooks
         for hook in self.hooks.iter() {
-            if hook.can_be_parallel() {
-                continue;
-            }
             if self.waiting.contains_key(&hook.id()) {
                 continue;
             }
 
-            self.waiting.insert(hook.id(), BinaryHeap::new());
+            if let Some(limit) = concurrency_limit(&*hook, self.max_threads) {
+                self.waiting.insert(hook.id(), BinaryHeap::new());
+                self.limits.insert(hook.id(), limit);
+            }
         }
+
+        self.prerequisites = collect_prerequisites(&*self.hooks);
+        self.max_queue_ages = collect_max_queue_ages(&*self.hooks);
+        self.labels = collect_labels(&*self.hooks);
+        self.costs = collect_costs(&*self.hooks, self.max_threads);
     }
 
     fn run_jobs(&mut self) {
@@ -336,11 +834,23 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
             return;
         }
 
+        if self.kind == SchedulerKind::Shared {
+            return self.run_shared_jobs();
+        }
+
         // Here there is a loop so if for some reason there are multiple
         // threads available and there are enough elements in the queue,
         // all of them are processed
         'main: loop {
+            // Don't take jobs out of the queue if no thread can run them, so
+            // their prerequisites are checked only when they're executed
+            if self.threads.values().all(|thread| thread.busy()) {
+                break;
+            }
+
             if let Some(mut job) = self.get_job() {
+                let cost = job.cost();
+
                 // Try to run the job in a thread
                 for thread in self.threads.values_mut() {
                     // The process() method returns Some(ScheduledJob) if
@@ -348,21 +858,43 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                     if let Some(j) = thread.process(job) {
                         job = j;
                     } else {
+                        self.used_capacity += cost;
                         continue 'main;
                     }
                 }
                 self.queue_job(job);
+
+                // Try again if some threads exited and were replaced
+                if self.replace_disconnected_threads() {
+                    continue;
+                }
             }
             break;
         }
     }
 
+    fn run_shared_jobs(&mut self) {
+        // Send jobs to the shared queue only if there are idle threads, so
+        // priorities and limits are still enforced by the scheduler
+        while (self.busy as usize) < self.threads.len() {
+            if let Some(job) = self.get_job() {
+                self.busy += 1;
+                self.used_capacity += job.cost();
+                *self.running.entry(job.hook_id()).or_insert(0) += 1;
+
+                let _ = self.shared_send.send(ThreadInput::Process(job));
+            } else {
+                break;
+            }
+        }
+    }
+
     fn queue_job(&mut self, job: ScheduledJob<S>) {
         let hook_id = job.hook_id();
 
-        // Put the job in waiting if it can't be parallel and
-        // it's already running
-        if self.is_running(hook_id) {
+        // Put the job in waiting if too many instances of the hook are
+        // already running
+        if self.is_at_limit(hook_id) {
             if let Some(mut waiting) = self.waiting.get_mut(&hook_id) {
                 waiting.push(job);
                 return;
@@ -373,19 +905,58 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
     }
 
     fn get_job(&mut self) -> Option<ScheduledJob<S>> {
+        let running = self.running_jobs();
         loop {
-            if let Some(job) = self.queue.pop() {
+            if let Some(mut job) = self.queue.pop(&running) {
                 let hook_id = job.hook_id();
 
-                // Put the job in waiting if it can't be parallel and
-                // it's already running
-                if self.is_running(hook_id) {
+                // Put the job in waiting if too many instances of the hook
+                // are already running
+                if self.is_at_limit(hook_id) {
                     if let Some(mut waiting) = self.waiting.get_mut(&hook_id) {
                         waiting.push(job);
                         continue;
                     }
                 }
 
+                // Discard the job if it waited in the queue for too long
+                if let Err(error) = self.check_queue_age(&job) {
+                    report_not_executed(&job, error);
+                    self.stats.record_expired(
+                        job.hook_name(), job.is_status_job(),
+                    );
+                    self.job_finished(&job);
+                    self.subscribers.emit(JobEvent::Dropped {
+                        script: job.hook_name().to_string(),
+                    });
+                    continue;
+                }
+
+                // Skip the job if the scripts it requires didn't succeed
+                if let Err(error) = self.check_prerequisites(hook_id) {
+                    report_not_executed(&job, error);
+                    self.stats.record_skipped(
+                        job.hook_name(), job.is_status_job(),
+                    );
+                    self.job_finished(&job);
+                    self.subscribers.emit(JobEvent::Dropped {
+                        script: job.hook_name().to_string(),
+                    });
+                    continue;
+                }
+
+                // Leave the job in the queue if there isn't enough capacity
+                // to run it, so cheaper jobs can't starve it
+                let cost = self.costs.get(&hook_id).cloned().unwrap_or(1);
+                let free = self.max_threads.saturating_sub(self.used_capacity);
+                if cost > free {
+                    self.queue.push(job);
+                    return None;
+                }
+
+                job.set_cost(cost);
+                let last_attempt = job.attempts() >= self.retry.max_retries;
+                job.set_last_attempt(last_attempt);
                 return Some(job);
             } else {
                 return None;
@@ -393,14 +964,77 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         }
     }
 
-    fn is_running(&self, hook: ScriptId<S>) -> bool {
-        for thread in self.threads.values() {
-            if thread.currently_running() == Some(hook) {
-                return true;
+    fn check_queue_age(&self, job: &ScheduledJob<S>) -> Result<()> {
+        if let Some(max_age) = self.max_queue_ages.get(&job.hook_id()) {
+            if job.queued_at().elapsed() > *max_age {
+                return Err(ErrorKind::JobExpired(*max_age).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_prerequisites(&self, hook: ScriptId<S>) -> Result<()> {
+        if let Some(needed) = self.prerequisites.get(&hook) {
+            for script in &needed.scripts {
+                // Scripts which never ran count as failed
+                let met = match self.stats.last_run(script) {
+                    Some((ended_at, successful)) => {
+                        successful && needed.within.map(|within| {
+                            ended_at.elapsed() <= within
+                        }).unwrap_or(true)
+                    },
+                    None => false,
+                };
+
+                if ! met {
+                    return Err(
+                        ErrorKind::PrerequisiteNotMet(script.clone()).into()
+                    );
+                }
             }
         }
 
-        return false;
+        Ok(())
+    }
+
+    // Return how many jobs of each hook are running
+    fn running_jobs(&self) -> HashMap<ScriptId<S>, usize> {
+        match self.kind {
+            SchedulerKind::PerThread => {
+                let mut running = HashMap::new();
+                for thread in self.threads.values() {
+                    if let Some(hook) = thread.currently_running() {
+                        *running.entry(hook).or_insert(0) += 1;
+                    }
+                }
+                running
+            },
+            SchedulerKind::Shared => {
+                self.running.iter()
+                    .map(|(hook, count)| (*hook, *count as usize))
+                    .collect()
+            },
+        }
+    }
+
+    fn is_at_limit(&self, hook: ScriptId<S>) -> bool {
+        if let Some(limit) = self.limits.get(&hook) {
+            let running = match self.kind {
+                SchedulerKind::PerThread => {
+                    self.threads.values()
+                        .filter(|t| t.currently_running() == Some(hook))
+                        .count()
+                },
+                SchedulerKind::Shared => {
+                    self.running.get(&hook).cloned().unwrap_or(0) as usize
+                },
+            };
+
+            running >= *limit as usize
+        } else {
+            false
+        }
     }
 }
 
@@ -409,12 +1043,18 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
 mod tests {
     use std::collections::VecDeque;
     use std::sync::{Arc, Mutex, mpsc};
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use fisher_common::prelude::*;
     use fisher_common::state::State;
+    use fisher_common::structs::{ConcurrencyLimit, JobCounters};
+    use fisher_common::structs::Prerequisites;
 
     use super::super::test_utils::*;
-    use super::super::Processor;
+    use super::super::{Processor, RetryPolicy, LoadShedding, JobEvent};
+    use super::super::ProcessorConfig;
+    use super::SchedulerKind;
 
 
     #[test]
@@ -424,7 +1064,10 @@ mod tests {
             let repo = Arc::new(Repository::<()>::new());
 
             let processor = Processor::new(
-                1, repo, Arc::new(()), Arc::new(State::new()),
+                repo, Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
             ).unwrap();
             processor.stop()?;
 
@@ -447,7 +1090,10 @@ mod tests {
 
             let repo = Arc::new(repo);
             let processor = Processor::new(
-                1, repo.clone(), Arc::new(()), Arc::new(State::new()),
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
             )?;
 
             processor.api().queue(
@@ -469,7 +1115,54 @@ mod tests {
     }
 
 
-    fn run_multiple_append(threads: u16, prioritized: bool) -> Result<String> {
+    #[test]
+    fn test_processor_stop_timeout() {
+        test_wrapper(|| {
+
+            let repo = Repository::<()>::new();
+
+            let (started_send, started_recv) = mpsc::channel();
+            let (release_send, release_recv) = mpsc::channel::<()>();
+            let release_recv = Mutex::new(release_recv);
+            repo.add_script("stuck", true, move |_| {
+                started_send.send(())?;
+                release_recv.lock()?.recv()?;
+                Ok(())
+            });
+            repo.add_script("quick", true, |_| Ok(()));
+
+            let repo = Arc::new(repo);
+            let new_processor = || Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
+            );
+
+            // Processors without running jobs stop cleanly
+            let processor = new_processor()?;
+            processor.api().queue(repo.job("quick", ()).unwrap(), 0)?;
+            assert!(processor.stop_timeout(Duration::from_secs(5))?);
+
+            // Stuck jobs are abandoned after the timeout
+            let processor = new_processor()?;
+            processor.api().queue(repo.job("stuck", ()).unwrap(), 0)?;
+            started_recv.recv_timeout(Duration::from_secs(5)).unwrap();
+
+            let start = Instant::now();
+            assert!(! processor.stop_timeout(Duration::from_millis(100))?);
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            // Let the abandoned thread exit
+            release_send.send(())?;
+
+            Ok(())
+        });
+    }
+
+
+    fn run_multiple_append(threads: u16, prioritized: bool,
+                           kind: SchedulerKind) -> Result<String> {
         let repo = Repository::<char>::new();
 
         let (append_send, append_recv) = mpsc::channel();
@@ -480,7 +1173,12 @@ mod tests {
 
         let repo = Arc::new(repo);
         let processor = Processor::new(
-            threads, repo.clone(), Arc::new(()), Arc::new(State::new()),
+            repo.clone(), Arc::new(()), Arc::new(State::new()),
+            ProcessorConfig {
+                max_threads: threads,
+                scheduler: kind,
+                .. ProcessorConfig::default()
+            },
         )?;
 
         let api = processor.api();
@@ -517,22 +1215,78 @@ mod tests {
 
     #[test]
     fn test_processor_one_thread_correct_order() {
-        let output = run_multiple_append(1, false).unwrap();
-        assert_eq!(output.as_str(), "0123456789");
+        for kind in &[SchedulerKind::PerThread, SchedulerKind::Shared] {
+            let output = run_multiple_append(1, false, *kind).unwrap();
+            assert_eq!(output.as_str(), "0123456789");
+        }
     }
 
 
     #[test]
     fn test_processor_one_thread_correct_order_prioritized() {
-        let output = run_multiple_append(1, true).unwrap();
-        assert_eq!(output.as_str(), "8967452301");
+        for kind in &[SchedulerKind::PerThread, SchedulerKind::Shared] {
+            let output = run_multiple_append(1, true, *kind).unwrap();
+            assert_eq!(output.as_str(), "8967452301");
+        }
     }
 
 
     #[test]
     fn test_processor_multiple_threads() {
-        let output = run_multiple_append(4, false).unwrap();
-        assert_eq!(output.len(), 10);
+        for kind in &[SchedulerKind::PerThread, SchedulerKind::Shared] {
+            let output = run_multiple_append(4, false, *kind).unwrap();
+            assert_eq!(output.len(), 10);
+        }
+    }
+
+
+    fn run_many_jobs(kind: SchedulerKind, count: usize) -> Result<Vec<usize>> {
+        let repo = Repository::<usize>::new();
+
+        let (done_send, done_recv) = mpsc::channel();
+        repo.add_script("count", true, move |arg| {
+            done_send.send(arg)?;
+            Ok(())
+        });
+
+        let repo = Arc::new(repo);
+        let processor = Processor::new(
+            repo.clone(), Arc::new(()), Arc::new(State::new()),
+            ProcessorConfig {
+                max_threads: 8,
+                scheduler: kind,
+                .. ProcessorConfig::default()
+            },
+        )?;
+        let api = processor.api();
+
+        for i in 0..count {
+            api.queue(repo.job("count", i).unwrap(), 0)?;
+        }
+
+        processor.stop()?;
+
+        // Count how many times each job was executed
+        let mut executions = vec![0; count];
+        while let Ok(i) = done_recv.try_recv() {
+            executions[i] += 1;
+        }
+        Ok(executions)
+    }
+
+
+    #[test]
+    fn test_shared_queue_runs_jobs_once() {
+        let executions = run_many_jobs(SchedulerKind::Shared, 1000).unwrap();
+        assert!(executions.iter().all(|count| *count == 1));
+    }
+
+
+    #[test]
+    fn test_per_thread_queue_runs_jobs_once() {
+        let executions = run_many_jobs(SchedulerKind::PerThread, 1000)
+            .unwrap();
+        assert!(executions.iter().all(|count| *count == 1));
     }
 
     #[test]
@@ -548,7 +1302,11 @@ mod tests {
 
             let repo = Arc::new(repo);
             let processor = Processor::new(
-                2, repo.clone(), Arc::new(()), Arc::new(State::new()),
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    max_threads: 2,
+                    .. ProcessorConfig::default()
+                },
             )?;
             let api = processor.api();
 
@@ -594,23 +1352,295 @@ mod tests {
         });
     }
 
-    #[test]
-    fn test_health_details() {
-        test_wrapper(|| {
-            let repo = Repository::<
-                Option<Arc<Mutex<mpsc::Receiver<()>>>>
-            >::new();
+    fn busy_threads_with_limit(threads: u16, parallel: bool,
+                               limit: Option<ConcurrencyLimit>) -> Result<u16> {
+        busy_threads_with_limit_kind(
+            SchedulerKind::PerThread, threads, parallel, limit,
+        )
+    }
 
-            repo.add_script("noop", true, |_| { Ok(()) });
-            repo.add_script("wait", true, |recv| {
-                let recv = recv.unwrap();
-                recv.lock()?.recv()?;
+    fn busy_threads_with_limit_kind(kind: SchedulerKind, threads: u16,
+                                    parallel: bool,
+                                    limit: Option<ConcurrencyLimit>)
+                                    -> Result<u16> {
+        let repo = Repository::<Arc<Mutex<mpsc::Receiver<()>>>>::new();
+
+        let (done_send, done_recv) = mpsc::channel();
+        repo.add_limited_script("wait", parallel, limit, move |recv| {
+            recv.lock()?.recv()?;
+            done_send.send(())?;
+            Ok(())
+        });
+
+        let repo = Arc::new(repo);
+        let processor = Processor::new(
+            repo.clone(), Arc::new(()), Arc::new(State::new()),
+            ProcessorConfig {
+                max_threads: threads,
+                scheduler: kind,
+                .. ProcessorConfig::default()
+            },
+        )?;
+        let api = processor.api();
+
+        // Queue twice the jobs the processor is able to run
+        let mut waiters = Vec::new();
+        for _ in 0..(threads * 2) {
+            let (unlock_send, unlock_recv) = mpsc::channel();
+
+            api.queue(
+                repo.job("wait", Arc::new(Mutex::new(unlock_recv))).unwrap(),
+                0,
+            )?;
+            waiters.push(unlock_send);
+        }
+
+        let status = api.health_details()?;
+        assert_eq!(
+            status.queued_jobs + status.busy_threads as usize,
+            threads as usize * 2
+        );
+
+        // Let all the jobs complete
+        for waiter in waiters.drain(..) {
+            waiter.send(())?;
+        }
+        processor.stop()?;
+
+        // Every job should have been executed, even the ones waiting
+        assert_eq!(done_recv.try_iter().count(), threads as usize * 2);
+
+        Ok(status.busy_threads)
+    }
+
+
+    #[test]
+    fn test_limited_parallel_processing() {
+        test_wrapper(|| {
+            // Without a limit all the threads are used
+            assert_eq!(busy_threads_with_limit(4, true, None)?, 4);
+
+            // Absolute limits
+            assert_eq!(busy_threads_with_limit(
+                4, true, Some(ConcurrencyLimit::Threads(2)),
+            )?, 2);
+            assert_eq!(busy_threads_with_limit(
+                4, true, Some(ConcurrencyLimit::Threads(10)),
+            )?, 4);
+
+            // Limits relative to the number of threads
+            assert_eq!(busy_threads_with_limit(
+                4, true, Some(ConcurrencyLimit::Fraction(0.75)),
+            )?, 3);
+            assert_eq!(busy_threads_with_limit(
+                4, true, Some(ConcurrencyLimit::Fraction(0.1)),
+            )?, 1);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_limited_non_parallel_processing() {
+        test_wrapper(|| {
+            // Non-parallel hooks are never run more than once at a time,
+            // even if their limit is higher
+            assert_eq!(busy_threads_with_limit(4, false, None)?, 1);
+            assert_eq!(busy_threads_with_limit(
+                4, false, Some(ConcurrencyLimit::Threads(3)),
+            )?, 1);
+            assert_eq!(busy_threads_with_limit(
+                4, false, Some(ConcurrencyLimit::Fraction(1.0)),
+            )?, 1);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_shared_queue_limits() {
+        test_wrapper(|| {
+            let shared = SchedulerKind::Shared;
+
+            // The limits are enforced with the shared queue too
+            assert_eq!(busy_threads_with_limit_kind(
+                shared, 4, true, None,
+            )?, 4);
+            assert_eq!(busy_threads_with_limit_kind(
+                shared, 4, true, Some(ConcurrencyLimit::Threads(2)),
+            )?, 2);
+            assert_eq!(busy_threads_with_limit_kind(
+                shared, 4, false, None,
+            )?, 1);
+
+            Ok(())
+        });
+    }
+
+
+    fn busy_and_queued_with_costs(kind: SchedulerKind, jobs: &[&str])
+                                  -> Result<(u16, usize)> {
+        let repo = Repository::<Arc<Mutex<mpsc::Receiver<()>>>>::new();
+
+        let (done_send, done_recv) = mpsc::channel();
+        for &(name, cost) in &[("light", 1), ("heavy", 3), ("huge", 10)] {
+            let done_send = done_send.clone();
+            repo.add_costly_script(name, cost, move |recv| {
+                recv.lock()?.recv()?;
+                done_send.send(())?;
+                Ok(())
+            });
+        }
+
+        let repo = Arc::new(repo);
+        let processor = Processor::new(
+            repo.clone(), Arc::new(()), Arc::new(State::new()),
+            ProcessorConfig {
+                max_threads: 4,
+                scheduler: kind,
+                .. ProcessorConfig::default()
+            },
+        )?;
+        let api = processor.api();
+
+        let mut waiters = Vec::new();
+        for name in jobs {
+            let (unlock_send, unlock_recv) = mpsc::channel();
+
+            api.queue(
+                repo.job(name, Arc::new(Mutex::new(unlock_recv))).unwrap(),
+                0,
+            )?;
+            waiters.push(unlock_send);
+        }
+
+        let status = api.health_details()?;
+
+        // Let all the jobs complete
+        for waiter in waiters.drain(..) {
+            waiter.send(())?;
+        }
+        processor.stop()?;
+
+        // Every job should have been executed, even the ones waiting
+        assert_eq!(done_recv.try_iter().count(), jobs.len());
+
+        Ok((status.busy_threads, status.queued_jobs))
+    }
+
+
+    #[test]
+    fn test_job_costs() {
+        test_wrapper(|| {
+            for kind in &[SchedulerKind::PerThread, SchedulerKind::Shared] {
+                // Jobs costing 1 use a thread each
+                assert_eq!(busy_and_queued_with_costs(
+                    *kind, &["light", "light", "light", "light", "light"],
+                )?, (4, 1));
+
+                // The second heavy job doesn't fit in the capacity left, and
+                // the jobs queued after it wait for it
+                assert_eq!(busy_and_queued_with_costs(
+                    *kind, &["heavy", "light", "heavy", "light"],
+                )?, (2, 2));
+
+                // Jobs costing more than the capacity run alone
+                assert_eq!(busy_and_queued_with_costs(
+                    *kind, &["huge", "light"],
+                )?, (1, 1));
+            }
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_crashed_threads_replaced() {
+        test_wrapper(|| {
+            for kind in &[SchedulerKind::PerThread, SchedulerKind::Shared] {
+                let repo = Repository::<Option<mpsc::Sender<()>>>::new();
+                repo.add_costly_script("job", 2, |done| {
+                    if let Some(done) = done {
+                        done.send(())?;
+                    }
+                    Ok(())
+                });
+
+                let repo = Arc::new(repo);
+                let processor = Processor::new(
+                    repo.clone(), Arc::new(()), Arc::new(State::new()),
+                    ProcessorConfig {
+                        max_threads: 2,
+                        scheduler: *kind,
+                        .. ProcessorConfig::default()
+                    },
+                )?;
+                let api = processor.api();
+
+                // The job crashing the thread uses all the capacity, which
+                // must be released for the next job to run
+                api.queue(
+                    repo.crashing_job("job", None).unwrap()
+                        .with_dedup_key("key"),
+                    0,
+                )?;
+                let (done_send, done_recv) = mpsc::channel();
+                api.queue(repo.job("job", Some(done_send)).unwrap(), 0)?;
+                let timeout = Duration::from_secs(5);
+                assert!(done_recv.recv_timeout(timeout).is_ok());
+
+                // The crashed thread isn't counted as busy anymore
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while api.health_details()?.busy_threads != 0 {
+                    assert!(Instant::now() < deadline, "still busy");
+                    thread::sleep(Duration::from_millis(10));
+                }
+
+                // The deduplication key of the crashed job is released
+                let (done_send, done_recv) = mpsc::channel();
+                api.queue(
+                    repo.dedup_job("job", Some(done_send), "key").unwrap(), 0,
+                )?;
+                assert!(done_recv.recv_timeout(timeout).is_ok());
+
+                // And the crashed job isn't pending anymore
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while repo.pending("job") != 0 {
+                    assert!(Instant::now() < deadline, "still pending");
+                    thread::sleep(Duration::from_millis(10));
+                }
+
+                processor.stop()?;
+            }
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_health_details() {
+        test_wrapper(|| {
+            let repo = Repository::<
+                Option<Arc<Mutex<mpsc::Receiver<()>>>>
+            >::new();
+
+            repo.add_script("noop", true, |_| { Ok(()) });
+            repo.add_script("wait", true, |recv| {
+                let recv = recv.unwrap();
+                recv.lock()?.recv()?;
                 Ok(())
             });
 
             let repo = Arc::new(repo);
             let processor = Processor::new(
-                1, repo.clone(), Arc::new(()), Arc::new(State::new()),
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
             )?;
             let api = processor.api();
 
@@ -643,6 +1673,125 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_labeled_health_details() {
+        test_wrapper(|| {
+            let repo = Repository::<
+                Option<Arc<Mutex<mpsc::Receiver<()>>>>
+            >::new();
+
+            repo.add_labeled_script("critical", &["critical"], |recv| {
+                if let Some(recv) = recv {
+                    recv.lock()?.recv()?;
+                }
+                Ok(())
+            });
+            repo.add_labeled_script(
+                "background", &["background", "slow"], |_| { Ok(()) },
+            );
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            // Keep the only thread busy with a critical job
+            let (waiting_send, waiting_recv) = mpsc::channel();
+            api.queue(repo.job("critical", Some(
+                Arc::new(Mutex::new(waiting_recv))
+            )).unwrap(), 0)?;
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while api.health_details()?.busy_threads == 0 {
+                assert!(Instant::now() < deadline, "the job wasn't started");
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            api.queue(repo.job("critical", None).unwrap(), 0)?;
+            for _ in 0..3 {
+                api.queue(repo.job("background", None).unwrap(), 0)?;
+            }
+
+            // Only the jobs of the scripts with the label are counted
+            let status = api.labeled_health_details("critical")?;
+            assert_eq!(status.queued_jobs, 1);
+            assert_eq!(status.busy_threads, 1);
+            assert_eq!(status.max_threads, 1);
+
+            for label in &["background", "slow"] {
+                let status = api.labeled_health_details(label)?;
+                assert_eq!(status.queued_jobs, 3);
+                assert_eq!(status.busy_threads, 0);
+            }
+
+            let status = api.labeled_health_details("missing")?;
+            assert_eq!(status.queued_jobs, 0);
+            assert_eq!(status.busy_threads, 0);
+
+            // Without a label every job is counted
+            let status = api.health_details()?;
+            assert_eq!(status.queued_jobs, 4);
+            assert_eq!(status.busy_threads, 1);
+
+            waiting_send.send(())?;
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_health_details_stats() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+            repo.add_script("noop", true, |_| { Ok(()) });
+            repo.add_script("unused", true, |_| { Ok(()) });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            for _ in 0..10 {
+                api.queue(repo.job("noop", ()).unwrap(), 0)?;
+            }
+
+            // Wait until all the jobs are processed
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let mut status;
+            loop {
+                status = api.health_details()?;
+                if status.queued_jobs == 0 && status.busy_threads == 0 {
+                    break;
+                }
+
+                assert!(Instant::now() < deadline, "the jobs weren't run");
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            // Only the executed hook should have statistics
+            assert_eq!(status.hooks.len(), 1);
+            let stats = status.hooks.get("noop").unwrap();
+            assert!(stats.queue_time.p50 <= stats.queue_time.p95);
+            assert!(stats.queue_time.p95 <= stats.queue_time.p99);
+            assert!(stats.execution_time.p50 <= stats.execution_time.p95);
+            assert!(stats.execution_time.p95 <= stats.execution_time.p99);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_cleanup_hooks() {
         test_wrapper(|| {
@@ -655,7 +1804,10 @@ mod tests {
 
             let repo = Arc::new(repo);
             let processor = Processor::new(
-                1, repo.clone(), Arc::new(()), Arc::new(State::new()),
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
             )?;
             let api = processor.api();
 
@@ -726,4 +1878,778 @@ mod tests {
             Ok(())
         });
     }
+
+
+    #[test]
+    fn test_status_jobs_retries() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+
+            let (status_send, status_recv) = mpsc::channel();
+            repo.add_script("trigger", true, |_| Ok(()));
+            repo.add_script("failing-status", true, move |_| {
+                status_send.send(())?;
+                Err(ErrorKind::Dummy.into())
+            });
+            repo.add_status_job("failing-status", ());
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    retry: RetryPolicy {
+                        max_retries: 2,
+                        base_delay: Duration::from_millis(10),
+                        max_delay: Duration::from_millis(50),
+                    },
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            api.queue(repo.job("trigger", ()).unwrap(), 0)?;
+
+            // The status job should be executed once, and then retried twice
+            for _ in 0..3 {
+                status_recv.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+
+            // Wait until the processor gives up on the status job
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let stats = loop {
+                let details = api.health_details()?;
+                if let Some(stats) = details.hooks.get("failing-status") {
+                    if stats.status_jobs.gave_up == 1 {
+                        break stats.clone();
+                    }
+                }
+
+                assert!(Instant::now() < deadline, "the job wasn't given up");
+                thread::sleep(Duration::from_millis(10));
+            };
+
+            // The failures are counted as status jobs
+            assert_eq!(stats.jobs, JobCounters::default());
+            assert_eq!(stats.status_jobs, JobCounters {
+                executed: 3,
+                failed: 3,
+                retried: 2,
+                gave_up: 1,
+                skipped: 0,
+                expired: 0,
+            });
+
+            // No more retries should happen
+            assert!(status_recv.recv_timeout(
+                Duration::from_millis(200)
+            ).is_err());
+            assert_eq!(api.health_details()?.queued_jobs, 0);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_stop_waits_for_status_jobs() {
+        test_wrapper(|| {
+            for kind in &[SchedulerKind::PerThread, SchedulerKind::Shared] {
+                let repo = Repository::<()>::new();
+
+                let (status_send, status_recv) = mpsc::channel();
+                let attempts = Arc::new(Mutex::new(0));
+                repo.add_script("trigger", true, |_| Ok(()));
+                repo.add_script("flaky-status", true, move |_| {
+                    let mut attempts = attempts.lock().unwrap();
+                    *attempts += 1;
+                    status_send.send(*attempts)?;
+
+                    // Fail the first time, so the job is retried later
+                    if *attempts == 1 {
+                        Err(ErrorKind::Dummy.into())
+                    } else {
+                        Ok(())
+                    }
+                });
+                repo.add_status_job("flaky-status", ());
+
+                let repo = Arc::new(repo);
+                let processor = Processor::new(
+                    repo.clone(), Arc::new(()), Arc::new(State::new()),
+                    ProcessorConfig {
+                        max_threads: 2,
+                        retry: RetryPolicy {
+                            max_retries: 1,
+                            base_delay: Duration::from_millis(200),
+                            max_delay: Duration::from_millis(200),
+                        },
+                        scheduler: *kind,
+                        .. ProcessorConfig::default()
+                    },
+                )?;
+
+                processor.api().queue(repo.job("trigger", ()).unwrap(), 0)?;
+                assert_eq!(
+                    status_recv.recv_timeout(Duration::from_secs(5)).unwrap(),
+                    1
+                );
+
+                // The processor should wait for the retry of the status job
+                // before stopping
+                processor.stop()?;
+                assert_eq!(status_recv.try_recv().unwrap(), 2);
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_gave_up_jobs() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+            repo.add_script("failing", true, |_| {
+                Err(ErrorKind::Dummy.into())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    retry: RetryPolicy {
+                        max_retries: 1,
+                        base_delay: Duration::from_millis(10),
+                        max_delay: Duration::from_millis(50),
+                    },
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            api.queue(repo.job("failing", ()).unwrap(), 0)?;
+
+            // Wait until the processor gives up on the job
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let details = api.health_details()?;
+                if let Some(stats) = details.hooks.get("failing") {
+                    if stats.jobs.gave_up == 1 {
+                        break;
+                    }
+                }
+
+                assert!(Instant::now() < deadline, "the job wasn't given up");
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            // The job is notified with the last error
+            assert_eq!(
+                repo.gave_up("failing"),
+                vec![("dummy_error".to_string(), 2)]
+            );
+
+            // Only the last attempt is reported as a permanent failure
+            assert_eq!(
+                repo.failed_permanently("failing"),
+                vec![Some("dummy_error".to_string())]
+            );
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_finished_jobs() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+            repo.add_script("working", true, |_| Ok(()));
+            repo.add_script("failing", true, |_| {
+                Err(ErrorKind::Dummy.into())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    retry: RetryPolicy {
+                        max_retries: 1,
+                        base_delay: Duration::from_millis(10),
+                        max_delay: Duration::from_millis(50),
+                    },
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            // Jobs are pending until they're executed
+            api.lock()?;
+            api.queue(repo.job("working", ()).unwrap(), 0)?;
+            api.queue(repo.job("failing", ()).unwrap(), 0)?;
+            api.health_details()?;
+            assert_eq!(repo.pending("working"), 1);
+            assert_eq!(repo.pending("failing"), 1);
+            api.unlock()?;
+
+            // Failed jobs are finished when the processor gives up
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let details = api.health_details()?;
+                if let Some(stats) = details.hooks.get("failing") {
+                    if stats.jobs.gave_up == 1 {
+                        break;
+                    }
+                }
+
+                assert!(Instant::now() < deadline, "the job wasn't given up");
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(repo.pending("working"), 0);
+            assert_eq!(repo.pending("failing"), 0);
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_prerequisites() {
+        test_wrapper(|| {
+            let repo = Repository::<bool>::new();
+
+            let (deploy_send, deploy_recv) = mpsc::channel();
+            repo.add_script("build", true, |succeed| {
+                if succeed {
+                    Ok(())
+                } else {
+                    Err(ErrorKind::Dummy.into())
+                }
+            });
+            repo.add_dependent_script("deploy", Prerequisites {
+                scripts: vec!["build".into()],
+                within: Some(Duration::from_millis(500)),
+            }, move |_| {
+                deploy_send.send(())?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            // Wait until the processor skipped the provided number of jobs
+            let wait_skipped = |count| -> Result<()> {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                loop {
+                    let details = api.health_details()?;
+                    if let Some(stats) = details.hooks.get("deploy") {
+                        if stats.jobs.skipped == count {
+                            return Ok(());
+                        }
+                    }
+
+                    assert!(Instant::now() < deadline, "no job skipped");
+                    thread::sleep(Duration::from_millis(10));
+                }
+            };
+
+            // The job is skipped if the prerequisite never ran
+            api.queue(repo.job("deploy", false).unwrap(), 0)?;
+            wait_skipped(1)?;
+
+            // The job is skipped if the prerequisite failed
+            api.queue(repo.job("build", false).unwrap(), 0)?;
+            api.queue(repo.job("deploy", false).unwrap(), 0)?;
+            wait_skipped(2)?;
+
+            // The job is executed if the prerequisite succeeded
+            api.queue(repo.job("build", true).unwrap(), 0)?;
+            api.queue(repo.job("deploy", false).unwrap(), 0)?;
+            deploy_recv.recv_timeout(Duration::from_secs(5)).unwrap();
+
+            // The job is skipped if the success is too old
+            ::std::thread::sleep(Duration::from_millis(600));
+            api.queue(repo.job("deploy", false).unwrap(), 0)?;
+            wait_skipped(3)?;
+            assert!(deploy_recv.try_recv().is_err());
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_max_queue_age() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+
+            let (unlock_send, unlock_recv) = mpsc::channel();
+            let unlock_recv = Mutex::new(unlock_recv);
+            repo.add_script("block", true, move |_| {
+                unlock_recv.lock().unwrap().recv()?;
+                Ok(())
+            });
+
+            let (deploy_send, deploy_recv) = mpsc::channel();
+            repo.add_expiring_script(
+                "deploy", Duration::from_millis(100), move |_| {
+                    deploy_send.send(())?;
+                    Ok(())
+                }
+            );
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            // Jobs which don't wait too long are executed
+            api.queue(repo.job("deploy", ()).unwrap(), 0)?;
+            deploy_recv.recv_timeout(Duration::from_secs(5)).unwrap();
+
+            // Keep the only thread busy while the job ages in the queue
+            api.queue(repo.job("block", ()).unwrap(), 0)?;
+            api.queue(repo.job("deploy", ()).unwrap(), 0)?;
+            ::std::thread::sleep(Duration::from_millis(200));
+            unlock_send.send(())?;
+
+            // The job is dropped instead of being executed
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let details = api.health_details()?;
+                if details.hooks["deploy"].jobs.expired == 1 {
+                    assert_eq!(details.hooks["deploy"].jobs.executed, 1);
+                    break;
+                }
+
+                assert!(Instant::now() < deadline, "the job didn't expire");
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(deploy_recv.try_recv().is_err());
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_load_shedding() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+
+            let (unlock_send, unlock_recv) = mpsc::channel();
+            let unlock_recv = Mutex::new(unlock_recv);
+            repo.add_script("block", true, move |_| {
+                unlock_recv.lock().unwrap().recv()?;
+                Ok(())
+            });
+            repo.add_script("job", true, |_| Ok(()));
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    shedding: Some(LoadShedding {
+                        high_watermark: 2,
+                        low_watermark: 1,
+                        min_priority: 0,
+                    }),
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            // Keep the only thread busy while the queue fills up
+            api.queue(repo.job("block", ()).unwrap(), 0)?;
+            for _ in 0..3 {
+                api.queue(repo.job("job", ()).unwrap(), 0)?;
+            }
+            // Wait for the scheduler to process all the jobs
+            assert_eq!(api.health_details()?.queued_jobs, 3);
+
+            // Only the low-priority jobs are rejected
+            match api.queue(repo.job("job", ()).unwrap(), -1) {
+                Err(error) => match *error.kind() {
+                    ErrorKind::Overloaded => {},
+                    _ => panic!("wrong error: {}", error),
+                },
+                Ok(..) => panic!("the job wasn't rejected"),
+            }
+            api.queue(repo.job("job", ()).unwrap(), 0)?;
+            api.queue(repo.job("job", ()).unwrap(), 10)?;
+
+            // Low-priority jobs are accepted again once the queue drains
+            unlock_send.send(())?;
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while api.health_details()?.queued_jobs > 0 {
+                assert!(Instant::now() < deadline, "the queue didn't drain");
+                thread::sleep(Duration::from_millis(10));
+            }
+            api.queue(repo.job("job", ()).unwrap(), -1)?;
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_max_queue_size() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+
+            let (unlock_send, unlock_recv) = mpsc::channel();
+            let unlock_recv = Mutex::new(unlock_recv);
+            repo.add_script("block", true, move |_| {
+                unlock_recv.lock().unwrap().recv()?;
+                Ok(())
+            });
+            repo.add_script("job", true, |_| Ok(()));
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    max_queue_size: Some(2),
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            // The running jobs don't count
+            api.queue(repo.job("block", ()).unwrap(), 0)?;
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while api.health_details()?.busy_threads == 0 {
+                assert!(Instant::now() < deadline, "the job wasn't started");
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            // Every job is rejected once the queue is full, even the ones
+            // not received by the scheduler yet
+            api.queue(repo.job("job", ()).unwrap(), 0)?;
+            api.queue(repo.job("job", ()).unwrap(), 0)?;
+            match api.queue(repo.job("job", ()).unwrap(), 10) {
+                Err(error) => match *error.kind() {
+                    ErrorKind::QueueFull => {},
+                    _ => panic!("wrong error: {}", error),
+                },
+                Ok(..) => panic!("the job wasn't rejected"),
+            }
+            assert!(api.queue_wait(repo.job("job", ()).unwrap(), 0).is_err());
+            assert_eq!(api.health_details()?.queued_jobs, 2);
+
+            // Jobs are accepted again once the queue drains
+            unlock_send.send(())?;
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while api.health_details()?.queued_jobs > 0 {
+                assert!(Instant::now() < deadline, "the queue didn't drain");
+                thread::sleep(Duration::from_millis(10));
+            }
+            api.queue(repo.job("job", ()).unwrap(), 0)?;
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_job_events() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+            repo.add_script("passing", true, |_| Ok(()));
+            repo.add_script("failing", true, |_| {
+                Err(ErrorKind::Dummy.into())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            let (events_send, events_recv) = mpsc::channel();
+            api.subscribe(events_send)?;
+
+            let next = || {
+                events_recv.recv_timeout(Duration::from_secs(5)).unwrap()
+            };
+            let script = |name: &str| name.to_string();
+
+            api.queue(repo.job("passing", ()).unwrap(), 0)?;
+            assert_eq!(next(), JobEvent::Queued {
+                script: script("passing"),
+            });
+            let thread = match next() {
+                JobEvent::Started { script, thread } => {
+                    assert_eq!(script, "passing");
+                    thread
+                },
+                other => panic!("unexpected event: {:?}", other),
+            };
+            match next() {
+                JobEvent::Succeeded { script, code, .. } => {
+                    assert_eq!(script, "passing");
+                    assert_eq!(code, None);
+                },
+                other => panic!("unexpected event: {:?}", other),
+            }
+
+            api.queue(repo.job("failing", ()).unwrap(), 0)?;
+            assert_eq!(next(), JobEvent::Queued {
+                script: script("failing"),
+            });
+            // There is only one thread, so it executes every job
+            assert_eq!(next(), JobEvent::Started {
+                script: script("failing"),
+                thread: thread,
+            });
+            assert_eq!(next(), JobEvent::Failed {
+                script: script("failing"),
+                error: Error::new(ErrorKind::Dummy).to_string(),
+            });
+            // There are no retries, so the job is dropped right away
+            assert_eq!(next(), JobEvent::Dropped {
+                script: script("failing"),
+            });
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_job_panic() {
+        test_wrapper(|| {
+            for kind in &[SchedulerKind::PerThread, SchedulerKind::Shared] {
+                // The panic poisons the lock of the script, so a new one is
+                // needed every time
+                let repo = Repository::<()>::new();
+                repo.add_script("panicking", true, |_| panic!("malformed"));
+                repo.add_script("passing", true, |_| Ok(()));
+                let repo = Arc::new(repo);
+
+                let processor = Processor::new(
+                    repo.clone(), Arc::new(()), Arc::new(State::new()),
+                    ProcessorConfig {
+                        scheduler: *kind,
+                        .. ProcessorConfig::default()
+                    },
+                )?;
+                let api = processor.api();
+
+                let (events_send, events_recv) = mpsc::channel();
+                api.subscribe(events_send)?;
+                let mut next_ended = || loop {
+                    match events_recv.recv_timeout(Duration::from_secs(5)) {
+                        Ok(JobEvent::Queued { .. }) |
+                        Ok(JobEvent::Started { .. }) => continue,
+                        Ok(event) => return event,
+                        Err(error) => panic!("no event received: {}", error),
+                    }
+                };
+
+                // The panic is reported as a failure of the job
+                api.queue(repo.job("panicking", ()).unwrap(), 0)?;
+                assert_eq!(next_ended(), JobEvent::Failed {
+                    script: "panicking".into(),
+                    error: "the job panicked: malformed".into(),
+                });
+                assert_eq!(next_ended(), JobEvent::Dropped {
+                    script: "panicking".into(),
+                });
+
+                // The only thread is still available for the other jobs
+                api.queue(repo.job("passing", ()).unwrap(), 0)?;
+                match next_ended() {
+                    JobEvent::Succeeded { ref script, .. } => {
+                        assert_eq!(script, "passing");
+                    },
+                    other => panic!("unexpected event: {:?}", other),
+                }
+                assert_eq!(api.health_details()?.busy_threads, 0);
+
+                processor.stop()?;
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_history() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+            repo.add_script("passing", true, |_| Ok(()));
+            repo.add_script("failing", true, |_| {
+                Err(ErrorKind::Dummy.into())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    retry: RetryPolicy {
+                        max_retries: 1,
+                        base_delay: Duration::from_millis(10),
+                        max_delay: Duration::from_millis(50),
+                    },
+                    history_size: 2,
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            let (events_send, events_recv) = mpsc::channel();
+            api.subscribe(events_send)?;
+
+            // Wait for the failing job to be dropped after its retry
+            api.queue(repo.job("passing", ()).unwrap(), 0)?;
+            api.queue(repo.job("failing", ()).unwrap(), 0)?;
+            let timeout = Duration::from_secs(5);
+            while let Ok(event) = events_recv.recv_timeout(timeout) {
+                if let JobEvent::Dropped { .. } = event {
+                    break;
+                }
+            }
+
+            let history = api.history("passing")?;
+            assert_eq!(history.len(), 1);
+            assert!(history[0].successful);
+            assert!(! history[0].retry);
+
+            let history = api.history("failing")?;
+            assert_eq!(history.len(), 2);
+            assert!(! history[0].successful);
+            assert!(! history[0].retry);
+            assert!(! history[1].successful);
+            assert!(history[1].retry);
+
+            assert!(api.history("missing")?.is_empty());
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_dedup_keys() {
+        test_wrapper(|| {
+            let gate = Arc::new(Mutex::new(()));
+            let executed = Arc::new(Mutex::new(Vec::new()));
+
+            let repo = Repository::<&'static str>::new();
+            let gate_inner = gate.clone();
+            let executed_inner = executed.clone();
+            repo.add_script("deploy", true, move |name| {
+                drop(gate_inner.lock().unwrap());
+                executed_inner.lock().unwrap().push(name);
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    max_threads: 2,
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+
+            let (events_send, events_recv) = mpsc::channel();
+            api.subscribe(events_send)?;
+            let wait_succeeded = |mut count: usize| {
+                let timeout = Duration::from_secs(5);
+                while let Ok(event) = events_recv.recv_timeout(timeout) {
+                    if let JobEvent::Succeeded { .. } = event {
+                        count -= 1;
+                        if count == 0 {
+                            break;
+                        }
+                    }
+                }
+            };
+
+            // The duplicate is skipped while the first job is running
+            let guard = gate.lock().unwrap();
+            api.queue(repo.dedup_job("deploy", "first", "a").unwrap(), 0)?;
+            api.queue(repo.dedup_job("deploy", "dup", "a").unwrap(), 0)?;
+            api.queue(repo.dedup_job("deploy", "other", "b").unwrap(), 0)?;
+            drop(guard);
+            wait_succeeded(2);
+
+            // The key can be used again after the job ended
+            api.queue(repo.dedup_job("deploy", "again", "a").unwrap(), 0)?;
+            wait_succeeded(1);
+
+            processor.stop()?;
+
+            let mut executed = executed.lock().unwrap().clone();
+            executed.sort();
+            assert_eq!(executed, vec!["again", "first", "other"]);
+            assert_eq!(repo.pending("deploy"), 0);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_queue_wait() {
+        test_wrapper(|| {
+            let repo = Repository::<bool>::new();
+            repo.add_script("job", true, |fail| {
+                if fail {
+                    Err(ErrorKind::Dummy.into())
+                } else {
+                    Ok(())
+                }
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                repo.clone(), Arc::new(()), Arc::new(State::new()),
+                ProcessorConfig {
+                    .. ProcessorConfig::default()
+                },
+            )?;
+            let api = processor.api();
+            let timeout = Duration::from_secs(5);
+
+            // The output of the job is sent back
+            let output = api.queue_wait(repo.job("job", false).unwrap(), 0)?;
+            assert_eq!(output.recv_timeout(timeout), Ok(Ok("job".into())));
+
+            // The error is sent back if the job doesn't return an output
+            let output = api.queue_wait(repo.job("job", true).unwrap(), 0)?;
+            match output.recv_timeout(timeout) {
+                Ok(Err(..)) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+
+            processor.stop()?;
+            Ok(())
+        });
+    }
 }
@@ -0,0 +1,186 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use fisher_common::prelude::*;
+
+
+/// This struct contains when the processor should reject the low-priority
+/// jobs because it's overloaded.
+///
+/// Jobs start being rejected when more than `high_watermark` jobs are
+/// queued, and they're accepted again only after the queue drains below
+/// `low_watermark`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LoadShedding {
+    /// How many queued jobs are needed to start rejecting jobs.
+    pub high_watermark: usize,
+
+    /// How many queued jobs are needed to stop rejecting jobs.
+    pub low_watermark: usize,
+
+    /// Jobs with a priority lower than this are rejected while the processor
+    /// is overloaded.
+    pub min_priority: isize,
+}
+
+
+// This decides which jobs are rejected before sending them to the scheduler,
+// either because of the load shedding or because the queue is full. The
+// scheduler updates how many jobs are queued, and the jobs sent but not
+// received by it yet are counted too, so bursts can't overflow the queue.
+#[derive(Debug)]
+pub struct Shedder {
+    config: Option<LoadShedding>,
+    shedding: AtomicBool,
+    max_queue_size: Option<usize>,
+    queued: AtomicUsize,
+    sent: AtomicUsize,
+}
+
+impl Shedder {
+
+    pub fn new(config: Option<LoadShedding>, max_queue_size: Option<usize>)
+               -> Result<Self> {
+        if let Some(config) = config {
+            if config.low_watermark > config.high_watermark {
+                return Err(ErrorKind::InvalidInput(
+                    "the low watermark is bigger than the high one".into()
+                ).into());
+            }
+        }
+
+        if max_queue_size == Some(0) {
+            return Err(ErrorKind::InvalidInput(
+                "the maximum size of the queue can't be zero".into()
+            ).into());
+        }
+
+        Ok(Shedder {
+            config: config,
+            shedding: AtomicBool::new(false),
+            max_queue_size: max_queue_size,
+            queued: AtomicUsize::new(0),
+            sent: AtomicUsize::new(0),
+        })
+    }
+
+    /// Start or stop shedding jobs based on how many of them are queued.
+    pub fn update(&self, queued_jobs: usize) {
+        self.queued.store(queued_jobs, Ordering::SeqCst);
+
+        if let Some(config) = self.config {
+            if queued_jobs > config.high_watermark {
+                self.shedding.store(true, Ordering::SeqCst);
+            } else if queued_jobs < config.low_watermark {
+                self.shedding.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub fn accepts(&self, priority: isize) -> bool {
+        match self.config {
+            Some(config) => {
+                priority >= config.min_priority
+                    || ! self.shedding.load(Ordering::SeqCst)
+            },
+            None => true,
+        }
+    }
+
+    /// Reserve a place in the queue for a job about to be sent to the
+    /// scheduler, returning false if the queue is full.
+    pub fn reserve(&self) -> bool {
+        let sent = self.sent.fetch_add(1, Ordering::SeqCst) + 1;
+
+        if let Some(max) = self.max_queue_size {
+            if self.queued.load(Ordering::SeqCst) + sent > max {
+                self.sent.fetch_sub(1, Ordering::SeqCst);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Release the place reserved for a job, once the scheduler received it.
+    pub fn received(&self) {
+        self.sent.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{LoadShedding, Shedder};
+
+
+    #[test]
+    fn test_shedder() {
+        let shedder = Shedder::new(Some(LoadShedding {
+            high_watermark: 10,
+            low_watermark: 5,
+            min_priority: 0,
+        }), None).unwrap();
+
+        assert!(shedder.accepts(-1));
+
+        // Low-priority jobs are rejected above the high watermark
+        shedder.update(11);
+        assert!(! shedder.accepts(-1));
+        assert!(shedder.accepts(0));
+
+        // They're still rejected until the queue drains enough
+        shedder.update(5);
+        assert!(! shedder.accepts(-1));
+        shedder.update(4);
+        assert!(shedder.accepts(-1));
+
+        // Without a configuration nothing is rejected
+        let shedder = Shedder::new(None, None).unwrap();
+        shedder.update(1000);
+        assert!(shedder.accepts(-1000));
+        assert!(shedder.reserve());
+
+        assert!(Shedder::new(Some(LoadShedding {
+            high_watermark: 5,
+            low_watermark: 10,
+            min_priority: 0,
+        }), None).is_err());
+    }
+
+
+    #[test]
+    fn test_max_queue_size() {
+        let shedder = Shedder::new(None, Some(3)).unwrap();
+
+        // The jobs not received by the scheduler yet are counted too
+        shedder.update(1);
+        assert!(shedder.reserve());
+        assert!(shedder.reserve());
+        assert!(! shedder.reserve());
+
+        // Places are freed once the jobs are dequeued
+        shedder.received();
+        shedder.received();
+        shedder.update(3);
+        assert!(! shedder.reserve());
+        shedder.update(2);
+        assert!(shedder.reserve());
+
+        assert!(Shedder::new(None, Some(0)).is_err());
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use fisher_common::structs::{ScriptStats, Percentiles, JobCounters};
+use fisher_common::structs::HistoryEntry;
+
+
+// Only the most recent samples are kept, to avoid the memory usage growing
+// forever and to reflect the current behavior of the scripts
+const RESERVOIR_SIZE: usize = 1024;
+
+// The throughput is measured only on the jobs completed this recently
+const THROUGHPUT_WINDOW_SECS: u64 = 60;
+
+
+#[derive(Debug, Clone)]
+pub struct ExecutionReport {
+    pub script_name: String,
+    pub queue_time: Duration,
+    pub execution_time: Duration,
+    pub status_job: bool,
+    pub failed: bool,
+    pub successful: bool,
+    pub started_at: SystemTime,
+    pub exit_code: Option<i32>,
+    pub retry: bool,
+}
+
+
+#[derive(Debug)]
+struct Reservoir {
+    samples: VecDeque<f64>,
+}
+
+impl Reservoir {
+
+    fn new() -> Self {
+        Reservoir {
+            samples: VecDeque::with_capacity(RESERVOIR_SIZE),
+        }
+    }
+
+    fn record(&mut self, duration: Duration) {
+        if self.samples.len() >= RESERVOIR_SIZE {
+            let _ = self.samples.pop_front();
+        }
+
+        self.samples.push_back(as_secs(duration));
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        let mut sorted = self.samples.iter().cloned().collect::<Vec<f64>>();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        Percentiles {
+            p50: percentile(&sorted, 50.0),
+            p95: percentile(&sorted, 95.0),
+            p99: percentile(&sorted, 99.0),
+        }
+    }
+}
+
+
+fn as_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64
+    + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+
+// This uses the nearest-rank method
+fn percentile(sorted: &[f64], percentile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    if rank == 0 {
+        sorted[0]
+    } else if rank > sorted.len() {
+        sorted[sorted.len() - 1]
+    } else {
+        sorted[rank - 1]
+    }
+}
+
+
+#[derive(Debug)]
+struct ScriptRecorder {
+    queue_time: Reservoir,
+    execution_time: Reservoir,
+    jobs: JobCounters,
+    status_jobs: JobCounters,
+    last_run: Option<(Instant, bool)>,
+    history: VecDeque<HistoryEntry>,
+}
+
+impl ScriptRecorder {
+
+    fn new() -> Self {
+        ScriptRecorder {
+            queue_time: Reservoir::new(),
+            execution_time: Reservoir::new(),
+            jobs: JobCounters::default(),
+            status_jobs: JobCounters::default(),
+            last_run: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    fn counters(&mut self, status_job: bool) -> &mut JobCounters {
+        if status_job {
+            &mut self.status_jobs
+        } else {
+            &mut self.jobs
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct StatsRecorder {
+    scripts: HashMap<String, ScriptRecorder>,
+    completed: VecDeque<Instant>,
+    history_size: usize,
+}
+
+impl StatsRecorder {
+
+    pub fn new(history_size: usize) -> Self {
+        StatsRecorder {
+            scripts: HashMap::new(),
+            completed: VecDeque::new(),
+            history_size: history_size,
+        }
+    }
+
+    pub fn record(&mut self, report: &ExecutionReport) {
+        self.record_completed(Instant::now());
+
+        let history_size = self.history_size;
+        let script = self.script(&report.script_name);
+
+        script.queue_time.record(report.queue_time);
+        script.execution_time.record(report.execution_time);
+
+        script.last_run = Some((Instant::now(), report.successful));
+
+        // Only the most recent executions are kept in the history
+        if history_size > 0 {
+            if script.history.len() >= history_size {
+                let _ = script.history.pop_front();
+            }
+            script.history.push_back(HistoryEntry {
+                started_at: report.started_at.duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0),
+                duration: as_secs(report.execution_time),
+                exit_code: report.exit_code,
+                successful: report.successful,
+                retry: report.retry,
+            });
+        }
+
+        let counters = script.counters(report.status_job);
+        counters.executed += 1;
+        if report.failed {
+            counters.failed += 1;
+        }
+    }
+
+    pub fn record_retry(&mut self, script_name: &str, status_job: bool) {
+        self.script(script_name).counters(status_job).retried += 1;
+    }
+
+    pub fn record_gave_up(&mut self, script_name: &str, status_job: bool) {
+        self.script(script_name).counters(status_job).gave_up += 1;
+    }
+
+    pub fn record_skipped(&mut self, script_name: &str, status_job: bool) {
+        self.script(script_name).counters(status_job).skipped += 1;
+    }
+
+    pub fn record_expired(&mut self, script_name: &str, status_job: bool) {
+        self.script(script_name).counters(status_job).expired += 1;
+    }
+
+    /// Return when the last job of the script ended, and if it was successful
+    pub fn last_run(&self, script_name: &str) -> Option<(Instant, bool)> {
+        self.scripts.get(script_name).and_then(|script| script.last_run)
+    }
+
+    /// Return the most recent executions of the script, from the oldest one
+    pub fn history(&self, script_name: &str) -> Vec<HistoryEntry> {
+        self.scripts.get(script_name)
+            .map(|script| script.history.iter().cloned().collect())
+            .unwrap_or_else(Vec::new)
+    }
+
+    fn script(&mut self, name: &str) -> &mut ScriptRecorder {
+        self.scripts.entry(name.to_string())
+            .or_insert_with(ScriptRecorder::new)
+    }
+
+    /// Return how many jobs were completed each second, on average over the
+    /// last minute.
+    pub fn throughput(&self) -> f64 {
+        self.throughput_at(Instant::now())
+    }
+
+    fn throughput_at(&self, now: Instant) -> f64 {
+        let window = Duration::from_secs(THROUGHPUT_WINDOW_SECS);
+        let recent = self.completed.iter()
+            .filter(|at| now.duration_since(**at) < window)
+            .count();
+
+        recent as f64 / THROUGHPUT_WINDOW_SECS as f64
+    }
+
+    fn record_completed(&mut self, now: Instant) {
+        // The jobs outside of the window aren't needed anymore
+        let window = Duration::from_secs(THROUGHPUT_WINDOW_SECS);
+        while let Some(&oldest) = self.completed.front() {
+            if now.duration_since(oldest) < window {
+                break;
+            }
+            let _ = self.completed.pop_front();
+        }
+
+        self.completed.push_back(now);
+    }
+
+    pub fn summary(&self) -> HashMap<String, ScriptStats> {
+        self.scripts.iter().map(|(name, script)| {
+            (name.clone(), ScriptStats {
+                queue_time: script.queue_time.percentiles(),
+                execution_time: script.execution_time.percentiles(),
+                jobs: script.jobs,
+                status_jobs: script.status_jobs,
+            })
+        }).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use super::{StatsRecorder, ExecutionReport, RESERVOIR_SIZE};
+
+
+    fn report(name: &str, queue_millis: u64, exec_millis: u64)
+              -> ExecutionReport {
+        ExecutionReport {
+            script_name: name.to_string(),
+            queue_time: Duration::from_millis(queue_millis),
+            execution_time: Duration::from_millis(exec_millis),
+            status_job: false,
+            failed: false,
+            successful: true,
+            started_at: UNIX_EPOCH + Duration::from_secs(42),
+            exit_code: Some(0),
+            retry: false,
+        }
+    }
+
+    fn assert_near(value: f64, expected: f64) {
+        assert!(
+            (value - expected).abs() < 0.000001,
+            "{} is not {}", value, expected
+        );
+    }
+
+
+    #[test]
+    fn test_percentiles() {
+        let mut stats = StatsRecorder::new(0);
+
+        // Record durations from 1ms to 100ms, in a random-ish order
+        for i in 0..100 {
+            let millis = (i * 37) % 100 + 1;
+            stats.record(&report("test", millis, millis * 10));
+        }
+
+        let summary = stats.summary();
+        let script = summary.get("test").unwrap();
+
+        assert_near(script.queue_time.p50, 0.050);
+        assert_near(script.queue_time.p95, 0.095);
+        assert_near(script.queue_time.p99, 0.099);
+        assert_near(script.execution_time.p50, 0.5);
+        assert_near(script.execution_time.p95, 0.95);
+        assert_near(script.execution_time.p99, 0.99);
+
+        // Scripts are tracked separately
+        assert!(summary.get("other").is_none());
+        stats.record(&report("other", 1, 2));
+        let summary = stats.summary();
+        assert_near(summary.get("other").unwrap().queue_time.p99, 0.001);
+        assert_near(summary.get("test").unwrap().queue_time.p99, 0.099);
+    }
+
+
+    #[test]
+    fn test_percentiles_only_recent_samples() {
+        let mut stats = StatsRecorder::new(0);
+
+        // Old slow jobs are forgotten after enough new jobs are recorded
+        for _ in 0..100 {
+            stats.record(&report("test", 10000, 10000));
+        }
+        for _ in 0..RESERVOIR_SIZE {
+            stats.record(&report("test", 1, 1));
+        }
+
+        let summary = stats.summary();
+        let script = summary.get("test").unwrap();
+        assert_near(script.queue_time.p99, 0.001);
+        assert_near(script.execution_time.p99, 0.001);
+    }
+
+
+    #[test]
+    fn test_counters() {
+        let mut stats = StatsRecorder::new(0);
+
+        stats.record(&report("test", 1, 1));
+        stats.record(&ExecutionReport {
+            failed: true,
+            .. report("test", 1, 1)
+        });
+        stats.record_retry("test", false);
+        stats.record(&ExecutionReport {
+            status_job: true,
+            failed: true,
+            .. report("test", 1, 1)
+        });
+        stats.record_gave_up("test", true);
+        stats.record_skipped("test", false);
+        stats.record_expired("test", true);
+
+        let summary = stats.summary();
+        let script = summary.get("test").unwrap();
+
+        assert_eq!(script.jobs.executed, 2);
+        assert_eq!(script.jobs.failed, 1);
+        assert_eq!(script.jobs.retried, 1);
+        assert_eq!(script.jobs.gave_up, 0);
+        assert_eq!(script.jobs.skipped, 1);
+        assert_eq!(script.jobs.expired, 0);
+
+        assert_eq!(script.status_jobs.executed, 1);
+        assert_eq!(script.status_jobs.failed, 1);
+        assert_eq!(script.status_jobs.retried, 0);
+        assert_eq!(script.status_jobs.gave_up, 1);
+        assert_eq!(script.status_jobs.skipped, 0);
+        assert_eq!(script.status_jobs.expired, 1);
+    }
+
+
+    #[test]
+    fn test_last_run() {
+        let mut stats = StatsRecorder::new(0);
+        assert!(stats.last_run("test").is_none());
+
+        stats.record(&report("test", 1, 1));
+        assert_eq!(stats.last_run("test").map(|(_, ok)| ok), Some(true));
+
+        // Only the most recent execution is considered
+        stats.record(&ExecutionReport {
+            successful: false,
+            .. report("test", 1, 1)
+        });
+        assert_eq!(stats.last_run("test").map(|(_, ok)| ok), Some(false));
+
+        // Skipped jobs don't count as executions
+        stats.record_skipped("test", false);
+        assert_eq!(stats.last_run("test").map(|(_, ok)| ok), Some(false));
+    }
+
+
+    #[test]
+    fn test_history() {
+        let mut stats = StatsRecorder::new(2);
+        assert!(stats.history("test").is_empty());
+
+        stats.record(&report("test", 1, 1));
+        stats.record(&ExecutionReport {
+            successful: false,
+            exit_code: Some(1),
+            .. report("test", 1, 1)
+        });
+        stats.record(&ExecutionReport {
+            retry: true,
+            .. report("test", 1, 1500)
+        });
+
+        // Only the most recent executions are kept, the oldest first
+        let history = stats.history("test");
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].started_at, 42);
+        assert_eq!(history[0].exit_code, Some(1));
+        assert!(! history[0].successful);
+        assert!(! history[0].retry);
+        assert!(history[1].successful);
+        assert!(history[1].retry);
+        assert_near(history[1].duration, 1.5);
+
+        // Scripts are tracked separately
+        assert!(stats.history("other").is_empty());
+
+        // The history can be disabled
+        let mut stats = StatsRecorder::new(0);
+        stats.record(&report("test", 1, 1));
+        assert!(stats.history("test").is_empty());
+    }
+
+
+    #[test]
+    fn test_throughput() {
+        let mut stats = StatsRecorder::new(0);
+        assert_near(stats.throughput(), 0.0);
+
+        let start = Instant::now();
+        for i in 0..30 {
+            stats.record_completed(start + Duration::from_secs(i));
+        }
+        assert_near(stats.throughput_at(start + Duration::from_secs(30)), 0.5);
+
+        // Only the jobs completed in the last minute are counted
+        let later = start + Duration::from_secs(74);
+        assert_near(stats.throughput_at(later), 0.25);
+        stats.record_completed(later);
+        assert_eq!(stats.completed.len(), 16);
+
+        // Every execution counts
+        let mut stats = StatsRecorder::new(0);
+        stats.record(&report("test", 1, 1));
+        assert!(stats.throughput() > 0.0);
+    }
+}
@@ -17,15 +17,25 @@ use std::collections::{HashMap, VecDeque};
 use std::fmt::{self, Debug};
 use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use fisher_common::prelude::*;
+use fisher_common::structs::{ConcurrencyLimit, Prerequisites};
 
 
 pub struct Script<I: Send + Sync + Debug + Clone> {
     id: usize,
     name: String,
     can_be_parallel: bool,
+    max_parallel: Option<ConcurrencyLimit>,
+    prerequisites: Option<Prerequisites>,
+    max_queue_age: Option<Duration>,
+    labels: Vec<String>,
+    cost: u16,
     func: Arc<Mutex<Box<Fn(I) -> Result<()> + Send>>>,
+    gave_up: Mutex<Vec<(String, u32)>>,
+    failed_permanently: Mutex<Vec<Option<String>>>,
+    pending: AtomicUsize,
 }
 
 impl<I: Send + Sync + Debug + Clone> ScriptTrait for Script<I> {
@@ -38,6 +48,26 @@ impl<I: Send + Sync + Debug + Clone> ScriptTrait for Script<I> {
     fn can_be_parallel(&self) -> bool {
         self.can_be_parallel
     }
+
+    fn max_parallel(&self) -> Option<ConcurrencyLimit> {
+        self.max_parallel
+    }
+
+    fn prerequisites(&self) -> Option<Prerequisites> {
+        self.prerequisites.clone()
+    }
+
+    fn max_queue_age(&self) -> Option<Duration> {
+        self.max_queue_age
+    }
+
+    fn labels(&self) -> Vec<String> {
+        self.labels.clone()
+    }
+
+    fn cost(&self) -> u16 {
+        self.cost
+    }
 }
 
 impl<I: Send + Sync + Debug + Clone> Debug for Script<I> {
@@ -54,14 +84,25 @@ impl<I: Send + Sync + Debug + Clone> Debug for Script<I> {
 pub struct Job<I: Send + Sync + Debug + Clone> {
     script: Arc<Script<I>>,
     args: I,
+    dedup_key: Option<String>,
+    crashes: bool,
+}
+
+impl<I: Send + Sync + Debug + Clone> Job<I> {
+
+    pub fn with_dedup_key(mut self, key: &str) -> Self {
+        self.dedup_key = Some(key.to_string());
+        self
+    }
 }
 
 impl<I: Send + Sync + Debug + Clone> JobTrait<Script<I>> for Job<I> {
     type Context = ();
-    type Output = ();
+    type Output = String;
 
-    fn execute(&self, _: &()) -> Result<()> {
-        (self.script.func.lock().unwrap())(self.args.clone())
+    fn execute(&self, _: &()) -> Result<String> {
+        (self.script.func.lock().unwrap())(self.args.clone())?;
+        Ok(self.script.name.clone())
     }
 
     fn script_id(&self) -> usize {
@@ -71,6 +112,39 @@ impl<I: Send + Sync + Debug + Clone> JobTrait<Script<I>> for Job<I> {
     fn script_name(&self) -> &str {
         &self.script.name
     }
+
+    fn dedup_key(&self) -> Option<String> {
+        self.dedup_key.clone()
+    }
+
+    fn is_successful(&self, _output: &String) -> bool {
+        // This is called outside of the job, so the whole thread crashes
+        if self.crashes {
+            panic!("the thread crashed");
+        }
+        true
+    }
+
+    fn gave_up(&self, _: &(), error: &str, attempts: u32) {
+        self.script.gave_up.lock().unwrap().push(
+            (error.to_string(), attempts)
+        );
+    }
+
+    fn failed_permanently(&self, _: &(), _output: Option<&String>,
+                          error: Option<&str>) {
+        self.script.failed_permanently.lock().unwrap().push(
+            error.map(|error| error.to_string())
+        );
+    }
+
+    fn queued(&self, _: &()) {
+        self.script.pending.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finished(&self, _: &()) {
+        self.script.pending.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 
@@ -78,6 +152,7 @@ pub struct Repository<I: Send + Sync + Debug + Clone> {
     last_id: AtomicUsize,
     scripts: RwLock<HashMap<String, Arc<Script<I>>>>,
     ids: RwLock<Vec<usize>>,
+    status_jobs: RwLock<Vec<(String, I)>>,
 }
 
 impl<I: Send + Sync + Debug + Clone> Repository<I> {
@@ -87,24 +162,127 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
             last_id: AtomicUsize::new(0),
             ids: RwLock::new(Vec::new()),
             scripts: RwLock::new(HashMap::new()),
+            status_jobs: RwLock::new(Vec::new()),
         }
     }
 
+    /// Run the script after every job of another script completes, as a
+    /// status job
+    pub fn add_status_job(&self, name: &str, args: I) {
+        self.status_jobs.write().unwrap().push((name.to_string(), args));
+    }
+
     pub fn add_script<F: Fn(I) -> Result<()> + 'static + Send>(
         &self, name: &str, parallel: bool, func: F
+    ) {
+        self.add_limited_script(name, parallel, None, func);
+    }
+
+    pub fn add_limited_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self, name: &str, parallel: bool, limit: Option<ConcurrencyLimit>,
+        func: F
+    ) {
+        self.insert_script(
+            name, parallel, limit, None, None, Vec::new(), 1, func,
+        );
+    }
+
+    pub fn add_dependent_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self, name: &str, prerequisites: Prerequisites, func: F
+    ) {
+        self.insert_script(
+            name, true, None, Some(prerequisites), None, Vec::new(), 1,
+            func,
+        );
+    }
+
+    pub fn add_expiring_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self, name: &str, max_queue_age: Duration, func: F
+    ) {
+        self.insert_script(
+            name, true, None, None, Some(max_queue_age), Vec::new(), 1,
+            func,
+        );
+    }
+
+    pub fn add_labeled_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self, name: &str, labels: &[&str], func: F
+    ) {
+        let labels = labels.iter().map(|label| label.to_string()).collect();
+        self.insert_script(name, true, None, None, None, labels, 1, func);
+    }
+
+    pub fn add_costly_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self, name: &str, cost: u16, func: F
+    ) {
+        self.insert_script(
+            name, true, None, None, None, Vec::new(), cost, func,
+        );
+    }
+
+    fn insert_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self, name: &str, parallel: bool, limit: Option<ConcurrencyLimit>,
+        prerequisites: Option<Prerequisites>,
+        max_queue_age: Option<Duration>, labels: Vec<String>, cost: u16,
+        func: F
     ) {
         self.ids.write().unwrap().push(self.last_id.load(Ordering::SeqCst));
         self.scripts.write().unwrap().insert(name.to_string(), Arc::new(Script {
             id: self.last_id.fetch_add(1, Ordering::SeqCst),
             name: name.to_string(),
             can_be_parallel: parallel,
+            max_parallel: limit,
+            prerequisites: prerequisites,
+            max_queue_age: max_queue_age,
+            labels: labels,
+            cost: cost,
             func: Arc::new(Mutex::new(Box::new(func))),
+            gave_up: Mutex::new(Vec::new()),
+            failed_permanently: Mutex::new(Vec::new()),
+            pending: AtomicUsize::new(0),
         }));
     }
 
     pub fn job(&self, name: &str, args: I) -> Option<Job<I>> {
         self.scripts.read().unwrap().get(name).cloned()
-                    .map(|script| Job { script, args })
+                    .map(|script| Job {
+                        script, args, dedup_key: None, crashes: false,
+                    })
+    }
+
+    pub fn dedup_job(&self, name: &str, args: I, key: &str)
+                     -> Option<Job<I>> {
+        self.job(name, args).map(|job| job.with_dedup_key(key))
+    }
+
+    /// Return a job crashing the thread running it after it's executed
+    pub fn crashing_job(&self, name: &str, args: I) -> Option<Job<I>> {
+        self.job(name, args).map(|mut job| {
+            job.crashes = true;
+            job
+        })
+    }
+
+    /// Return the errors and the attempts of the jobs of the script the
+    /// processor gave up on
+    pub fn gave_up(&self, name: &str) -> Vec<(String, u32)> {
+        self.scripts.read().unwrap().get(name)
+            .map(|script| script.gave_up.lock().unwrap().clone())
+            .unwrap_or_else(Vec::new)
+    }
+
+    /// Return the errors of the jobs of the script which failed permanently
+    pub fn failed_permanently(&self, name: &str) -> Vec<Option<String>> {
+        self.scripts.read().unwrap().get(name)
+            .map(|script| script.failed_permanently.lock().unwrap().clone())
+            .unwrap_or_else(Vec::new)
+    }
+
+    /// Return how many jobs of the script were queued and not finished yet
+    pub fn pending(&self, name: &str) -> usize {
+        self.scripts.read().unwrap().get(name)
+            .map(|script| script.pending.load(Ordering::SeqCst))
+            .unwrap_or(0)
     }
 
     pub fn hook_id_of(&self, name: &str) -> Option<usize> {
@@ -119,9 +297,14 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
         self.scripts.write().unwrap().clear();
 
         for script in scripts.drain(..) {
-            self.add_script(
+            self.insert_script(
                 &script.name,
                 script.can_be_parallel,
+                script.max_parallel,
+                script.prerequisites.clone(),
+                script.max_queue_age,
+                script.labels.clone(),
+                script.cost,
                 |_| { Ok(()) },
             );
         }
@@ -144,8 +327,18 @@ impl<I: Send + Sync + Debug + Clone> ScriptsRepositoryTrait for Repository<I> {
         )
     }
 
-    fn jobs_after_output(&self, _: ()) -> Option<Self::JobsIter> {
-        None
+    fn jobs_after_output(&self, script: String) -> Option<Self::JobsIter> {
+        let status_jobs = self.status_jobs.read().unwrap();
+
+        // Status jobs don't trigger other status jobs
+        if status_jobs.iter().any(|&(ref name, _)| *name == script) {
+            return None;
+        }
+
+        let jobs = status_jobs.iter().filter_map(|&(ref name, ref args)| {
+            self.job(name, args.clone())
+        }).collect();
+        Some(SimpleIter::new(jobs))
     }
 }
 
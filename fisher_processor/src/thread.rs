@@ -13,31 +13,69 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::sync::{Arc, mpsc};
+use std::any::Any;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Instant;
 use std::thread;
 use std::fmt;
 use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
 
 use fisher_common::prelude::*;
 use fisher_common::state::{State, IdKind, UniqueId};
 
+use super::events::JobEvent;
 use super::scheduled_job::ScheduledJob;
 use super::scheduler::SchedulerInternalApi;
 use super::types::{ScriptId, JobContext};
 
 
 #[derive(Debug)]
-enum ThreadInput<S: ScriptsRepositoryTrait> {
+pub enum ThreadInput<S: ScriptsRepositoryTrait> {
     Process(ScheduledJob<S>),
     StopSignal,
 }
 
 
+pub type SharedInput<S> = Arc<Mutex<mpsc::Receiver<ThreadInput<S>>>>;
+
+
+/// Tell the scheduler if the thread crashes outside of a job, so it can
+/// release the job the thread was running and replace the thread.
+struct CrashGuard<S: ScriptsRepositoryTrait> {
+    id: UniqueId,
+    processor: SchedulerInternalApi<S>,
+    running: Option<ScheduledJob<S>>,
+}
+
+impl<S: ScriptsRepositoryTrait> Drop for CrashGuard<S> {
+
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let running = self.running.take();
+            let _ = self.processor.thread_crashed(self.id, running);
+        }
+    }
+}
+
+
+fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".into()
+    }
+}
+
+
 pub struct Thread<S: ScriptsRepositoryTrait + 'static> {
     id: UniqueId,
     currently_running: Option<ScriptId<S>>,
 
     should_stop: bool,
+    disconnected: bool,
 
     handle: thread::JoinHandle<()>,
     input: mpsc::Sender<ThreadInput<S>>,
@@ -50,68 +88,217 @@ impl<S: ScriptsRepositoryTrait> Thread<S> {
         let (input_send, input_recv) = mpsc::channel();
         let id = state.next_id(IdKind::ThreadId);
 
-        let handle = thread::spawn(move || {
-            for input in input_recv.iter() {
+        let handle = Self::spawn(id, processor, ctx, move || {
+            input_recv.recv().ok()
+        });
+
+        Thread {
+            id: id,
+            currently_running: None,
+
+            should_stop: false,
+            disconnected: false,
+
+            handle: handle,
+            input: input_send,
+        }
+    }
+
+    /// Create a thread which pulls the jobs from a queue shared with the
+    /// other threads, instead of receiving them from the scheduler.
+    pub fn new_shared(processor: SchedulerInternalApi<S>,
+                      ctx: Arc<JobContext<S>>, state: &Arc<State>,
+                      input_send: mpsc::Sender<ThreadInput<S>>,
+                      input_recv: SharedInput<S>) -> Self {
+        let id = state.next_id(IdKind::ThreadId);
+
+        let handle = Self::spawn(id, processor, ctx, move || {
+            // The lock is held while waiting, so only one idle thread at a
+            // time is waiting on the queue
+            match input_recv.lock() {
+                Ok(recv) => recv.recv().ok(),
+                Err(..) => None,
+            }
+        });
+
+        Thread {
+            id: id,
+            currently_running: None,
+
+            should_stop: false,
+            disconnected: false,
+
+            handle: handle,
+            input: input_send,
+        }
+    }
+
+    fn spawn<F>(id: UniqueId, processor: SchedulerInternalApi<S>,
+                ctx: Arc<JobContext<S>>, next_input: F)
+                -> thread::JoinHandle<()>
+        where F: Fn() -> Option<ThreadInput<S>> + Send + 'static
+    {
+        thread::spawn(move || {
+            let mut guard = CrashGuard {
+                id: id,
+                processor: processor,
+                running: None,
+            };
+            let processor = &guard.processor;
+
+            while let Some(input) = next_input() {
                 match input {
                     // A new job should be processed
                     ThreadInput::Process(job) => {
-                        let result = job.execute(ctx.deref());
-
-                        // Display the error if there is one
-                        match result {
-                            Ok(output) => {
-                                processor.record_output(output).unwrap();
-                            },
-                            Err(mut error) => {
-                                error.set_location(
-                                    ErrorLocation::HookProcessing(
-                                        job.hook_name().to_string()
-                                    )
-                                );
-                                error.pretty_print();
-                            }
+                        if processor.job_started(id, &job).is_err() {
+                            break;
                         }
 
-                        processor.job_ended(id, &job).unwrap();
+                        // The guard owns the job while it's running, so it
+                        // can be released if the thread crashes
+                        guard.running = Some(job);
+                        let started_at = Instant::now();
+                        let result = Self::run_job(
+                            guard.running.as_mut().unwrap(), ctx.deref(),
+                            processor, started_at,
+                        );
+                        let (failed, successful, code, event) = match result {
+                            Some(result) => result,
+                            // The processor is gone, so there is no one to
+                            // report to anymore
+                            None => break,
+                        };
+
+                        let job = guard.running.take().unwrap();
+                        let ended = processor.job_ended(
+                            id, job, started_at, failed, successful, code,
+                            event,
+                        );
+                        if ended.is_err() {
+                            break;
+                        }
                     },
 
                     // Please stop, thanks!
                     ThreadInput::StopSignal => break,
                 }
             }
-        });
+        })
+    }
 
-        Thread {
-            id: id,
-            currently_running: None,
+    /// Execute the job, returning whether it failed, whether it was
+    /// successful, its exit code and the event to emit. Nothing is returned
+    /// if the processor is gone.
+    fn run_job(job: &mut ScheduledJob<S>, ctx: &JobContext<S>,
+               processor: &SchedulerInternalApi<S>, started_at: Instant)
+               -> Option<(bool, bool, Option<i32>, JobEvent)> {
+        // A panicking job is treated as a failed one, so the thread keeps
+        // running and the scheduler is still told the job ended
+        let result = panic::catch_unwind(AssertUnwindSafe(
+            || job.execute(ctx)
+        )).unwrap_or_else(|payload| Err(
+            ErrorKind::JobPanicked(panic_message(&payload)).into()
+        ));
 
-            should_stop: false,
+        // Display the error if there is one
+        match result {
+            Ok(output) => {
+                let successful = job.is_successful(&output);
+                let code = job.exit_code(&output);
+                let script = job.hook_name().to_string();
+                let event = if successful {
+                    JobEvent::Succeeded {
+                        script: script,
+                        code: code,
+                        duration: started_at.elapsed(),
+                    }
+                } else {
+                    JobEvent::Failed {
+                        script: script,
+                        error: match code {
+                            Some(code) => format!("exited with code {}", code),
+                            None => "unsuccessful".into(),
+                        },
+                    }
+                };
 
-            handle: handle,
-            input: input_send,
+                // Unsuccessful jobs are retried like the ones which
+                // returned an error
+                if ! successful {
+                    job.failed(&ErrorKind::JobFailed(
+                        code, String::new(),
+                    ).into());
+                    if job.is_last_attempt() {
+                        job.failed_permanently(ctx, Some(&output));
+                    }
+                }
+
+                job.notify_waiter(&output);
+                if processor.record_output(output).is_err() {
+                    return None;
+                }
+                Some((false, successful, code, event))
+            },
+            Err(mut error) => {
+                error.set_location(
+                    ErrorLocation::HookProcessing(job.hook_name().to_string())
+                );
+                error.pretty_print();
+                job.failed(&error);
+                job.notify_waiter_error(&error);
+                if job.is_last_attempt() {
+                    job.failed_permanently(ctx, None);
+                }
+
+                let event = JobEvent::Failed {
+                    script: job.hook_name().to_string(),
+                    error: error.to_string(),
+                };
+                Some((true, false, None, event))
+            }
         }
     }
 
     // Here, None equals to success, and Some(job) equals to failure
     pub fn process(&mut self, job: ScheduledJob<S>) -> Option<ScheduledJob<S>> {
         // Do some consistency checks
-        if self.should_stop || self.busy() {
+        if self.should_stop || self.disconnected || self.busy() {
             return Some(job);
         }
+        let job_hook_id = job.hook_id();
 
-        self.currently_running = Some(job.hook_id());
-        self.input.send(ThreadInput::Process(job)).unwrap();
+        // If the thread exited the job is returned, so it can be sent to
+        // another thread
+        if let Err(mpsc::SendError(input)) = self.input.send(
+            ThreadInput::Process(job)
+        ) {
+            self.disconnected = true;
+            if let ThreadInput::Process(job) = input {
+                return Some(job);
+            }
+        }
 
+        self.currently_running = Some(job_hook_id);
         None
     }
 
     pub fn stop(mut self) {
         self.should_stop = true;
-        self.input.send(ThreadInput::StopSignal).unwrap();
+
+        // The thread might have already exited on its own
+        let _ = self.input.send(ThreadInput::StopSignal);
 
         self.handle.join().unwrap();
     }
 
+    /// Wait for the thread to exit, without asking it to stop. This is used
+    /// with shared queues, where the stop signals can't be sent to a specific
+    /// thread, and with threads which already exited on their own.
+    pub fn join(self) {
+        // Threads which crashed already reported it to the scheduler
+        let _ = self.handle.join();
+    }
+
     pub fn id(&self) -> UniqueId {
         self.id
     }
@@ -120,6 +307,16 @@ impl<S: ScriptsRepositoryTrait> Thread<S> {
         self.currently_running
     }
 
+    /// Check if the thread exited without being asked to, and it can't
+    /// process jobs anymore.
+    pub fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    pub fn mark_disconnected(&mut self) {
+        self.disconnected = true;
+    }
+
     pub fn busy(&self) -> bool {
         self.currently_running.is_some()
     }
@@ -137,3 +334,56 @@ impl<S: ScriptsRepositoryTrait> fmt::Debug for Thread<S> {
         )
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, mpsc};
+    use std::time::{Duration, Instant};
+
+    use fisher_common::state::State;
+    use fisher_common::serial::Serial;
+
+    use super::super::scheduled_job::ScheduledJob;
+    use super::super::scheduler::SchedulerInternalApi;
+    use super::super::test_utils::*;
+    use super::Thread;
+
+
+    #[test]
+    fn test_processor_gone() {
+        let repo = Repository::<()>::new();
+        repo.add_script("noop", true, |_| Ok(()));
+        let job = || ScheduledJob::<Repository<()>>::new(
+            repo.job("noop", ()).unwrap(), 0, Serial::zero(),
+        );
+
+        let (input_send, input_recv) = mpsc::channel();
+        let mut thread = Thread::new(
+            SchedulerInternalApi::new(input_send), Arc::new(()),
+            &Arc::new(State::new()),
+        );
+
+        // Tear down the processor while the job is being dispatched
+        drop(input_recv);
+        assert!(thread.process(job()).is_none());
+        assert!(thread.busy());
+
+        // The thread exits quietly, and the jobs dispatched to it are
+        // returned to the caller
+        let started_at = Instant::now();
+        loop {
+            thread.mark_idle();
+            if let Some(..) = thread.process(job()) {
+                break;
+            }
+
+            assert!(started_at.elapsed() < Duration::from_secs(5));
+        }
+        assert!(thread.disconnected());
+        assert!(! thread.busy());
+
+        // Stopping the thread doesn't panic
+        thread.stop();
+    }
+}
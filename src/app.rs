@@ -14,18 +14,37 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::net;
-use std::sync::Arc;
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+
+use ansi_term::Colour;
 
 use fisher_common::prelude::*;
 use fisher_common::state::State;
-use fisher_processor::{Processor, ProcessorApi};
-
-use hooks::{HookNamesIter, Hooks, HooksBlueprint, Hook};
-use web::WebApp;
-use jobs::Context;
+use fisher_processor::{Processor, ProcessorApi, RetryPolicy, SchedulerKind};
+use fisher_processor::{JobEvent, LoadShedding, ProcessorConfig};
+
+use hooks::{HookNamesIter, Hooks, HooksBlueprint, HooksDiff, HooksWatcher};
+use hooks::{Hook, HookSummary};
+use web::{WebApp, WebConfig, WebRequest, TlsConfig, DEFAULT_MAX_BODY_SIZE};
+use web::{DEFAULT_WAIT_TIMEOUT, DEFAULT_REQUEST_LOG_MAX_SIZE};
+use web::DEFAULT_MAX_WAITERS;
+use web::{DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEADERS_SIZE};
+use web::DEFAULT_PROXY_HEADERS;
+use jobs::{Context, Job, JobOutput, KillPolicy, Sandbox};
+use jobs::DEFAULT_MAX_OUTPUT_SIZE;
+use jobs::{DEFAULT_ENV_PREFIX, DEFAULT_ENV_PASSTHROUGH, is_valid_env_prefix};
+use providers::{ProviderRegistry, ProviderErrorPolicy};
 use utils;
+use utils::{IpNetwork, Redactor};
+use native;
+use event_log::{self, LogFormat};
+use queue_store::QueueStore;
+use failure_webhook::FailureWebhook;
+use job_logs;
+use requests::{Request, RequestType};
 
 
 pub trait IntoHook {
@@ -49,8 +68,55 @@ impl IntoHook for Arc<Hook> {
 pub struct Fisher<'a> {
     pub max_threads: u16,
     pub behind_proxies: u8,
+    pub proxy_headers: Vec<String>,
+    pub trusted_proxies: Vec<IpNetwork>,
     pub bind: &'a str,
+    pub extra_binds: Vec<String>,
+    pub admin_bind: Option<&'a str>,
     pub enable_health: bool,
+    pub health_secret: Option<&'a str>,
+    pub dry_run: bool,
+    pub access_log: Option<f64>,
+    pub request_log: Option<&'a str>,
+    pub request_log_max_size: u64,
+    pub max_body_memory: Option<usize>,
+    pub max_body_size: Option<u64>,
+    pub max_headers: Option<usize>,
+    pub max_headers_size: Option<usize>,
+    pub max_output_size: Option<usize>,
+    pub tls_cert: Option<&'a str>,
+    pub tls_key: Option<&'a str>,
+    pub kill_signal: &'a str,
+    pub kill_grace: Duration,
+    pub job_timeout: Option<Duration>,
+    pub sandbox: Sandbox,
+    pub scheduler: SchedulerKind,
+    pub retry: RetryPolicy,
+    pub dead_letter_dir: Option<&'a str>,
+    pub failure_webhook: Option<&'a str>,
+    pub work_dir: Option<&'a str>,
+    pub logs_dir: Option<&'a str>,
+    pub logs_keep: usize,
+    pub state_dir: Option<&'a str>,
+    pub logs_token: Option<&'a str>,
+    pub url_prefix: &'a str,
+    pub env_prefix: &'a str,
+    pub env_passthrough: Vec<String>,
+    pub idle_timeout: Option<Duration>,
+    pub shutdown_timeout: Option<Duration>,
+    pub provider_errors: ProviderErrorPolicy,
+    pub open_hooks: Vec<String>,
+    pub cgroup_parent: Option<&'a str>,
+    pub cgroup_fallback: bool,
+    pub log_redact: Vec<String>,
+    pub ack_fast: bool,
+    pub load_shedding: Option<LoadShedding>,
+    pub history_size: usize,
+    pub max_queue_size: Option<usize>,
+    pub status_hooks: bool,
+    pub kill_process_group: bool,
+    pub watch_hooks: bool,
+    pub log_format: LogFormat,
 
     state: Arc<State>,
     hooks: Hooks,
@@ -68,8 +134,57 @@ impl<'a> Fisher<'a> {
         Fisher {
             max_threads: 1,
             behind_proxies: 0,
+            proxy_headers: DEFAULT_PROXY_HEADERS.iter()
+                .map(|name| name.to_string()).collect(),
+            trusted_proxies: Vec::new(),
             bind: "127.0.0.1:8000",
+            extra_binds: Vec::new(),
+            admin_bind: None,
             enable_health: true,
+            health_secret: None,
+            dry_run: false,
+            access_log: None,
+            request_log: None,
+            request_log_max_size: DEFAULT_REQUEST_LOG_MAX_SIZE,
+            max_body_memory: None,
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            max_headers: Some(DEFAULT_MAX_HEADERS),
+            max_headers_size: Some(DEFAULT_MAX_HEADERS_SIZE),
+            max_output_size: Some(DEFAULT_MAX_OUTPUT_SIZE),
+            tls_cert: None,
+            tls_key: None,
+            kill_signal: "SIGKILL",
+            kill_grace: Duration::from_secs(0),
+            job_timeout: None,
+            sandbox: Sandbox::default(),
+            scheduler: SchedulerKind::default(),
+            retry: RetryPolicy::default(),
+            dead_letter_dir: None,
+            failure_webhook: None,
+            work_dir: None,
+            logs_dir: None,
+            logs_keep: job_logs::DEFAULT_KEEP,
+            state_dir: None,
+            logs_token: None,
+            url_prefix: "",
+            env_prefix: DEFAULT_ENV_PREFIX,
+            env_passthrough: DEFAULT_ENV_PASSTHROUGH.iter()
+                .map(|name| name.to_string()).collect(),
+            idle_timeout: None,
+            shutdown_timeout: None,
+            provider_errors: ProviderErrorPolicy::default(),
+            open_hooks: Vec::new(),
+            cgroup_parent: None,
+            cgroup_fallback: false,
+            log_redact: Vec::new(),
+            ack_fast: false,
+            load_shedding: None,
+            history_size: 10,
+            max_queue_size: None,
+            status_hooks: true,
+            kill_process_group: true,
+            watch_hooks: false,
+            log_format: LogFormat::default(),
 
             state: Arc::new(State::new()),
             hooks: hooks,
@@ -93,35 +208,250 @@ impl<'a> Fisher<'a> {
         Ok(())
     }
 
+    pub fn providers_mut(&mut self) -> &mut ProviderRegistry {
+        self.hooks_blueprint.providers_mut()
+    }
+
     pub fn collect_hooks<P: AsRef<Path>>(&mut self, path: P, recursive: bool)
                                          -> Result<()> {
         self.hooks_blueprint.collect_path(path, recursive)?;
         Ok(())
     }
 
+    /// Load the hooks in a directory without collecting them, returning
+    /// the summary of the valid ones and the error of the invalid ones.
+    pub fn check_hooks<P: AsRef<Path>>(&self, path: P, recursive: bool)
+                                       -> Result<Vec<Result<HookSummary>>> {
+        self.hooks_blueprint.check_path(path, recursive)
+    }
+
+    pub fn verify_manifest<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.hooks_blueprint.verify_manifest(path)?;
+        Ok(())
+    }
+
     pub fn hook_names(&self) -> HookNamesIter {
         self.hooks.names()
     }
 
+    /// Run a single hook in the foreground with the provided request,
+    /// without starting the HTTP server. The output is missing if the hook
+    /// wasn't executed, for example because the request wasn't valid.
+    pub fn run_hook(&self, name: &str, request: WebRequest)
+                    -> Result<(RequestType, Option<JobOutput>)> {
+        let hook = match self.hooks.get_by_name(name) {
+            Some(hook) => hook,
+            None => return Err(ErrorKind::InvalidInput(
+                format!("the hook doesn't exist: {}", name)
+            ).into()),
+        };
+
+        let request = Request::Web(request);
+        let provider = match hook.validate(&request, self.provider_errors) {
+            (RequestType::ExecuteHook, provider) => provider,
+            (request_type, _) => return Ok((request_type, None)),
+        };
+
+        let context = Context {
+            environment: self.environment.clone(),
+            dry_run: self.dry_run,
+            timeout: self.job_timeout,
+            kill: KillPolicy {
+                signal: self.kill_signal()?,
+                grace: self.kill_grace,
+            },
+            sandbox: self.sandbox,
+            max_output_size: self.max_output_size,
+            env_prefix: self.env_prefix.into(),
+            env_passthrough: self.env_passthrough.clone(),
+            work_dir: self.work_dir.map(PathBuf::from),
+            // Status hooks are only scheduled by the processor
+            status_hooks: false,
+            .. Context::default()
+        };
+
+        let job = Job::new(self.hooks.next_job_id(), hook, provider, request);
+        Ok((RequestType::ExecuteHook, Some(job.execute(&context)?)))
+    }
+
+    fn kill_signal(&self) -> Result<i32> {
+        match native::signal_by_name(self.kill_signal) {
+            Some(signal) => Ok(signal),
+            None => Err(ErrorKind::InvalidInput(
+                format!("unknown signal: {}", self.kill_signal)
+            ).into()),
+        }
+    }
+
     pub fn start(self) -> Result<RunningFisher> {
+        let kill_signal = self.kill_signal()?;
+
         // Finalize the hooks
         let hooks = Arc::new(self.hooks);
 
+        // Changes are collected for half a second, so a single reload is
+        // done when many files are changed at once
+        let hooks_watcher = if self.watch_hooks {
+            Some(HooksWatcher::new(
+                &self.hooks_blueprint, Duration::from_millis(500),
+            )?)
+        } else {
+            None
+        };
+
+        match self.sandbox.nice {
+            Some(nice) if nice < -20 || nice > 19 => {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "the nice level must be between -20 and 19: {}", nice,
+                )).into());
+            },
+            _ => {},
+        }
+
+        if ! is_valid_env_prefix(self.env_prefix) {
+            return Err(ErrorKind::InvalidInput(format!(
+                "invalid environment variables prefix: {}", self.env_prefix,
+            )).into());
+        }
+
+        if self.behind_proxies > 0 && self.proxy_headers.is_empty() {
+            return Err(ErrorKind::InvalidInput(
+                "at least a proxy header is required behind proxies".into()
+            ).into());
+        }
+
+        if self.request_log_max_size == 0 {
+            return Err(ErrorKind::InvalidInput(
+                "the maximum size of the request log can't be zero".into()
+            ).into());
+        }
+
+        // The working directories of the jobs are created in the directory
+        if let Some(dir) = self.work_dir {
+            if ! Path::new(dir).is_dir() {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "the work directory doesn't exist: {}", dir,
+                )).into());
+            }
+        }
+
+        // HTTPS is served only if both the certificate and the key are set
+        let tls = match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => Some(
+                TlsConfig::load(Path::new(cert), Path::new(key))?
+            ),
+            (None, None) => None,
+            _ => return Err(ErrorKind::InvalidInput(
+                "both the TLS certificate and private key are required".into()
+            ).into()),
+        };
+
+        let failure_webhook = match self.failure_webhook {
+            Some(url) => Some(FailureWebhook::new(url)?),
+            None => None,
+        };
+
+        let redactor = Redactor::new(&self.log_redact);
+
+        // Clients waiting for the output of a job can't wait longer than
+        // the job is allowed to run
+        let wait_timeout = match self.job_timeout {
+            Some(timeout) => timeout + self.kill_grace,
+            None => Duration::from_secs(DEFAULT_WAIT_TIMEOUT),
+        };
+
         let context = Arc::new(Context {
-            environment: self.environment,
+            environment: self.environment.clone(),
+            dry_run: self.dry_run,
+            timeout: self.job_timeout,
+            kill: KillPolicy {
+                signal: kill_signal,
+                grace: self.kill_grace,
+            },
+            sandbox: self.sandbox,
+            dead_letter: self.dead_letter_dir.map(PathBuf::from),
+            failure_webhook: failure_webhook,
+            cgroup_parent: self.cgroup_parent.map(PathBuf::from),
+            cgroup_fallback: self.cgroup_fallback,
+            redactor: redactor.clone(),
+            status_hooks: self.status_hooks,
+            kill_process_group: self.kill_process_group,
+            logs_dir: self.logs_dir.map(PathBuf::from),
+            logs_keep: self.logs_keep,
+            queue_store: self.state_dir.map(|dir| {
+                QueueStore::new(Path::new(dir).join("queue"))
+            }),
+            max_output_size: self.max_output_size,
+            env_prefix: self.env_prefix.into(),
+            env_passthrough: self.env_passthrough,
+            work_dir: self.work_dir.map(PathBuf::from),
         });
 
         // Start the processor
         let processor = Processor::new(
-            self.max_threads, hooks.clone(), context,
-            self.state.clone(),
+            hooks.clone(), context.clone(), self.state.clone(),
+            ProcessorConfig {
+                max_threads: self.max_threads,
+                retry: self.retry,
+                scheduler: self.scheduler,
+                shedding: self.load_shedding,
+                history_size: self.history_size,
+                max_queue_size: self.max_queue_size,
+            },
         )?;
         let processor_api = processor.api();
 
+        event_log::set_log_format(self.log_format);
+        if self.log_format == LogFormat::Json {
+            let (events_send, events_recv) = mpsc::channel();
+            processor_api.subscribe(events_send)?;
+            event_log::print_json(events_recv);
+        }
+
+        // The jobs left in the queue are restored before accepting any new
+        // request, so they're executed first
+        if let Some(ref store) = context.queue_store {
+            let restored = restore_queue(
+                store, &hooks, &processor_api, self.provider_errors,
+            );
+            if let Err(error) = restored {
+                processor.stop()?;
+                return Err(error);
+            }
+        }
+
+        let web_config = WebConfig {
+            bind: self.bind.to_string(),
+            extra_binds: self.extra_binds,
+            admin_bind: self.admin_bind.map(|bind| bind.to_string()),
+            enable_health: self.enable_health,
+            health_secret: self.health_secret.map(String::from),
+            behind_proxies: self.behind_proxies,
+            proxy_headers: self.proxy_headers,
+            trusted_proxies: self.trusted_proxies,
+            access_log: self.access_log,
+            request_log: self.request_log.map(PathBuf::from),
+            request_log_max_size: self.request_log_max_size,
+            max_body_memory: self.max_body_memory,
+            max_body_size: self.max_body_size,
+            max_headers: self.max_headers,
+            max_headers_size: self.max_headers_size,
+            tls: tls,
+            provider_errors: self.provider_errors,
+            open_hooks: self.open_hooks,
+            redactor: redactor,
+            ack_fast: self.ack_fast,
+            logs_dir: self.logs_dir.map(PathBuf::from),
+            logs_token: self.logs_token.map(String::from),
+            url_prefix: self.url_prefix.into(),
+            wait_timeout: wait_timeout,
+            max_waiters: DEFAULT_MAX_WAITERS,
+            environment: self.environment,
+        };
+
         // Start the Web API
         let web_api = match WebApp::new(
-            hooks.clone(), self.enable_health, self.behind_proxies, self.bind,
-            processor_api,
+            hooks.clone(), &web_config, processor_api,
         ) {
             Ok(socket) => socket,
             Err(error) => {
@@ -136,25 +466,134 @@ impl<'a> Fisher<'a> {
             processor,
             web_api,
             self.hooks_blueprint,
+            hooks_watcher,
+            self.idle_timeout,
+            self.shutdown_timeout,
         ))
     }
 }
 
 
+/// Queue again the jobs which weren't executed before Fisher stopped.
+fn restore_queue(store: &QueueStore, hooks: &Hooks,
+                 processor: &ProcessorApi<Hooks>,
+                 provider_errors: ProviderErrorPolicy) -> Result<()> {
+    let discarded = |hook: &str, stored_id: &str, reason: &str| {
+        if event_log::json_output() {
+            event_log::log_message("discarded", json!({
+                "hook": hook,
+                "reason": reason,
+            }));
+        } else {
+            println!("{} a queued job of {}: {}",
+                Colour::Yellow.bold().paint("Discarded"), hook, reason,
+            );
+        }
+        store.forget(stored_id, hook);
+    };
+
+    // These jobs are left on disk, and restored again at the next start
+    let kept = |hook: &str, reason: &str| {
+        if event_log::json_output() {
+            event_log::log_message("kept", json!({
+                "hook": hook,
+                "reason": reason,
+            }));
+        } else {
+            println!("{} a queued job of {} for the next start: {}",
+                Colour::Yellow.bold().paint("Kept"), hook, reason,
+            );
+        }
+    };
+
+    let mut restored = 0;
+    for pending in store.restore()? {
+        let stored_id = pending.stored_id().to_string();
+        let hook = match hooks.get_by_name(&pending.hook) {
+            Some(hook) => hook,
+            None => {
+                discarded(
+                    &pending.hook, &stored_id,
+                    "the hook doesn't exist anymore",
+                );
+                continue;
+            },
+        };
+
+        // The request is validated again, since the hook could have changed.
+        // A rejection isn't final though, since the providers could check
+        // how old the request is, or a secret could be rotated back.
+        let request = Request::Web(pending.request);
+        let provider = if pending.unvalidated {
+            None
+        } else {
+            match hook.validate(&request, provider_errors) {
+                (RequestType::ExecuteHook, provider) => provider,
+                (RequestType::Invalid, _) => {
+                    kept(&pending.hook, "the request isn't valid anymore");
+                    continue;
+                },
+                _ => {
+                    discarded(
+                        &pending.hook, &stored_id,
+                        "the hook doesn't execute the request anymore",
+                    );
+                    continue;
+                },
+            }
+        };
+
+        let mut job = Job::new(
+            hooks.next_job_id(), hook.clone(), provider, request,
+        );
+        job.set_path_params(pending.path_params);
+        job.set_restored_from(&stored_id);
+        // The queue could be full or overloaded only for now
+        if let Err(error) = processor.queue(job, hook.priority()) {
+            kept(&pending.hook, &error.to_string());
+            continue;
+        }
+
+        restored += 1;
+    }
+
+    if restored > 0 && event_log::json_output() {
+        event_log::log_message("restored", json!({ "jobs": restored }));
+    } else if restored > 0 {
+        println!("{} {} queued jobs",
+            Colour::Green.bold().paint("Restored"), restored,
+        );
+    }
+
+    Ok(())
+}
+
+
 pub struct RunningFisher {
     processor: Processor<Hooks>,
     web_api: WebApp<ProcessorApi<Hooks>>,
     hooks_blueprint: HooksBlueprint,
+    hooks_watcher: Option<HooksWatcher>,
+    idle_timeout: Option<Duration>,
+    idle_since: Instant,
+    shutdown_timeout: Option<Duration>,
 }
 
 impl RunningFisher {
 
     fn new(processor: Processor<Hooks>, web_api: WebApp<ProcessorApi<Hooks>>,
-           hooks_blueprint: HooksBlueprint) -> Self {
+           hooks_blueprint: HooksBlueprint,
+           hooks_watcher: Option<HooksWatcher>,
+           idle_timeout: Option<Duration>,
+           shutdown_timeout: Option<Duration>) -> Self {
         RunningFisher {
             processor: processor,
             web_api: web_api,
             hooks_blueprint: hooks_blueprint,
+            hooks_watcher: hooks_watcher,
+            idle_timeout: idle_timeout,
+            idle_since: Instant::now(),
+            shutdown_timeout: shutdown_timeout,
         }
     }
 
@@ -162,7 +601,15 @@ impl RunningFisher {
         self.web_api.addr()
     }
 
-    pub fn reload(&mut self) -> Result<()> {
+    pub fn web_addresses(&self) -> Vec<net::SocketAddr> {
+        self.web_api.addrs()
+    }
+
+    pub fn admin_address(&self) -> Option<&net::SocketAddr> {
+        self.web_api.admin_addr()
+    }
+
+    pub fn reload(&mut self) -> Result<HooksDiff> {
         let processor = self.processor.api();
 
         self.web_api.lock();
@@ -179,11 +626,345 @@ impl RunningFisher {
         result
     }
 
-    pub fn stop(self) -> Result<()> {
+    /// Check if the files of the hooks changed since the last call, so the
+    /// hooks should be reloaded. This is always false if the hooks aren't
+    /// watched.
+    pub fn hooks_changed(&self) -> bool {
+        match self.hooks_watcher {
+            Some(ref watcher) => watcher.changed(),
+            None => false,
+        }
+    }
+
+    /// Send to the provided channel the events of the jobs, as they're
+    /// processed.
+    pub fn subscribe(&self, events: mpsc::Sender<JobEvent>) -> Result<()> {
+        self.processor.api().subscribe(events)
+    }
+
+    /// Check if no requests were received and no jobs were executed for
+    /// longer than the idle timeout. This is always false if no idle timeout
+    /// was set.
+    pub fn is_idle(&mut self) -> Result<bool> {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(false),
+        };
+
+        let details = self.processor.api().health_details()?;
+        if details.queued_jobs > 0 || details.busy_threads > 0 {
+            self.idle_since = Instant::now();
+            return Ok(false);
+        }
+
+        let last_request = self.web_api.last_request();
+        if last_request > self.idle_since {
+            self.idle_since = last_request;
+        }
+
+        Ok(self.idle_since.elapsed() >= timeout)
+    }
+
+    /// Stop Fisher, waiting for the running jobs to end. If a shutdown
+    /// timeout is set, `false` is returned when some jobs were still running
+    /// after it expired.
+    pub fn stop(self) -> Result<bool> {
         self.web_api.lock();
-        self.processor.stop()?;
+        let drained = if let Some(timeout) = self.shutdown_timeout {
+            self.processor.stop_timeout(timeout)?
+        } else {
+            self.processor.stop()?;
+            true
+        };
         self.web_api.stop();
 
-        Ok(())
+        Ok(drained)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::Read;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use hyper;
+
+    use fisher_common::prelude::*;
+    use fisher_common::state::{IdKind, State};
+    use fisher_processor::{JobEvent, RetryPolicy};
+
+    use queue_store;
+    use requests::RequestType;
+    use utils::testing::*;
+    use utils;
+
+    use super::Fisher;
+
+
+    #[test]
+    fn test_idle_timeout() {
+        let hooks_dir = sample_hooks();
+
+        let mut factory = Fisher::new();
+        factory.bind = "127.0.0.1:0";
+        factory.idle_timeout = Some(Duration::from_millis(300));
+        factory.collect_hooks(&hooks_dir, false).unwrap();
+        let mut app = factory.start().unwrap();
+
+        // The request resets the timer, and the job keeps Fisher busy
+        let url = format!("http://{}/hook/long.sh", app.web_address());
+        let requested_at = Instant::now();
+        let res = hyper::Client::new().get(&url).send().unwrap();
+        assert_eq!(res.status, hyper::status::StatusCode::Ok);
+        assert!(! app.is_idle().unwrap());
+
+        while ! app.is_idle().unwrap() {
+            if requested_at.elapsed() > Duration::from_secs(10) {
+                panic!("fisher never became idle");
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // The job takes half a second, and the timeout starts after it
+        assert!(requested_at.elapsed() >= Duration::from_millis(800));
+
+        app.stop().unwrap();
+        fs::remove_dir_all(&hooks_dir).unwrap();
+    }
+
+    #[test]
+    fn test_retry_unsuccessful_jobs() {
+        let hooks_dir = sample_hooks();
+
+        let mut factory = Fisher::new();
+        factory.bind = "127.0.0.1:0";
+        factory.retry = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        };
+        factory.collect_hooks(&hooks_dir, false).unwrap();
+        let mut app = factory.start().unwrap();
+
+        let (events_send, events_recv) = mpsc::channel();
+        app.subscribe(events_send).unwrap();
+
+        // The hook exits with code 1, so it's retried until giving up
+        let url = format!("http://{}/hook/failing.sh", app.web_address());
+        let res = hyper::Client::new().get(&url).send().unwrap();
+        assert_eq!(res.status, hyper::status::StatusCode::Ok);
+
+        let mut started = 0;
+        let mut retries = Vec::new();
+        loop {
+            let timeout = Duration::from_secs(5);
+            match events_recv.recv_timeout(timeout).unwrap() {
+                JobEvent::Started { .. } => started += 1,
+                JobEvent::Retrying { retry, .. } => retries.push(retry),
+                JobEvent::Dropped { ref script } => {
+                    assert_eq!(script, "failing.sh");
+                    break;
+                },
+                _ => {},
+            }
+        }
+        assert_eq!(started, 3);
+        assert_eq!(retries, vec![1, 2]);
+
+        app.stop().unwrap();
+        fs::remove_dir_all(&hooks_dir).unwrap();
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let hooks_dir = sample_hooks();
+        let output_dir = utils::create_temp_dir().unwrap();
+
+        let mut factory = Fisher::new();
+        factory.bind = "127.0.0.1:0";
+        factory.dry_run = true;
+        factory.collect_hooks(&hooks_dir, false).unwrap();
+        let mut app = factory.start().unwrap();
+
+        let (events_send, events_recv) = mpsc::channel();
+        app.subscribe(events_send).unwrap();
+
+        // The request is accepted as usual
+        let url = format!(
+            "http://{}/hook/jobs-details.sh?env={}",
+            app.web_address(), output_dir.to_str().unwrap(),
+        );
+        let res = hyper::Client::new().get(&url).send().unwrap();
+        assert_eq!(res.status, hyper::status::StatusCode::Ok);
+
+        // The job is reported as successful, but the hook never runs
+        loop {
+            let timeout = Duration::from_secs(5);
+            match events_recv.recv_timeout(timeout).unwrap() {
+                JobEvent::Succeeded { ref script, .. } => {
+                    assert_eq!(script, "jobs-details.sh");
+                    break;
+                },
+                JobEvent::Failed { ref error, .. } => {
+                    panic!("the job failed: {}", error);
+                },
+                _ => {},
+            }
+        }
+        assert!(! output_dir.join("executed").exists());
+
+        app.stop().unwrap();
+        fs::remove_dir_all(&hooks_dir).unwrap();
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_tls_options() {
+        // Both the certificate and the key are needed to serve HTTPS
+        let partial = [(Some("cert.pem"), None), (None, Some("key.pem"))];
+        for &(cert, key) in &partial {
+            let mut factory = Fisher::new();
+            factory.bind = "127.0.0.1:0";
+            factory.tls_cert = cert;
+            factory.tls_key = key;
+
+            let error = factory.start().err().expect("fisher started");
+            match *error.kind() {
+                ErrorKind::InvalidInput(..) => {},
+                ref other => panic!("unexpected error: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sandbox_options() {
+        // The nice level must be a valid one
+        for &nice in &[-21, 20] {
+            let mut factory = Fisher::new();
+            factory.bind = "127.0.0.1:0";
+            factory.sandbox.nice = Some(nice);
+
+            let error = factory.start().err().expect("fisher started");
+            match *error.kind() {
+                ErrorKind::InvalidInput(..) => {},
+                ref other => panic!("unexpected error: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_hook() {
+        let hooks_dir = sample_hooks();
+
+        let mut factory = Fisher::new();
+        factory.collect_hooks(&hooks_dir, false).unwrap();
+
+        let run = |hook: &str, params: &[(&str, &str)]| {
+            let mut req = dummy_web_request();
+            for &(key, value) in params {
+                req.params.insert(key.into(), value.into());
+            }
+            factory.run_hook(hook, req)
+        };
+
+        // The output of the job is returned
+        let (request_type, output) = run("example.sh", &[]).unwrap();
+        assert_eq!(request_type, RequestType::ExecuteHook);
+        let output = output.unwrap();
+        assert!(output.success);
+        assert_eq!(output.exit_code, Some(0));
+        assert_eq!(output.stdout, "Hello world\n");
+
+        let output = run("failing.sh", &[]).unwrap().1.unwrap();
+        assert!(! output.success);
+        assert_eq!(output.exit_code, Some(1));
+
+        // The requests are validated by the providers
+        let (request_type, output) = run(
+            "example.sh", &[("secret", "wrong")],
+        ).unwrap();
+        assert_eq!(request_type, RequestType::Invalid);
+        assert!(output.is_none());
+
+        let (request_type, output) = run(
+            "example.sh", &[("request_type", "ping")],
+        ).unwrap();
+        assert_eq!(request_type, RequestType::Ping);
+        assert!(output.is_none());
+
+        let error = run("missing.sh", &[]).err().expect("hook executed");
+        match *error.kind() {
+            ErrorKind::InvalidInput(..) => {},
+            ref other => panic!("unexpected error: {:?}", other),
+        }
+
+        fs::remove_dir_all(&hooks_dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_queue() {
+        let hooks_dir = sample_hooks();
+        let state_dir = utils::create_temp_dir().unwrap();
+        let queue_dir = state_dir.join("queue");
+        let output = state_dir.join("output");
+
+        // Store some jobs as if they were left in the queue
+        let state = State::new();
+        let store = |hook: &str, env: &str, secret: &str| {
+            let mut req = dummy_web_request();
+            req.params.insert("env".into(), env.into());
+            req.params.insert("secret".into(), secret.into());
+            queue_store::write(
+                &queue_dir, state.next_id(IdKind::JobId), hook, &req,
+                &HashMap::new(), false,
+            ).unwrap();
+        };
+        let env = |value: &str| {
+            format!("{}>{}", output.to_str().unwrap(), value)
+        };
+        store("append-val.sh", &env("first"), "testing");
+        store("append-val.sh", &env("invalid"), "wrong");
+        store("missing.sh", &env("missing"), "testing");
+        store("append-val.sh", &env("second"), "testing");
+
+        let mut factory = Fisher::new();
+        factory.bind = "127.0.0.1:0";
+        factory.state_dir = Some(state_dir.to_str().unwrap());
+        factory.collect_hooks(&hooks_dir, false).unwrap();
+        let app = factory.start().unwrap();
+
+        // Only the valid jobs are executed, in the order they were queued
+        let started_at = Instant::now();
+        let mut content = String::new();
+        while content.lines().count() < 2 {
+            if started_at.elapsed() > Duration::from_secs(10) {
+                panic!("the jobs weren't restored");
+            }
+            thread::sleep(Duration::from_millis(10));
+
+            content.clear();
+            if let Ok(mut file) = fs::File::open(&output) {
+                file.read_to_string(&mut content).unwrap();
+            }
+        }
+        assert_eq!(content, "first\nsecond\n");
+
+        // Executed jobs are removed from the queue, while the invalid one is
+        // kept for the next start
+        app.stop().unwrap();
+        let pending = queue_store::restore(&queue_dir).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            pending[0].request.params.get("secret").map(|s| s.as_str()),
+            Some("wrong"),
+        );
+
+        fs::remove_dir_all(&hooks_dir).unwrap();
+        fs::remove_dir_all(&state_dir).unwrap();
     }
 }
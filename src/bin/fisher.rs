@@ -18,29 +18,105 @@ extern crate ansi_term;
 extern crate signal;
 extern crate libc;
 extern crate fisher;
+#[macro_use] extern crate serde_json;
 
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
 use std::time::{Instant, Duration};
 
-use clap::{App, Arg};
-use libc::{SIGINT, SIGTERM, SIGUSR1};
+use clap::{App, AppSettings, Arg, SubCommand};
+use libc::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
 use ansi_term::{Style, Colour};
 
 
 struct CliArgs {
     hooks_dir: String,
     recursive: bool,
-    bind: String,
+    watch: bool,
+    manifest: Option<String>,
+    binds: Vec<String>,
+    admin_bind: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
     env: Vec<String>,
     max_threads: u16,
     behind_proxies: u8,
+    proxy_headers: Option<Vec<String>>,
+    trusted_proxies: Vec<fisher::IpNetwork>,
     enable_health: bool,
+    health_secret: Option<String>,
+    status_hooks: bool,
+    kill_process_group: bool,
+    dry_run: bool,
+    daemon: bool,
+    pid_file: Option<String>,
+    log_file: Option<String>,
+    access_log: Option<f64>,
+    request_log: Option<String>,
+    request_log_max_size: Option<u64>,
+    max_body_memory: Option<usize>,
+    max_body_size: Option<u64>,
+    max_headers: Option<usize>,
+    max_headers_size: Option<usize>,
+    max_output_size: Option<usize>,
+    history_size: usize,
+    max_queue: Option<usize>,
+    kill_signal: Option<String>,
+    kill_grace: u64,
+    timeout: Option<u64>,
+    sandbox: fisher::Sandbox,
+    idle_timeout: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    scheduler: fisher::SchedulerKind,
+    retry: fisher::RetryPolicy,
+    load_shedding: Option<fisher::LoadShedding>,
+    dead_letter_dir: Option<String>,
+    failure_webhook: Option<String>,
+    work_dir: Option<String>,
+    logs_dir: Option<String>,
+    logs_keep: usize,
+    logs_token: Option<String>,
+    url_prefix: Option<String>,
+    env_prefix: Option<String>,
+    env_passthrough: Option<Vec<String>>,
+    state_dir: Option<String>,
+    provider_errors: fisher::ProviderErrorPolicy,
+    open_hooks: Vec<String>,
+    cgroup_parent: Option<String>,
+    cgroup_fallback: bool,
+    log_redact: Vec<String>,
+    ack_fast: bool,
+    log_format: fisher::LogFormat,
 }
 
 
-fn parse_cli() -> fisher::Result<CliArgs> {
+enum CliCommand {
+    Start(CliArgs),
+    Check(CliArgs),
+    Replay(String, String),
+    Run(RunArgs),
+}
+
+
+struct RunArgs {
+    hooks_dir: String,
+    recursive: bool,
+    hook: String,
+    env: Vec<String>,
+    params: Vec<String>,
+    headers: Vec<String>,
+    body: Option<String>,
+}
+
+
+fn parse_cli() -> fisher::Result<CliCommand> {
     let matches = App::new("Fisher")
         .about("Simple webhooks catcher")
         .version(env!("CARGO_PKG_VERSION"))
+        .setting(AppSettings::SubcommandsNegateReqs)
 
         .arg(Arg::with_name("hooks").required(true).index(1)
              .value_name("DIR")
@@ -50,10 +126,30 @@ fn parse_cli() -> fisher::Result<CliArgs> {
              .long("recursive").short("r")
              .help("Search for hooks recursively"))
 
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .help("Reload the hooks when their files change"))
+
         .arg(Arg::with_name("bind").takes_value(true)
+             .multiple(true).number_of_values(1)
              .long("bind").short("b")
              .value_name("PORT")
-             .help("The port to bind fish to"))
+             .help("An address to bind to, or unix:PATH (can be repeated)"))
+
+        .arg(Arg::with_name("admin_bind").takes_value(true)
+             .long("admin-bind")
+             .value_name("PORT")
+             .help("Serve the administrative endpoints on another address"))
+
+        .arg(Arg::with_name("tls_cert").takes_value(true)
+             .long("tls-cert")
+             .value_name("FILE")
+             .help("Serve HTTPS with this PEM certificate"))
+
+        .arg(Arg::with_name("tls_key").takes_value(true)
+             .long("tls-key")
+             .value_name("FILE")
+             .help("The PEM private key of the TLS certificate"))
 
         .arg(Arg::with_name("env").takes_value(true)
              .multiple(true)
@@ -66,21 +162,387 @@ fn parse_cli() -> fisher::Result<CliArgs> {
              .value_name("JOBS_COUNT")
              .help("How much concurrent jobs to run"))
 
+        .arg(Arg::with_name("shed_high_watermark").takes_value(true)
+             .long("shed-high-watermark")
+             .value_name("JOBS")
+             .help("Reject low-priority jobs when more than JOBS are queued"))
+
+        .arg(Arg::with_name("shed_low_watermark").takes_value(true)
+             .long("shed-low-watermark")
+             .value_name("JOBS")
+             .requires("shed_high_watermark")
+             .help("Accept them again when fewer than JOBS are queued"))
+
+        .arg(Arg::with_name("shed_min_priority").takes_value(true)
+             .long("shed-min-priority")
+             .value_name("PRIORITY")
+             .requires("shed_high_watermark")
+             .help("The lowest priority which isn't rejected (default 0)"))
+
+        .arg(Arg::with_name("max_queue").takes_value(true)
+             .long("max-queue")
+             .value_name("JOBS")
+             .help("Reject all the jobs when JOBS are already queued"))
+
         .arg(Arg::with_name("disable_health")
              .long("no-health")
-             .help("Disable the /health endpoint"))
+             .help("Disable the health, metrics, hooks and history endpoints"))
+
+        .arg(Arg::with_name("health_secret").takes_value(true)
+             .long("health-secret")
+             .value_name("SECRET")
+             .conflicts_with("disable_health")
+             .help("Require SECRET to access the health endpoints"))
+
+        .arg(Arg::with_name("disable_status_hooks")
+             .long("no-status-hooks")
+             .help("Don't run the status hooks after the jobs"))
+
+        .arg(Arg::with_name("disable_kill_process_group")
+             .long("no-kill-process-group")
+             .help("Kill only the jobs, not the processes they spawned"))
 
         .arg(Arg::with_name("behind_proxies").takes_value(true)
              .long("behind-proxies")
              .value_name("PROXIES_COUNT")
              .help("How much proxies are behind the app"))
 
+        .arg(Arg::with_name("proxy_headers").takes_value(true)
+             .long("proxy-headers")
+             .value_name("NAMES")
+             .use_delimiter(true)
+             .help("The headers the proxies put the client IP in"))
+
+        .arg(Arg::with_name("trusted_proxies").takes_value(true)
+             .long("trusted-proxies")
+             .value_name("NETWORKS")
+             .use_delimiter(true)
+             .help("Only accept proxied requests from these CIDR networks"))
+
+        .arg(Arg::with_name("dry_run")
+             .long("dry-run")
+             .help("Accept requests without executing the hooks"))
+
+        .arg(Arg::with_name("daemon")
+             .long("daemon")
+             .help("Run Fisher in the background"))
+
+        .arg(Arg::with_name("pid_file").takes_value(true)
+             .long("pid-file")
+             .value_name("FILE")
+             .help("Write the PID of Fisher to FILE"))
+
+        .arg(Arg::with_name("log_file").takes_value(true)
+             .long("log-file")
+             .value_name("FILE")
+             .requires("daemon")
+             .help("Append the output to FILE when in the background"))
+
+        .arg(Arg::with_name("check")
+             .long("check")
+             .help("Check the hooks are valid without starting Fisher"))
+
+        .arg(Arg::with_name("access_log")
+             .long("access-log")
+             .help("Log every incoming request"))
+
+        .arg(Arg::with_name("access_log_sample").takes_value(true)
+             .long("access-log-sample")
+             .value_name("RATE")
+             .requires("access_log")
+             .help("Fraction of the successful requests to log"))
+
+        .arg(Arg::with_name("request_log").takes_value(true)
+             .long("request-log")
+             .value_name("FILE")
+             .help("Log every request to the hooks in this file"))
+
+        .arg(Arg::with_name("request_log_max_size").takes_value(true)
+             .long("request-log-max-size")
+             .value_name("BYTES")
+             .requires("request_log")
+             .help("Rotate the request log at this size (default 10MB)"))
+
+        .arg(Arg::with_name("max_body_memory").takes_value(true)
+             .long("max-body-memory")
+             .value_name("BYTES")
+             .help("Store on disk the request bodies bigger than this"))
+
+        .arg(Arg::with_name("max_body_size").takes_value(true)
+             .long("max-body-size")
+             .value_name("BYTES")
+             .help("Reject request bodies bigger than this (default 10MB)"))
+
+        .arg(Arg::with_name("max_headers").takes_value(true)
+             .long("max-headers")
+             .value_name("COUNT")
+             .help("Reject requests with more headers (default 100)"))
+
+        .arg(Arg::with_name("max_headers_size").takes_value(true)
+             .long("max-headers-size")
+             .value_name("BYTES")
+             .help("Reject request headers bigger than this (default 32KB)"))
+
+        .arg(Arg::with_name("max_output_size").takes_value(true)
+             .long("max-output-size")
+             .value_name("BYTES")
+             .help("Keep only this much of the output of jobs (default 1MB)"))
+
+        .arg(Arg::with_name("history_size").takes_value(true)
+             .long("history-size")
+             .value_name("COUNT")
+             .help("How many executions of each hook to keep (default 10)"))
+
+        .arg(Arg::with_name("timeout").takes_value(true)
+             .long("timeout")
+             .value_name("SECONDS")
+             .help("Kill the jobs running for longer than this"))
+
+        .arg(Arg::with_name("kill_signal").takes_value(true)
+             .long("kill-signal")
+             .value_name("SIGNAL")
+             .help("The signal sent to the jobs which need to be killed"))
+
+        .arg(Arg::with_name("kill_grace").takes_value(true)
+             .long("kill-grace")
+             .value_name("SECONDS")
+             .help("How long to wait before sending SIGKILL to the jobs"))
+
+        .arg(Arg::with_name("job_nice").takes_value(true)
+             .long("job-nice")
+             .value_name("LEVEL")
+             .allow_hyphen_values(true)
+             .help("Run the jobs with this nice level (-20 to 19)"))
+
+        .arg(Arg::with_name("job_uid").takes_value(true)
+             .long("job-uid")
+             .value_name("UID")
+             .help("Run the jobs as this user"))
+
+        .arg(Arg::with_name("job_gid").takes_value(true)
+             .long("job-gid")
+             .value_name("GID")
+             .help("Run the jobs as this group"))
+
+        .arg(Arg::with_name("job_cpu_limit").takes_value(true)
+             .long("job-cpu-limit")
+             .value_name("SECONDS")
+             .help("Limit the CPU time of each process of the jobs"))
+
+        .arg(Arg::with_name("job_memory_limit").takes_value(true)
+             .long("job-memory-limit")
+             .value_name("BYTES")
+             .help("Limit the virtual memory of each process of the jobs"))
+
+        .arg(Arg::with_name("idle_timeout").takes_value(true)
+             .long("idle-timeout")
+             .value_name("MINUTES")
+             .help("Exit after being idle for this many minutes"))
+
+        .arg(Arg::with_name("shutdown_timeout").takes_value(true)
+             .long("shutdown-timeout")
+             .value_name("SECONDS")
+             .help("How long to wait for the running jobs when exiting"))
+
+        .arg(Arg::with_name("max_retries").takes_value(true)
+             .long("max-retries")
+             .value_name("COUNT")
+             .help("Execute the failed jobs again up to COUNT times"))
+
+        .arg(Arg::with_name("retry_delay").takes_value(true)
+             .long("retry-delay")
+             .value_name("SECONDS")
+             .requires("max_retries")
+             .help("Wait before the first retry, doubling it every time"))
+
+        .arg(Arg::with_name("max_retry_delay").takes_value(true)
+             .long("max-retry-delay")
+             .value_name("SECONDS")
+             .requires("max_retries")
+             .help("The longest wait between two retries (default 60)"))
+
+        .arg(Arg::with_name("scheduler").takes_value(true)
+             .long("scheduler")
+             .value_name("KIND")
+             .possible_values(&["per-thread", "shared"])
+             .help("How the jobs are dispatched to the threads"))
+
+        .arg(Arg::with_name("log_format").takes_value(true)
+             .long("log-format")
+             .value_name("FORMAT")
+             .possible_values(&["text", "json"])
+             .help("Log the events and the errors as JSON objects"))
+
+        .arg(Arg::with_name("dead_letter_dir").takes_value(true)
+             .long("dead-letter-dir")
+             .value_name("DIR")
+             .help("Store the jobs which failed permanently in a directory"))
+
+        .arg(Arg::with_name("failure_webhook").takes_value(true)
+             .long("failure-webhook")
+             .value_name("URL")
+             .help("Send a POST request to URL every time a job fails"))
+
+        .arg(Arg::with_name("work_dir").takes_value(true)
+             .long("work-dir")
+             .value_name("DIR")
+             .help("Create the working directories of the jobs in DIR"))
+
+        .arg(Arg::with_name("logs_dir").takes_value(true)
+             .long("logs-dir")
+             .value_name("DIR")
+             .help("Store the output of the jobs in a directory"))
+
+        .arg(Arg::with_name("logs_keep").takes_value(true)
+             .long("logs-keep")
+             .value_name("COUNT")
+             .requires("logs_dir")
+             .help("How many job logs to keep (default 1000)"))
+
+        .arg(Arg::with_name("logs_token").takes_value(true)
+             .long("logs-token")
+             .value_name("TOKEN")
+             .requires("logs_dir")
+             .help("Serve the stored output at /jobs/<id>/log with TOKEN"))
+
+        .arg(Arg::with_name("url_prefix").takes_value(true)
+             .long("url-prefix")
+             .value_name("PREFIX")
+             .help("Serve all the endpoints under PREFIX"))
+
+        .arg(Arg::with_name("env_prefix").takes_value(true)
+             .long("env-prefix")
+             .value_name("PREFIX")
+             .help("Prefix of the variables set by Fisher (default FISHER)"))
+
+        .arg(Arg::with_name("env_passthrough").takes_value(true)
+             .long("env-passthrough")
+             .value_name("NAMES")
+             .use_delimiter(true)
+             .help("Variables inherited by the jobs (default PATH, LANG...)"))
+
+        .arg(Arg::with_name("state_dir").takes_value(true)
+             .long("state-dir")
+             .value_name("DIR")
+             .help("Keep the queued jobs in a directory across restarts"))
+
+        .arg(Arg::with_name("provider_error_policy").takes_value(true)
+             .long("provider-error-policy")
+             .value_name("POLICY")
+             .possible_values(&["closed", "open"])
+             .help("Accept or reject requests providers failed to validate"))
+
+        .arg(Arg::with_name("open_hooks").takes_value(true)
+             .long("open-hooks")
+             .value_name("HOOKS")
+             .use_delimiter(true)
+             .help("Hooks which can be called without being validated"))
+
+        .arg(Arg::with_name("cgroup_parent").takes_value(true)
+             .long("cgroup-parent")
+             .value_name("DIR")
+             .help("The cgroup (v2) the jobs with resource limits run in"))
+
+        .arg(Arg::with_name("cgroup_fallback")
+             .long("cgroup-fallback")
+             .help("Run the jobs without limits if cgroups are unavailable"))
+
+        .arg(Arg::with_name("log_redact").takes_value(true)
+             .long("log-redact")
+             .value_name("NAMES")
+             .use_delimiter(true)
+             .help("Params and headers whose values are masked in the logs"))
+
+        .arg(Arg::with_name("manifest").takes_value(true)
+             .long("manifest")
+             .value_name("FILE")
+             .help("Only load the hooks matching the checksums in FILE"))
+
+        .arg(Arg::with_name("ack_fast")
+             .long("ack-fast")
+             .help("Reply to the requests before validating them"))
+
+        .subcommand(SubCommand::with_name("replay")
+             .about("Send again a job stored in the dead letter directory")
+             .arg(Arg::with_name("file").required(true).index(1)
+                  .value_name("FILE")
+                  .help("The file describing the failed job"))
+             .arg(Arg::with_name("to").takes_value(true)
+                  .long("to")
+                  .value_name("ADDRESS")
+                  .help("The address Fisher is listening on, like --bind")))
+
+        .subcommand(SubCommand::with_name("run")
+             .about("Run a single hook in the foreground")
+             .arg(Arg::with_name("hooks").required(true).index(1)
+                  .value_name("DIR")
+                  .help("The directory which contains the hooks"))
+             .arg(Arg::with_name("hook").required(true).index(2)
+                  .value_name("HOOK")
+                  .help("The name of the hook to run"))
+             .arg(Arg::with_name("recursive")
+                  .long("recursive").short("r")
+                  .help("Search for hooks recursively"))
+             .arg(Arg::with_name("env").takes_value(true)
+                  .multiple(true).number_of_values(1)
+                  .long("env").short("e")
+                  .value_name("KEY=VALUE")
+                  .help("Add additional environment variables"))
+             .arg(Arg::with_name("param").takes_value(true)
+                  .multiple(true).number_of_values(1)
+                  .long("param").short("p")
+                  .value_name("KEY=VALUE")
+                  .help("Add a query string parameter to the request"))
+             .arg(Arg::with_name("header").takes_value(true)
+                  .multiple(true).number_of_values(1)
+                  .long("header").short("H")
+                  .value_name("NAME=VALUE")
+                  .help("Add a header to the request"))
+             .arg(Arg::with_name("body").takes_value(true)
+                  .long("body")
+                  .value_name("BODY")
+                  .help("The body of the request, or @FILE to read a file")))
+
         .get_matches();
 
-    Ok(CliArgs {
+    if let Some(matches) = matches.subcommand_matches("replay") {
+        return Ok(CliCommand::Replay(
+            matches.value_of("file").unwrap().into(),
+            matches.value_of("to").unwrap_or("127.0.0.1:8000").into(),
+        ));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("run") {
+        let values = |name| -> Vec<String> {
+            if let Some(values) = matches.values_of(name) {
+                values.map(|v| v.to_string()).collect()
+            } else { Vec::new() }
+        };
+
+        return Ok(CliCommand::Run(RunArgs {
+            hooks_dir: matches.value_of("hooks").unwrap().into(),
+            recursive: matches.is_present("recursive"),
+            hook: matches.value_of("hook").unwrap().into(),
+            env: values("env"),
+            params: values("param"),
+            headers: values("header"),
+            body: matches.value_of("body").map(|b| b.to_string()),
+        }));
+    }
+
+    let args = CliArgs {
         hooks_dir: matches.value_of("hooks").unwrap().into(),
+        manifest: matches.value_of("manifest").map(|m| m.to_string()),
         recursive: matches.is_present("recursive"),
-        bind: matches.value_of("bind").unwrap_or("127.0.0.1:8000").into(),
+        watch: matches.is_present("watch"),
+        binds: {
+            if let Some(values) = matches.values_of("bind") {
+                values.map(|v| v.to_string()).collect()
+            } else { vec!["127.0.0.1:8000".to_string()] }
+        },
+        admin_bind: matches.value_of("admin_bind").map(|b| b.to_string()),
+        tls_cert: matches.value_of("tls_cert").map(|c| c.to_string()),
+        tls_key: matches.value_of("tls_key").map(|k| k.to_string()),
         env: {
             if let Some(values) = matches.values_of("env") {
                 values.map(|v| v.to_string()).collect()
@@ -89,13 +551,221 @@ fn parse_cli() -> fisher::Result<CliArgs> {
         max_threads: {
             matches.value_of("max_threads").unwrap_or("1").parse::<u16>()?
         },
+        load_shedding: match matches.value_of("shed_high_watermark") {
+            Some(high) => {
+                let high = high.parse::<usize>()?;
+                let low = match matches.value_of("shed_low_watermark") {
+                    Some(low) => low.parse::<usize>()?,
+                    None => high / 2,
+                };
+                Some(fisher::LoadShedding {
+                    high_watermark: high,
+                    low_watermark: low,
+                    min_priority: matches.value_of("shed_min_priority")
+                        .unwrap_or("0").parse::<isize>()?,
+                })
+            },
+            None => None,
+        },
         behind_proxies: {
             if let Some(count) = matches.value_of("behind_proxies") {
                 count.parse::<u8>()?
             } else { 0 }
         },
+        proxy_headers: matches.values_of("proxy_headers").map(|values| {
+            values.filter(|v| ! v.is_empty())
+                  .map(|v| v.to_string()).collect()
+        }),
+        trusted_proxies: {
+            let mut networks = Vec::new();
+            if let Some(values) = matches.values_of("trusted_proxies") {
+                for value in values.filter(|v| ! v.is_empty()) {
+                    networks.push(value.parse::<fisher::IpNetwork>()?);
+                }
+            }
+            networks
+        },
         enable_health: ! matches.is_present("disable_health"),
-    })
+        health_secret: {
+            matches.value_of("health_secret").map(|s| s.to_string())
+        },
+        status_hooks: ! matches.is_present("disable_status_hooks"),
+        kill_process_group: ! matches.is_present(
+            "disable_kill_process_group"
+        ),
+        dry_run: matches.is_present("dry_run"),
+        daemon: matches.is_present("daemon"),
+        pid_file: matches.value_of("pid_file").map(|f| f.to_string()),
+        log_file: matches.value_of("log_file").map(|f| f.to_string()),
+        access_log: {
+            if matches.is_present("access_log") {
+                let rate = matches.value_of("access_log_sample")
+                                  .unwrap_or("1").parse::<f64>();
+                match rate {
+                    Ok(rate) if rate >= 0.0 && rate <= 1.0 => Some(rate),
+                    _ => return Err(fisher::ErrorKind::InvalidInput(
+                        "the access log sample rate must be between 0 and 1"
+                            .into()
+                    ).into()),
+                }
+            } else { None }
+        },
+        request_log: matches.value_of("request_log").map(|f| f.to_string()),
+        request_log_max_size: {
+            if let Some(bytes) = matches.value_of("request_log_max_size") {
+                Some(bytes.parse::<u64>()?)
+            } else { None }
+        },
+        max_body_memory: {
+            if let Some(bytes) = matches.value_of("max_body_memory") {
+                Some(bytes.parse::<usize>()?)
+            } else { None }
+        },
+        max_body_size: {
+            if let Some(bytes) = matches.value_of("max_body_size") {
+                Some(bytes.parse::<u64>()?)
+            } else { None }
+        },
+        max_headers: {
+            if let Some(count) = matches.value_of("max_headers") {
+                Some(count.parse::<usize>()?)
+            } else { None }
+        },
+        max_headers_size: {
+            if let Some(bytes) = matches.value_of("max_headers_size") {
+                Some(bytes.parse::<usize>()?)
+            } else { None }
+        },
+        max_output_size: {
+            if let Some(bytes) = matches.value_of("max_output_size") {
+                Some(bytes.parse::<usize>()?)
+            } else { None }
+        },
+        kill_signal: matches.value_of("kill_signal").map(|s| s.to_string()),
+        history_size: {
+            matches.value_of("history_size").unwrap_or("10")
+                .parse::<usize>()?
+        },
+        max_queue: {
+            if let Some(jobs) = matches.value_of("max_queue") {
+                Some(jobs.parse::<usize>()?)
+            } else { None }
+        },
+        kill_grace: {
+            matches.value_of("kill_grace").unwrap_or("0").parse::<u64>()?
+        },
+        timeout: {
+            if let Some(seconds) = matches.value_of("timeout") {
+                Some(seconds.parse::<u64>()?)
+            } else { None }
+        },
+        sandbox: fisher::Sandbox {
+            nice: match matches.value_of("job_nice") {
+                Some(level) => Some(level.parse::<i32>()?),
+                None => None,
+            },
+            uid: match matches.value_of("job_uid") {
+                Some(uid) => Some(uid.parse::<u32>()?),
+                None => None,
+            },
+            gid: match matches.value_of("job_gid") {
+                Some(gid) => Some(gid.parse::<u32>()?),
+                None => None,
+            },
+            cpu_time: match matches.value_of("job_cpu_limit") {
+                Some(seconds) => Some(seconds.parse::<u64>()?),
+                None => None,
+            },
+            address_space: match matches.value_of("job_memory_limit") {
+                Some(bytes) => Some(bytes.parse::<u64>()?),
+                None => None,
+            },
+        },
+        idle_timeout: {
+            if let Some(minutes) = matches.value_of("idle_timeout") {
+                Some(minutes.parse::<u64>()?)
+            } else { None }
+        },
+        shutdown_timeout: {
+            if let Some(seconds) = matches.value_of("shutdown_timeout") {
+                Some(seconds.parse::<u64>()?)
+            } else { None }
+        },
+        scheduler: match matches.value_of("scheduler") {
+            Some("shared") => fisher::SchedulerKind::Shared,
+            _ => fisher::SchedulerKind::PerThread,
+        },
+        retry: {
+            let default = fisher::RetryPolicy::default();
+            let seconds = |name: &str| -> fisher::Result<Option<Duration>> {
+                match matches.value_of(name) {
+                    Some(value) => Ok(Some(
+                        Duration::from_secs(value.parse::<u64>()?)
+                    )),
+                    None => Ok(None),
+                }
+            };
+
+            fisher::RetryPolicy {
+                max_retries: matches.value_of("max_retries").unwrap_or("0")
+                    .parse::<u32>()?,
+                base_delay: seconds("retry_delay")?
+                    .unwrap_or(default.base_delay),
+                max_delay: seconds("max_retry_delay")?
+                    .unwrap_or(default.max_delay),
+            }
+        },
+        dead_letter_dir: {
+            matches.value_of("dead_letter_dir").map(|d| d.to_string())
+        },
+        failure_webhook: {
+            matches.value_of("failure_webhook").map(|u| u.to_string())
+        },
+        work_dir: matches.value_of("work_dir").map(|d| d.to_string()),
+        logs_dir: matches.value_of("logs_dir").map(|d| d.to_string()),
+        logs_keep: {
+            matches.value_of("logs_keep").unwrap_or("1000")
+                .parse::<usize>()?
+        },
+        logs_token: matches.value_of("logs_token").map(|t| t.to_string()),
+        url_prefix: matches.value_of("url_prefix").map(|p| p.to_string()),
+        env_prefix: matches.value_of("env_prefix").map(|p| p.to_string()),
+        env_passthrough: matches.values_of("env_passthrough").map(|values| {
+            values.filter(|v| ! v.is_empty()).map(|v| v.to_string()).collect()
+        }),
+        state_dir: matches.value_of("state_dir").map(|d| d.to_string()),
+        provider_errors: match matches.value_of("provider_error_policy") {
+            Some("open") => fisher::ProviderErrorPolicy::Open,
+            _ => fisher::ProviderErrorPolicy::Closed,
+        },
+        open_hooks: {
+            if let Some(values) = matches.values_of("open_hooks") {
+                values.filter(|v| ! v.is_empty())
+                      .map(|v| v.to_string()).collect()
+            } else { Vec::new() }
+        },
+        cgroup_parent: {
+            matches.value_of("cgroup_parent").map(|c| c.to_string())
+        },
+        cgroup_fallback: matches.is_present("cgroup_fallback"),
+        log_redact: {
+            if let Some(values) = matches.values_of("log_redact") {
+                values.filter(|v| ! v.is_empty())
+                      .map(|v| v.to_string()).collect()
+            } else { Vec::new() }
+        },
+        ack_fast: matches.is_present("ack_fast"),
+        log_format: match matches.value_of("log_format") {
+            Some("json") => fisher::LogFormat::Json,
+            _ => fisher::LogFormat::Text,
+        },
+    };
+
+    if matches.is_present("check") {
+        Ok(CliCommand::Check(args))
+    } else {
+        Ok(CliCommand::Start(args))
+    }
 }
 
 
@@ -109,52 +779,633 @@ fn print_err<T>(result: fisher::Result<T>) -> fisher::Result<T> {
 }
 
 
-fn app() -> fisher::Result<()> {
-    let signal_trap = signal::trap::Trap::trap(&[
-        SIGINT,  // Interrupt the program
-        SIGTERM,  // Interrupt the program
-        SIGUSR1,  // Reload Fisher
-    ]);
+fn print_hooks_diff(diff: &fisher::HooksDiff) {
+    if fisher::json_output() {
+        fisher::log_message("reloaded", json!({
+            "added": diff.added,
+            "removed": diff.removed,
+            "changed": diff.changed,
+        }));
+        return;
+    }
 
-    // Load the options from the CLI arguments
-    let args = parse_cli()?;
+    if diff.is_empty() {
+        println!("No hooks changed");
+        return;
+    }
+
+    for name in &diff.added {
+        println!("{} {}", Colour::Green.paint("+"), name);
+    }
+    for name in &diff.removed {
+        println!("{} {}", Colour::Red.paint("-"), name);
+    }
+    for name in &diff.changed {
+        println!("{} {}", Colour::Yellow.paint("~"), name);
+    }
+}
+
+
+fn replay(file: &str, addr: &str) -> fisher::Result<()> {
+    let status = fisher::replay_dead_letter(file, addr)?;
+    if status != 200 {
+        return Err(fisher::ErrorKind::InvalidInput(
+            format!("the server replied with status code {}", status)
+        ).into());
+    }
+
+    println!("{} {} to {}",
+        Colour::Green.bold().paint("Replayed"), file, addr,
+    );
+    Ok(())
+}
+
+
+fn parse_pairs(pairs: &[String]) -> fisher::Result<HashMap<String, String>> {
+    let mut result = HashMap::new();
+    for pair in pairs {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if ! key.is_empty() => {
+                result.insert(key.to_string(), value.to_string());
+            },
+            _ => return Err(fisher::ErrorKind::InvalidInput(
+                format!("expected KEY=VALUE: {}", pair)
+            ).into()),
+        }
+    }
+    Ok(result)
+}
+
+
+fn run(args: &RunArgs) -> fisher::Result<()> {
+    let mut factory = fisher::Fisher::new();
+    for env in &args.env {
+        factory.raw_env(env)?;
+    }
+    factory.collect_hooks(&args.hooks_dir, args.recursive)?;
+
+    // Bodies starting with @ are read from a file, like curl does
+    let body = match args.body {
+        Some(ref body) if body.starts_with('@') => {
+            let mut content = String::new();
+            File::open(&body[1..])?.read_to_string(&mut content)?;
+            content
+        },
+        Some(ref body) => body.clone(),
+        None => String::new(),
+    };
+
+    let request = fisher::WebRequest {
+        source: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        method: "POST".into(),
+        url: format!("/hook/{}", args.hook),
+        headers: parse_pairs(&args.headers)?,
+        params: parse_pairs(&args.params)?,
+        body: body,
+        body_file: None,
+    };
+
+    let output = match factory.run_hook(&args.hook, request)? {
+        (_, Some(output)) => output,
+        (request_type, None) => {
+            println!("{} the request is {}",
+                Colour::Yellow.bold().paint("Not executed:"),
+                match request_type {
+                    fisher::RequestType::Ping => "a ping",
+                    fisher::RequestType::Skip => "skipped by the hook",
+                    _ => "not valid",
+                },
+            );
+            ::std::process::exit(1);
+        },
+    };
+
+    io::stdout().write_all(output.stdout.as_bytes())?;
+    io::stderr().write_all(output.stderr.as_bytes())?;
+
+    let status = if output.timed_out {
+        "timed out".to_string()
+    } else if let Some(code) = output.exit_code {
+        format!("exit code {}", code)
+    } else if let Some(signal) = output.signal {
+        format!("killed by signal {}", signal)
+    } else {
+        "unknown".to_string()
+    };
+    if output.success {
+        println!("{} {}", Colour::Green.bold().paint("Succeeded:"), status);
+        Ok(())
+    } else {
+        println!("{} {}", Colour::Red.bold().paint("Failed:"), status);
+        ::std::process::exit(1);
+    }
+}
+
+
+fn check(args: &CliArgs) -> fisher::Result<()> {
+    let mut factory = fisher::Fisher::new();
+    if let Some(ref manifest) = args.manifest {
+        factory.verify_manifest(manifest)?;
+    }
+
+    let mut hooks = factory.check_hooks(&args.hooks_dir, args.recursive)?;
+    hooks.sort_by_key(|hook| match *hook {
+        Ok(ref summary) => summary.exec.clone(),
+        Err(ref error) => Some(error.location().to_string()),
+    });
+
+    let mut invalid = 0;
+    for hook in &hooks {
+        match *hook {
+            Ok(ref summary) => {
+                println!("{} {}",
+                    Colour::Green.bold().paint("valid  "), summary.name,
+                );
+                if ! summary.providers.is_empty() {
+                    println!("        providers: {}",
+                        summary.providers.join(", "),
+                    );
+                }
+            },
+            Err(ref error) => {
+                invalid += 1;
+                println!("{} {}",
+                    Colour::Red.bold().paint("invalid"), error.location(),
+                );
+                println!("        {}", error);
+            },
+        }
+    }
 
-    // Show the relevant options
+    println!("{} {} valid, {} invalid",
+        Style::new().bold().paint("Checked hooks:"),
+        hooks.len() - invalid, invalid,
+    );
+    if invalid > 0 {
+        ::std::process::exit(1);
+    }
+    Ok(())
+}
+
+
+fn show_options(args: &CliArgs) {
     println!("{} {}",
         Style::new().bold().paint("Concurrent jobs:"),
         args.max_threads
     );
+    if args.scheduler == fisher::SchedulerKind::Shared {
+        println!("{} {}",
+            Style::new().bold().paint("Scheduler:      "),
+            "shared queue",
+        );
+    }
+    if let Some(shedding) = args.load_shedding {
+        println!("{} priority < {} rejected above {} queued jobs, \
+                  until below {}",
+            Style::new().bold().paint("Load shedding:  "),
+            shedding.min_priority, shedding.high_watermark,
+            shedding.low_watermark,
+        );
+    }
+    if let Some(jobs) = args.max_queue {
+        println!("{} {}",
+            Style::new().bold().paint("Max queue:      "),
+            format!("{} jobs", jobs),
+        );
+    }
     println!("{} {}",
         Style::new().bold().paint("Health checks:  "),
-        if args.enable_health { "enabled" } else { "disabled" }
+        if ! args.enable_health {
+            "disabled"
+        } else if args.health_secret.is_some() {
+            "enabled (with a secret)"
+        } else {
+            "enabled"
+        }
     );
+    if ! args.status_hooks {
+        println!("{} disabled",
+            Style::new().bold().paint("Status hooks:   "),
+        );
+    }
+    if args.watch {
+        println!("{} enabled",
+            Style::new().bold().paint("Hooks watcher:  "),
+        );
+    }
+    if ! args.kill_process_group {
+        println!("{} only the jobs",
+            Style::new().bold().paint("Kill processes: "),
+        );
+    }
     println!("{} {}",
         Style::new().bold().paint("Proxy support:  "),
         if args.behind_proxies != 0 {
             format!("enabled (behind {} proxies)", args.behind_proxies)
         } else { "disabled".to_string() }
     );
+    if args.behind_proxies != 0 {
+        if let Some(ref names) = args.proxy_headers {
+            println!("{} {}",
+                Style::new().bold().paint("Proxy headers:  "),
+                names.join(", "),
+            );
+        }
+        if ! args.trusted_proxies.is_empty() {
+            println!("{} {}",
+                Style::new().bold().paint("Trusted proxies:"),
+                args.trusted_proxies.iter()
+                    .map(|network| network.to_string())
+                    .collect::<Vec<_>>().join(", "),
+            );
+        }
+    }
+    if let Some(ref prefix) = args.url_prefix {
+        println!("{} {}",
+            Style::new().bold().paint("URL prefix:     "),
+            prefix,
+        );
+    }
+    if let Some(ref prefix) = args.env_prefix {
+        println!("{} {}",
+            Style::new().bold().paint("Env prefix:     "),
+            prefix,
+        );
+    }
+    if let Some(ref names) = args.env_passthrough {
+        println!("{} {}",
+            Style::new().bold().paint("Inherited env:  "),
+            if names.is_empty() {
+                "nothing".to_string()
+            } else {
+                names.join(", ")
+            },
+        );
+    }
+    if args.tls_cert.is_some() {
+        println!("{} {}",
+            Style::new().bold().paint("HTTPS:          "),
+            "enabled",
+        );
+    }
+    if args.dry_run {
+        println!("{} {}",
+            Style::new().bold().paint("Dry-run mode:   "),
+            Colour::Yellow.paint("enabled (hooks won't be executed)")
+        );
+    }
+    if let Some(ref path) = args.pid_file {
+        println!("{} {}",
+            Style::new().bold().paint("PID file:       "), path,
+        );
+    }
+
+    if let Some(rate) = args.access_log {
+        println!("{} {}",
+            Style::new().bold().paint("Access log:     "),
+            if rate < 1.0 {
+                format!(
+                    "enabled ({}% of the successful requests)", rate * 100.0,
+                )
+            } else { "enabled".to_string() }
+        );
+    }
+
+    if let Some(ref file) = args.request_log {
+        println!("{} {}",
+            Style::new().bold().paint("Request log:    "), file,
+        );
+    }
+
+    if let Some(seconds) = args.timeout {
+        println!("{} {}",
+            Style::new().bold().paint("Job timeout:    "),
+            format!("{} seconds", seconds),
+        );
+    }
+
+    if args.retry.max_retries > 0 {
+        println!("{} {}",
+            Style::new().bold().paint("Retries:        "),
+            format!("up to {} (after {}s, doubling up to {}s)",
+                args.retry.max_retries, args.retry.base_delay.as_secs(),
+                args.retry.max_delay.as_secs(),
+            ),
+        );
+    }
+
+    if let Some(minutes) = args.idle_timeout {
+        println!("{} {}",
+            Style::new().bold().paint("Idle timeout:   "),
+            format!("exit after {} minutes", minutes),
+        );
+    }
+
+    if let Some(seconds) = args.shutdown_timeout {
+        println!("{} {}",
+            Style::new().bold().paint("Shutdown:       "),
+            format!("wait up to {} seconds for the jobs", seconds),
+        );
+    }
+
+    if let Some(bytes) = args.max_body_memory {
+        println!("{} {}",
+            Style::new().bold().paint("Bodies on disk: "),
+            format!("when bigger than {} bytes", bytes),
+        );
+    }
+
+    if let Some(bytes) = args.max_body_size {
+        println!("{} {}",
+            Style::new().bold().paint("Max body size:  "),
+            format!("{} bytes", bytes),
+        );
+    }
+
+    if let Some(count) = args.max_headers {
+        println!("{} {}",
+            Style::new().bold().paint("Max headers:    "),
+            format!("{} headers", count),
+        );
+    }
+
+    if let Some(bytes) = args.max_headers_size {
+        println!("{} {}",
+            Style::new().bold().paint("Max headers size:"),
+            format!("{} bytes", bytes),
+        );
+    }
+
+    if let Some(bytes) = args.max_output_size {
+        println!("{} {}",
+            Style::new().bold().paint("Max output size:"),
+            format!("{} bytes", bytes),
+        );
+    }
+
+    if let Some(ref signal) = args.kill_signal {
+        println!("{} {}",
+            Style::new().bold().paint("Kill signal:    "),
+            format!("{} (SIGKILL after {}s)", signal, args.kill_grace),
+        );
+    }
+
+    if ! args.sandbox.is_empty() {
+        let mut restrictions = Vec::new();
+        if let Some(nice) = args.sandbox.nice {
+            restrictions.push(format!("nice {}", nice));
+        }
+        if let Some(uid) = args.sandbox.uid {
+            restrictions.push(format!("uid {}", uid));
+        }
+        if let Some(gid) = args.sandbox.gid {
+            restrictions.push(format!("gid {}", gid));
+        }
+        if let Some(seconds) = args.sandbox.cpu_time {
+            restrictions.push(format!("{}s of CPU", seconds));
+        }
+        if let Some(bytes) = args.sandbox.address_space {
+            restrictions.push(format!("{} bytes of memory", bytes));
+        }
+
+        println!("{} {}",
+            Style::new().bold().paint("Job sandbox:    "),
+            restrictions.join(", "),
+        );
+    }
+
+    if args.provider_errors == fisher::ProviderErrorPolicy::Open {
+        println!("{} {}",
+            Style::new().bold().paint("Provider errors:"),
+            Colour::Yellow.paint("requests are accepted"),
+        );
+    }
+
+    if let Some(ref dir) = args.dead_letter_dir {
+        println!("{} {}",
+            Style::new().bold().paint("Dead letters:   "), dir,
+        );
+    }
+
+    if let Some(ref url) = args.failure_webhook {
+        println!("{} {}",
+            Style::new().bold().paint("Failure webhook:"), url,
+        );
+    }
+
+    if let Some(ref dir) = args.work_dir {
+        println!("{} {}",
+            Style::new().bold().paint("Work directory: "), dir,
+        );
+    }
+
+    if let Some(ref dir) = args.logs_dir {
+        println!("{} {}{}",
+            Style::new().bold().paint("Job logs:       "), dir,
+            if args.logs_token.is_some() {
+                " (served at /jobs/<id>/log)"
+            } else { "" },
+        );
+    }
+
+    if let Some(ref dir) = args.state_dir {
+        println!("{} {}",
+            Style::new().bold().paint("State:          "), dir,
+        );
+    }
+
+    if let Some(ref manifest) = args.manifest {
+        println!("{} {}",
+            Style::new().bold().paint("Manifest:       "), manifest,
+        );
+    }
+
+    if ! args.log_redact.is_empty() {
+        println!("{} {}",
+            Style::new().bold().paint("Redacted in logs:"),
+            args.log_redact.join(", "),
+        );
+    }
+
+    if args.ack_fast {
+        println!("{} {}",
+            Style::new().bold().paint("Responses:      "),
+            Colour::Yellow.paint("sent before validating the requests"),
+        );
+    }
+
+    if let Some(ref cgroup) = args.cgroup_parent {
+        println!("{} {}{}",
+            Style::new().bold().paint("Cgroup parent:  "), cgroup,
+            if args.cgroup_fallback {
+                " (limits ignored if unavailable)"
+            } else { "" },
+        );
+    }
 
     println!("");
+}
+
+
+fn app() -> fisher::Result<()> {
+    let signal_trap = signal::trap::Trap::trap(&[
+        SIGINT,  // Interrupt the program
+        SIGTERM,  // Interrupt the program
+        SIGUSR1,  // Reload Fisher
+        SIGHUP,  // Reload Fisher, like most daemons
+    ]);
+
+    // Load the options from the CLI arguments
+    let args = match parse_cli()? {
+        CliCommand::Start(args) => args,
+        CliCommand::Check(args) => return check(&args),
+        CliCommand::Replay(file, addr) => return replay(&file, &addr),
+        CliCommand::Run(args) => return run(&args),
+    };
+
+    // Fisher must be detached before any thread is started
+    if args.daemon {
+        fisher::daemonize(args.log_file.as_ref().map(Path::new))?;
+    }
+
+    // The PID file is removed when this is dropped, at the end of a clean
+    // shutdown
+    let pid_file = match args.pid_file {
+        Some(ref path) => Some(fisher::PidFile::create(path)?),
+        None => None,
+    };
+
+    // The options are shown only in the human readable format, while the
+    // other messages are printed in JSON too
+    fisher::set_log_format(args.log_format);
+    if args.log_format == fisher::LogFormat::Text {
+        show_options(&args);
+    }
 
     // Create a new Fisher instance
     let mut factory = fisher::Fisher::new();
 
     factory.max_threads = args.max_threads;
     factory.behind_proxies = args.behind_proxies;
-    factory.bind = &args.bind;
+    if let Some(ref names) = args.proxy_headers {
+        factory.proxy_headers = names.clone();
+    }
+    factory.trusted_proxies = args.trusted_proxies.clone();
+    factory.bind = &args.binds[0];
+    factory.extra_binds = args.binds[1..].to_vec();
+    factory.admin_bind = args.admin_bind.as_ref().map(|b| b.as_str());
+    factory.tls_cert = args.tls_cert.as_ref().map(|c| c.as_str());
+    factory.tls_key = args.tls_key.as_ref().map(|k| k.as_str());
     factory.enable_health = args.enable_health;
+    factory.health_secret = args.health_secret.as_ref().map(|s| s.as_str());
+    factory.status_hooks = args.status_hooks;
+    factory.kill_process_group = args.kill_process_group;
+    factory.watch_hooks = args.watch;
+    factory.dry_run = args.dry_run;
+    factory.access_log = args.access_log;
+    factory.request_log = args.request_log.as_ref().map(|f| f.as_str());
+    if let Some(bytes) = args.request_log_max_size {
+        factory.request_log_max_size = bytes;
+    }
+    factory.max_body_memory = args.max_body_memory;
+    if args.max_body_size.is_some() {
+        factory.max_body_size = args.max_body_size;
+    }
+    if args.max_headers.is_some() {
+        factory.max_headers = args.max_headers;
+    }
+    if args.max_headers_size.is_some() {
+        factory.max_headers_size = args.max_headers_size;
+    }
+    if args.max_output_size.is_some() {
+        factory.max_output_size = args.max_output_size;
+    }
+    factory.scheduler = args.scheduler;
+    factory.retry = args.retry;
+    factory.load_shedding = args.load_shedding;
+    factory.dead_letter_dir = args.dead_letter_dir.as_ref()
+        .map(|d| d.as_str());
+    factory.failure_webhook = args.failure_webhook.as_ref()
+        .map(|u| u.as_str());
+    factory.work_dir = args.work_dir.as_ref().map(|d| d.as_str());
+    factory.logs_dir = args.logs_dir.as_ref().map(|d| d.as_str());
+    factory.logs_keep = args.logs_keep;
+    factory.logs_token = args.logs_token.as_ref().map(|t| t.as_str());
+    if let Some(ref prefix) = args.url_prefix {
+        factory.url_prefix = prefix;
+    }
+    if let Some(ref prefix) = args.env_prefix {
+        factory.env_prefix = prefix;
+    }
+    if let Some(ref names) = args.env_passthrough {
+        factory.env_passthrough = names.clone();
+    }
+    factory.state_dir = args.state_dir.as_ref().map(|d| d.as_str());
+    factory.provider_errors = args.provider_errors;
+    factory.open_hooks = args.open_hooks.clone();
+    factory.cgroup_parent = args.cgroup_parent.as_ref().map(|c| c.as_str());
+    factory.cgroup_fallback = args.cgroup_fallback;
+    factory.log_redact = args.log_redact.clone();
+    factory.ack_fast = args.ack_fast;
+    factory.log_format = args.log_format;
+    factory.history_size = args.history_size;
+    factory.max_queue_size = args.max_queue;
+    factory.kill_grace = Duration::from_secs(args.kill_grace);
+    factory.job_timeout = args.timeout.map(Duration::from_secs);
+    factory.sandbox = args.sandbox;
+    factory.idle_timeout = args.idle_timeout.map(|minutes| {
+        Duration::from_secs(minutes * 60)
+    });
+    factory.shutdown_timeout = args.shutdown_timeout.map(Duration::from_secs);
+    if let Some(ref signal) = args.kill_signal {
+        factory.kill_signal = signal;
+    }
 
+    if let Some(ref manifest) = args.manifest {
+        factory.verify_manifest(manifest)?;
+    }
     factory.collect_hooks(args.hooks_dir, args.recursive)?;
     {
         let mut hook_names = factory.hook_names().collect::<Vec<String>>();
         hook_names.sort();
 
-        println!("{} ({} total)",
-            Style::new().bold().paint("Collected hooks:"), hook_names.len(),
-        );
-        for name in &hook_names {
-            println!("- {}", name);
+        if fisher::json_output() {
+            fisher::log_message("collected", json!({ "hooks": hook_names }));
+        } else {
+            println!("{} ({} total)",
+                Style::new().bold().paint("Collected hooks:"),
+                hook_names.len(),
+            );
+            for name in &hook_names {
+                println!("- {}", name);
+            }
+        }
+
+        // Calling those hooks bypasses their providers, so make sure it
+        // doesn't go unnoticed
+        if ! args.open_hooks.is_empty() && fisher::json_output() {
+            let (found, missing): (Vec<_>, Vec<_>) = args.open_hooks.iter()
+                .partition(|name| hook_names.contains(name));
+            fisher::log_message("open_hooks", json!({
+                "hooks": found,
+                "not_found": missing,
+            }));
+        } else if ! args.open_hooks.is_empty() {
+            println!("{} {}",
+                Colour::Red.bold().paint("Open hooks:"),
+                Colour::Red.paint("can be called without any validation!"),
+            );
+            for name in &args.open_hooks {
+                if hook_names.contains(name) {
+                    println!("- {}", Colour::Red.paint(name.as_str()));
+                } else {
+                    println!("- {} {}",
+                        Colour::Red.paint(name.as_str()),
+                        Colour::Yellow.paint("(not found)"),
+                    );
+                }
+            }
         }
     }
 
@@ -166,38 +1417,106 @@ fn app() -> fisher::Result<()> {
     // Start Fisher
     let app_result = factory.start();
     if let Err(error) = app_result {
-        println!("{} on {}: {}",
-            Colour::Red.bold().paint("Failed to start the Web API"),
-            args.bind, error,
-        );
+        if fisher::json_output() {
+            fisher::log_message("start_failed", json!({
+                "binds": args.binds,
+                "error": error.to_string(),
+            }));
+        } else {
+            println!("{} on {}: {}",
+                Colour::Red.bold().paint("Failed to start the Web API"),
+                args.binds.join(", "), error,
+            );
+        }
+        drop(pid_file);
         ::std::process::exit(1);
     }
     let mut app = app_result.unwrap();
 
-    println!("{} on {}",
-        Colour::Green.bold().paint("Web API listening"), app.web_address(),
-    );
+    // Unix sockets have no address, so their path is shown instead
+    let display_address = |bind: &str, address: &::std::net::SocketAddr| {
+        if bind.starts_with("unix:") {
+            bind.to_string()
+        } else {
+            address.to_string()
+        }
+    };
+    let listening = |api: &str, address: String| {
+        if fisher::json_output() {
+            fisher::log_message("listening", json!({
+                "api": api,
+                "address": address,
+            }));
+        } else {
+            println!("{} on {}",
+                Colour::Green.bold().paint(format!("{} API listening", api)),
+                address,
+            );
+        }
+    };
+    for (bind, address) in args.binds.iter().zip(app.web_addresses()) {
+        listening("Web", display_address(bind, &address));
+    }
+    if let (Some(admin_address), Some(admin_bind)) =
+           (app.admin_address(), args.admin_bind.as_ref()) {
+        listening("Admin", display_address(admin_bind, admin_address));
+    }
 
     // Wait for signals
+    let mut idle_checked_at = Instant::now();
     loop {
+        // Checking if Fisher is idle requires asking the processor, so it's
+        // not done on every iteration
+        if idle_checked_at.elapsed() >= Duration::from_secs(1) {
+            idle_checked_at = Instant::now();
+            if app.is_idle()? {
+                if fisher::json_output() {
+                    fisher::log_message("idle", json!({}));
+                } else {
+                    println!("{} after being idle",
+                        Colour::Yellow.bold().paint("Shutting down"),
+                    );
+                }
+                break;
+            }
+        }
+
+        // The hooks are reloaded either when asked to or when their files
+        // changed
+        let mut should_reload = app.hooks_changed();
         match signal_trap.wait(Instant::now()) {
             Some(SIGINT) | Some(SIGTERM) => break,
-            Some(SIGUSR1) => {
+            Some(SIGUSR1) | Some(SIGHUP) => should_reload = true,
+            _ => {},
+        }
+
+        if should_reload {
+            if fisher::json_output() {
+                fisher::log_message("reloading", json!({}));
+            } else {
                 println!("{} hooks list",
                     Colour::Green.bold().paint("Reloading")
                 );
+            }
 
-                // Don't crash if the reload fails, just show errors
-                // No changes are applied if the reload fails
-                let _ = print_err(app.reload());
-            },
-            _ => {},
+            // Don't crash if the reload fails, just show errors
+            // No changes are applied if the reload fails
+            if let Ok(diff) = print_err(app.reload()) {
+                print_hooks_diff(&diff);
+            }
         }
         ::std::thread::sleep(Duration::new(0, 100));
     }
 
     // Stop Fisher
-    app.stop()?;
+    let finished = app.stop()?;
+    if ! finished && fisher::json_output() {
+        fisher::log_message("abandoned", json!({}));
+    } else if ! finished {
+        println!("{} some jobs were still running after the shutdown timeout",
+            Colour::Yellow.bold().paint("Abandoned:"),
+        );
+    }
 
     Ok(())
 }
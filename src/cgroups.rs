@@ -0,0 +1,274 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::ffi::{CStr, CString};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use fisher_common::prelude::*;
+
+
+// The period used to limit the CPU time, in microseconds
+const CPU_PERIOD: u64 = 100000;
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum memory usage, in bytes
+    pub memory: Option<u64>,
+    /// The maximum CPU usage, as a percentage of a single CPU
+    pub cpu: Option<u64>,
+}
+
+impl Limits {
+
+    pub fn parse(memory: Option<&str>, cpu: Option<&str>) -> Result<Self> {
+        Ok(Limits {
+            memory: match memory {
+                Some(memory) => Some(parse_memory(memory)?),
+                None => None,
+            },
+            cpu: match cpu {
+                Some(cpu) => Some(parse_cpu(cpu)?),
+                None => None,
+            },
+        })
+    }
+}
+
+
+fn parse_memory(input: &str) -> Result<u64> {
+    let multiplier = match input.chars().last() {
+        Some('K') | Some('k') => Some(1024),
+        Some('M') | Some('m') => Some(1024 * 1024),
+        Some('G') | Some('g') => Some(1024 * 1024 * 1024),
+        _ => None,
+    };
+    let (number, multiplier) = match multiplier {
+        Some(multiplier) => (&input[..input.len() - 1], multiplier),
+        None => (input, 1),
+    };
+
+    let parsed = number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(multiplier));
+
+    match parsed {
+        Some(bytes) if bytes > 0 => Ok(bytes),
+        _ => Err(ErrorKind::InvalidInput(
+            format!("invalid memory limit: {}", input)
+        ).into()),
+    }
+}
+
+
+fn parse_cpu(input: &str) -> Result<u64> {
+    let parsed = if input.ends_with('%') {
+        input[..input.len() - 1].parse::<u64>().ok()
+    } else {
+        None
+    };
+
+    match parsed {
+        Some(percentage) if percentage > 0 => {
+            cpu_quota(percentage)?;
+            Ok(percentage)
+        },
+        _ => Err(ErrorKind::InvalidInput(
+            format!("invalid CPU limit (it must be a percentage): {}", input)
+        ).into()),
+    }
+}
+
+
+// Convert a percentage of a single CPU into the CPU time allowed in each
+// period
+fn cpu_quota(percentage: u64) -> Result<u64> {
+    match percentage.checked_mul(CPU_PERIOD) {
+        Some(quota) => Ok(quota / 100),
+        None => Err(ErrorKind::InvalidInput(
+            format!("CPU limit too high: {}%", percentage)
+        ).into()),
+    }
+}
+
+
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    let result = OpenOptions::new().write(true).open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()));
+
+    if let Err(error) = result {
+        return Err(ErrorKind::CgroupsUnavailable(
+            format!("can't write to {}: {}", path.display(), error)
+        ).into());
+    }
+    Ok(())
+}
+
+
+/// A cgroup (v2) with resource limits applied, removed when dropped.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    procs: CString,
+}
+
+impl Cgroup {
+
+    pub fn create(parent: &Path, name: &str, limits: &Limits)
+                  -> Result<Self> {
+        let cpu_quota = match limits.cpu {
+            Some(cpu) => Some(cpu_quota(cpu)?),
+            None => None,
+        };
+
+        // The controllers must be enabled in the parent before they can be
+        // used by its children -- if this fails, setting the limits fails
+        let mut controllers = Vec::new();
+        if limits.memory.is_some() {
+            controllers.push("+memory");
+        }
+        if limits.cpu.is_some() {
+            controllers.push("+cpu");
+        }
+        let _ = write_file(
+            &parent.join("cgroup.subtree_control"), &controllers.join(" "),
+        );
+
+        let path = parent.join(name);
+        if let Err(error) = fs::create_dir(&path) {
+            return Err(ErrorKind::CgroupsUnavailable(
+                format!("can't create {}: {}", path.display(), error)
+            ).into());
+        }
+
+        // Create the struct right away, so the cgroup is removed if applying
+        // the limits fails
+        let procs = path.join("cgroup.procs");
+        let cgroup = Cgroup {
+            procs: CString::new(procs.as_os_str().as_bytes()).unwrap(),
+            path: path,
+        };
+
+        if let Some(memory) = limits.memory {
+            write_file(&cgroup.path.join("memory.max"), &memory.to_string())?;
+
+            // Without this the memory over the limit would be swapped out
+            // instead of killing the process
+            let _ = write_file(&cgroup.path.join("memory.swap.max"), "0");
+        }
+        if let Some(quota) = cpu_quota {
+            write_file(&cgroup.path.join("cpu.max"), &format!(
+                "{} {}", quota, CPU_PERIOD,
+            ))?;
+        }
+
+        Ok(cgroup)
+    }
+
+    /// Return the path of the file which processes need to write to in
+    /// order to join this cgroup.
+    pub fn procs(&self) -> &CStr {
+        &self.procs
+    }
+}
+
+impl Drop for Cgroup {
+
+    fn drop(&mut self) {
+        // Kill the processes left behind by the job, since a cgroup can't be
+        // removed while there are processes in it
+        let _ = write_file(&self.path.join("cgroup.kill"), "1");
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use fisher_common::prelude::*;
+
+    use utils;
+
+    use super::{Cgroup, Limits};
+
+
+    #[test]
+    fn test_parse_limits() {
+        assert_eq!(Limits::parse(None, None).unwrap(), Limits {
+            memory: None,
+            cpu: None,
+        });
+        assert_eq!(Limits::parse(Some("512M"), Some("50%")).unwrap(), Limits {
+            memory: Some(512 * 1024 * 1024),
+            cpu: Some(50),
+        });
+        assert_eq!(Limits::parse(Some("1G"), Some("200%")).unwrap(), Limits {
+            memory: Some(1024 * 1024 * 1024),
+            cpu: Some(200),
+        });
+        assert_eq!(Limits::parse(Some("4096"), None).unwrap(), Limits {
+            memory: Some(4096),
+            cpu: None,
+        });
+
+        for &(memory, cpu) in &[
+            (Some("512X"), None), (Some("M"), None), (Some("0"), None),
+            (None, Some("50")), (None, Some("0%")), (None, Some("half%")),
+            (Some("18446744073709551615K"), None),
+            (Some("17179869184G"), None),
+            (None, Some("18446744073709551615%")),
+        ] {
+            assert_err!(
+                Limits::parse(memory, cpu), ErrorKind::InvalidInput(..)
+            );
+        }
+    }
+
+
+    #[test]
+    fn test_cgroups_unavailable() {
+        // A plain directory isn't a cgroup, so the limits can't be applied
+        let parent = utils::create_temp_dir().unwrap();
+        let limits = Limits::parse(Some("16M"), None).unwrap();
+
+        assert_err!(
+            Cgroup::create(&parent.join("missing"), "test", &limits),
+            ErrorKind::CgroupsUnavailable(..)
+        );
+        assert_err!(
+            Cgroup::create(&parent, "test", &limits),
+            ErrorKind::CgroupsUnavailable(..)
+        );
+
+        // The half-configured cgroup is removed
+        assert!(! parent.join("test").exists());
+
+        // Limits which can't be represented are rejected before creating
+        // anything
+        let limits = Limits {
+            memory: None,
+            cpu: Some(::std::u64::MAX),
+        };
+        assert_err!(
+            Cgroup::create(&parent, "test", &limits),
+            ErrorKind::InvalidInput(..)
+        );
+        assert!(! parent.join("test").exists());
+
+        ::std::fs::remove_dir_all(&parent).unwrap();
+    }
+}
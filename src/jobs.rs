@@ -14,42 +14,178 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
-use std::process;
+use std::process::{self, Stdio};
 use std::os::unix::process::ExitStatusExt;
 use std::os::unix::process::CommandExt;
 use std::fs;
 use std::env;
-use std::path::PathBuf;
-use std::io::Write;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex, mpsc};
 use std::net::IpAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ansi_term::Colour;
+use libc;
 
 use fisher_common::prelude::*;
 use fisher_common::state::UniqueId;
 
 use hooks::Hook;
-use utils;
+use cgroups::{Cgroup, Limits};
+use utils::{Redactor, TempDir};
 use requests::Request;
 use providers::Provider;
 use native;
+use dead_letter;
+use event_log;
+use failure_webhook::FailureWebhook;
+use job_logs;
+use queue_store::{self, QueueStore};
+
+
+/// The environment variables of Fisher inherited by the jobs by default
+pub const DEFAULT_ENV_PASSTHROUGH: &'static [&'static str] = &[
+    "PATH",
+    "USER",
+    "SHELL",
+
+    // Internationalization stuff
+    "LC_ALL",
+    "LANG",
+];
+
+/// How much of the standard output and error of each job is kept by default
+pub const DEFAULT_MAX_OUTPUT_SIZE: usize = 1024 * 1024;
+
+/// The prefix of the environment variables set by Fisher by default
+pub const DEFAULT_ENV_PREFIX: &'static str = "FISHER";
+
+// How long the output of a killed job is still read. The processes which
+// survived the kill can keep the pipes open forever.
+const KILLED_OUTPUT_GRACE_MS: u64 = 1000;
+
+
+/// Check if the prefix can be used in the names of environment variables:
+/// only letters, digits and underscores, without a leading digit
+pub fn is_valid_env_prefix(prefix: &str) -> bool {
+    let leading_digit = match prefix.chars().next() {
+        Some(first) => first.is_digit(10),
+        None => return false,
+    };
+
+    ! leading_digit && prefix.chars().all(|c| match c {
+        'A'...'Z' | 'a'...'z' | '0'...'9' | '_' => true,
+        _ => false,
+    })
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KillPolicy {
+    pub signal: libc::c_int,
+    pub grace: Duration,
+}
+
+impl Default for KillPolicy {
+
+    fn default() -> Self {
+        KillPolicy {
+            signal: libc::SIGKILL,
+            grace: Duration::from_secs(0),
+        }
+    }
+}
+
+
+/// The restrictions applied to the processes of the jobs, right before the
+/// hooks are executed. This is only supported on Unix, and the default
+/// doesn't restrict anything.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    /// The niceness of the processes, from -20 to 19.
+    pub nice: Option<i32>,
+
+    /// The user the processes run as.
+    pub uid: Option<u32>,
+
+    /// The group the processes run as, without supplementary groups.
+    pub gid: Option<u32>,
+
+    /// How many seconds of CPU time each process can use (`RLIMIT_CPU`).
+    pub cpu_time: Option<u64>,
 
+    /// How many bytes of virtual memory each process can use (`RLIMIT_AS`).
+    pub address_space: Option<u64>,
+}
+
+impl Sandbox {
+
+    /// Check if the sandbox restricts anything.
+    pub fn is_empty(&self) -> bool {
+        *self == Sandbox::default()
+    }
+
+    // This is called between fork and exec, so it must only call
+    // async-signal-safe functions
+    fn apply(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice {
+            native::set_nice(nice)?;
+        }
+        if let Some(seconds) = self.cpu_time {
+            native::limit_cpu_time(seconds)?;
+        }
+        if let Some(bytes) = self.address_space {
+            native::limit_address_space(bytes)?;
+        }
 
-lazy_static! {
-    static ref DEFAULT_ENV: Vec<String> = vec![
-        "PATH".to_string(),
-        "USER".to_string(),
-        "SHELL".to_string(),
+        // This is done last, since the other changes might need privileges
+        native::drop_privileges(self.uid, self.gid)
+    }
+
+    // The working directory is created private to the user running Fisher,
+    // so it's given to the user of the sandbox along with its content, or
+    // the job wouldn't be able to use it
+    fn grant_access(&self, path: &Path) -> io::Result<()> {
+        if self.uid.is_none() && self.gid.is_none() {
+            return Ok(());
+        }
+
+        native::chown(path, self.uid, self.gid)?;
+        if fs::symlink_metadata(path)?.is_dir() {
+            for entry in fs::read_dir(path)? {
+                self.grant_access(&entry?.path())?;
+            }
+        }
 
-        // Internationalization stuff
-        "LC_ALL".to_string(),
-        "LANG".to_string(),
-    ];
+        Ok(())
+    }
 }
 
 
 #[derive(Debug)]
 pub struct Context {
     pub environment: HashMap<String, String>,
+    pub dry_run: bool,
+    pub timeout: Option<Duration>,
+    pub kill: KillPolicy,
+    pub sandbox: Sandbox,
+    pub dead_letter: Option<PathBuf>,
+    pub failure_webhook: Option<FailureWebhook>,
+    pub cgroup_parent: Option<PathBuf>,
+    pub cgroup_fallback: bool,
+    pub redactor: Redactor,
+    pub status_hooks: bool,
+    pub kill_process_group: bool,
+    pub logs_dir: Option<PathBuf>,
+    pub logs_keep: usize,
+    pub queue_store: Option<QueueStore>,
+    pub max_output_size: Option<usize>,
+    pub env_prefix: String,
+    pub env_passthrough: Vec<String>,
+    pub work_dir: Option<PathBuf>,
 }
 
 impl Default for Context {
@@ -57,6 +193,25 @@ impl Default for Context {
     fn default() -> Self {
         Context {
             environment: HashMap::new(),
+            dry_run: false,
+            timeout: None,
+            kill: KillPolicy::default(),
+            sandbox: Sandbox::default(),
+            dead_letter: None,
+            failure_webhook: None,
+            cgroup_parent: None,
+            cgroup_fallback: false,
+            redactor: Redactor::default(),
+            status_hooks: true,
+            kill_process_group: true,
+            logs_dir: None,
+            logs_keep: job_logs::DEFAULT_KEEP,
+            queue_store: None,
+            max_output_size: Some(DEFAULT_MAX_OUTPUT_SIZE),
+            env_prefix: DEFAULT_ENV_PREFIX.into(),
+            env_passthrough: DEFAULT_ENV_PASSTHROUGH.iter()
+                .map(|name| name.to_string()).collect(),
+            work_dir: None,
         }
     }
 }
@@ -64,22 +219,44 @@ impl Default for Context {
 
 #[derive(Debug, Clone)]
 pub struct Job {
+    id: UniqueId,
     hook: Arc<Hook>,
     provider: Option<Arc<Provider>>,
     request: Request,
+    path_params: HashMap<String, String>,
+    restored_from: Option<String>,
 }
 
 impl Job {
 
-    pub fn new(hook: Arc<Hook>, provider: Option<Arc<Provider>>,
+    pub fn new(id: UniqueId, hook: Arc<Hook>, provider: Option<Arc<Provider>>,
                request: Request) -> Job {
         Job {
+            id: id,
             hook: hook,
             provider: provider,
             request: request,
+            path_params: HashMap::new(),
+            restored_from: None,
         }
     }
 
+    /// Set the variables captured from the path of the request, which are
+    /// exported as `FISHER_PATH_<NAME>`.
+    pub fn set_path_params(&mut self, params: HashMap<String, String>) {
+        self.path_params = params;
+    }
+
+    /// Set the ID the job was stored with before Fisher restarted. The job
+    /// isn't stored again, and the old copy is removed once it's finished.
+    pub fn set_restored_from(&mut self, stored_id: &str) {
+        self.restored_from = Some(stored_id.to_string());
+    }
+
+    pub fn id(&self) -> UniqueId {
+        self.id
+    }
+
     pub fn request_ip(&self) -> IpAddr {
         match self.request {
             Request::Web(ref req) => req.source,
@@ -95,20 +272,102 @@ impl Job {
         }
     }
 
+    /// The values the job had access to, which shouldn't be leaked
+    fn secret_values(&self, ctx: &Context) -> HashMap<String, String> {
+        let mut values = ctx.environment.clone();
+        if let Request::Web(ref req) = self.request {
+            values.extend(req.params.clone());
+            values.extend(req.headers.clone());
+        }
+        values
+    }
+
+    fn notify_failure(&self, ctx: &Context, output: Option<&JobOutput>,
+                      error: Option<&str>) {
+        let webhook = match ctx.failure_webhook {
+            Some(ref webhook) => webhook,
+            None => return,
+        };
+
+        // The secrets are redacted like in the logs
+        let stderr = output.map(|output| {
+            ctx.redactor.text(&output.stderr, &self.secret_values(ctx))
+        });
+
+        webhook.notify(self.hook.name(), json!({
+            "hook": self.hook.name(),
+            "job_id": self.id.to_string(),
+            "error": error,
+            "exit_code": output.and_then(|output| output.exit_code),
+            "signal": output.and_then(|output| output.signal),
+            "timed_out": output.map(|output| output.timed_out),
+            "stderr": stderr,
+        }));
+    }
+
+    fn save_log(&self, ctx: &Context, dir: &Path, output: &JobOutput) {
+        let values = self.secret_values(ctx);
+        let result = job_logs::write(
+            dir, self.id,
+            &ctx.redactor.text(&output.stdout, &values),
+            &ctx.redactor.text(&output.stderr, &values), ctx.logs_keep,
+        );
+        if let Err(mut error) = result {
+            // Losing the log shouldn't stop Fisher
+            error.set_location(
+                ErrorLocation::HookProcessing(self.hook.name().into())
+            );
+            error.pretty_print();
+        }
+    }
+
     fn process(&self, ctx: &Context) -> Result<JobOutput> {
-        let mut command = process::Command::new(&self.hook.exec());
+        // Don't execute anything in dry-run mode
+        if ctx.dry_run {
+            return Ok(self.simulate(ctx));
+        }
+
+        // Apply the resource limits of the hook, if it has any
+        let cgroup = match self.hook.limits() {
+            Some(limits) => self.create_cgroup(limits, ctx)?,
+            None => None,
+        };
+
+        // Execute the hook through its interpreter, if it has one
+        let mut command = if let Some(interpreter) = self.hook.interpreter() {
+            let mut command = process::Command::new(interpreter);
+            command.arg(&self.hook.exec());
+            command
+        } else {
+            process::Command::new(&self.hook.exec())
+        };
 
         // Prepare the command's environment variables
-        self.prepare_env(&mut command);
+        command.env_clear();
+        for (key, value) in self.environment(ctx) {
+            command.env(key, value);
+        }
 
-        // Use a random working directory
-        let working_directory = utils::create_temp_dir()?;
-        command.current_dir(working_directory.to_str().unwrap());
+        // Use a random working directory, unless the provider has one. The
+        // directory is removed when the guard is dropped, so it doesn't leak
+        // when returning early, or when the job panics
+        let temp_dir = TempDir::create(
+            ctx.work_dir.as_ref().map(|dir| dir.as_path())
+        )?;
+        let working_directory = temp_dir.path().to_path_buf();
+        let configured_directory = self.provider.as_ref().and_then(|p| {
+            p.working_directory(&self.request)
+        });
+        if let Some(ref directory) = configured_directory {
+            command.current_dir(directory);
+        } else {
+            command.current_dir(working_directory.to_str().unwrap());
+        }
         command.env("HOME".to_string(), working_directory.to_str().unwrap());
 
         // Set the request IP
         command.env(
-            "FISHER_REQUEST_IP".to_string(),
+            format!("{}_REQUEST_IP", ctx.env_prefix),
             format!("{}", self.request_ip())
         );
 
@@ -116,7 +375,7 @@ impl Job {
         let request_body = self.save_request_body(&working_directory)?;
         if let Some(path) = request_body {
             command.env(
-                "FISHER_REQUEST_BODY".to_string(),
+                format!("{}_REQUEST_BODY", ctx.env_prefix),
                 path.to_str().unwrap().to_string()
             );
         }
@@ -127,80 +386,425 @@ impl Job {
                 &self.request, &working_directory
             )?;
         }
+        ctx.sandbox.grant_access(&working_directory)?;
 
         // Apply the custom environment
         for (key, value) in ctx.environment.iter() {
             command.env(&key, &value);
         }
 
+        // The environment configured for the hook overrides everything else
+        if let Some(ref provider) = self.provider {
+            for (key, value) in provider.override_env(&self.request) {
+                command.env(key, value);
+            }
+        }
+
         // Make sure the process is isolated
-        command.before_exec(|| {
+        let procs = cgroup.as_ref().map(|cgroup| cgroup.procs().to_owned());
+        let sandbox = ctx.sandbox;
+        command.before_exec(move || {
             native::isolate_process();
-            Ok(())
+            if let Some(ref procs) = procs {
+                native::join_cgroup(procs)?;
+            }
+            sandbox.apply()
         });
 
         // Execute the hook
-        let output = command.output()?;
+        let (output, timed_out) = match self.run(command, ctx) {
+            Ok(result) => result,
+            Err(error) => {
+                if error.kind() == io::ErrorKind::NotFound {
+                    if let Some(interpreter) = self.hook.interpreter() {
+                        return Err(ErrorKind::InterpreterNotFound(
+                            interpreter.to_string()
+                        ).into());
+                    }
+                }
+                return Err(error.into());
+            },
+        };
 
         // Remove the temp directory
-        fs::remove_dir_all(&working_directory)?;
+        drop(temp_dir);
+
+        // The job still has an output, so the status hooks are triggered
+        let mut output: JobOutput = (self, output).into();
+        if timed_out {
+            let mut error: Error = ErrorKind::JobTimedOut(
+                self.hook.timeout(ctx.timeout)
+                    .unwrap_or_else(|| Duration::from_secs(0))
+            ).into();
+            error.set_location(
+                ErrorLocation::HookProcessing(self.hook.name().into())
+            );
+            error.pretty_print();
+
+            // The job might exit successfully after being asked to stop,
+            // but it still didn't complete in time
+            output.timed_out = true;
+            output.success = false;
+        } else if ! output.success {
+            // Show why the job failed, without leaking its secrets
+            let mut error: Error = ErrorKind::JobFailed(
+                output.exit_code,
+                ctx.redactor.text(&output.stderr, &self.secret_values(ctx)),
+            ).into();
+            error.set_location(
+                ErrorLocation::HookProcessing(self.hook.name().into())
+            );
+            error.pretty_print();
+        }
 
-        // Return the job output
-        Ok((self, output).into())
+        Ok(output)
     }
 
-    fn prepare_env(&self, command: &mut process::Command) {
-        // First of all clear the environment
-        command.env_clear();
+    /// Run the command, returning its output and whether it was killed
+    /// because it timed out.
+    fn run(&self, mut command: process::Command, ctx: &Context)
+           -> io::Result<(process::Output, bool)> {
+        command.stdin(Stdio::null());
+        command.stdout(Stdio::piped());
+        command.stderr(Stdio::piped());
+
+        let mut child = command.spawn()?;
+
+        // Read the output in the background, so the process doesn't block
+        // when the pipes are full
+        let stdout = read_in_background(
+            child.stdout.take(), ctx.max_output_size,
+        );
+        let stderr = read_in_background(
+            child.stderr.take(), ctx.max_output_size,
+        );
+
+        // The hook can override the default timeout
+        let timeout = self.hook.timeout(ctx.timeout);
+        let (status, timed_out) = if let Some(timeout) = timeout {
+            match wait_timeout(&mut child, timeout)? {
+                Some(status) => (status, false),
+                None => (kill(
+                    &mut child, self.hook.kill_policy(&ctx.kill),
+                    ctx.kill_process_group,
+                )?, true),
+            }
+        } else {
+            (child.wait()?, false)
+        };
+
+        let grace = if timed_out {
+            Some(Duration::from_millis(KILLED_OUTPUT_GRACE_MS))
+        } else {
+            None
+        };
+
+        Ok((process::Output {
+            status: status,
+            stdout: stdout.finish(grace),
+            stderr: stderr.finish(grace),
+        }, timed_out))
+    }
+
+    fn create_cgroup(&self, limits: &Limits, ctx: &Context)
+                     -> Result<Option<Cgroup>> {
+        let result = match ctx.cgroup_parent {
+            Some(ref parent) => Cgroup::create(
+                parent, &format!("fisher-{}-job-{}", process::id(), self.id),
+                limits,
+            ),
+            None => Err(ErrorKind::CgroupsUnavailable(
+                "no parent cgroup configured".into()
+            ).into()),
+        };
+
+        match result {
+            Ok(cgroup) => Ok(Some(cgroup)),
+            Err(mut error) => if ctx.cgroup_fallback {
+                // Run the job without limits, but tell about it
+                error.set_location(
+                    ErrorLocation::HookProcessing(self.hook.name().into())
+                );
+                error.pretty_print();
+                Ok(None)
+            } else {
+                Err(error)
+            },
+        }
+    }
+
+    fn simulate(&self, ctx: &Context) -> JobOutput {
+        // Show the environment the hook would have received, except for the
+        // variables which depend on the working directory
+        let mut env = self.environment(ctx);
+        env.insert(
+            format!("{}_REQUEST_IP", ctx.env_prefix),
+            format!("{}", self.request_ip())
+        );
+        for (key, value) in ctx.environment.iter() {
+            env.insert(key.clone(), value.clone());
+        }
+
+        let mut env = env.into_iter().collect::<Vec<_>>();
+        env.sort();
+
+        println!("{} {}{} with this environment:",
+            Colour::Cyan.bold().paint("[dry-run] Would execute"),
+            match self.hook.interpreter() {
+                Some(interpreter) => format!("{} ", interpreter),
+                None => String::new(),
+            },
+            self.hook.exec(),
+        );
+        for (key, value) in env {
+            println!("  {}={}", key, ctx.redactor.value(&key, &value));
+        }
+
+        JobOutput {
+            stdout: String::new(),
+            stderr: String::new(),
+
+            success: true,
+            exit_code: Some(0),
+            signal: None,
+            timed_out: false,
+
+            hook_name: self.script_name().into(),
+            request_ip: self.request_ip(),
+
+            trigger_status_hooks: self.trigger_status_hooks(),
+        }
+    }
 
-        // Apply the default environment
+    fn environment(&self, ctx: &Context) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+
+        // Apply the inherited environment
         // This is done (instead of the automatic inheritage) to whitelist
         // which environment variables we want
         for (key, value) in env::vars() {
             // Set only whitelisted keys
-            if ! DEFAULT_ENV.contains(&key) {
+            if ! ctx.env_passthrough.contains(&key) {
                 continue;
             }
 
-            command.env(key, value);
+            result.insert(key, value);
         }
 
         // Apply the hook-specific environment
         if let Some(ref provider) = self.provider {
+            let is_status = match self.request {
+                Request::Status(..) => true,
+                Request::Web(..) => false,
+            };
+
             for (key, value) in provider.env(&self.request) {
+                // The output of the job a status hook is about is also
+                // exported with names not tied to the provider
+                if is_status && (key == "STDOUT" || key == "STDERR") {
+                    result.insert(
+                        format!("{}_LAST_{}", ctx.env_prefix, key),
+                        value.clone(),
+                    );
+                }
+
                 let real_key = format!(
-                    "FISHER_{}_{}", provider.name().to_uppercase(), key
+                    "{}_{}_{}", ctx.env_prefix, provider.name().to_uppercase(),
+                    key,
                 );
-                command.env(real_key, value);
+                result.insert(real_key, value);
             }
         }
+
+        for (key, value) in &self.path_params {
+            result.insert(
+                format!("{}_PATH_{}", ctx.env_prefix, key), value.clone(),
+            );
+        }
+
+        result
     }
 
+    // The body is written to the `request_body` file of the working
+    // directory, whose path is exported as `<PREFIX>_REQUEST_BODY`. The file
+    // is removed along with the working directory once the job ends.
     fn save_request_body(&self, base: &PathBuf)
                         -> Result<Option<PathBuf>> {
         // Get the request body, even if some request kinds don't have one
-        let body = match self.request {
-            Request::Web(ref req) => &req.body,
+        let req = match self.request {
+            Request::Web(ref req) => req,
             Request::Status(..) => return Ok(None),
         };
 
         let mut path = base.clone();
         path.push("request_body");
 
-        // Write the request body on disk
-        let mut file = fs::File::create(&path)?;
-        write!(file, "{}\n", body)?;
+        // Write the request body on disk, copying it if it's already there
+        if let Some(ref body_file) = req.body_file {
+            fs::copy(body_file.path(), &path)?;
+
+            let mut file = fs::OpenOptions::new().append(true).open(&path)?;
+            write!(file, "\n")?;
+        } else {
+            let mut file = fs::File::create(&path)?;
+            write!(file, "{}\n", req.body)?;
+        }
 
         Ok(Some(path))
     }
 }
 
+/// A buffer shared with the thread reading a pipe, so what was already read
+/// can be taken even if the pipe is never closed.
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.lock() {
+            Ok(mut buffer) => buffer.write(buf),
+            Err(..) => Err(io::Error::new(
+                io::ErrorKind::Other, "the output buffer is poisoned",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+struct OutputReader {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    done: mpsc::Receiver<()>,
+}
+
+impl OutputReader {
+    /// Wait until the whole output is read, or until the grace period
+    /// expires, and return what was read so far.
+    fn finish(self, grace: Option<Duration>) -> Vec<u8> {
+        if let Some(grace) = grace {
+            let _ = self.done.recv_timeout(grace);
+        } else {
+            let _ = self.done.recv();
+        }
+
+        match self.buffer.lock() {
+            Ok(mut buffer) => mem::replace(&mut *buffer, Vec::new()),
+            Err(..) => Vec::new(),
+        }
+    }
+}
+
+
+fn read_in_background<R>(pipe: Option<R>, limit: Option<usize>)
+                         -> OutputReader
+    where R: Read + Send + 'static
+{
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let (done_send, done_recv) = mpsc::channel();
+
+    let mut shared = SharedBuffer(buffer.clone());
+    thread::spawn(move || {
+        if let Some(pipe) = pipe {
+            read_limited(pipe, limit, &mut shared);
+        }
+        let _ = done_send.send(());
+    });
+
+    OutputReader {
+        buffer: buffer,
+        done: done_recv,
+    }
+}
+
+/// Read everything from the pipe, keeping only the first `limit` bytes and
+/// telling how many were discarded
+fn read_limited<R: Read, W: Write>(mut pipe: R, limit: Option<usize>,
+                                   buffer: &mut W) {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => {
+            let _ = io::copy(&mut pipe, buffer);
+            return;
+        },
+    };
+
+    let _ = io::copy(&mut pipe.by_ref().take(limit as u64), buffer);
+
+    // The rest is still read, otherwise the process would block when the
+    // pipe is full
+    let discarded = io::copy(&mut pipe, &mut io::sink()).unwrap_or(0);
+    if discarded > 0 {
+        let _ = buffer.write_all(format!(
+            "\n[output truncated, {} more bytes discarded]\n", discarded,
+        ).as_bytes());
+    }
+}
+
+
+fn wait_timeout(child: &mut process::Child, timeout: Duration)
+                -> io::Result<Option<process::ExitStatus>> {
+    let started_at = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if started_at.elapsed() >= timeout {
+            return Ok(None);
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+
+fn kill(child: &mut process::Child, policy: KillPolicy, group: bool)
+        -> io::Result<process::ExitStatus> {
+    let pid = child.id();
+    let send = |signal| if group {
+        // The job is the leader of its own process group
+        native::send_signal_to_group(pid, signal)
+    } else {
+        native::send_signal(pid, signal)
+    };
+
+    send(policy.signal)?;
+
+    // Give the process some time to clean up before killing it for real
+    if policy.signal != libc::SIGKILL {
+        if let Some(status) = wait_timeout(child, policy.grace)? {
+            // Don't leave behind the processes which ignored the signal
+            if group {
+                let _ = send(libc::SIGKILL);
+            }
+            return Ok(status);
+        }
+        send(libc::SIGKILL)?;
+    }
+
+    child.wait()
+}
+
+
 impl JobTrait<Hook> for Job {
     type Context = Context;
     type Output = JobOutput;
 
     fn execute(&self, ctx: &Context) -> Result<JobOutput> {
-        self.process(ctx)
+        let mut output = self.process(ctx)?;
+
+        // Skip looking for status hooks entirely if they're disabled
+        if ! ctx.status_hooks {
+            output.trigger_status_hooks = false;
+        }
+
+        if let Some(ref dir) = ctx.logs_dir {
+            self.save_log(ctx, dir, &output);
+        }
+
+        Ok(output)
     }
 
     fn script_id(&self) -> UniqueId {
@@ -210,6 +814,93 @@ impl JobTrait<Hook> for Job {
     fn script_name(&self) -> &str {
         self.hook.name()
     }
+
+    fn is_successful(&self, output: &JobOutput) -> bool {
+        output.success
+    }
+
+    fn exit_code(&self, output: &JobOutput) -> Option<i32> {
+        output.exit_code
+    }
+
+    fn dedup_key(&self) -> Option<String> {
+        self.provider.as_ref().and_then(|provider| {
+            provider.dedup_key(&self.request)
+        })
+    }
+
+    fn gave_up(&self, ctx: &Context, error: &str, attempts: u32) {
+        let dir = match ctx.dead_letter {
+            Some(ref dir) => dir,
+            None => return,
+        };
+
+        // Status jobs can't be replayed, so they're not stored
+        let req = match self.request {
+            Request::Web(ref req) => req,
+            Request::Status(..) => return,
+        };
+
+        match dead_letter::write(
+            dir, self.id, self.hook.name(), req, error, attempts,
+        ) {
+            Ok(path) => if event_log::json_output() {
+                event_log::log_message("dead_letter", json!({
+                    "hook": self.hook.name(),
+                    "path": path.to_string_lossy(),
+                }));
+            } else {
+                println!("{} {} to {}",
+                    Colour::Yellow.bold().paint("Gave up on"),
+                    self.hook.name(), path.display(),
+                );
+            },
+            Err(mut error) => {
+                // The job is lost, but that shouldn't stop Fisher
+                error.set_location(
+                    ErrorLocation::HookProcessing(self.hook.name().into())
+                );
+                error.pretty_print();
+            },
+        }
+    }
+
+    fn failed_permanently(&self, ctx: &Context, output: Option<&JobOutput>,
+                          error: Option<&str>) {
+        self.notify_failure(ctx, output, error);
+    }
+
+    fn queued(&self, ctx: &Context) {
+        let store = match ctx.queue_store {
+            Some(ref store) => store,
+            None => return,
+        };
+
+        // Status jobs are queued again by the jobs they're about, so they're
+        // not stored
+        let req = match self.request {
+            Request::Web(ref req) => req,
+            Request::Status(..) => return,
+        };
+
+        // The restored jobs keep the copy they were restored from
+        if self.restored_from.is_none() {
+            store.store(
+                self.id, self.hook.name(), req, &self.path_params,
+                self.provider.is_none(),
+            );
+        }
+    }
+
+    fn finished(&self, ctx: &Context) {
+        if let Some(ref store) = ctx.queue_store {
+            let stored_id = match self.restored_from {
+                Some(ref stored_id) => stored_id.clone(),
+                None => queue_store::stored_id(self.id),
+            };
+            store.forget(&stored_id, self.hook.name());
+        }
+    }
 }
 
 
@@ -221,6 +912,7 @@ pub struct JobOutput {
     pub success: bool,
     pub exit_code: Option<i32>,
     pub signal: Option<i32>,
+    pub timed_out: bool,
 
     pub hook_name: String,
     pub request_ip: IpAddr,
@@ -235,9 +927,10 @@ impl<'a> From<(&'a Job, process::Output)> for JobOutput {
             stdout: String::from_utf8_lossy(&data.1.stdout).into_owned(),
             stderr: String::from_utf8_lossy(&data.1.stderr).into_owned(),
 
-            success: data.1.status.success(),
+            success: data.0.hook.is_success(data.1.status.code()),
             exit_code: data.1.status.code(),
             signal: data.1.status.signal(),
+            timed_out: false,
 
             hook_name: data.0.script_name().into(),
             request_ip: data.0.request_ip(),
@@ -251,14 +944,28 @@ impl<'a> From<(&'a Job, process::Output)> for JobOutput {
 #[cfg(test)]
 mod tests {
     use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::io::Read;
     use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use fisher_common::prelude::*;
+    use libc;
+    use serde_json;
 
+    use failure_webhook::FailureWebhook;
+    use providers::StatusEvent;
+    use requests::Request;
     use utils::testing::*;
     use utils;
 
-    use super::{DEFAULT_ENV, Context};
+    use super::{DEFAULT_ENV_PASSTHROUGH, Context, KillPolicy, Sandbox};
+    use super::read_limited;
+    use super::is_valid_env_prefix;
 
 
     macro_rules! read {
@@ -371,9 +1078,7 @@ mod tests {
 
         // Get all the required environment variables
         let mut required_env = {
-            let mut res: Vec<&str> = DEFAULT_ENV.iter().map(|i| {
-                i.as_str()
-            }).collect();
+            let mut res: Vec<&str> = DEFAULT_ENV_PASSTHROUGH.to_vec();
 
             // Those are from the provider
             res.push("FISHER_TESTING_ENV");
@@ -423,14 +1128,11 @@ mod tests {
 
         // The value of the environment variables forwarded from the current
         // env must have the same content of the current env
-        for key in DEFAULT_ENV.iter() {
+        for key in DEFAULT_ENV_PASSTHROUGH {
             // If the key is not present in the testing environment, ignore it
             match env::var(key) {
                 Ok(content) => {
-                    assert_eq!(
-                        content.as_str(),
-                        *job_env.get(key.as_str()).unwrap()
-                    );
+                    assert_eq!(content.as_str(), *job_env.get(key).unwrap());
                 },
                 Err(..) => {},
             }
@@ -451,6 +1153,7 @@ mod tests {
                 extra_env.insert("TEST_ENV".into(), "yes".into());
                 extra_env
             },
+            .. Context::default()
         };
 
         // Create a temp directory which will contain the output
@@ -474,4 +1177,640 @@ mod tests {
 
         env.cleanup();
     }
+
+
+    #[test]
+    fn test_env_passthrough() {
+        let mut env = TestingEnv::new();
+        env::set_var("FISHER_TEST_PASSTHROUGH", "inherited");
+
+        // Only the listed variables are inherited from Fisher
+        let ctx = Context {
+            env_passthrough: vec!["FISHER_TEST_PASSTHROUGH".into()],
+            .. Context::default()
+        };
+
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap();
+        env.delete_also(&output);
+
+        let mut req = dummy_web_request();
+        req.params.insert("env".into(), output.to_string());
+
+        let job = env.create_job("jobs-details.sh", req.into());
+        assert!(job.process(&ctx).is_ok());
+
+        let raw_env = read!(output, "env");
+        let job_env = parse_env(&raw_env);
+        assert_eq!(job_env["FISHER_TEST_PASSTHROUGH"], "inherited");
+        assert!(! job_env.contains_key("LANG"));
+        assert!(! job_env.contains_key("USER"));
+
+        // The variables set by Fisher are still there
+        assert!(job_env.contains_key("FISHER_REQUEST_IP"));
+
+        env.cleanup();
+    }
+
+
+    #[test]
+    fn test_configured_environment() {
+        let mut env = TestingEnv::new();
+        let ctx = Context::default();
+
+        // The output directory is sent in the body, since the configured
+        // environment can't change between requests
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap();
+        env.delete_also(&output);
+
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "testing".into());
+        req.body = output.to_string();
+
+        let job = env.create_job("configured.sh", req.into());
+        assert!(job.process(&ctx).is_ok());
+
+        // The hook is run from the configured directory
+        assert_eq!(read!(output, "pwd"), "/\n");
+
+        // The configured variables override the ones set by Fisher
+        let raw_env = read!(output, "env");
+        let job_env = parse_env(&raw_env);
+        assert_eq!(*job_env.get("CONFIGURED").unwrap(), "yes");
+        assert_eq!(*job_env.get("FISHER_REQUEST_IP").unwrap(), "none");
+
+        env.cleanup();
+    }
+
+
+    #[test]
+    fn test_dry_run() {
+        let mut env = TestingEnv::new();
+        let ctx = Context {
+            dry_run: true,
+            .. Context::default()
+        };
+
+        // Create a temp directory which would contain the output
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap();
+        env.delete_also(&output);
+
+        let mut req = dummy_web_request();
+        req.params.insert("env".into(), output.to_string());
+
+        // The job should report a success without running the hook
+        let job = env.create_job("jobs-details.sh", req.into());
+        let result = job.process(&ctx).unwrap();
+        assert!(result.success);
+        assert_eq!(result.exit_code, Some(0));
+        assert_eq!(result.hook_name, "jobs-details.sh");
+        assert!(! output_path.join("executed").exists());
+
+        // Even failing hooks are reported as successful
+        let job = env.create_job("failing.sh", dummy_web_request().into());
+        assert!(job.process(&ctx).unwrap().success);
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_interpreter() {
+        let mut env = TestingEnv::new();
+        let ctx = Context::default();
+
+        // Create a temp directory which will contain the output
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap();
+        env.delete_also(&output);
+
+        // The hook is not executable, but its interpreter should run it
+        let mut req = dummy_web_request();
+        req.params.insert("env".into(), output.to_string());
+        let job = env.create_job("interpreter.sh", req.into());
+        assert!(job.process(&ctx).unwrap().success);
+        assert_eq!(read!(output, "executed"), "interpreted\n".to_string());
+
+        // A missing interpreter should fail the job
+        let job = env.create_job(
+            "missing-interpreter.sh", dummy_web_request().into(),
+        );
+        assert_err!(job.process(&ctx), ErrorKind::InterpreterNotFound(..));
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_success_codes() {
+        let env = TestingEnv::new();
+        let ctx = Context::default();
+
+        // By default only the exit code 0 is a success
+        let job = env.create_job("exit-78.sh", dummy_web_request().into());
+        let output = job.process(&ctx).unwrap();
+        assert_eq!(output.exit_code, Some(78));
+        assert!(! output.success);
+
+        // The hook can declare other exit codes as successful
+        let job = env.create_job(
+            "success-codes.sh", dummy_web_request().into(),
+        );
+        let output = job.process(&ctx).unwrap();
+        assert_eq!(output.exit_code, Some(78));
+        assert!(output.success);
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_timeout() {
+        let env = TestingEnv::new();
+        let ctx = Context {
+            timeout: Some(Duration::from_millis(200)),
+            .. Context::default()
+        };
+
+        // The default timeout applies to the hooks without their own
+        let job = env.create_job("long.sh", dummy_web_request().into());
+        let result = job.process(&ctx).unwrap();
+        assert!(! result.success);
+        assert!(result.timed_out);
+
+        // While the hooks can disable it
+        let job = env.create_job("no-timeout.sh", dummy_web_request().into());
+        let result = job.process(&ctx).unwrap();
+        assert!(result.success);
+        assert!(! result.timed_out);
+
+        // Jobs exiting successfully once asked to stop still failed
+        let ctx = Context {
+            timeout: Some(Duration::from_millis(200)),
+            kill: KillPolicy {
+                signal: libc::SIGTERM,
+                grace: Duration::from_secs(5),
+            },
+            .. Context::default()
+        };
+        let job = env.create_job(
+            "trap-term-success.sh", dummy_web_request().into(),
+        );
+        let result = job.process(&ctx).unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, Some(0));
+        assert!(! result.success);
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_sandbox() {
+        let env = TestingEnv::new();
+
+        let ctx = Context {
+            sandbox: Sandbox {
+                nice: Some(19),
+                cpu_time: Some(60),
+                address_space: Some(1024 * 1024 * 1024),
+                .. Sandbox::default()
+            },
+            .. Context::default()
+        };
+        assert!(! ctx.sandbox.is_empty());
+        let job = env.create_job("sandbox.sh", dummy_web_request().into());
+        let result = job.process(&ctx).unwrap();
+        assert!(result.success);
+        assert_eq!(result.stdout, "19 60 1048576\n");
+
+        // Dropping privileges to the current user always works
+        let ctx = Context {
+            sandbox: Sandbox {
+                uid: Some(unsafe { libc::geteuid() }),
+                .. Sandbox::default()
+            },
+            .. Context::default()
+        };
+        let job = env.create_job("sandbox.sh", dummy_web_request().into());
+        assert!(job.process(&ctx).unwrap().success);
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_sandbox_other_user() {
+        // Only root is able to run the jobs as another user
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let env = TestingEnv::new();
+
+        let mut req = dummy_web_request();
+        req.body = "hello".into();
+        let job = env.create_job("sandbox-user.sh", req.into());
+
+        // The directory of the hooks is private, but nobody must be able to
+        // execute the hook itself
+        let hooks_dir = Path::new(job.hook.exec()).parent().unwrap();
+        fs::set_permissions(hooks_dir, fs::Permissions::from_mode(0o755))
+            .unwrap();
+
+        // The job is able to write in its working directory and to read the
+        // request body
+        let ctx = Context {
+            sandbox: Sandbox {
+                uid: Some(65534),
+                gid: Some(65534),
+                .. Sandbox::default()
+            },
+            .. Context::default()
+        };
+        let result = job.process(&ctx).unwrap();
+        assert!(result.success, "{}", result.stderr);
+        assert_eq!(result.stdout, "65534 65534 hello\n");
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_kill_signal() {
+        let mut env = TestingEnv::new();
+
+        // Create a temp directory which will contain the hook output
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap();
+        env.delete_also(&output);
+        let cleaned_up = output_path.join("cleaned_up");
+
+        let mut req = dummy_web_request();
+        req.params.insert(
+            "env".to_string(), cleaned_up.to_str().unwrap().to_string(),
+        );
+
+        // By default the job is killed right away
+        let ctx = Context {
+            timeout: Some(Duration::from_millis(200)),
+            .. Context::default()
+        };
+        let job = env.create_job("trap-term.sh", req.clone().into());
+        let result = job.process(&ctx).unwrap();
+        assert!(! result.success);
+        assert!(result.timed_out);
+        assert_eq!(result.signal, Some(libc::SIGKILL));
+        assert!(! cleaned_up.exists());
+
+        // With SIGTERM the script is able to clean up before exiting
+        let ctx = Context {
+            timeout: Some(Duration::from_millis(200)),
+            kill: KillPolicy {
+                signal: libc::SIGTERM,
+                grace: Duration::from_secs(5),
+            },
+            .. Context::default()
+        };
+        let started_at = Instant::now();
+        let job = env.create_job("trap-term.sh", req.into());
+        let result = job.process(&ctx).unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, Some(1));
+        assert_eq!(result.signal, None);
+        assert_eq!(read!(output, "cleaned_up"), "cleaned up\n".to_string());
+
+        // The job didn't wait for the whole grace period
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        env.cleanup();
+    }
+
+    fn is_running(pid: &str) -> bool {
+        // Zombies are dead too, even if they weren't reaped yet
+        match fs::File::open(format!("/proc/{}/stat", pid)) {
+            Ok(mut file) => {
+                let mut stat = String::new();
+                file.read_to_string(&mut stat).unwrap();
+                ! stat.contains(") Z ")
+            },
+            Err(..) => false,
+        }
+    }
+
+    #[test]
+    fn test_job_kill_process_group() {
+        let mut env = TestingEnv::new();
+
+        let output = utils::create_temp_dir().unwrap();
+        env.delete_also(output.to_str().unwrap());
+        let pid_file = output.join("pid");
+
+        let mut req = dummy_web_request();
+        req.params.insert(
+            "env".to_string(), pid_file.to_str().unwrap().to_string(),
+        );
+        let job = env.create_job("grandchild.sh", req.into());
+
+        // The processes spawned by the job are killed with it
+        let ctx = Context {
+            timeout: Some(Duration::from_millis(500)),
+            .. Context::default()
+        };
+        let result = job.process(&ctx).unwrap();
+        assert_eq!(result.signal, Some(libc::SIGKILL));
+        let grandchild = read!(output.to_str().unwrap(), "pid");
+        assert!(! is_running(grandchild.trim()));
+
+        // Unless only the job itself should be killed
+        let ctx = Context {
+            timeout: Some(Duration::from_millis(500)),
+            kill_process_group: false,
+            .. Context::default()
+        };
+        let result = job.process(&ctx).unwrap();
+        assert_eq!(result.signal, Some(libc::SIGKILL));
+        let grandchild = read!(output.to_str().unwrap(), "pid");
+        assert!(is_running(grandchild.trim()));
+
+        unsafe {
+            libc::kill(grandchild.trim().parse().unwrap(), libc::SIGKILL);
+        }
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_killed_with_output_kept_open() {
+        let mut env = TestingEnv::new();
+
+        let output = utils::create_temp_dir().unwrap();
+        env.delete_also(output.to_str().unwrap());
+        let pid_file = output.join("pid");
+
+        let mut req = dummy_web_request();
+        req.params.insert(
+            "env".to_string(), pid_file.to_str().unwrap().to_string(),
+        );
+        let job = env.create_job("grandchild-output.sh", req.into());
+
+        // The surviving process still has the pipes open, but the job
+        // returns with the output read so far
+        let ctx = Context {
+            timeout: Some(Duration::from_millis(500)),
+            kill_process_group: false,
+            .. Context::default()
+        };
+        let started_at = Instant::now();
+        let result = job.process(&ctx).unwrap();
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+        assert_eq!(result.signal, Some(libc::SIGKILL));
+        assert_eq!(result.stdout, "started\n");
+
+        let grandchild = read!(output.to_str().unwrap(), "pid");
+        unsafe {
+            libc::kill(grandchild.trim().parse().unwrap(), libc::SIGKILL);
+        }
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_limits() {
+        let env = TestingEnv::new();
+
+        // The limits can't be applied without cgroups
+        let ctx = Context::default();
+        let job = env.create_job("memory-hog.sh", dummy_web_request().into());
+        assert_err!(job.process(&ctx), ErrorKind::CgroupsUnavailable(..));
+
+        // Unless the job is allowed to run without them
+        let ctx = Context {
+            cgroup_fallback: true,
+            .. Context::default()
+        };
+        assert!(job.process(&ctx).unwrap().success);
+
+        // Enforcing the limits requires a cgroup (v2) delegated to the user
+        // running the tests, so it's checked only if one is provided
+        if let Ok(parent) = env::var("FISHER_TEST_CGROUP_PARENT") {
+            let ctx = Context {
+                cgroup_parent: Some(parent.into()),
+                timeout: Some(Duration::from_secs(30)),
+                .. Context::default()
+            };
+            let output = job.process(&ctx).unwrap();
+            assert!(! output.success);
+            assert_eq!(output.signal, Some(libc::SIGKILL));
+        }
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_job_gave_up() {
+        let mut env = TestingEnv::new();
+
+        let dir = utils::create_temp_dir().unwrap();
+        env.delete_also(dir.to_str().unwrap());
+
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "testing".into());
+        req.body = "a body!".into();
+        let job = env.create_job("failing.sh", req.into());
+
+        // Without a dead letter directory nothing is stored
+        job.gave_up(&Context::default(), "dummy_error", 3);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let ctx = Context {
+            dead_letter: Some(dir.clone()),
+            .. Context::default()
+        };
+        job.gave_up(&ctx, "dummy_error", 3);
+
+        let files = fs::read_dir(&dir).unwrap().collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        let path = files[0].as_ref().unwrap().path();
+        assert_eq!(path.extension().unwrap(), "json");
+
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        let letter: serde_json::Value = serde_json::from_str(&content)
+            .unwrap();
+        assert_eq!(letter["hook"], json!("failing.sh"));
+        assert_eq!(letter["attempts"], json!(3));
+        assert_eq!(letter["error"], json!("dummy_error"));
+        assert_eq!(letter["params"]["secret"], json!("testing"));
+        assert_eq!(letter["body"], json!("a body!"));
+
+        // An unwritable directory doesn't stop the processing
+        let ctx = Context {
+            dead_letter: Some(dir.join("file.json")),
+            .. Context::default()
+        };
+        fs::File::create(dir.join("file.json")).unwrap();
+        job.gave_up(&ctx, "dummy_error", 3);
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_failure_webhook() {
+        let env = TestingEnv::new();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ctx = Context {
+            failure_webhook: Some(FailureWebhook::new(&format!(
+                "http://{}/alerts", listener.local_addr().unwrap(),
+            )).unwrap()),
+            .. Context::default()
+        };
+
+        // Executing the job doesn't notify anything, since it could be
+        // retried later
+        let job = env.create_job("failing.sh", dummy_web_request().into());
+        let output = job.execute(&ctx).unwrap();
+        assert!(! output.success);
+
+        listener.set_nonblocking(true).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(listener.accept().is_err());
+        listener.set_nonblocking(false).unwrap();
+
+        // Failed jobs are notified with their details
+        job.failed_permanently(&ctx, Some(&output), None);
+
+        let (request_line, body) = receive_http_request(&listener, 200);
+        assert_eq!(request_line, "POST /alerts HTTP/1.1");
+        let payload: serde_json::Value = serde_json::from_str(&body)
+            .unwrap();
+        assert_eq!(payload["hook"], json!("failing.sh"));
+        assert_eq!(payload["exit_code"], json!(1));
+        assert_eq!(payload["timed_out"], json!(false));
+        assert_eq!(payload["stderr"], json!(""));
+        assert_eq!(payload["error"], json!(null));
+
+        // Jobs which didn't return an output are notified with the error
+        job.failed_permanently(&ctx, None, Some("the hook timed out"));
+
+        let (_, body) = receive_http_request(&listener, 200);
+        let payload: serde_json::Value = serde_json::from_str(&body)
+            .unwrap();
+        assert_eq!(payload["error"], json!("the hook timed out"));
+        assert_eq!(payload["exit_code"], json!(null));
+
+        env.cleanup();
+    }
+
+    #[test]
+    fn test_read_limited() {
+        let mut buffer = Vec::new();
+        read_limited(&b"hello world"[..], None, &mut buffer);
+        assert_eq!(buffer, b"hello world");
+
+        let mut buffer = Vec::new();
+        read_limited(&b"hello world"[..], Some(11), &mut buffer);
+        assert_eq!(buffer, b"hello world");
+
+        // The discarded bytes are replaced by a marker
+        let mut buffer = Vec::new();
+        read_limited(&b"hello world"[..], Some(5), &mut buffer);
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "hello\n[output truncated, 6 more bytes discarded]\n",
+        );
+    }
+
+    #[test]
+    fn test_env_prefix() {
+        let mut env = TestingEnv::new();
+        let ctx = Context {
+            env_prefix: "MYAPP".into(),
+            .. Context::default()
+        };
+
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap();
+        env.delete_also(&output);
+
+        let mut req = dummy_web_request();
+        req.params.insert("env".into(), output.to_string());
+
+        let job = env.create_job("env-prefix.sh", req.into());
+        assert!(job.process(&ctx).is_ok());
+
+        // Both the variables of Fisher and of the providers use the prefix
+        let raw_env = read!(output, "env");
+        let job_env = parse_env(&raw_env);
+        assert_eq!(*job_env.get("MYAPP_TESTING_ENV").unwrap(), output);
+        assert_eq!(*job_env.get("MYAPP_REQUEST_IP").unwrap(), "127.0.0.1");
+        assert!(job_env.contains_key("MYAPP_REQUEST_BODY"));
+        assert!(! job_env.keys().any(|key| key.starts_with("FISHER_")));
+
+        env.cleanup();
+    }
+
+
+    #[test]
+    fn test_status_output_environment() {
+        let env = TestingEnv::new();
+        let ctx = Context::default();
+
+        let mut output = dummy_job_output();
+        output.hook_name = "trigger-status".into();
+        let req = Request::Status(StatusEvent::JobCompleted(output));
+        let job = env.create_job("status-example.sh", req);
+
+        // The output is available both with and without the provider name
+        let job_env = job.environment(&ctx);
+        for key in &["FISHER_LAST_STDOUT", "FISHER_STATUS_STDOUT"] {
+            assert_eq!(job_env.get(*key).unwrap(), "hello world");
+        }
+        for key in &["FISHER_LAST_STDERR", "FISHER_STATUS_STDERR"] {
+            assert_eq!(job_env.get(*key).unwrap(), "something happened");
+        }
+
+        env.cleanup();
+    }
+
+
+    #[test]
+    fn test_is_valid_env_prefix() {
+        for valid in &["FISHER", "my_app", "_X", "APP2"] {
+            assert!(is_valid_env_prefix(valid), valid.to_string());
+        }
+        for invalid in &["", "2APP", "MY-APP", "MY APP", "APPé"] {
+            assert!(! is_valid_env_prefix(invalid), invalid.to_string());
+        }
+    }
+
+    #[test]
+    fn test_work_dir() {
+        let mut env = TestingEnv::new();
+
+        let work_dir = utils::create_temp_dir().unwrap();
+        env.delete_also(work_dir.to_str().unwrap());
+        let ctx = Context {
+            work_dir: Some(work_dir.clone()),
+            timeout: Some(Duration::from_millis(100)),
+            .. Context::default()
+        };
+
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap();
+        env.delete_also(&output);
+
+        let mut req = dummy_web_request();
+        req.params.insert("env".to_string(), output.to_string());
+        let req: Request = req.into();
+
+        // The job is executed in a directory inside the work directory,
+        // which is removed after the job ends
+        let job = env.create_job("jobs-details.sh", req.clone());
+        assert!(job.process(&ctx).is_ok());
+        let pwd = read!(output, "pwd");
+        assert!(Path::new(pwd.trim()).starts_with(&work_dir));
+        assert_eq!(fs::read_dir(&work_dir).unwrap().count(), 0);
+
+        // The directory is removed also when the job times out
+        let job = env.create_job("long.sh", req);
+        assert!(job.process(&ctx).unwrap().timed_out);
+        assert_eq!(fs::read_dir(&work_dir).unwrap().count(), 0);
+
+        env.cleanup();
+    }
 }
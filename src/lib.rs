@@ -26,7 +26,10 @@ extern crate libc;
 #[macro_use] extern crate serde_json;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate lazy_static;
-#[cfg(feature = "provider-github")] extern crate ring;
+extern crate ring;
+extern crate toml;
+extern crate mio;
+extern crate httparse;
 #[cfg(test)] extern crate hyper;
 
 extern crate fisher_common;
@@ -40,7 +43,26 @@ mod web;
 mod app;
 mod requests;
 mod native;
+mod dead_letter;
+mod failure_webhook;
+mod job_logs;
+mod queue_store;
+mod event_log;
+mod cgroups;
+mod manifest;
 
 // Public API
 pub use app::{Fisher, RunningFisher};
+pub use hooks::{HooksDiff, HookSummary};
 pub use fisher_common::prelude::*;
+pub use fisher_common::auth::constant_time_eq;
+pub use providers::{ProviderTrait, ProviderRegistry, ProviderErrorPolicy};
+pub use requests::{Request, RequestType};
+pub use jobs::{JobOutput, Sandbox};
+pub use web::WebRequest;
+pub use fisher_processor::{SchedulerKind, LoadShedding, JobEvent};
+pub use fisher_processor::RetryPolicy;
+pub use dead_letter::replay as replay_dead_letter;
+pub use event_log::{LogFormat, json_output, log_message, set_log_format};
+pub use native::daemonize;
+pub use utils::{IpNetwork, PidFile};
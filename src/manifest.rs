@@ -0,0 +1,169 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fs::{canonicalize, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use ring::digest;
+
+use fisher_common::prelude::*;
+
+use utils;
+
+
+/// The SHA-256 checksums the hooks must match, in the same format as the
+/// output of `sha256sum`. Relative paths are resolved from the directory
+/// containing the manifest.
+#[derive(Debug)]
+pub struct Manifest {
+    checksums: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl Manifest {
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        let reader = BufReader::new(File::open(path)?);
+
+        let mut checksums = HashMap::new();
+        let mut line_number: u32 = 0;
+        for line in reader.lines() {
+            line_number += 1;
+            let line = line?;
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let checksum = parts.next().and_then(|c| utils::from_hex(c).ok());
+            let file = parts.next().map(|f| f.trim_left())
+                // sha256sum marks the files read in binary mode with a *
+                .map(|f| f.trim_left_matches('*'));
+
+            match (checksum, file) {
+                (Some(ref checksum), Some(file))
+                    if checksum.len() == 32 && ! file.is_empty() =>
+                {
+                    let file = base.join(file);
+                    let file = canonicalize(&file).unwrap_or(file);
+                    checksums.insert(file, checksum.clone());
+                },
+                _ => {
+                    let mut error: Error = ErrorKind::InvalidInput(
+                        "invalid line in the manifest".into()
+                    ).into();
+                    error.set_location(ErrorLocation::File(
+                        path.to_string_lossy().into_owned(), Some(line_number),
+                    ));
+                    return Err(error);
+                },
+            }
+        }
+
+        Ok(Manifest {
+            checksums: checksums,
+        })
+    }
+
+    /// Check if the content of the file matches its checksum.
+    pub fn verify(&self, file: &Path) -> Result<()> {
+        let result = match self.checksums.get(file) {
+            Some(expected) => {
+                let mut content = Vec::new();
+                File::open(file)?.read_to_end(&mut content)?;
+
+                let actual = digest::digest(&digest::SHA256, &content);
+                if actual.as_ref() == expected.as_slice() {
+                    Ok(())
+                } else {
+                    Err(ErrorKind::ChecksumMismatch.into())
+                }
+            },
+            None => Err(ErrorKind::NotInManifest.into()),
+        };
+
+        result.map_err(|mut error: Error| {
+            error.set_location(ErrorLocation::File(
+                file.to_string_lossy().into_owned(), None,
+            ));
+            error
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use fisher_common::prelude::*;
+
+    use utils;
+
+    use super::Manifest;
+
+
+    // SHA-256 of "echo hello\n"
+    static HELLO: &'static str =
+        "5dbad7dd0b9b122dcd9956884390f4aac4738caba8ff53498a7ab6718b176c30";
+
+
+    fn write(path: &::std::path::Path, content: &str) {
+        fs::File::create(path).unwrap()
+            .write_all(content.as_bytes()).unwrap();
+    }
+
+
+    #[test]
+    fn test_manifest() {
+        let base = utils::create_temp_dir().unwrap();
+        let hook = base.join("hook.sh");
+        write(&hook, "echo hello\n");
+        let hook = fs::canonicalize(&hook).unwrap();
+
+        write(&base.join("hooks.sha256"), &format!(
+            "# Trusted hooks\n{}  hook.sh\n\n{} *other.sh\n", HELLO, HELLO,
+        ));
+        let manifest = Manifest::load(&base.join("hooks.sha256")).unwrap();
+
+        // The file matches its checksum
+        manifest.verify(&hook).unwrap();
+
+        // The file was tampered with
+        write(&hook, "echo pwned\n");
+        assert_err!(manifest.verify(&hook), ErrorKind::ChecksumMismatch);
+
+        // The file isn't listed
+        let missing = base.join("missing.sh");
+        write(&missing, "echo hello\n");
+        assert_err!(manifest.verify(&missing), ErrorKind::NotInManifest);
+
+        // Invalid manifests are rejected
+        for content in &["abcd  hook.sh\n".into(), format!("{}\n", HELLO)] {
+            write(&base.join("invalid.sha256"), content);
+            assert_err!(
+                Manifest::load(&base.join("invalid.sha256")),
+                ErrorKind::InvalidInput(..)
+            );
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -13,6 +13,13 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
 use libc;
 
 
@@ -26,6 +33,291 @@ pub fn isolate_process() {
 }
 
 
+/// Move the current process into the cgroup with the provided
+/// `cgroup.procs` file. This is called between fork and exec, so it only
+/// calls async-signal-safe functions.
+pub fn join_cgroup(procs: &CStr) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Writing 0 moves the process which is writing
+        let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+
+        if written != 1 {
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Set the niceness of the current process. This is called between fork
+/// and exec, so it only calls async-signal-safe functions.
+pub fn set_nice(nice: i32) -> io::Result<()> {
+    let result = unsafe {
+        libc::setpriority(libc::PRIO_PROCESS as _, 0, nice as libc::c_int)
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+
+// The type of the resources changes between the platforms, so this can't
+// be a function
+macro_rules! set_rlimit {
+    ($resource:expr, $value:expr) => {{
+        let limit = libc::rlimit {
+            rlim_cur: $value as libc::rlim_t,
+            rlim_max: $value as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit($resource, &limit) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }};
+}
+
+
+/// Limit how many seconds of CPU time the current process can use.
+pub fn limit_cpu_time(seconds: u64) -> io::Result<()> {
+    set_rlimit!(libc::RLIMIT_CPU, seconds)
+}
+
+
+/// Limit how many bytes of virtual memory the current process can use.
+pub fn limit_address_space(bytes: u64) -> io::Result<()> {
+    set_rlimit!(libc::RLIMIT_AS, bytes)
+}
+
+
+/// Change the owner of the path, without following symlinks. The user or
+/// the group are left untouched if they're not provided.
+pub fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>)
+             -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let uid = uid.map(|uid| uid as libc::uid_t).unwrap_or(!0);
+    let gid = gid.map(|gid| gid as libc::gid_t).unwrap_or(!0);
+
+    if unsafe { libc::lchown(path.as_ptr(), uid, gid) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+
+/// Run the current process as another user and group. The supplementary
+/// groups are dropped when the group is changed, and the user is changed
+/// last, since it usually removes the permission to change the group.
+pub fn drop_privileges(uid: Option<u32>, gid: Option<u32>)
+                       -> io::Result<()> {
+    if let Some(gid) = gid {
+        let gid = gid as libc::gid_t;
+        unsafe {
+            if libc::setgroups(1, &gid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setgid(gid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid as libc::uid_t) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+
+pub fn signal_by_name(name: &str) -> Option<libc::c_int> {
+    // The "SIG" prefix is optional
+    let name = if name.starts_with("SIG") { &name[3..] } else { name };
+
+    Some(match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        _ => return None,
+    })
+}
+
+
+pub fn send_signal(pid: u32, signal: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+
+/// Send a signal to all the processes in the group. Since jobs are isolated
+/// in their own process group, this also reaches the processes they spawned.
+pub fn send_signal_to_group(pgid: u32, signal: libc::c_int)
+                            -> io::Result<()> {
+    if unsafe { libc::killpg(pgid as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+
+/// Detach Fisher from the terminal, continuing in a child process in a new
+/// session. The standard output and error are appended to the log file, or
+/// discarded without one. This must be called before any thread is started.
+pub fn daemonize(log: Option<&Path>) -> io::Result<()> {
+    // The files are opened before forking, so the errors are still shown
+    let null = fs::OpenOptions::new().read(true).write(true)
+        .open("/dev/null")?;
+    let output = match log {
+        Some(path) => {
+            fs::OpenOptions::new().create(true).append(true).open(path)?
+        },
+        None => null.try_clone()?,
+    };
+
+    // Otherwise the buffered output would be printed twice
+    io::stdout().flush()?;
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // A process which isn't a session leader can't acquire a controlling
+    // terminal anymore
+    fork_and_exit_parent()?;
+
+    for &(file, target) in &[
+        (&null, libc::STDIN_FILENO),
+        (&output, libc::STDOUT_FILENO),
+        (&output, libc::STDERR_FILENO),
+    ] {
+        if unsafe { libc::dup2(file.as_raw_fd(), target) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+
+// Those are the inotify events which mean the content of a directory changed
+const IN_MODIFY: u32 = 0x002;
+const IN_ATTRIB: u32 = 0x004;
+const IN_MOVED_FROM: u32 = 0x040;
+const IN_MOVED_TO: u32 = 0x080;
+const IN_CREATE: u32 = 0x100;
+const IN_DELETE: u32 = 0x200;
+const IN_DELETE_SELF: u32 = 0x400;
+const IN_MOVE_SELF: u32 = 0x800;
+
+
+/// A non-blocking inotify instance, closed when dropped.
+#[derive(Debug)]
+pub struct Inotify {
+    fd: libc::c_int,
+}
+
+impl Inotify {
+
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            inotify_init1(libc::O_NONBLOCK | libc::O_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Inotify {
+            fd: fd,
+        })
+    }
+
+    /// Watch the changes to the file, or to the files in the directory.
+    pub fn watch(&self, path: &Path) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let mask = IN_MODIFY | IN_ATTRIB | IN_MOVED_FROM | IN_MOVED_TO
+                 | IN_CREATE | IN_DELETE | IN_DELETE_SELF | IN_MOVE_SELF;
+
+        if unsafe { inotify_add_watch(self.fd, path.as_ptr(), mask) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check if something changed since the last call, without blocking.
+    pub fn changed(&self) -> io::Result<bool> {
+        let mut buffer = [0u8; 4096];
+        let mut changed = false;
+
+        // Discard all the pending events, only their presence matters
+        loop {
+            let read = unsafe {
+                libc::read(
+                    self.fd, buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+
+            if read > 0 {
+                changed = true;
+            } else if read == 0 {
+                return Ok(changed);
+            } else {
+                let error = io::Error::last_os_error();
+                return if error.kind() == io::ErrorKind::WouldBlock {
+                    Ok(changed)
+                } else {
+                    Err(error)
+                };
+            }
+        }
+    }
+}
+
+impl Drop for Inotify {
+
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+
 extern {
     fn setpgid(pid: libc::pid_t, pgid: libc::pid_t) -> libc::c_int;
+    fn inotify_init1(flags: libc::c_int) -> libc::c_int;
+    fn inotify_add_watch(fd: libc::c_int, path: *const libc::c_char,
+                         mask: u32) -> libc::c_int;
 }
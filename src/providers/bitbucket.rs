@@ -0,0 +1,302 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde_json;
+
+use providers::prelude::*;
+use utils::verify_signature;
+use fisher_common::prelude::*;
+use fisher_common::env_map::EnvMap;
+
+
+lazy_static! {
+    static ref BITBUCKET_EVENTS: Vec<&'static str> = vec![
+        "repo:push", "repo:fork", "repo:updated", "repo:transfer",
+        "repo:commit_comment_created", "repo:commit_status_created",
+        "repo:commit_status_updated", "issue:created", "issue:updated",
+        "issue:comment_created", "pullrequest:created", "pullrequest:updated",
+        "pullrequest:approved", "pullrequest:unapproved",
+        "pullrequest:fulfilled", "pullrequest:rejected",
+        "pullrequest:comment_created", "pullrequest:comment_updated",
+        "pullrequest:comment_deleted",
+    ];
+}
+
+// Sent when the "Test connection" button is clicked in the settings
+const PING_EVENT: &'static str = "diagnostics:ping";
+
+
+#[derive(Debug, Deserialize)]
+pub struct BitbucketProvider {
+    secret: Option<String>,
+    events: Option<Vec<String>>,
+
+    #[serde(default)]
+    env_map: EnvMap,
+    #[serde(default)]
+    env_map_strict: bool,
+}
+
+impl ProviderTrait for BitbucketProvider {
+
+    fn new(input: &str) -> Result<BitbucketProvider> {
+        let inst: BitbucketProvider = serde_json::from_str(input)?;
+        inst.env_map.validate()?;
+
+        if let Some(ref events) = inst.events {
+            for event in events {
+                if ! BITBUCKET_EVENTS.contains(&event.as_ref()) {
+                    return Err(ErrorKind::InvalidInput(format!(
+                        r#""{}" is not a Bitbucket event"#, event
+                    )).into());
+                }
+            }
+        }
+
+        Ok(inst)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        self.try_validate(request).unwrap_or(RequestType::Invalid)
+    }
+
+    fn try_validate(&self, request: &Request) -> Result<RequestType> {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return Ok(RequestType::Invalid);
+        }
+
+        let event = if let Some(event) = req.headers.get("X-Event-Key") {
+            event
+        } else {
+            return Ok(RequestType::Invalid);
+        };
+
+        let body = req.read_body()?;
+
+        // Bitbucket signs the requests only if a secret is set, so the
+        // signature is required only in that case
+        if let Some(ref secret) = self.secret {
+            let valid = match req.headers.get("X-Hub-Signature") {
+                Some(signature) => verify_signature(secret, &body, signature),
+                None => false,
+            };
+            if ! valid {
+                return Ok(RequestType::Invalid);
+            }
+        }
+
+        // Check if the event exists and if it should be accepted
+        if event != PING_EVENT {
+            if ! BITBUCKET_EVENTS.contains(&event.as_ref()) {
+                return Ok(RequestType::Invalid);
+            }
+            if let Some(ref events) = self.events {
+                if ! events.contains(event) {
+                    return Ok(RequestType::Invalid);
+                }
+            }
+        }
+
+        // Check if the JSON in the body is valid
+        if req.json_body().is_err() {
+            return Ok(RequestType::Invalid);
+        }
+
+        if event == PING_EVENT {
+            Ok(RequestType::Ping)
+        } else {
+            Ok(RequestType::ExecuteHook)
+        }
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return HashMap::new();
+        }
+
+        let mut res = HashMap::new();
+        if let Some(event) = req.headers.get("X-Event-Key") {
+            res.insert("EVENT".to_string(), event.clone());
+        }
+
+        if let Ok(body) = req.json_body() {
+            // Every event includes the repository it's about
+            let repository = body.pointer("/repository/full_name")
+                .and_then(|name| name.as_str());
+            if let Some(repository) = repository {
+                res.insert("REPOSITORY".to_string(), repository.to_string());
+            }
+
+            // Extract the variables the user asked for from the body
+            if ! self.env_map.is_empty() {
+                res.extend(self.env_map.extract(&body, self.env_map_strict));
+            }
+        }
+
+        res
+    }
+
+    fn needs_full_body(&self) -> bool {
+        // The signature covers the whole body
+        self.secret.is_some()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::{Request, RequestType};
+    use providers::ProviderTrait;
+
+    use super::{BITBUCKET_EVENTS, BitbucketProvider};
+
+
+    fn request(event: &str, headers: &[(&str, &str)], body: &str)
+               -> Request {
+        let mut request = dummy_web_request();
+        request.headers.insert("X-Event-Key".into(), event.into());
+        for &(name, value) in headers {
+            request.headers.insert(name.into(), value.into());
+        }
+        request.body = body.into();
+        request.into()
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{}"#,
+            r#"{"secret": "abcde"}"#,
+            r#"{"events": ["repo:push", "pullrequest:created"]}"#,
+            r#"{"env_map": {"BRANCH": "/push/changes/0/new/name"}}"#,
+        ] {
+            assert!(BitbucketProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{"secret": 12345}"#,
+            r#"{"events": "repo:push"}"#,
+            r#"{"events": [12345]}"#,
+            r#"{"events": ["push"]}"#,
+            r#"{"events": ["diagnostics:ping"]}"#,
+            r#"{"env_map": {"BRANCH": "push"}}"#,
+        ] {
+            assert!(BitbucketProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_request_type() {
+        let provider = BitbucketProvider::new("{}").unwrap();
+
+        for event in BITBUCKET_EVENTS.iter() {
+            assert_eq!(
+                provider.validate(&request(event, &[], "{}")),
+                RequestType::ExecuteHook
+            );
+        }
+        assert_eq!(
+            provider.validate(&request("diagnostics:ping", &[], "{}")),
+            RequestType::Ping
+        );
+
+        // Unknown events and invalid bodies are rejected
+        assert_eq!(
+            provider.validate(&request("repo:unknown", &[], "{}")),
+            RequestType::Invalid
+        );
+        assert_eq!(
+            provider.validate(&request("repo:push", &[], "invalid")),
+            RequestType::Invalid
+        );
+
+        // The event header is required
+        let mut missing = dummy_web_request();
+        missing.body = "{}".into();
+        assert_eq!(provider.validate(&missing.into()), RequestType::Invalid);
+
+        // Only the allowed events are accepted, but pings always are
+        let provider = BitbucketProvider::new(
+            r#"{"events": ["repo:push"]}"#
+        ).unwrap();
+        assert_eq!(
+            provider.validate(&request("repo:push", &[], "{}")),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            provider.validate(&request("repo:fork", &[], "{}")),
+            RequestType::Invalid
+        );
+        assert_eq!(
+            provider.validate(&request("diagnostics:ping", &[], "{}")),
+            RequestType::Ping
+        );
+    }
+
+
+    #[test]
+    fn test_signature() {
+        let provider = BitbucketProvider::new(
+            r#"{"secret": "secret"}"#
+        ).unwrap();
+
+        let sha256 = "sha256=77325902caca812dc259733aacd046b7\
+                      3817372c777b8d95b402647474516e13";
+
+        assert_eq!(
+            provider.validate(&request("repo:push", &[], "{}")),
+            RequestType::Invalid
+        );
+        assert_eq!(
+            provider.validate(&request("repo:push", &[
+                ("X-Hub-Signature", "sha256=00"),
+            ], "{}")),
+            RequestType::Invalid
+        );
+        assert_eq!(
+            provider.validate(&request("repo:push", &[
+                ("X-Hub-Signature", sha256),
+            ], "{}")),
+            RequestType::ExecuteHook
+        );
+    }
+
+
+    #[test]
+    fn test_env() {
+        let provider = BitbucketProvider::new("{}").unwrap();
+
+        let env = provider.env(&request(
+            "repo:push", &[], r#"{"repository": {"full_name": "team/repo"}}"#,
+        ));
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["EVENT"], "repo:push");
+        assert_eq!(env["REPOSITORY"], "team/repo");
+
+        // The repository is optional
+        let env = provider.env(&request("repo:push", &[], "{}"));
+        assert_eq!(env.len(), 1);
+        assert_eq!(env["EVENT"], "repo:push");
+    }
+}
@@ -14,11 +14,11 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use serde_json;
-use ring;
 
 use providers::prelude::*;
-use utils;
+use utils::verify_signature;
 use fisher_common::prelude::*;
+use fisher_common::env_map::EnvMap;
 
 
 lazy_static! {
@@ -33,9 +33,14 @@ lazy_static! {
 
     static ref GITHUB_HEADERS: Vec<&'static str> = vec![
         "X-GitHub-Event",
-        "X-Hub-Signature",
         "X-GitHub-Delivery",
     ];
+
+    // The first signature present in the request is checked
+    static ref SIGNATURE_HEADERS: Vec<&'static str> = vec![
+        "X-Hub-Signature-256",
+        "X-Hub-Signature",
+    ];
 }
 
 
@@ -43,12 +48,18 @@ lazy_static! {
 pub struct GitHubProvider {
     secret: Option<String>,
     events: Option<Vec<String>>,
+
+    #[serde(default)]
+    env_map: EnvMap,
+    #[serde(default)]
+    env_map_strict: bool,
 }
 
 impl ProviderTrait for GitHubProvider {
 
     fn new(input: &str) -> Result<GitHubProvider> {
         let inst: GitHubProvider = serde_json::from_str(input)?;
+        inst.env_map.validate()?;
 
         if let Some(ref events) = inst.events {
             // Check if the events exists
@@ -66,26 +77,38 @@ impl ProviderTrait for GitHubProvider {
     }
 
     fn validate(&self, request: &Request) -> RequestType {
+        self.try_validate(request).unwrap_or(RequestType::Invalid)
+    }
+
+    fn try_validate(&self, request: &Request) -> Result<RequestType> {
         let req;
         if let Request::Web(ref inner) = *request {
             req = inner;
         } else {
-            return RequestType::Invalid;
+            return Ok(RequestType::Invalid);
         }
 
         // Check if the correct headers are present
         for header in GITHUB_HEADERS.iter() {
             if ! req.headers.contains_key(*header) {
-                return RequestType::Invalid;
+                return Ok(RequestType::Invalid);
             }
         }
 
+        let body = req.read_body()?;
+
         // Check the signature only if a secret key was provided
         if let Some(ref secret) = self.secret {
             // Check if the signature is valid
-            let signature = &req.headers["X-Hub-Signature"];
-            if ! verify_signature(secret, &req.body, signature) {
-                return RequestType::Invalid;
+            let signature = SIGNATURE_HEADERS.iter()
+                .filter_map(|header| req.headers.get(*header))
+                .next();
+            let valid = match signature {
+                Some(signature) => verify_signature(secret, &body, signature),
+                None => false,
+            };
+            if ! valid {
+                return Ok(RequestType::Invalid);
             }
         }
 
@@ -95,28 +118,28 @@ impl ProviderTrait for GitHubProvider {
             GITHUB_EVENTS.contains(&event.as_ref())
             || *event == "ping"
         ) {
-            return RequestType::Invalid;
+            return Ok(RequestType::Invalid);
         }
 
         // Check if the event should be accepted
         if let Some(ref events) = self.events {
             if !( events.contains(event) || *event == "ping") {
-                return RequestType::Invalid;
+                return Ok(RequestType::Invalid);
             }
         }
 
         // Check if the JSON in the body is valid
-        if serde_json::from_str::<serde_json::Value>(&req.body).is_err() {
-            return RequestType::Invalid;
+        if req.json_body().is_err() {
+            return Ok(RequestType::Invalid);
         }
 
         // The "ping" event is a ping (doh!)
         if event == "ping" {
-            return RequestType::Ping;
+            return Ok(RequestType::Ping);
         }
 
         // Process the hook in the other cases
-        RequestType::ExecuteHook
+        Ok(RequestType::ExecuteHook)
     }
 
     fn env(&self, request: &Request) -> HashMap<String, String> {
@@ -139,43 +162,30 @@ impl ProviderTrait for GitHubProvider {
             req.headers["X-GitHub-Delivery"].clone()
         );
 
+        // Extract the variables the user asked for from the body
+        if ! self.env_map.is_empty() {
+            if let Ok(body) = req.json_body() {
+                res.extend(self.env_map.extract(&body, self.env_map_strict));
+            }
+        }
+
         res
     }
-}
-
 
-fn verify_signature(secret: &str, payload: &str, raw_signature: &str) -> bool {
-    // The signature must have a =
-    if ! raw_signature.contains('=') {
-        return false;
+    fn dedup_key(&self, request: &Request) -> Option<String> {
+        // GitHub keeps the same delivery ID when a delivery is retried
+        match *request {
+            Request::Web(ref req) => {
+                req.headers.get("X-GitHub-Delivery").cloned()
+            },
+            _ => None,
+        }
     }
 
-    // Split the raw signature to get the algorithm and the signature
-    let splitted: Vec<&str> = raw_signature.split('=').collect();
-    let algorithm = &splitted[0];
-    let hex_signature = splitted.iter().skip(1).map(|i| *i)
-                                .collect::<Vec<&str>>().join("=");
-
-    // Convert the signature from hex
-    let signature = if let Ok(converted) = utils::from_hex(&hex_signature) {
-        converted
-    } else {
-        // This is not hex
-        return false;
-    };
-
-    // Get the correct digest
-    let digest = match *algorithm {
-        "sha1" => &ring::digest::SHA1,
-        _ => {
-            // Unknown digest, return false
-            return false;
-        },
-    };
-
-    // Verify the HMAC signature
-    let key = ring::hmac::VerificationKey::new(digest, secret.as_bytes());
-    ring::hmac::verify(&key, payload.as_bytes(), &signature).is_ok()
+    fn needs_full_body(&self) -> bool {
+        // The signature covers the whole body
+        self.secret.is_some()
+    }
 }
 
 
@@ -185,7 +195,7 @@ mod tests {
     use requests::RequestType;
     use providers::ProviderTrait;
 
-    use super::{GITHUB_EVENTS, GitHubProvider, verify_signature};
+    use super::{GITHUB_EVENTS, GitHubProvider};
 
 
     #[test]
@@ -196,6 +206,7 @@ mod tests {
             r#"{"secret": "abcde"}"#,
             r#"{"events": ["push", "fork"]}"#,
             r#"{"secret": "abcde", "events": ["push", "fork"]}"#,
+            r#"{"env_map": {"REF": "/ref"}, "env_map_strict": true}"#,
         ] {
             assert!(GitHubProvider::new(right).is_ok(), right.to_string());
         }
@@ -211,6 +222,8 @@ mod tests {
             r#"{"events": [12345]}"#,
             r#"{"events": [true]}"#,
             r#"{"events": ["invalid_event"]}"#,
+            r#"{"env_map": {"REF": "ref"}}"#,
+            r#"{"env_map": ["/ref"]}"#,
         ] {
             assert!(GitHubProvider::new(wrong).is_err(), wrong.to_string());
         }
@@ -250,6 +263,53 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_signature_headers() {
+        let provider = GitHubProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        let request_with = |headers: &[(&str, &str)]| {
+            let mut request = dummy_web_request();
+            request.headers.insert("X-GitHub-Event".into(), "push".into());
+            request.headers.insert("X-GitHub-Delivery".into(), "12345".into());
+            for &(name, value) in headers {
+                request.headers.insert(name.into(), value.into());
+            }
+            request.body = "{}".into();
+            request.into()
+        };
+
+        let sha1 = "sha1=5d61605c3feea9799210ddcb71307d4ba264225f";
+        let sha256 = "sha256=77325902caca812dc259733aacd046b7\
+                      3817372c777b8d95b402647474516e13";
+
+        // Requests without a signature are rejected
+        assert_eq!(
+            provider.validate(&request_with(&[])), RequestType::Invalid
+        );
+
+        // Both the SHA-256 and the legacy SHA-1 signatures are accepted
+        assert_eq!(
+            provider.validate(&request_with(&[
+                ("X-Hub-Signature-256", sha256),
+            ])),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            provider.validate(&request_with(&[("X-Hub-Signature", sha1)])),
+            RequestType::ExecuteHook
+        );
+
+        // The SHA-256 signature is the one checked if both are present
+        assert_eq!(
+            provider.validate(&request_with(&[
+                ("X-Hub-Signature-256", "sha256=00"),
+                ("X-Hub-Signature", sha1),
+            ])),
+            RequestType::Invalid
+        );
+    }
+
+
     #[test]
     fn test_env() {
         let provider = GitHubProvider::new("{}").unwrap();
@@ -265,36 +325,46 @@ mod tests {
             "12345".to_string()
         );
 
+        let request = request.into();
+
         // Get the env
-        let env = provider.env(&request.into());
+        let env = provider.env(&request);
 
         assert_eq!(env.len(), 2);
         assert_eq!(*env.get("EVENT").unwrap(), "ping".to_string());
         assert_eq!(*env.get("DELIVERY_ID").unwrap(), "12345".to_string());
+
+        // The delivery ID identifies retried deliveries
+        assert_eq!(provider.dedup_key(&request), Some("12345".into()));
     }
 
 
     #[test]
-    fn test_verify_signature() {
-        // Check if the function allows invalid signatures
-        for signature in &[
-            "invalid",  // No algorithm
-            "invalid=invalid",  // Invalid algorithm
-            "sha1=g",  // The signature is not hex
-
-            // Invalid signature (the first "e" should be "f")
-            "sha1=e75efc0f29bf50c23f99b30b86f7c78fdaf5f11d",
-        ] {
-            assert!(
-                ! verify_signature("secret", "payload", signature),
-                signature.to_string()
-            );
-        }
-
-        // This is known to be right
-        assert!(verify_signature(
-            "secret", "payload",
-            "sha1=f75efc0f29bf50c23f99b30b86f7c78fdaf5f11d"
-        ));
+    fn test_env_map() {
+        let mut request = dummy_web_request();
+        request.headers.insert("X-GitHub-Event".into(), "push".into());
+        request.headers.insert("X-GitHub-Delivery".into(), "12345".into());
+        request.body = r#"{"ref": "refs/heads/master", "size": 2}"#.into();
+        let request = request.into();
+
+        let config = r#"{"env_map": {
+            "REF": "/ref", "SIZE": "/size", "MISSING": "/missing"
+        }"#;
+
+        // Missing pointers are not set by default
+        let provider = GitHubProvider::new(&format!("{}}}", config)).unwrap();
+        let env = provider.env(&request);
+        assert_eq!(env.len(), 4);
+        assert_eq!(env["REF"], "refs/heads/master");
+        assert_eq!(env["SIZE"], "2");
+        assert!(! env.contains_key("MISSING"));
+
+        // In strict mode they're set to an empty string
+        let provider = GitHubProvider::new(
+            &format!(r#"{}, "env_map_strict": true}}"#, config)
+        ).unwrap();
+        let env = provider.env(&request);
+        assert_eq!(env.len(), 5);
+        assert_eq!(env["MISSING"], "");
     }
 }
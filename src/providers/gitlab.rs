@@ -16,6 +16,8 @@
 use serde_json;
 
 use providers::prelude::*;
+use fisher_common::auth::constant_time_eq;
+use fisher_common::env_map::EnvMap;
 use fisher_common::prelude::*;
 
 
@@ -35,12 +37,30 @@ lazy_static! {
 pub struct GitLabProvider {
     secret: Option<String>,
     events: Option<Vec<String>>,
+
+    #[serde(default)]
+    secret_source: SecretSource,
+
+    ping_when: Option<PingWhen>,
+
+    #[serde(default)]
+    env_map: EnvMap,
+    #[serde(default)]
+    env_map_strict: bool,
 }
 
 impl ProviderTrait for GitLabProvider {
 
     fn new(config: &str) -> Result<Self> {
-        let inst: GitLabProvider = serde_json::from_str(config)?;
+        let mut inst: GitLabProvider = serde_json::from_str(config)?;
+        inst.env_map.validate()?;
+
+        // Events can also be named as in the X-Gitlab-Event header
+        inst.events = inst.events.map(|events| {
+            events.iter()
+                .map(|event| normalize_event_name(event).to_string())
+                .collect()
+        });
 
         // Check the validity of the events
         if let Some(ref events) = inst.events {
@@ -59,30 +79,40 @@ impl ProviderTrait for GitLabProvider {
     }
 
     fn validate(&self, request: &Request) -> RequestType {
+        self.try_validate(request).unwrap_or(RequestType::Invalid)
+    }
+
+    fn try_validate(&self, request: &Request) -> Result<RequestType> {
         let req;
         if let Request::Web(ref inner) = *request {
             req = inner;
         } else {
-            return RequestType::Invalid;
+            return Ok(RequestType::Invalid);
         }
 
         // Check if the correct headers are provided
         for header in GITLAB_HEADERS.iter() {
             if ! req.headers.contains_key(*header) {
-                return RequestType::Invalid;
+                return Ok(RequestType::Invalid);
             }
         }
 
         // Check if the secret token is correct
         if let Some(ref secret) = self.secret {
-            // The header with the token must be present
-            if let Some(token) = req.headers.get("X-Gitlab-Token") {
-                // The token must match
-                if token != secret {
-                    return RequestType::Invalid;
+            let token = if self.secret_source == SecretSource::Authorization {
+                SecretSource::authorization(req)
+                    .map(|auth| auth.secret().to_string())
+            } else {
+                req.headers.get("X-Gitlab-Token").cloned()
+            };
+
+            // The token must be present and it must match
+            if let Some(token) = token {
+                if ! constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+                    return Ok(RequestType::Invalid);
                 }
             } else {
-                return RequestType::Invalid;
+                return Ok(RequestType::Invalid);
             }
         }
 
@@ -94,16 +124,22 @@ impl ProviderTrait for GitLabProvider {
         if let Some(ref events) = self.events {
             // The event is whitelisted
             if ! events.contains(&event.to_string()) {
-                return RequestType::Invalid;
+                return Ok(RequestType::Invalid);
             }
         }
 
         // Check if the JSON body is valid
-        if ! serde_json::from_str::<serde_json::Value>(&req.body).is_ok() {
-            return RequestType::Invalid;
+        if req.json_body().is_err() {
+            return Ok(RequestType::Invalid);
+        }
+
+        if let Some(ref ping_when) = self.ping_when {
+            if ping_when.matches(req)? {
+                return Ok(RequestType::Ping);
+            }
         }
 
-        RequestType::ExecuteHook
+        Ok(RequestType::ExecuteHook)
     }
 
     fn env(&self, request: &Request) -> HashMap<String, String> {
@@ -122,6 +158,20 @@ impl ProviderTrait for GitLabProvider {
         let mut res = HashMap::new();
         res.insert("EVENT".to_string(), event_header.to_string());
 
+        if let Ok(body) = req.json_body() {
+            // Every event includes the project it's about
+            let project = body.pointer("/project/path_with_namespace")
+                .and_then(|path| path.as_str());
+            if let Some(project) = project {
+                res.insert("PROJECT".to_string(), project.to_string());
+            }
+
+            // Extract the variables the user asked for from the body
+            if ! self.env_map.is_empty() {
+                res.extend(self.env_map.extract(&body, self.env_map_strict));
+            }
+        }
+
         res
     }
 }
@@ -171,6 +221,8 @@ mod tests {
             r#"{"secret": "abcde"}"#,
             r#"{"events": ["Push", "Issue"]}"#,
             r#"{"secret": "abcde", "events": ["Push", "Issue"]}"#,
+            r#"{"events": ["Push Hook", "Issue Hook"]}"#,
+            r#"{"secret": "abcde", "secret_source": "authorization"}"#,
         ] {
             assert!(GitLabProvider::new(right).is_ok(), right.to_string());
         }
@@ -185,6 +237,8 @@ mod tests {
             r#"{"events": [12345]}"#,
             r#"{"events": [true]}"#,
             r#"{"events": ["invalid_event"]}"#,
+            r#"{"events": ["Invalid Hook"]}"#,
+            r#"{"secret": "abcde", "secret_source": "body"}"#,
         ] {
             assert!(GitLabProvider::new(wrong).is_err(), wrong.to_string());
         }
@@ -251,6 +305,24 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_validate_ping_when() {
+        let provider = GitLabProvider::new(
+            r#"{"ping_when": {"field": "object_kind", "equals": "test"}}"#
+        ).unwrap();
+
+        let mut req = base_request();
+        req.body = r#"{"object_kind": "push"}"#.into();
+        assert_eq!(
+            provider.validate(&req.clone().into()),
+            RequestType::ExecuteHook
+        );
+
+        req.body = r#"{"object_kind": "test"}"#.into();
+        assert_eq!(provider.validate(&req.into()), RequestType::Ping);
+    }
+
+
     #[test]
     fn test_validate_secret() {
         let provider = GitLabProvider::new(r#"{"secret": "abcde"}"#).unwrap();
@@ -286,6 +358,33 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_validate_secret_authorization() {
+        let provider = GitLabProvider::new(
+            r#"{"secret": "abcde", "secret_source": "authorization"}"#
+        ).unwrap();
+
+        // The GitLab header is not checked anymore
+        let mut req = base_request();
+        req.headers.insert("X-Gitlab-Token".to_string(), "abcde".to_string());
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        // Check a request with a wrong token
+        let mut req = base_request();
+        req.headers.insert(
+            "Authorization".to_string(), "Bearer 12345".to_string()
+        );
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        // Check a request with the right token
+        let mut req = base_request();
+        req.headers.insert(
+            "Authorization".to_string(), "Bearer abcde".to_string()
+        );
+        assert_eq!(provider.validate(&req.into()), RequestType::ExecuteHook);
+    }
+
+
     #[test]
     fn test_validate_events() {
         let config = r#"{"events": ["Push", "Issue"]}"#;
@@ -311,6 +410,19 @@ mod tests {
             RequestType::Invalid
         );
 
+        // The events can be named as in the header
+        let provider = GitLabProvider::new(
+            r#"{"events": ["Push Hook"]}"#
+        ).unwrap();
+        assert_eq!(
+            provider.validate(&with_event("Push Hook")),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            provider.validate(&with_event("Issue Hook")),
+            RequestType::Invalid
+        );
+
         // Without a list of allowed events
         let provider = GitLabProvider::new("{}").unwrap();
         assert_eq!(
@@ -339,6 +451,20 @@ mod tests {
         );
 
         let provider = GitLabProvider::new("{}").unwrap();
+        assert_eq!(provider.env(&req.clone().into()), expected);
+
+        // Values can be extracted from the body
+        req.body = r#"{"ref": "refs/heads/master"}"#.into();
+        expected.insert("REF".to_string(), "refs/heads/master".to_string());
+        let provider = GitLabProvider::new(
+            r#"{"env_map": {"REF": "/ref", "USER": "/user_name"}}"#
+        ).unwrap();
+        assert_eq!(provider.env(&req.clone().into()), expected);
+
+        // The path of the project is always exported
+        req.body = r#"{"project": {"path_with_namespace": "a/b"}}"#.into();
+        expected.remove("REF");
+        expected.insert("PROJECT".to_string(), "a/b".to_string());
         assert_eq!(provider.env(&req.into()), expected);
     }
 
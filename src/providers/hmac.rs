@@ -0,0 +1,333 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use ring;
+use serde_json;
+
+use providers::prelude::*;
+use utils::from_hex;
+use fisher_common::auth::constant_time_eq;
+
+
+const BASE64_CHARS: &'static [u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+
+fn base64(input: &[u8]) -> String {
+    let mut result = String::with_capacity((input.len() + 2) / 3 * 4);
+
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let indexes = [
+            bytes[0] >> 2,
+            (bytes[0] & 0x03) << 4 | bytes[1] >> 4,
+            (bytes[1] & 0x0f) << 2 | bytes[2] >> 6,
+            bytes[2] & 0x3f,
+        ];
+
+        // Missing bytes in the last chunk are replaced by padding
+        for (i, index) in indexes.iter().enumerate() {
+            if i <= chunk.len() {
+                result.push(BASE64_CHARS[*index as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+
+    result
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+enum Algorithm {
+    #[serde(rename = "sha1")]
+    Sha1,
+    #[serde(rename = "sha256")]
+    Sha256,
+    #[serde(rename = "sha512")]
+    Sha512,
+}
+
+impl Algorithm {
+
+    fn digest(&self) -> &'static ring::digest::Algorithm {
+        match *self {
+            Algorithm::Sha1 => &ring::digest::SHA1,
+            Algorithm::Sha256 => &ring::digest::SHA256,
+            Algorithm::Sha512 => &ring::digest::SHA512,
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+enum Encoding {
+    #[serde(rename = "hex")]
+    Hex,
+    #[serde(rename = "base64")]
+    Base64,
+}
+
+impl Default for Encoding {
+
+    fn default() -> Self {
+        Encoding::Hex
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct HmacProvider {
+    header: String,
+    algorithm: Algorithm,
+    secret: String,
+
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    encoding: Encoding,
+
+    ping_when: Option<PingWhen>,
+}
+
+impl HmacProvider {
+
+    fn verify(&self, body: &str, signature: &str) -> bool {
+        let key = ring::hmac::SigningKey::new(
+            self.algorithm.digest(), self.secret.as_bytes(),
+        );
+        let expected = ring::hmac::sign(&key, body.as_bytes());
+
+        match self.encoding {
+            // Hex signatures are accepted in both lowercase and uppercase
+            Encoding::Hex => match from_hex(signature) {
+                Ok(signature) => {
+                    constant_time_eq(&signature, expected.as_ref())
+                },
+                Err(..) => false,
+            },
+            Encoding::Base64 => constant_time_eq(
+                signature.as_bytes(), base64(expected.as_ref()).as_bytes(),
+            ),
+        }
+    }
+}
+
+impl ProviderTrait for HmacProvider {
+
+    fn new(input: &str) -> Result<HmacProvider> {
+        let inst: HmacProvider = serde_json::from_str(input)?;
+
+        if inst.secret.is_empty() {
+            return Err(ErrorKind::InvalidInput(
+                "the secret can't be empty".into()
+            ).into());
+        }
+        if inst.header.is_empty() {
+            return Err(ErrorKind::InvalidInput(
+                "the header can't be empty".into()
+            ).into());
+        }
+
+        Ok(inst)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        self.try_validate(request).unwrap_or(RequestType::Invalid)
+    }
+
+    fn try_validate(&self, request: &Request) -> Result<RequestType> {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return Ok(RequestType::Invalid);
+        }
+
+        let signature = match req.headers.get(&self.header) {
+            Some(header) if header.starts_with(&self.prefix) => {
+                &header[self.prefix.len()..]
+            },
+            _ => return Ok(RequestType::Invalid),
+        };
+
+        // The signature is computed over the raw body of the request
+        if ! self.verify(&req.read_body()?, signature) {
+            return Ok(RequestType::Invalid);
+        }
+
+        if let Some(ref ping_when) = self.ping_when {
+            if ping_when.matches(req)? {
+                return Ok(RequestType::Ping);
+            }
+        }
+
+        Ok(RequestType::ExecuteHook)
+    }
+
+    fn env(&self, _request: &Request) -> HashMap<String, String> {
+        HashMap::new()
+    }
+
+    fn needs_full_body(&self) -> bool {
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::{Request, RequestType};
+    use providers::ProviderTrait;
+
+    use super::{base64, HmacProvider};
+
+
+    const SHA1: &'static str = "f75efc0f29bf50c23f99b30b86f7c78fdaf5f11d";
+    const SHA256: &'static str = "b82fcb791acec57859b989b430a82648\
+                                  8ce2e479fdf92326bd0a2e8375a42ba4";
+    const SHA512: &'static str = "291ddaaa23cafa3aaae1c9755391f4be\
+                                  f35bbdbcb92739a5618a5c896f6520d2\
+                                  b0d28d2d2987dac97479e31214a51d96\
+                                  cfceafa28e46a4f961b63c46352a189e";
+
+
+    fn request(headers: &[(&str, &str)], body: &str) -> Request {
+        let mut request = dummy_web_request();
+        for &(name, value) in headers {
+            request.headers.insert(name.into(), value.into());
+        }
+        request.body = body.into();
+        request.into()
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"header": "X-Sig", "algorithm": "sha1", "secret": "a"}"#,
+            r#"{"header": "X-Sig", "algorithm": "sha256", "secret": "a",
+                "prefix": "sha256=", "encoding": "base64"}"#,
+            r#"{"header": "X-Sig", "algorithm": "sha512", "secret": "a",
+                "ping_when": {"header": "X-Event", "equals": "ping"}}"#,
+        ] {
+            assert!(HmacProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"header": "X-Sig", "algorithm": "sha256"}"#,
+            r#"{"header": "X-Sig", "secret": "a"}"#,
+            r#"{"algorithm": "sha256", "secret": "a"}"#,
+            r#"{"header": "X-Sig", "algorithm": "md5", "secret": "a"}"#,
+            r#"{"header": "X-Sig", "algorithm": "sha256", "secret": ""}"#,
+            r#"{"header": "", "algorithm": "sha256", "secret": "a"}"#,
+            r#"{"header": "X-Sig", "algorithm": "sha256", "secret": "a",
+                "encoding": "base32"}"#,
+        ] {
+            assert!(HmacProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_validate() {
+        let provider = |algorithm: &str, prefix: &str| {
+            HmacProvider::new(&format!(
+                r#"{{"header": "X-Sig", "algorithm": "{}", "secret": "secret",
+                    "prefix": "{}"}}"#, algorithm, prefix,
+            )).unwrap()
+        };
+
+        for &(algorithm, signature) in &[
+            ("sha1", SHA1), ("sha256", SHA256), ("sha512", SHA512),
+        ] {
+            let provider = provider(algorithm, "");
+            assert_eq!(provider.validate(&request(&[
+                ("X-Sig", signature),
+            ], "payload")), RequestType::ExecuteHook);
+            assert_eq!(provider.validate(&request(&[
+                ("X-Sig", &signature.to_uppercase()),
+            ], "payload")), RequestType::ExecuteHook);
+
+            // The signature must match the body
+            assert_eq!(provider.validate(&request(&[
+                ("X-Sig", signature),
+            ], "tampered")), RequestType::Invalid);
+        }
+
+        // The prefix is required, and it's not part of the signature
+        let provider = provider("sha256", "sha256=");
+        let prefixed = format!("sha256={}", SHA256);
+        assert_eq!(provider.validate(&request(&[
+            ("X-Sig", &prefixed),
+        ], "payload")), RequestType::ExecuteHook);
+        for signature in &[SHA256, "sha256=", "sha256=zz", "sha1=00"] {
+            assert_eq!(provider.validate(&request(&[
+                ("X-Sig", *signature),
+            ], "payload")), RequestType::Invalid);
+        }
+
+        // Other headers aren't checked
+        assert_eq!(provider.validate(&request(&[
+            ("X-Other", &prefixed),
+        ], "payload")), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"a"), "YQ==");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"abc"), "YWJj");
+
+        let provider = HmacProvider::new(
+            r#"{"header": "X-Shopify-Hmac-Sha256", "algorithm": "sha256",
+                "secret": "secret", "encoding": "base64"}"#
+        ).unwrap();
+        assert_eq!(provider.validate(&request(&[
+            ("X-Shopify-Hmac-Sha256",
+             "uC/LeRrOxXhZuYm0MKgmSIzi5Hn9+SMmvQoug3WkK6Q="),
+        ], "payload")), RequestType::ExecuteHook);
+        assert_eq!(provider.validate(&request(&[
+            ("X-Shopify-Hmac-Sha256", SHA256),
+        ], "payload")), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_ping() {
+        let provider = HmacProvider::new(
+            r#"{"header": "X-Sig", "algorithm": "sha1", "secret": "secret",
+                "ping_when": {"header": "X-Event", "equals": "ping"}}"#
+        ).unwrap();
+
+        assert_eq!(provider.validate(&request(&[
+            ("X-Sig", SHA1), ("X-Event", "ping"),
+        ], "payload")), RequestType::Ping);
+
+        // Pings must be signed too
+        assert_eq!(provider.validate(&request(&[
+            ("X-Sig", "00"), ("X-Event", "ping"),
+        ], "payload")), RequestType::Invalid);
+    }
+}
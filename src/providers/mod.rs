@@ -17,6 +17,9 @@ mod status;
 mod standalone;
 #[cfg(feature = "provider-github")] mod github;
 #[cfg(feature = "provider-gitlab")] mod gitlab;
+#[cfg(feature = "provider-bitbucket")] mod bitbucket;
+#[cfg(feature = "provider-slack")] mod slack;
+#[cfg(feature = "provider-hmac")] mod hmac;
 #[cfg(test)] pub mod testing;
 
 
@@ -25,7 +28,7 @@ pub mod prelude {
     pub use std::collections::HashMap;
     pub use std::path::PathBuf;
 
-    pub use providers::ProviderTrait;
+    pub use providers::{ProviderTrait, SecretSource, PingWhen};
     pub use requests::{Request, RequestType};
     pub use fisher_common::prelude::*;
 }
@@ -37,10 +40,119 @@ pub use self::status::{StatusEvent, StatusEventKind};
 use std::collections::HashMap;
 use std::path::PathBuf;
 
+use serde_json;
+
 use requests::{Request, RequestType};
+use web::WebRequest;
+use fisher_common::auth::Authorization;
 use fisher_common::prelude::*;
 
 
+/// Where providers should look for the secret of incoming requests
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+pub enum SecretSource {
+    /// The place specific to the provider, for example a custom header
+    #[serde(rename = "default")]
+    Default,
+
+    /// The standard Authorization header, with either the Bearer or the
+    /// Basic scheme
+    #[serde(rename = "authorization")]
+    Authorization,
+}
+
+impl Default for SecretSource {
+
+    fn default() -> Self {
+        SecretSource::Default
+    }
+}
+
+impl SecretSource {
+
+    /// Get the credentials from the Authorization header of the request, if
+    /// they're present and valid
+    pub fn authorization(req: &WebRequest) -> Option<Authorization> {
+        match req.headers.get("Authorization") {
+            Some(header) => Authorization::parse(header),
+            None => None,
+        }
+    }
+}
+
+
+/// Which requests providers should treat as pings, configured with the
+/// `ping_when` option
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum PingWhen {
+    /// The header has the provided value
+    Header { header: String, equals: String },
+
+    /// The field of the JSON body has the provided value. Nested fields are
+    /// separated by dots
+    Field { field: String, equals: String },
+}
+
+impl PingWhen {
+
+    /// Check if the request is a ping
+    pub fn matches(&self, req: &WebRequest) -> Result<bool> {
+        match *self {
+            PingWhen::Header { ref header, ref equals } => {
+                Ok(req.headers.get(header) == Some(equals))
+            },
+            PingWhen::Field { ref field, ref equals } => {
+                let json = match req.json_body() {
+                    Ok(json) => json,
+                    // Bodies which aren't JSON can't contain the field
+                    Err(..) => return Ok(false),
+                };
+
+                let pointer = format!("/{}", field.replace('.', "/"));
+                Ok(match json.pointer(&pointer) {
+                    Some(&serde_json::Value::String(ref value)) => {
+                        value == equals
+                    },
+                    Some(value) => value.to_string() == *equals,
+                    None => false,
+                })
+            },
+        }
+    }
+}
+
+
+/// What to do with the requests a provider failed to validate because of an
+/// error
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProviderErrorPolicy {
+    /// Reject the request, as if the provider marked it as invalid
+    Closed,
+
+    /// Accept the request, as if the provider marked it as valid
+    Open,
+}
+
+impl Default for ProviderErrorPolicy {
+
+    fn default() -> Self {
+        ProviderErrorPolicy::Closed
+    }
+}
+
+impl ProviderErrorPolicy {
+
+    /// Get the type of the requests the provider failed to validate
+    pub fn request_type(&self) -> RequestType {
+        match *self {
+            ProviderErrorPolicy::Closed => RequestType::Invalid,
+            ProviderErrorPolicy::Open => RequestType::ExecuteHook,
+        }
+    }
+}
+
+
 /// This trait should be implemented by every Fisher provider
 /// The objects implementing this trait must also implement Clone and Debug
 pub trait ProviderTrait: ::std::fmt::Debug {
@@ -53,10 +165,34 @@ pub trait ProviderTrait: ::std::fmt::Debug {
     /// type if the request is valid
     fn validate(&self, &Request) -> RequestType;
 
+    /// This method should validate an incoming request like `validate`, but
+    /// it can also report the errors which prevented the validation. By
+    /// default it calls `validate`
+    fn try_validate(&self, req: &Request) -> Result<RequestType> {
+        Ok(self.validate(req))
+    }
+
     /// This method should provide the environment variables of the provided
     /// request. Those variables will be passed to the process
     fn env(&self, &Request) -> HashMap<String, String>;
 
+    /// This method should provide the environment variables configured for
+    /// the hook. They're passed to the process without any prefix, and they
+    /// override every other variable, including the ones returned by `env`
+    /// and the ones set by Fisher. You're not required to implement this
+    /// method
+    fn override_env(&self, _req: &Request) -> HashMap<String, String> {
+        HashMap::new()
+    }
+
+    /// This method should return the directory the hook will be run from.
+    /// If it returns None the hook is run from the directory prepared with
+    /// `prepare_directory`, which is always its `$HOME`. You're not required
+    /// to implement this method
+    fn working_directory(&self, _req: &Request) -> Option<PathBuf> {
+        None
+    }
+
     /// This method should prepare the directory in which the hook will be run.
     /// This means, if you want to add extra files in there you should use
     /// this. You're not required to implement this method
@@ -71,6 +207,80 @@ pub trait ProviderTrait: ::std::fmt::Debug {
     fn trigger_status_hooks(&self, _req: &Request) -> bool {
         true
     }
+
+    /// This method should return a key identifying the request, if the same
+    /// request can be delivered more than once. A job isn't queued if
+    /// another one with the same key is already queued or running. You're
+    /// not required to implement this method
+    fn dedup_key(&self, _req: &Request) -> Option<String> {
+        None
+    }
+
+    /// This method should return true if the provider needs all the bytes
+    /// of the request body to validate it, for example to check its
+    /// signature. Those providers can't be used by the hooks which stream
+    /// the body to disk. You're not required to implement this method
+    fn needs_full_body(&self) -> bool {
+        false
+    }
+
+    /// This method should return the HTTP status sent to the client when
+    /// a job is queued, if it should be different from 200. Only 2xx
+    /// statuses should be returned. You're not required to implement this
+    /// method
+    fn accepted_status(&self) -> Option<u16> {
+        None
+    }
+}
+
+
+type Constructor = Fn(&str) -> Result<Box<ProviderTrait + Send + Sync>>
+                   + Send + Sync;
+
+
+/// This struct contains the custom providers available to the hooks, in
+/// addition to the built-in ones
+#[derive(Default)]
+pub struct ProviderRegistry {
+    constructors: HashMap<String, Box<Constructor>>,
+}
+
+impl ProviderRegistry {
+
+    /// Create a new, empty registry
+    pub fn new() -> Self {
+        ProviderRegistry {
+            constructors: HashMap::new(),
+        }
+    }
+
+    /// Register a custom provider with the given name. The constructor
+    /// receives the configuration string of the hook, and should return the
+    /// provider instance. Built-in providers take precedence over the custom
+    /// ones with the same name
+    pub fn register<F>(&mut self, name: &str, constructor: F)
+        where F: Fn(&str) -> Result<Box<ProviderTrait + Send + Sync>>
+                 + Send + Sync + 'static
+    {
+        let _ = self.constructors.insert(
+            name.to_string(), Box::new(constructor),
+        );
+    }
+
+    fn create(&self, name: &str, config: &str)
+              -> Option<Result<Box<ProviderTrait + Send + Sync>>> {
+        self.constructors.get(name).map(|constructor| constructor(config))
+    }
+}
+
+impl ::std::fmt::Debug for ProviderRegistry {
+
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let mut names = self.constructors.keys().collect::<Vec<_>>();
+        names.sort();
+
+        write!(f, "ProviderRegistry {{ providers: {:?} }}", names)
+    }
 }
 
 
@@ -83,11 +293,13 @@ macro_rules! ProviderEnum {
                 #[cfg($cfg)]
                 $name($provider),
             )*
+            Custom(String, Box<ProviderTrait + Send + Sync>),
         }
 
         impl Provider {
 
-            pub fn new(name: &str, config: &str) -> Result<Provider> {
+            pub fn new(name: &str, config: &str, registry: &ProviderRegistry)
+                       -> Result<Provider> {
                 match name {
                     $(
                         #[cfg($cfg)]
@@ -99,20 +311,28 @@ macro_rules! ProviderEnum {
                             }
                         },
                     )*
-                    _ => Err(
-                        ErrorKind::ProviderNotFound(name.to_string()).into()
-                    ),
+                    _ => match registry.create(name, config) {
+                        Some(Ok(prov)) => {
+                            Ok(Provider::Custom(name.to_string(), prov))
+                        },
+                        Some(Err(err)) => Err(err),
+                        None => Err(
+                            ErrorKind::ProviderNotFound(name.to_string())
+                                .into()
+                        ),
+                    },
                 }
             }
 
-            pub fn validate(&self, req: &Request) -> RequestType {
+            pub fn validate(&self, req: &Request) -> Result<RequestType> {
                 match *self {
                     $(
                         #[cfg($cfg)]
                         Provider::$name(ref prov) => {
-                            (prov as &ProviderTrait).validate(req)
+                            (prov as &ProviderTrait).try_validate(req)
                         },
                     )*
+                    Provider::Custom(_, ref prov) => prov.try_validate(req),
                 }
             }
 
@@ -124,6 +344,34 @@ macro_rules! ProviderEnum {
                             (prov as &ProviderTrait).env(req)
                         },
                     )*
+                    Provider::Custom(_, ref prov) => prov.env(req),
+                }
+            }
+
+            pub fn override_env(&self, req: &Request)
+                                -> HashMap<String, String> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).override_env(req)
+                        },
+                    )*
+                    Provider::Custom(_, ref prov) => prov.override_env(req),
+                }
+            }
+
+            pub fn working_directory(&self, req: &Request) -> Option<PathBuf> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).working_directory(req)
+                        },
+                    )*
+                    Provider::Custom(_, ref prov) => {
+                        prov.working_directory(req)
+                    },
                 }
             }
 
@@ -137,6 +385,9 @@ macro_rules! ProviderEnum {
                                 .prepare_directory(req, path)
                         },
                     )*
+                    Provider::Custom(_, ref prov) => {
+                        prov.prepare_directory(req, path)
+                    },
                 }
             }
 
@@ -148,6 +399,60 @@ macro_rules! ProviderEnum {
                             (prov as &ProviderTrait).trigger_status_hooks(req)
                         }
                     )*
+                    Provider::Custom(_, ref prov) => {
+                        prov.trigger_status_hooks(req)
+                    },
+                }
+            }
+
+            pub fn dedup_key(&self, req: &Request) -> Option<String> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).dedup_key(req)
+                        }
+                    )*
+                    Provider::Custom(_, ref prov) => prov.dedup_key(req),
+                }
+            }
+
+            pub fn needs_full_body(&self) -> bool {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).needs_full_body()
+                        }
+                    )*
+                    Provider::Custom(_, ref prov) => prov.needs_full_body(),
+                }
+            }
+
+            pub fn accepted_status(&self) -> Option<u16> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).accepted_status()
+                        }
+                    )*
+                    Provider::Custom(_, ref prov) => prov.accepted_status(),
+                }
+            }
+
+            /// Return the name of the built-in provider matching the given
+            /// one, ignoring its case.
+            pub fn builtin_name(name: &str) -> Option<&'static str> {
+                let name = name.to_lowercase();
+                match name.as_str() {
+                    $(
+                        #[cfg($cfg)]
+                        _ if stringify!($name).to_lowercase() == name => {
+                            Some(stringify!($name))
+                        },
+                    )*
+                    _ => None,
                 }
             }
 
@@ -157,6 +462,7 @@ macro_rules! ProviderEnum {
                         #[cfg($cfg)]
                         Provider::$name(..) => stringify!($name),
                     )*
+                    Provider::Custom(ref name, _) => name,
                 }
             }
         }
@@ -169,5 +475,9 @@ ProviderEnum! {
     any(test, not(test)) | Status => self::status::StatusProvider,
     feature="provider-github" | GitHub => self::github::GitHubProvider,
     feature="provider-gitlab" | GitLab => self::gitlab::GitLabProvider,
+    feature="provider-bitbucket" | Bitbucket =>
+        self::bitbucket::BitbucketProvider,
+    feature="provider-slack" | Slack => self::slack::SlackProvider,
+    feature="provider-hmac" | Hmac => self::hmac::HmacProvider,
     test | Testing => self::testing::TestingProvider
 }
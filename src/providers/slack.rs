@@ -0,0 +1,327 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+use url::form_urlencoded;
+
+use providers::prelude::*;
+use utils::verify_signature;
+use fisher_common::prelude::*;
+
+
+// Older requests are rejected, so captured requests can't be replayed
+const MAX_AGE: u64 = 5 * 60;
+
+// The form fields of the slash commands exported to the hooks
+const ENV_FIELDS: &'static [(&'static str, &'static str)] = &[
+    ("command", "COMMAND"),
+    ("text", "TEXT"),
+];
+
+
+// Parse the form fields from the body only, since the signature doesn't cover
+// the querystring merged in the params of the request
+fn signed_fields(body: &str) -> HashMap<String, String> {
+    form_urlencoded::parse(body.as_bytes()).into_owned().collect()
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct SlackProvider {
+    signing_secret: String,
+}
+
+impl SlackProvider {
+
+    fn validate_at(&self, request: &Request, now: u64)
+                   -> Result<RequestType> {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return Ok(RequestType::Invalid);
+        }
+
+        let raw_timestamp = req.headers.get("X-Slack-Request-Timestamp");
+        let raw_timestamp = match raw_timestamp {
+            Some(timestamp) => timestamp,
+            None => return Ok(RequestType::Invalid),
+        };
+        let timestamp = match raw_timestamp.parse::<u64>() {
+            Ok(timestamp) => timestamp,
+            Err(..) => return Ok(RequestType::Invalid),
+        };
+
+        // Reject the requests too far from the current time
+        let age = if now > timestamp {
+            now - timestamp
+        } else {
+            timestamp - now
+        };
+        if age > MAX_AGE {
+            return Ok(RequestType::Invalid);
+        }
+
+        // The signature is an HMAC-SHA256 of the version, the timestamp and
+        // the body, with the "v0=" prefix
+        let signature = match req.headers.get("X-Slack-Signature") {
+            Some(signature) if signature.starts_with("v0=") => {
+                format!("sha256={}", &signature[3..])
+            },
+            _ => return Ok(RequestType::Invalid),
+        };
+
+        let body = req.read_body()?;
+        let payload = format!("v0:{}:{}", raw_timestamp, body);
+        if ! verify_signature(&self.signing_secret, &payload, &signature) {
+            return Ok(RequestType::Invalid);
+        }
+
+        // Every slash command includes the command itself
+        if ! signed_fields(&body).contains_key("command") {
+            return Ok(RequestType::Invalid);
+        }
+
+        Ok(RequestType::ExecuteHook)
+    }
+}
+
+impl ProviderTrait for SlackProvider {
+
+    fn new(input: &str) -> Result<SlackProvider> {
+        let inst: SlackProvider = serde_json::from_str(input)?;
+
+        if inst.signing_secret.is_empty() {
+            return Err(ErrorKind::InvalidInput(
+                "the signing secret can't be empty".into()
+            ).into());
+        }
+
+        Ok(inst)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        self.try_validate(request).unwrap_or(RequestType::Invalid)
+    }
+
+    fn try_validate(&self, request: &Request) -> Result<RequestType> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+
+        self.validate_at(request, now)
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return HashMap::new();
+        }
+
+        let fields = match req.read_body() {
+            Ok(body) => signed_fields(&body),
+            Err(..) => return HashMap::new(),
+        };
+
+        let mut res = HashMap::new();
+        for &(field, name) in ENV_FIELDS {
+            if let Some(value) = fields.get(field) {
+                res.insert(name.to_string(), value.clone());
+            }
+        }
+
+        res
+    }
+
+    fn needs_full_body(&self) -> bool {
+        // The signature covers the whole body, which is also a form
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::{Request, RequestType};
+    use providers::ProviderTrait;
+
+    use super::SlackProvider;
+
+
+    const NOW: u64 = 1500000000;
+    const BODY: &'static str = "command=%2Fdeploy&text=prod";
+    const SIGNATURE: &'static str = "v0=1087dd6bbb6151f35f9b9004e34e8a2a\
+                                     da5b4d6142b47820d88afa3213ecc4f1";
+
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        request_with_body(headers, BODY)
+    }
+
+
+    fn request_with_body(headers: &[(&str, &str)], body: &str) -> Request {
+        let mut request = dummy_web_request();
+        for &(name, value) in headers {
+            request.headers.insert(name.into(), value.into());
+        }
+        request.body = body.into();
+
+        // The form fields are added to the params when the body is read
+        request.params.insert("command".into(), "/deploy".into());
+        request.params.insert("text".into(), "prod".into());
+        request.into()
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"signing_secret": "abcde"}"#,
+        ] {
+            assert!(SlackProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"signing_secret": ""}"#,
+            r#"{"signing_secret": 12345}"#,
+        ] {
+            assert!(SlackProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_signature() {
+        let provider = SlackProvider::new(
+            r#"{"signing_secret": "secret"}"#
+        ).unwrap();
+        let timestamp = NOW.to_string();
+
+        assert_eq!(provider.validate_at(&request(&[
+            ("X-Slack-Request-Timestamp", &timestamp),
+            ("X-Slack-Signature", SIGNATURE),
+        ]), NOW).unwrap(), RequestType::ExecuteHook);
+
+        // Both the headers are required
+        assert_eq!(provider.validate_at(&request(&[
+            ("X-Slack-Signature", SIGNATURE),
+        ]), NOW).unwrap(), RequestType::Invalid);
+        assert_eq!(provider.validate_at(&request(&[
+            ("X-Slack-Request-Timestamp", &timestamp),
+        ]), NOW).unwrap(), RequestType::Invalid);
+
+        // The signature must match the timestamp and the body
+        for signature in &["v0=00", &SIGNATURE[3..], "sha256=00"] {
+            assert_eq!(provider.validate_at(&request(&[
+                ("X-Slack-Request-Timestamp", &timestamp),
+                ("X-Slack-Signature", *signature),
+            ]), NOW).unwrap(), RequestType::Invalid);
+        }
+        assert_eq!(provider.validate_at(&request(&[
+            ("X-Slack-Request-Timestamp", &(NOW + 1).to_string()),
+            ("X-Slack-Signature", SIGNATURE),
+        ]), NOW + 1).unwrap(), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_replay() {
+        let provider = SlackProvider::new(
+            r#"{"signing_secret": "secret"}"#
+        ).unwrap();
+        let req = request(&[
+            ("X-Slack-Request-Timestamp", &NOW.to_string()),
+            ("X-Slack-Signature", SIGNATURE),
+        ]);
+
+        // Requests are accepted only within five minutes of the timestamp
+        for &(now, expected) in &[
+            (NOW + 300, RequestType::ExecuteHook),
+            (NOW - 300, RequestType::ExecuteHook),
+            (NOW + 301, RequestType::Invalid),
+            (NOW - 301, RequestType::Invalid),
+        ] {
+            assert_eq!(provider.validate_at(&req, now).unwrap(), expected);
+        }
+
+        // The current time is used outside of the tests
+        assert_eq!(provider.validate(&req), RequestType::Invalid);
+    }
+
+
+    #[test]
+    fn test_env() {
+        let provider = SlackProvider::new(
+            r#"{"signing_secret": "secret"}"#
+        ).unwrap();
+
+        let env = provider.env(&request(&[]));
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["COMMAND"], "/deploy");
+        assert_eq!(env["TEXT"], "prod");
+
+        // Missing fields aren't exported
+        let env = provider.env(&dummy_web_request().into());
+        assert_eq!(env.len(), 0);
+    }
+
+
+    #[test]
+    fn test_query_params_ignored() {
+        let provider = SlackProvider::new(
+            r#"{"signing_secret": "secret"}"#
+        ).unwrap();
+        let timestamp = NOW.to_string();
+
+        // The querystring overrides the form fields in the params, but it
+        // isn't signed, so the provider must use the body only
+        let req = match request(&[]) {
+            Request::Web(mut req) => {
+                req.params.insert("command".into(), "/destroy".into());
+                req.params.insert("text".into(), "everything".into());
+                Request::Web(req)
+            },
+            _ => unreachable!(),
+        };
+        let env = provider.env(&req);
+        assert_eq!(env["COMMAND"], "/deploy");
+        assert_eq!(env["TEXT"], "prod");
+
+        // The command can't be added with the querystring
+        let mut req = request_with_body(&[
+            ("X-Slack-Request-Timestamp", &timestamp),
+            ("X-Slack-Signature", "v0=609ef36ef74d70326f84bf752b73401151f2\
+                                   75ac31c22ab36090f33769640214"),
+        ], "text=prod");
+        assert_eq!(
+            provider.validate_at(&req, NOW).unwrap(), RequestType::Invalid
+        );
+
+        // While the same body with the command is accepted
+        if let Request::Web(ref mut inner) = req {
+            inner.body = BODY.into();
+            inner.headers.insert("X-Slack-Signature".into(), SIGNATURE.into());
+        }
+        assert_eq!(
+            provider.validate_at(&req, NOW).unwrap(), RequestType::ExecuteHook
+        );
+    }
+}
@@ -16,6 +16,7 @@
 use serde_json;
 
 use providers::prelude::*;
+use fisher_common::auth::constant_time_eq;
 
 
 #[derive(Debug, Deserialize)]
@@ -24,6 +25,17 @@ pub struct StandaloneProvider {
 
     param_name: Option<String>,
     header_name: Option<String>,
+
+    #[serde(default)]
+    secret_source: SecretSource,
+
+    ping_when: Option<PingWhen>,
+
+    cwd: Option<PathBuf>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+
+    accepted_status: Option<u16>,
 }
 
 impl StandaloneProvider {
@@ -47,41 +59,114 @@ impl ProviderTrait for StandaloneProvider {
 
     fn new(config: &str) -> Result<Self> {
         // Check if it's possible to create a new instance and return it
-        let inst = serde_json::from_str(config)?;
+        let inst: StandaloneProvider = serde_json::from_str(config)?;
+
+        // Relative paths would depend on where Fisher was started from
+        if let Some(ref cwd) = inst.cwd {
+            if cwd.is_relative() {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "the working directory must be absolute: {}",
+                    cwd.display(),
+                )).into());
+            }
+        }
+
+        // The clients should see the request as successful
+        if let Some(status) = inst.accepted_status {
+            if status < 200 || status > 299 {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "the accepted status must be a 2xx status: {}", status,
+                )).into());
+            }
+        }
+
         Ok(inst)
     }
 
     fn validate(&self, request: &Request) -> RequestType {
+        self.try_validate(request).unwrap_or(RequestType::Invalid)
+    }
+
+    fn try_validate(&self, request: &Request) -> Result<RequestType> {
         let req;
         if let Request::Web(ref inner) = *request {
             req = inner;
         } else {
-            return RequestType::Invalid;
+            return Ok(RequestType::Invalid);
         }
 
         // First of all check the secret code
         let secret;
-        if let Some(found) = req.params.get(&self.param_name()) {
+        if self.secret_source == SecretSource::Authorization {
+            if let Some(auth) = SecretSource::authorization(req) {
+                // Secret in the Authorization header
+                secret = auth.secret().to_string();
+            } else {
+                return Ok(RequestType::Invalid);
+            }
+        } else if let Some(found) = req.params.get(&self.param_name()) {
             // Secret in the request parameters
-            secret = found;
+            secret = found.clone();
         } else if let Some(found) = req.headers.get(&self.header_name()) {
             // Secret in the HTTP headers
-            secret = found;
+            secret = found.clone();
         } else {
             // No secret present, abort!
-            return RequestType::Invalid;
+            return Ok(RequestType::Invalid);
         }
 
         // Abort if the secret doesn't match
-        if *secret != self.secret {
-            return RequestType::Invalid;
+        if ! constant_time_eq(secret.as_bytes(), self.secret.as_bytes()) {
+            return Ok(RequestType::Invalid);
+        }
+
+        if let Some(ref ping_when) = self.ping_when {
+            if ping_when.matches(req)? {
+                return Ok(RequestType::Ping);
+            }
+        }
+
+        Ok(RequestType::ExecuteHook)
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        let mut res = HashMap::new();
+
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return res;
+        }
+
+        // Export the query params, except for the secret one
+        let param_name = self.param_name();
+        for (name, value) in &req.params {
+            if *name == param_name {
+                continue;
+            }
+
+            // Only ASCII letters and numbers are kept in the names, and the
+            // other characters are replaced with underscores
+            let name = name.to_uppercase().chars().map(|chr| {
+                if chr.is_digit(36) { chr } else { '_' }
+            }).collect::<String>();
+            res.insert(format!("PARAM_{}", name), value.clone());
         }
 
-        RequestType::ExecuteHook
+        res
     }
 
-    fn env(&self, _req: &Request) -> HashMap<String, String> {
-        HashMap::new()
+    fn override_env(&self, _req: &Request) -> HashMap<String, String> {
+        self.env.clone()
+    }
+
+    fn working_directory(&self, _req: &Request) -> Option<PathBuf> {
+        self.cwd.clone()
+    }
+
+    fn accepted_status(&self) -> Option<u16> {
+        self.accepted_status
     }
 }
 
@@ -93,6 +178,7 @@ mod tests {
     use utils::testing::*;
     use requests::RequestType;
     use providers::ProviderTrait;
+    use web::WebRequest;
 
     use super::StandaloneProvider;
 
@@ -105,6 +191,12 @@ mod tests {
             r#"{"secret": "abcde", "param_name": "a"}"#,
             r#"{"secret": "abcde", "header_name": "X-b"}"#,
             r#"{"secret": "abcde", "param_name": "a", "header_name": "b"}"#,
+            r#"{"secret": "abcde", "secret_source": "default"}"#,
+            r#"{"secret": "abcde", "secret_source": "authorization"}"#,
+            r#"{"secret": "abcde", "cwd": "/srv/app"}"#,
+            r#"{"secret": "abcde", "env": {"RAILS_ENV": "production"}}"#,
+            r#"{"secret": "abcde", "accepted_status": 202}"#,
+            r#"{"secret": "abcde", "accepted_status": 200}"#,
         ];
         for one in &right {
             assert!(StandaloneProvider::new(one).is_ok());
@@ -122,6 +214,18 @@ mod tests {
             r#"{"secret": true}"#,
             r#"{"secret": ["a", "b"]}"#,
             r#"{"secret": {"a": "b"}}"#,
+            r#"{"secret": "abcde", "secret_source": "body"}"#,
+            r#"{"secret": "abcde", "secret_source": true}"#,
+            r#"{"secret": "abcde", "env": {"RAILS_ENV": 1}}"#,
+
+            // Relative working directories
+            r#"{"secret": "abcde", "cwd": "srv/app"}"#,
+
+            // Statuses which aren't successful
+            r#"{"secret": "abcde", "accepted_status": 302}"#,
+            r#"{"secret": "abcde", "accepted_status": 500}"#,
+            r#"{"secret": "abcde", "accepted_status": 199}"#,
+            r#"{"secret": "abcde", "accepted_status": "202"}"#,
         ];
         for one in &wrong {
             assert!(StandaloneProvider::new(one).is_err());
@@ -172,12 +276,103 @@ mod tests {
         assert_eq!(p.validate(&req.into()), RequestType::ExecuteHook);
     }
 
+    #[test]
+    fn test_validate_authorization() {
+        let config = r#"{"secret": "abcde", "secret_source": "authorization"}"#;
+        let p = StandaloneProvider::new(config).unwrap();
+
+        fn with_auth(header: &str) -> WebRequest {
+            let mut req = dummy_web_request();
+            req.headers.insert("Authorization".into(), header.into());
+            req
+        }
+
+        // Test with the bearer and the basic schemes ("user:abcde")
+        assert_eq!(
+            p.validate(&with_auth("Bearer abcde").into()),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            p.validate(&with_auth("Basic dXNlcjphYmNkZQ==").into()),
+            RequestType::ExecuteHook
+        );
+
+        // Test with a wrong secret and a malformed header
+        assert_eq!(
+            p.validate(&with_auth("Bearer 12345").into()),
+            RequestType::Invalid
+        );
+        assert_eq!(
+            p.validate(&with_auth("abcde").into()),
+            RequestType::Invalid
+        );
+
+        // The other places shouldn't be checked anymore
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "abcde".into());
+        req.headers.insert("X-Fisher-Secret".into(), "abcde".into());
+        assert_eq!(p.validate(&req.into()), RequestType::Invalid);
+    }
+
+    #[test]
+    fn test_validate_ping_when() {
+        // Pings detected from a header
+        let p = StandaloneProvider::new(concat!(
+            r#"{"secret": "abcde", "ping_when": "#,
+            r#"{"header": "X-Event", "equals": "ping"}}"#
+        )).unwrap();
+
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "abcde".into());
+        assert_eq!(p.validate(&req.clone().into()), RequestType::ExecuteHook);
+
+        req.headers.insert("X-Event".into(), "ping".into());
+        assert_eq!(p.validate(&req.clone().into()), RequestType::Ping);
+
+        // The secret is still required for pings
+        req.params.insert("secret".into(), "12345".into());
+        assert_eq!(p.validate(&req.into()), RequestType::Invalid);
+
+        // Pings detected from a field of the body
+        let p = StandaloneProvider::new(concat!(
+            r#"{"secret": "abcde", "ping_when": "#,
+            r#"{"field": "hook.test", "equals": "true"}}"#
+        )).unwrap();
+
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "abcde".into());
+        req.body = r#"{"hook": {"test": true}}"#.into();
+        assert_eq!(p.validate(&req.clone().into()), RequestType::Ping);
+
+        req.body = r#"{"hook": {"test": false}}"#.into();
+        assert_eq!(p.validate(&req.clone().into()), RequestType::ExecuteHook);
+
+        req.body = "not json".into();
+        assert_eq!(p.validate(&req.into()), RequestType::ExecuteHook);
+
+        // Incomplete conditions are rejected
+        assert!(StandaloneProvider::new(
+            r#"{"secret": "abcde", "ping_when": {"header": "X-Event"}}"#
+        ).is_err());
+    }
+
     #[test]
     fn test_env() {
         let p = StandaloneProvider::new(r#"{"secret": "abcde"}"#).unwrap();
 
-        // The environment must always be empty
+        // The environment is empty without query params
         assert!(p.env(&dummy_web_request().into()) == HashMap::new());
+
+        // The query params are exported, except for the secret
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "abcde".into());
+        req.params.insert("ref".into(), "master".into());
+        req.params.insert("dry-run".into(), "1".into());
+
+        let mut expected = HashMap::new();
+        expected.insert("PARAM_REF".to_string(), "master".to_string());
+        expected.insert("PARAM_DRY_RUN".to_string(), "1".to_string());
+        assert_eq!(p.env(&req.into()), expected);
     }
 
 }
@@ -24,6 +24,11 @@ use providers::prelude::*;
 use jobs::JobOutput;
 
 
+// The environment can't hold much data, so only the end of the output of
+// the job is put there
+const ENV_OUTPUT_SIZE: usize = 16 * 1024;
+
+
 #[derive(Debug, Clone)]
 pub enum StatusEvent {
     JobCompleted(JobOutput),
@@ -55,6 +60,29 @@ impl StatusEvent {
             StatusEvent::JobFailed(ref output) => output.request_ip,
         }
     }
+
+    #[inline]
+    pub fn output(&self) -> &JobOutput {
+        match *self {
+            StatusEvent::JobCompleted(ref output) |
+            StatusEvent::JobFailed(ref output) => output,
+        }
+    }
+}
+
+
+fn env_output(output: &str) -> String {
+    // Null bytes can't be part of environment variables
+    let output = output.replace('\0', "");
+    if output.len() <= ENV_OUTPUT_SIZE {
+        return output;
+    }
+
+    let mut start = output.len() - ENV_OUTPUT_SIZE;
+    while ! output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[output truncated]\n{}", &output[start..])
 }
 
 
@@ -77,10 +105,67 @@ impl StatusEventKind {
 }
 
 
+/// The exit codes of the jobs a status hook is interested in.
+#[derive(Debug, PartialEq, Eq)]
+struct ExitCodes {
+    ranges: Vec<(i32, i32)>,
+    signal: bool,
+}
+
+impl ExitCodes {
+
+    /// Parse a comma-separated list of exit codes and ranges of them, like
+    /// `1,3-5`. `signal` matches the jobs killed by a signal.
+    fn parse(spec: &str) -> Result<Self> {
+        let mut result = ExitCodes {
+            ranges: Vec::new(),
+            signal: false,
+        };
+
+        for part in spec.split(',').map(|part| part.trim()) {
+            if part == "signal" {
+                result.signal = true;
+                continue;
+            }
+
+            let mut bounds = part.splitn(2, '-');
+            let start = bounds.next().unwrap().trim().parse::<i32>()?;
+            let end = match bounds.next() {
+                Some(end) => end.trim().parse::<i32>()?,
+                None => start,
+            };
+            if start > end {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "the range of exit codes {} is empty", part,
+                )).into());
+            }
+
+            result.ranges.push((start, end));
+        }
+
+        Ok(result)
+    }
+
+    fn matches(&self, output: &JobOutput) -> bool {
+        match output.exit_code {
+            Some(code) => self.ranges.iter().any(|&(start, end)| {
+                start <= code && code <= end
+            }),
+            None => self.signal && output.signal.is_some(),
+        }
+    }
+}
+
+
 #[derive(Debug, Deserialize)]
 pub struct StatusProvider {
     events: Vec<StatusEventKind>,
     hooks: Option<Vec<String>>,
+    #[serde(rename = "exit_codes")]
+    exit_codes_spec: Option<String>,
+
+    #[serde(skip_deserializing)]
+    exit_codes: Option<ExitCodes>,
 }
 
 impl StatusProvider {
@@ -106,7 +191,13 @@ impl StatusProvider {
 impl ProviderTrait for StatusProvider {
 
     fn new(config: &str) -> Result<Self> {
-        Ok(serde_json::from_str(config)?)
+        let mut inst: StatusProvider = serde_json::from_str(config)?;
+
+        if let Some(ref spec) = inst.exit_codes_spec {
+            inst.exit_codes = Some(ExitCodes::parse(spec)?);
+        }
+
+        Ok(inst)
     }
 
     fn validate(&self, request: &Request) -> RequestType {
@@ -127,6 +218,13 @@ impl ProviderTrait for StatusProvider {
             return RequestType::Invalid;
         }
 
+        // The exit code must be allowed
+        if let Some(ref exit_codes) = self.exit_codes {
+            if ! exit_codes.matches(req.output()) {
+                return RequestType::Invalid;
+            }
+        }
+
         RequestType::ExecuteHook
     }
 
@@ -149,6 +247,7 @@ impl ProviderTrait for StatusProvider {
                 env.insert("SUCCESS".into(), "1".into());
                 env.insert("EXIT_CODE".into(), "0".into());
                 env.insert("SIGNAL".into(), String::new());
+                env.insert("TIMED_OUT".into(), "0".into());
             },
             StatusEvent::JobFailed(ref output) => {
                 env.insert("SUCCESS".into(), "0".into());
@@ -164,9 +263,16 @@ impl ProviderTrait for StatusProvider {
                         format!("{}", signal)
                     } else { String::new() }
                 );
+                env.insert(
+                    "TIMED_OUT".into(),
+                    if output.timed_out { "1" } else { "0" }.into(),
+                );
             },
         }
 
+        env.insert("STDOUT".into(), env_output(&req.output().stdout));
+        env.insert("STDERR".into(), env_output(&req.output().stderr));
+
         env
     }
 
@@ -215,7 +321,7 @@ mod tests {
     use requests::RequestType;
     use providers::ProviderTrait;
 
-    use super::{StatusEvent, StatusProvider};
+    use super::{StatusEvent, StatusProvider, env_output, ENV_OUTPUT_SIZE};
 
 
     #[test]
@@ -225,6 +331,8 @@ mod tests {
                 let provider = StatusProvider {
                     hooks: $hooks,
                     events: vec![],
+                    exit_codes_spec: None,
+                    exit_codes: None,
                 };
                 assert_eq!(
                     provider.hook_allowed(&$check.to_string()),
@@ -248,6 +356,9 @@ mod tests {
             r#"{"events": ["job_completed", "job_failed"]}"#,
             r#"{"events": [], "hooks": []}"#,
             r#"{"events": [], "hooks": ["abc"]}"#,
+            r#"{"events": [], "exit_codes": "1"}"#,
+            r#"{"events": [], "exit_codes": "1-255, signal"}"#,
+            r#"{"events": [], "exit_codes": "1,3-5,5-5"}"#,
         ] {
             assert!(StatusProvider::new(&right).is_ok());
         }
@@ -266,6 +377,11 @@ mod tests {
             r#"{"events": [true]}"#,
             r#"{"events": ["invalid_event"]}"#,
             r#"{"events": ["job_completed", "invalid_event"]}"#,
+            r#"{"events": [], "exit_codes": 1}"#,
+            r#"{"events": [], "exit_codes": ""}"#,
+            r#"{"events": [], "exit_codes": "a"}"#,
+            r#"{"events": [], "exit_codes": "1-"}"#,
+            r#"{"events": [], "exit_codes": "5-3"}"#,
         ] {
             assert!(StatusProvider::new(&wrong).is_err());
         }
@@ -304,6 +420,30 @@ mod tests {
             r#"{"events": ["job_completed"], "hooks": ["test"]}"#,
             RequestType::ExecuteHook
         );
+
+        // Test with the exit codes
+        let failed = |code, signal| {
+            let mut output = dummy_job_output();
+            output.success = false;
+            output.exit_code = code;
+            output.signal = signal;
+            StatusEvent::JobFailed(output).into()
+        };
+        let config = r#"{"events": ["job_failed"], "exit_codes": "1,3-5"}"#;
+        for &(code, expect) in &[
+            (0, RequestType::Invalid), (1, RequestType::ExecuteHook),
+            (2, RequestType::Invalid), (3, RequestType::ExecuteHook),
+            (5, RequestType::ExecuteHook), (6, RequestType::Invalid),
+        ] {
+            assert_validate!(&failed(Some(code), None), config, expect);
+        }
+        assert_validate!(&failed(None, Some(9)), config, RequestType::Invalid);
+
+        // Test with the signals
+        assert_validate!(&failed(None, Some(9)),
+            r#"{"events": ["job_failed"], "exit_codes": "1, signal"}"#,
+            RequestType::ExecuteHook
+        );
     }
 
 
@@ -316,26 +456,47 @@ mod tests {
         // Try with a job_completed event
         let event = StatusEvent::JobCompleted(dummy_job_output());
         let env = provider.env(&event.into());
-        assert_eq!(env.len(), 5);
+        assert_eq!(env.len(), 8);
         assert_eq!(env.get("EVENT").unwrap(), &"job_completed".to_string());
         assert_eq!(env.get("HOOK_NAME").unwrap(), &"test".to_string());
         assert_eq!(env.get("SUCCESS").unwrap(), &"1".to_string());
         assert_eq!(env.get("EXIT_CODE").unwrap(), &"0".to_string());
         assert_eq!(env.get("SIGNAL").unwrap(), &"".to_string());
+        assert_eq!(env.get("TIMED_OUT").unwrap(), &"0".to_string());
 
         // Try with a job_failed event
         let mut output = dummy_job_output();
         output.success = false;
         output.exit_code = None;
         output.signal = Some(9);
+        output.timed_out = true;
 
         let env = provider.env(&StatusEvent::JobFailed(output).into());
-        assert_eq!(env.len(), 5);
+        assert_eq!(env.len(), 8);
         assert_eq!(env.get("EVENT").unwrap(), &"job_failed".to_string());
         assert_eq!(env.get("HOOK_NAME").unwrap(), &"test".to_string());
         assert_eq!(env.get("SUCCESS").unwrap(), &"0".to_string());
         assert_eq!(env.get("EXIT_CODE").unwrap(), &"".to_string());
         assert_eq!(env.get("SIGNAL").unwrap(), &"9".to_string());
+        assert_eq!(env.get("TIMED_OUT").unwrap(), &"1".to_string());
+        assert_eq!(env.get("STDOUT").unwrap(), &"hello world".to_string());
+        assert_eq!(
+            env.get("STDERR").unwrap(), &"something happened".to_string()
+        );
+    }
+
+    #[test]
+    fn test_env_output() {
+        assert_eq!(env_output("a\0b"), "ab".to_string());
+
+        // Only the end of long outputs is kept
+        let long = format!(
+            "{}{}", "x".repeat(10), "é".repeat(ENV_OUTPUT_SIZE),
+        );
+        let truncated = env_output(&long);
+        assert!(truncated.starts_with("[output truncated]\né"));
+        assert!(! truncated.contains('x'));
+        assert!(truncated.len() <= ENV_OUTPUT_SIZE + 19);
     }
 
     #[test]
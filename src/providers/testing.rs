@@ -20,6 +20,7 @@ use std::io::Write;
 
 use providers::prelude::*;
 use fisher_common::prelude::*;
+use fisher_common::auth::constant_time_eq;
 
 
 #[derive(Debug)]
@@ -51,7 +52,7 @@ impl ProviderTrait for TestingProvider {
 
         // If the secret param is provided, validate it
         if let Some(secret) = req.params.get("secret") {
-            if secret != "testing" {
+            if ! constant_time_eq(secret.as_bytes(), b"testing") {
                 return RequestType::Invalid;
             }
         }
@@ -77,6 +78,17 @@ impl ProviderTrait for TestingProvider {
         RequestType::ExecuteHook
     }
 
+    fn try_validate(&self, request: &Request) -> Result<RequestType> {
+        // Allow to simulate an error during the validation
+        if let &Request::Web(ref inner) = request {
+            if inner.params.contains_key("provider_error") {
+                return Err(ErrorKind::Dummy.into());
+            }
+        }
+
+        Ok(self.validate(request))
+    }
+
     fn env(&self, request: &Request) -> HashMap<String, String> {
         let mut res = HashMap::new();
 
@@ -0,0 +1,493 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use fisher_common::prelude::*;
+use fisher_common::state::UniqueId;
+
+use utils::run_id;
+use web::WebRequest;
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredJob {
+    job_id: String,
+    hook: String,
+    queued_at: u64,
+    unvalidated: bool,
+    path_params: HashMap<String, String>,
+
+    source: IpAddr,
+    // Missing from the jobs stored by older versions
+    #[serde(default)]
+    method: String,
+    #[serde(default)]
+    url: String,
+    headers: HashMap<String, String>,
+    params: HashMap<String, String>,
+    body: Option<String>,
+    body_file: Option<String>,
+}
+
+
+/// A job which was still queued when Fisher stopped.
+#[derive(Debug)]
+pub struct PendingJob {
+    pub hook: String,
+    pub unvalidated: bool,
+    pub path_params: HashMap<String, String>,
+    pub request: WebRequest,
+    stored_id: String,
+}
+
+impl PendingJob {
+
+    /// Return the ID the job was stored with. The job keeps its stored copy
+    /// when it's queued again, so it needs to be forgotten with this ID.
+    pub fn stored_id(&self) -> &str {
+        &self.stored_id
+    }
+}
+
+
+enum Operation {
+    Write {
+        job_id: UniqueId,
+        hook: String,
+        request: WebRequest,
+        path_params: HashMap<String, String>,
+        unvalidated: bool,
+    },
+    Remove {
+        stored_id: String,
+        hook: String,
+    },
+}
+
+
+/// Stores the queued jobs from a background thread, so the processor doesn't
+/// wait for the disk. Dropping the store waits for the pending operations to
+/// be completed.
+#[derive(Debug)]
+pub struct QueueStore {
+    dir: PathBuf,
+    operations: Option<Mutex<mpsc::Sender<Operation>>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl QueueStore {
+
+    pub fn new(dir: PathBuf) -> Self {
+        let (operations_send, operations_recv) = mpsc::channel();
+
+        let worker_dir = dir.clone();
+        let worker = thread::spawn(move || {
+            for operation in operations_recv.iter() {
+                let (hook, result) = match operation {
+                    Operation::Write {
+                        job_id, hook, request, path_params, unvalidated,
+                    } => {
+                        let result = write(
+                            &worker_dir, job_id, &hook, &request,
+                            &path_params, unvalidated,
+                        ).map(|_| ());
+                        (hook, result)
+                    },
+                    Operation::Remove { stored_id, hook } => {
+                        let result = remove_stored(&worker_dir, &stored_id);
+                        (hook, result)
+                    },
+                };
+
+                // The job is still executed even if it couldn't be stored,
+                // it just won't survive a restart
+                if let Err(mut error) = result {
+                    error.set_location(ErrorLocation::HookProcessing(hook));
+                    error.pretty_print();
+                }
+            }
+        });
+
+        QueueStore {
+            dir: dir,
+            operations: Some(Mutex::new(operations_send)),
+            worker: Some(worker),
+        }
+    }
+
+    /// Load the jobs left in the store when Fisher stopped.
+    pub fn restore(&self) -> Result<Vec<PendingJob>> {
+        restore(&self.dir)
+    }
+
+    /// Store a queued job of the hook. The restored jobs already have their
+    /// copy, and they shouldn't be stored again.
+    pub fn store(&self, job_id: UniqueId, hook: &str, req: &WebRequest,
+                 path_params: &HashMap<String, String>, unvalidated: bool) {
+        self.send(Operation::Write {
+            job_id: job_id,
+            hook: hook.to_string(),
+            request: req.clone(),
+            path_params: path_params.clone(),
+            unvalidated: unvalidated,
+        });
+    }
+
+    /// Forget a job of the hook which doesn't need to be queued again.
+    pub fn forget(&self, stored_id: &str, hook: &str) {
+        self.send(Operation::Remove {
+            stored_id: stored_id.to_string(),
+            hook: hook.to_string(),
+        });
+    }
+
+    fn send(&self, operation: Operation) {
+        if let Some(ref operations) = self.operations {
+            // The worker only stops when the store is dropped
+            let _ = operations.lock().unwrap().send(operation);
+        }
+    }
+}
+
+impl Drop for QueueStore {
+
+    fn drop(&mut self) {
+        // Closing the channel stops the worker once it's done
+        self.operations.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+
+/// Return the ID a job is stored with. The IDs of the jobs are reused after
+/// a restart, so the ID of the current run is included, otherwise a new job
+/// could replace one which wasn't restored yet.
+pub fn stored_id(job_id: UniqueId) -> String {
+    format!("{}-{}", run_id(), job_id)
+}
+
+
+/// Store a queued job, so it can be queued again if Fisher stops before
+/// executing it. Jobs which weren't validated by the providers of their
+/// hook are not validated again.
+pub fn write(dir: &Path, job_id: UniqueId, hook: &str, req: &WebRequest,
+             path_params: &HashMap<String, String>, unvalidated: bool)
+             -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let stored_id = stored_id(job_id);
+
+    let mut job = StoredJob {
+        job_id: job_id.to_string(),
+        hook: hook.to_string(),
+        queued_at: SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0),
+        unvalidated: unvalidated,
+        path_params: path_params.clone(),
+
+        source: req.source,
+        method: req.method.clone(),
+        url: req.url.clone(),
+        headers: req.headers.clone(),
+        params: req.params.clone(),
+        body: None,
+        body_file: None,
+    };
+
+    // Big bodies are stored on disk, so they're copied alongside the job
+    if let Some(ref body_file) = req.body_file {
+        let body_name = format!("{}.body", stored_id);
+        fs::copy(body_file.path(), dir.join(&body_name))?;
+        job.body_file = Some(body_name);
+    } else {
+        job.body = Some(req.body.clone());
+    }
+
+    // The job is written to a temporary file first, so a crash while writing
+    // doesn't leave a broken job behind
+    let path = dir.join(format!("{}.json", stored_id));
+    let tmp_path = dir.join(format!("{}.json.tmp", stored_id));
+    fs::File::create(&tmp_path)?.write_all(
+        serde_json::to_string(&job)?.as_bytes()
+    )?;
+    fs::rename(&tmp_path, &path)?;
+
+    Ok(path)
+}
+
+
+/// Forget a job which doesn't need to be queued again.
+pub fn remove(dir: &Path, job_id: UniqueId) -> Result<()> {
+    remove_stored(dir, &stored_id(job_id))
+}
+
+
+fn remove_stored(dir: &Path, stored_id: &str) -> Result<()> {
+    remove_if_exists(&dir.join(format!("{}.json", stored_id)))?;
+    remove_if_exists(&dir.join(format!("{}.body", stored_id)))?;
+    Ok(())
+}
+
+
+fn load(dir: &Path, path: &Path) -> Result<(StoredJob, String, String)> {
+    let mut content = String::new();
+    fs::File::open(path)?.read_to_string(&mut content)?;
+    let job: StoredJob = serde_json::from_str(&content)?;
+
+    let body = if let Some(ref body_file) = job.body_file {
+        let mut body = String::new();
+        fs::File::open(dir.join(body_file))?.read_to_string(&mut body)?;
+        body
+    } else {
+        job.body.clone().unwrap_or_else(String::new)
+    };
+
+    // The name of the file is the ID the job was stored with
+    let stored_id = path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("")
+        .to_string();
+
+    Ok((job, body, stored_id))
+}
+
+
+/// Load all the stored jobs, in the order they were queued. The jobs are
+/// left in the directory, so they're not lost if Fisher stops before queueing
+/// them again: the caller is expected to replace or forget each one of them.
+/// Broken jobs are skipped.
+pub fn restore(dir: &Path) -> Result<Vec<PendingJob>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        },
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut stored = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => {},
+            // Leftovers of a write interrupted by a crash
+            Some("tmp") => {
+                remove_if_exists(&path)?;
+                continue;
+            },
+            Some("body") if ! path.with_extension("json").exists() => {
+                remove_if_exists(&path)?;
+                continue;
+            },
+            _ => continue,
+        }
+
+        match load(dir, &path) {
+            Ok(loaded) => stored.push(loaded),
+            Err(mut error) => {
+                // A broken job shouldn't prevent the others from being
+                // restored, and it's kept around to be inspected
+                error.set_location(ErrorLocation::File(
+                    path.to_string_lossy().into(), None,
+                ));
+                error.pretty_print();
+            },
+        }
+    }
+
+    // Job IDs only grow while Fisher is running, so they're the tie-breaker
+    // between the jobs queued in the same second
+    stored.sort_by_key(|&(ref job, _, _)| {
+        (job.queued_at, job.job_id.parse::<u64>().unwrap_or(0))
+    });
+
+    Ok(stored.into_iter().map(|(job, body, stored_id)| PendingJob {
+        stored_id: stored_id,
+        hook: job.hook,
+        unvalidated: job.unvalidated,
+        path_params: job.path_params,
+        request: WebRequest {
+            source: job.source,
+            method: job.method,
+            url: job.url,
+            headers: job.headers,
+            params: job.params,
+            body: body,
+            body_file: None,
+        },
+    }).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::Write;
+
+    use fisher_common::state::{IdKind, State};
+
+    use utils::testing::*;
+    use utils;
+
+    use super::{remove, restore, stored_id, write, QueueStore};
+
+
+    #[test]
+    fn test_write_and_restore() {
+        let dir = utils::create_temp_dir().unwrap();
+        let state = State::new();
+
+        let mut req = dummy_web_request();
+        req.method = "POST".into();
+        req.url = "/hook/first.sh?secret=testing".into();
+        req.params.insert("secret".into(), "testing".into());
+        req.headers.insert("X-Test".into(), "yes".into());
+        req.body = "a body!".into();
+
+        let mut path_params = HashMap::new();
+        path_params.insert("NAME".to_string(), "value".to_string());
+
+        let first = state.next_id(IdKind::JobId);
+        let second = state.next_id(IdKind::JobId);
+        let third = state.next_id(IdKind::JobId);
+        write(&dir, second, "second.sh", &req, &HashMap::new(), true)
+            .unwrap();
+        write(&dir, first, "first.sh", &req, &path_params, false).unwrap();
+        write(&dir, third, "third.sh", &req, &HashMap::new(), false)
+            .unwrap();
+
+        // Finished jobs are not restored
+        remove(&dir, third).unwrap();
+
+        let pending = restore(&dir).unwrap();
+        assert_eq!(
+            pending.iter().map(|job| job.hook.as_str()).collect::<Vec<_>>(),
+            vec!["first.sh", "second.sh"]
+        );
+        assert!(! pending[0].unvalidated);
+        assert!(pending[1].unvalidated);
+        assert_eq!(pending[0].path_params, path_params);
+
+        let restored = &pending[0].request;
+        assert_eq!(restored.source, req.source);
+        assert_eq!(restored.method, "POST");
+        assert_eq!(restored.url, "/hook/first.sh?secret=testing");
+        assert_eq!(restored.params, req.params);
+        assert_eq!(restored.headers, req.headers);
+        assert_eq!(restored.body, "a body!");
+
+        // The jobs are kept until they're forgotten
+        assert_eq!(restore(&dir).unwrap().len(), 2);
+        remove(&dir, first).unwrap();
+        remove(&dir, second).unwrap();
+        assert!(restore(&dir).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_broken_jobs_skipped() {
+        let dir = utils::create_temp_dir().unwrap();
+        let state = State::new();
+
+        let job_id = state.next_id(IdKind::JobId);
+        write(&dir, job_id, "valid.sh", &dummy_web_request(), &HashMap::new(),
+              false).unwrap();
+        fs::File::create(dir.join("broken.json")).unwrap()
+            .write_all(b"{not json").unwrap();
+        fs::File::create(dir.join("other.json.tmp")).unwrap();
+
+        let pending = restore(&dir).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].hook, "valid.sh");
+
+        // Broken jobs are kept, while interrupted writes are removed
+        assert!(dir.join("broken.json").exists());
+        assert!(! dir.join("other.json.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_after_restart() {
+        let dir = utils::create_temp_dir().unwrap();
+        let req = dummy_web_request();
+
+        // Store a job as if it was left by a previous run, which used the
+        // same job IDs
+        let old_id = State::new().next_id(IdKind::JobId);
+        write(&dir, old_id, "old.sh", &req, &HashMap::new(), false)
+            .unwrap();
+        fs::rename(
+            dir.join(format!("{}.json", stored_id(old_id))),
+            dir.join(format!("previous-{}.json", old_id)),
+        ).unwrap();
+        let pending = restore(&dir).unwrap();
+        assert_eq!(pending[0].stored_id(), format!("previous-{}", old_id));
+
+        // Dropping the store waits for the jobs to be written
+        let state = State::new();
+        let new_id = state.next_id(IdKind::JobId);
+        let other_id = state.next_id(IdKind::JobId);
+        assert_eq!(new_id, old_id);
+        {
+            let store = QueueStore::new(dir.clone());
+            store.store(new_id, "new.sh", &req, &HashMap::new(), false);
+            store.store(other_id, "other.sh", &req, &HashMap::new(), false);
+            store.forget(&stored_id(other_id), "other.sh");
+        }
+
+        // The new job doesn't replace the one of the previous run
+        let mut hooks = restore(&dir).unwrap().into_iter()
+            .map(|job| job.hook)
+            .collect::<Vec<_>>();
+        hooks.sort();
+        assert_eq!(hooks, vec!["new.sh".to_string(), "old.sh".to_string()]);
+
+        // Forgetting the restored job only removes its own copy
+        {
+            let store = QueueStore::new(dir.clone());
+            store.forget(pending[0].stored_id(), "old.sh");
+        }
+        let pending = restore(&dir).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].hook, "new.sh");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
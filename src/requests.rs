@@ -23,6 +23,9 @@ pub enum RequestType {
     ExecuteHook,
     Ping,
     Invalid,
+    /// The request is valid, but it doesn't match the conditions of the
+    /// hook, so the hook isn't executed.
+    Skip,
 }
 
 
@@ -17,12 +17,30 @@
 #[macro_use] pub mod testing;
 
 mod tempdir;
+mod pid_file;
 mod net;
 mod hex;
 mod parse_env;
+mod template;
+mod redact;
+mod run_id;
+#[cfg(any(
+    feature = "provider-github", feature = "provider-bitbucket",
+    feature = "provider-slack",
+))]
+mod signature;
 
 
-pub use utils::tempdir::create_temp_dir;
-pub use utils::net::parse_forwarded_for;
+pub use utils::tempdir::{create_temp_dir, TempDir};
+pub use utils::pid_file::PidFile;
+pub use utils::net::{IpNetwork, parse_ip_header};
 pub use utils::hex::from_hex;
 pub use utils::parse_env::parse_env;
+pub use utils::template::Template;
+pub use utils::redact::Redactor;
+pub use utils::run_id::run_id;
+#[cfg(any(
+    feature = "provider-github", feature = "provider-bitbucket",
+    feature = "provider-slack",
+))]
+pub use utils::signature::verify_signature;
@@ -14,7 +14,9 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
+use std::fmt;
 use std::net::IpAddr;
+use std::str::FromStr;
 
 use fisher_common::prelude::*;
 
@@ -22,10 +24,97 @@ use fisher_common::prelude::*;
 pub type Headers = HashMap<String, String>;
 
 
-pub fn parse_forwarded_for(headers: &Headers) -> Result<Vec<IpAddr>> {
+/// A range of IP addresses, written in the CIDR notation. A single address
+/// is also accepted, and it only contains itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.address, *ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                &network.octets(), &ip.octets(), self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => prefix_matches(
+                &network.octets(), &ip.octets(), self.prefix,
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(network: &str) -> Result<Self> {
+        let (address, prefix) = match network.find('/') {
+            Some(pos) => (&network[..pos], Some(&network[pos + 1..])),
+            None => (network, None),
+        };
+
+        let address = address.parse::<IpAddr>()?;
+        let max_prefix = match address {
+            IpAddr::V4(..) => 32,
+            IpAddr::V6(..) => 128,
+        };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>()?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(ErrorKind::InvalidInput(
+                format!("invalid network prefix: {}", network)
+            ).into());
+        }
+
+        Ok(IpNetwork {
+            address: address,
+            prefix: prefix,
+        })
+    }
+}
+
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    let remaining_bits = prefix % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = !0u8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+
+/// Parse the list of IP addresses in a header like `X-Forwarded-For`. The
+/// name of the header is case insensitive.
+pub fn parse_ip_header(headers: &Headers, name: &str)
+                       -> Result<Vec<IpAddr>> {
     let mut result = vec![];
 
-    if let Some(header) = headers.get("X-Forwarded-For".into()) {
+    let name = name.to_lowercase();
+    let header = headers.iter()
+        .find(|&(key, _)| key.to_lowercase() == name)
+        .map(|(_, value)| value);
+
+    if let Some(header) = header {
         // Parse the header content
         let splitted: Vec<&str> = header.split(',').collect();
 
@@ -43,14 +132,51 @@ pub fn parse_forwarded_for(headers: &Headers) -> Result<Vec<IpAddr>> {
 mod tests {
     use std::net::IpAddr;
 
-    use super::{Headers, parse_forwarded_for};
+    use super::{Headers, IpNetwork, parse_ip_header};
 
 
     #[test]
-    fn test_parse_forwarded_for() {
+    fn test_ip_network() {
+        macro_rules! contains {
+            ($network:expr, $ip:expr) => {
+                $network.parse::<IpNetwork>().unwrap()
+                    .contains(&$ip.parse::<IpAddr>().unwrap())
+            };
+        }
+
+        assert!(contains!("10.0.0.0/8", "10.1.2.3"));
+        assert!(! contains!("10.0.0.0/8", "11.1.2.3"));
+        assert!(contains!("192.168.1.64/26", "192.168.1.127"));
+        assert!(! contains!("192.168.1.64/26", "192.168.1.128"));
+        assert!(contains!("0.0.0.0/0", "8.8.8.8"));
+        assert!(contains!("127.0.0.1", "127.0.0.1"));
+        assert!(! contains!("127.0.0.1", "127.0.0.2"));
+        assert!(contains!("fd00::/8", "fd12:3456::1"));
+        assert!(! contains!("fd00::/8", "fe80::1"));
+
+        // IPv4 and IPv6 addresses never match each other
+        assert!(! contains!("0.0.0.0/0", "::1"));
+        assert!(! contains!("::/0", "127.0.0.1"));
+
+        assert_eq!(
+            "10.0.0.0/8".parse::<IpNetwork>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            "::1".parse::<IpNetwork>().unwrap().to_string(), "::1/128"
+        );
+
+        for invalid in &["10.0.0.0/33", "::/129", "10.0.0.0/", "nope/8"] {
+            assert!(invalid.parse::<IpNetwork>().is_err());
+        }
+    }
+
+
+    #[test]
+    fn test_parse_ip_header() {
         // Test with no headers
         assert_eq!(
-            parse_forwarded_for(&Headers::new()).unwrap(),
+            parse_ip_header(&Headers::new(), "X-Forwarded-For").unwrap(),
             Vec::<IpAddr>::new()
         );
 
@@ -58,7 +184,7 @@ mod tests {
         let mut headers = Headers::new();
         headers.insert("X-Forwarded-For".into(), "127.0.0.1".into());
         assert_eq!(
-            parse_forwarded_for(&headers).unwrap(),
+            parse_ip_header(&headers, "X-Forwarded-For").unwrap(),
             vec!["127.0.0.1".parse::<IpAddr>().unwrap()]
         );
 
@@ -69,7 +195,7 @@ mod tests {
             "127.0.0.1, 10.0.0.1".into()
         );
         assert_eq!(
-            parse_forwarded_for(&headers).unwrap(),
+            parse_ip_header(&headers, "X-Forwarded-For").unwrap(),
             vec![
                 "127.0.0.1".parse::<IpAddr>().unwrap(),
                 "10.0.0.1".parse::<IpAddr>().unwrap()
@@ -82,6 +208,14 @@ mod tests {
             "X-Forwarded-For".into(),
             "127.0.0.1, hey, 10.0.0.1".into()
         );
-        assert!(parse_forwarded_for(&headers).is_err());
+        assert!(parse_ip_header(&headers, "X-Forwarded-For").is_err());
+
+        // Test with a different name and case
+        let mut headers = Headers::new();
+        headers.insert("x-real-ip".into(), "10.0.0.1".into());
+        assert_eq!(
+            parse_ip_header(&headers, "X-Real-IP").unwrap(),
+            vec!["10.0.0.1".parse::<IpAddr>().unwrap()]
+        );
     }
 }
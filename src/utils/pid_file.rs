@@ -0,0 +1,85 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use libc;
+
+use fisher_common::prelude::*;
+
+
+/// A file containing the PID of Fisher, which is removed when this struct
+/// is dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut file = fs::File::create(&path)?;
+        writeln!(file, "{}", unsafe { libc::getpid() })?;
+
+        Ok(PidFile {
+            path: path,
+        })
+    }
+}
+
+impl Drop for PidFile {
+
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use libc;
+
+    use utils;
+
+    use super::PidFile;
+
+
+    #[test]
+    fn test_pid_file() {
+        let dir = utils::create_temp_dir().unwrap();
+        let path = dir.join("fisher.pid");
+
+        let pid_file = PidFile::create(&path).unwrap();
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, format!("{}\n", unsafe { libc::getpid() }));
+
+        // The file is removed when it's dropped
+        drop(pid_file);
+        assert!(! path.exists());
+
+        // Missing directories aren't created
+        assert!(PidFile::create(dir.join("missing/fisher.pid")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
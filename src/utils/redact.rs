@@ -0,0 +1,158 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use url::form_urlencoded;
+
+
+// Names containing those words are always redacted
+static DEFAULT_SENSITIVE: &'static [&'static str] = &[
+    "secret", "token", "password",
+];
+
+static REDACTED: &'static str = "[redacted]";
+
+
+/// This struct masks the values of sensitive params, headers and environment
+/// variables before they're logged.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    names: Vec<String>,
+}
+
+impl Redactor {
+
+    pub fn new<S: AsRef<str>>(names: &[S]) -> Self {
+        Redactor {
+            names: names.iter().map(|n| n.as_ref().to_lowercase()).collect(),
+        }
+    }
+
+    pub fn is_sensitive(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+
+        DEFAULT_SENSITIVE.iter().any(|word| name.contains(word))
+            || self.names.contains(&name)
+    }
+
+    pub fn value<'a>(&self, name: &str, value: &'a str) -> &'a str {
+        if self.is_sensitive(name) {
+            REDACTED
+        } else {
+            value
+        }
+    }
+
+    /// Mask in the text the values of the sensitive names among the
+    /// provided ones.
+    pub fn text(&self, text: &str, values: &HashMap<String, String>)
+                -> String {
+        let mut result = text.to_string();
+        for (name, value) in values {
+            if ! value.is_empty() && self.is_sensitive(name) {
+                result = result.replace(value.as_str(), REDACTED);
+            }
+        }
+        result
+    }
+
+    /// Mask the sensitive params in the query string of the URL.
+    pub fn url(&self, url: &str) -> String {
+        let mut parts = url.splitn(2, '?');
+        let path = parts.next().unwrap_or("");
+        let query = match parts.next() {
+            Some(query) => query,
+            None => return path.to_string(),
+        };
+
+        let params = query.split('&').map(|pair| {
+            let name = form_urlencoded::parse(pair.as_bytes()).next()
+                .map(|(name, _)| name.into_owned())
+                .unwrap_or_else(String::new);
+
+            if self.is_sensitive(&name) {
+                let raw_name = pair.splitn(2, '=').next().unwrap_or("");
+                format!("{}={}", raw_name, REDACTED)
+            } else {
+                pair.to_string()
+            }
+        }).collect::<Vec<_>>();
+
+        format!("{}?{}", path, params.join("&"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Redactor;
+
+
+    #[test]
+    fn test_is_sensitive() {
+        let redactor = Redactor::new(&["X-Hub-Signature", "ref"]);
+
+        // Names like those are always sensitive
+        assert!(redactor.is_sensitive("secret"));
+        assert!(redactor.is_sensitive("GITLAB_TOKEN"));
+        assert!(redactor.is_sensitive("db_password"));
+
+        // Custom names are compared case-insensitively
+        assert!(redactor.is_sensitive("x-hub-signature"));
+        assert!(redactor.is_sensitive("REF"));
+        assert!(! redactor.is_sensitive("reference"));
+        assert!(! redactor.is_sensitive("branch"));
+
+        assert_eq!(redactor.value("secret", "hunter2"), "[redacted]");
+        assert_eq!(redactor.value("branch", "master"), "master");
+    }
+
+
+    #[test]
+    fn test_url() {
+        let redactor = Redactor::new(&["email"]);
+
+        assert_eq!(redactor.url("/hook/a.sh"), "/hook/a.sh");
+        assert_eq!(redactor.url("/hook/a.sh?"), "/hook/a.sh?");
+        assert_eq!(
+            redactor.url("/hook/a.sh?secret=abc&ref=master&email=a%40b.c"),
+            "/hook/a.sh?secret=[redacted]&ref=master&email=[redacted]"
+        );
+        assert_eq!(
+            redactor.url("/hook/a.sh?my%20token=abc&flag"),
+            "/hook/a.sh?my%20token=[redacted]&flag"
+        );
+    }
+
+
+    #[test]
+    fn test_text() {
+        let redactor = Redactor::new(&["email"]);
+
+        let mut values = HashMap::new();
+        values.insert("GITHUB_TOKEN".to_string(), "abc123".to_string());
+        values.insert("email".to_string(), "a@b.c".to_string());
+        values.insert("branch".to_string(), "master".to_string());
+        values.insert("password".to_string(), String::new());
+
+        assert_eq!(
+            redactor.text("pushing master as a@b.c with abc123\n", &values),
+            "pushing master as [redacted] with [redacted]\n"
+        );
+    }
+}
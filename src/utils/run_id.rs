@@ -0,0 +1,52 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+lazy_static! {
+    static ref RUN_ID: String = {
+        let since = SystemTime::now().duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!(
+            "{}{:09}-{}", since.as_secs(), since.subsec_nanos(), process::id(),
+        )
+    };
+}
+
+
+/// Return an ID which is different every time Fisher is started. The IDs of
+/// the jobs restart from zero instead, so they need this prefix to name
+/// anything which outlives the process.
+pub fn run_id() -> &'static str {
+    &RUN_ID
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::run_id;
+
+
+    #[test]
+    fn test_run_id() {
+        // The ID doesn't change while Fisher is running
+        assert_eq!(run_id(), run_id());
+
+        // It's safe to use in file names and URLs
+        assert!(run_id().chars().all(|c| c.is_digit(10) || c == '-'));
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use ring;
+
+use utils::from_hex;
+
+
+/// Verify an HMAC signature in the `algorithm=hex` format, used by the
+/// `X-Hub-Signature` headers. Both SHA-1 and SHA-256 are supported.
+pub fn verify_signature(secret: &str, payload: &str, raw_signature: &str)
+                        -> bool {
+    // The signature must have a =
+    if ! raw_signature.contains('=') {
+        return false;
+    }
+
+    // Split the raw signature to get the algorithm and the signature
+    let splitted: Vec<&str> = raw_signature.split('=').collect();
+    let algorithm = &splitted[0];
+    let hex_signature = splitted.iter().skip(1).map(|i| *i)
+                                .collect::<Vec<&str>>().join("=");
+
+    // Convert the signature from hex
+    let signature = if let Ok(converted) = from_hex(&hex_signature) {
+        converted
+    } else {
+        // This is not hex
+        return false;
+    };
+
+    // Get the correct digest
+    let digest = match *algorithm {
+        "sha1" => &ring::digest::SHA1,
+        "sha256" => &ring::digest::SHA256,
+        _ => {
+            // Unknown digest, return false
+            return false;
+        },
+    };
+
+    // Verify the HMAC signature
+    let key = ring::hmac::VerificationKey::new(digest, secret.as_bytes());
+    ring::hmac::verify(&key, payload.as_bytes(), &signature).is_ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::verify_signature;
+
+
+    #[test]
+    fn test_verify_signature() {
+        // Check if the function allows invalid signatures
+        for signature in &[
+            "invalid",  // No algorithm
+            "invalid=invalid",  // Invalid algorithm
+            "sha1=g",  // The signature is not hex
+
+            // Invalid signature (the first "e" should be "f")
+            "sha1=e75efc0f29bf50c23f99b30b86f7c78fdaf5f11d",
+        ] {
+            assert!(
+                ! verify_signature("secret", "payload", signature),
+                signature.to_string()
+            );
+        }
+
+        // Those are known to be right
+        assert!(verify_signature(
+            "secret", "payload",
+            "sha1=f75efc0f29bf50c23f99b30b86f7c78fdaf5f11d"
+        ));
+        assert!(verify_signature(
+            "secret", "payload",
+            "sha256=b82fcb791acec57859b989b430a82648\
+                    8ce2e479fdf92326bd0a2e8375a42ba4"
+        ));
+    }
+}
@@ -56,16 +56,13 @@ impl TempDirCreator {
         })
     }
 
-    fn create(&mut self) -> Result<path::PathBuf> {
-        // The OS's base temp directory
-        let base = env::temp_dir();
-
+    fn create(&mut self, base: &path::Path) -> Result<path::PathBuf> {
         // Create a randomized temp directory
         loop {
             // Generate the random suffix
             let suffix: String = self.rng.gen_ascii_chars().take(10).collect();
 
-            let mut path = base.clone();
+            let mut path = base.to_path_buf();
             path.push(format!("{}-{}", self.prefix, suffix));
 
             // Be sure to set the 0700 permissions on the new directory
@@ -96,7 +93,43 @@ impl TempDirCreator {
 
 pub fn create_temp_dir() -> Result<path::PathBuf> {
     let mut creator = CREATOR.lock().unwrap();
-    creator.create()
+    creator.create(&env::temp_dir())
+}
+
+
+/// A temporary directory, which is removed with all its content when this
+/// struct is dropped.
+#[derive(Debug)]
+pub struct TempDir {
+    path: path::PathBuf,
+}
+
+impl TempDir {
+
+    /// Create a new temporary directory inside `base`, or inside the OS's
+    /// temp directory if no base is provided.
+    pub fn create(base: Option<&path::Path>) -> Result<Self> {
+        let mut creator = CREATOR.lock().unwrap();
+        let path = match base {
+            Some(base) => creator.create(base)?,
+            None => creator.create(&env::temp_dir())?,
+        };
+
+        Ok(TempDir {
+            path: path,
+        })
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
 }
 
 
@@ -110,7 +143,7 @@ mod tests {
     use rand;
     use rand::Rng;
 
-    use super::TempDirCreator;
+    use super::{TempDirCreator, TempDir};
 
 
     #[test]
@@ -136,7 +169,7 @@ mod tests {
         creator.seed(&[1]);
 
         // Try to create a temp directory, and remove it
-        let created = creator.create().unwrap();
+        let created = creator.create(&base).unwrap();
         assert!(created == expected1);
 
         // Check if its metadata is correct
@@ -150,7 +183,7 @@ mod tests {
 
         // This should be expected1, but since it already exists the output
         // must be expected2
-        let created = creator.create().unwrap();
+        let created = creator.create(&base).unwrap();
         assert!(created == expected2);
 
         // Delete the two directories
@@ -159,6 +192,23 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_temp_dir_guard() {
+        let base = TempDir::create(None).unwrap();
+
+        // The directory is created inside the base, and removed with its
+        // content when the guard is dropped
+        let dir = TempDir::create(Some(base.path())).unwrap();
+        assert!(dir.path().starts_with(base.path()));
+        fs::File::create(dir.path().join("file")).unwrap();
+
+        let path = dir.path().to_path_buf();
+        drop(dir);
+        assert!(! path.exists());
+        assert!(base.path().exists());
+    }
+
+
     fn generate_prefix() -> String {
         // Use this thread's random number generator
         let mut rng = rand::thread_rng();
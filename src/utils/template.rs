@@ -0,0 +1,162 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use fisher_common::prelude::*;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Key(String),
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+
+        let mut chars = input.chars().peekable();
+        while let Some(chr) = chars.next() {
+            match chr {
+                // Double braces are escaped braces
+                '{' if chars.peek() == Some(&'{') => {
+                    let _ = chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    let _ = chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let mut key = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => key.push(c),
+                            None => return Err(ErrorKind::InvalidInput(
+                                format!("unclosed key in template: {}", input)
+                            ).into()),
+                        }
+                    }
+
+                    if ! text.is_empty() {
+                        parts.push(Part::Text(text));
+                        text = String::new();
+                    }
+                    parts.push(Part::Key(key));
+                },
+                '}' => {
+                    return Err(ErrorKind::InvalidInput(
+                        format!("unmatched }} in template: {}", input)
+                    ).into());
+                },
+                _ => text.push(chr),
+            }
+        }
+
+        if ! text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Template {
+            parts: parts,
+        })
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        self.parts.iter().filter_map(|part| match *part {
+            Part::Key(ref key) => Some(key.as_str()),
+            Part::Text(..) => None,
+        }).collect()
+    }
+
+    pub fn render(&self, values: &HashMap<String, String>, strict: bool)
+                  -> Result<String> {
+        let mut result = String::new();
+
+        for part in &self.parts {
+            match *part {
+                Part::Text(ref text) => result.push_str(text),
+                Part::Key(ref key) => {
+                    if let Some(value) = values.get(key) {
+                        result.push_str(value);
+                    } else if strict {
+                        return Err(ErrorKind::InvalidInput(
+                            format!("missing template key: {}", key)
+                        ).into());
+                    }
+                },
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Template;
+
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Template::parse("").unwrap().keys(), Vec::<&str>::new());
+        assert_eq!(
+            Template::parse("{a} and {b:c}").unwrap().keys(),
+            vec!["a", "b:c"]
+        );
+        assert_eq!(
+            Template::parse("{{a}} and {{b}}").unwrap().keys(),
+            Vec::<&str>::new()
+        );
+
+        assert!(Template::parse("{a").is_err());
+        assert!(Template::parse("a}").is_err());
+    }
+
+
+    #[test]
+    fn test_render() {
+        let mut values = HashMap::new();
+        values.insert("hook".to_string(), "example.sh".to_string());
+
+        let template = Template::parse("Queued {hook} ({{{missing}}})")
+            .unwrap();
+
+        assert_eq!(
+            template.render(&values, false).unwrap(),
+            "Queued example.sh ({})".to_string()
+        );
+        assert!(template.render(&values, true).is_err());
+
+        values.insert("missing".to_string(), "found".to_string());
+        assert_eq!(
+            template.render(&values, true).unwrap(),
+            "Queued example.sh ({found})".to_string()
+        );
+    }
+}
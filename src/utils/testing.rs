@@ -14,21 +14,27 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
 
 use hyper::client as hyper;
 use hyper::method::Method;
 
 use fisher_common::prelude::*;
 use fisher_common::state::State;
-use fisher_common::structs::HealthDetails;
+use fisher_common::structs::{HealthDetails, HistoryEntry};
+
+use fisher_processor::{Processor, ProcessorApi, ProcessorConfig};
 
 use hooks::{Hooks, HooksBlueprint};
-use jobs::{Job, JobOutput};
-use web::{WebApp, WebRequest};
+use jobs::{Context, Job, JobOutput};
+use web::{WebApp, WebConfig, WebRequest};
 use requests::Request;
 use utils;
 
@@ -60,10 +66,13 @@ macro_rules! assert_err {
 
 pub fn dummy_web_request() -> WebRequest {
     WebRequest {
+        method: "GET".into(),
+        url: "/".into(),
         headers: HashMap::new(),
         params: HashMap::new(),
         source: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
         body: String::new(),
+        body_file: None,
     }
 }
 
@@ -76,6 +85,7 @@ pub fn dummy_job_output() -> JobOutput {
         success: true,
         exit_code: Some(0),
         signal: None,
+        timed_out: false,
 
         hook_name: "test".into(),
         request_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
@@ -112,6 +122,51 @@ macro_rules! create_hook {
 }
 
 
+/// Accept an HTTP request on the listener and reply to it with the status
+/// code, returning the request line and the body of the request.
+pub fn receive_http_request(listener: &TcpListener, status: u16)
+                            -> (String, String) {
+    let (mut stream, _) = listener.accept().unwrap();
+
+    // Read until the whole body declared in the headers is received
+    let mut received = Vec::new();
+    let mut buffer = [0; 1024];
+    let (head, body);
+    loop {
+        let read = stream.read(&mut buffer).unwrap();
+        assert!(read > 0, "the connection was closed early");
+        received.extend_from_slice(&buffer[..read]);
+
+        let content = String::from_utf8_lossy(&received).into_owned();
+        if let Some(end) = content.find("\r\n\r\n") {
+            let length = content[..end].lines()
+                .filter_map(|line| {
+                    let lower = line.to_lowercase();
+                    if lower.starts_with("content-length:") {
+                        line[15..].trim().parse::<usize>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next().unwrap_or(0);
+
+            if content.len() >= end + 4 + length {
+                head = content[..end].to_string();
+                body = content[end + 4..].to_string();
+                break;
+            }
+        }
+    }
+
+    write!(stream,
+        "HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\
+         Connection: close\r\n\r\n", status,
+    ).unwrap();
+
+    (head.lines().next().unwrap().to_string(), body)
+}
+
+
 pub fn sample_hooks() -> PathBuf {
     // Create a sample directory with some hooks
     let tempdir = utils::create_temp_dir().unwrap();
@@ -168,6 +223,175 @@ pub fn sample_hooks() -> PathBuf {
         r#"echo "triggered!""#
     );
 
+    // Those hooks are not executable, they're run by their interpreter
+    create_hook!(tempdir, "interpreter.sh",
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Interpreter: {"command": "/bin/sh"}"#,
+        r#"echo "interpreted" > "${FISHER_TESTING_ENV}/executed""#
+    );
+    create_hook!(tempdir, "missing-interpreter.sh",
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Interpreter: {"command": "/fisher-missing/sh"}"#,
+        r#"echo "Hello world""#
+    );
+    for name in &["interpreter.sh", "missing-interpreter.sh"] {
+        fs::set_permissions(
+            &tempdir.join(name), fs::Permissions::from_mode(0o644),
+        ).unwrap();
+    }
+
+    create_hook!(tempdir, "exit-78.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"exit 78"#
+    );
+    create_hook!(tempdir, "success-codes.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Success-Codes: {"codes": [0, 78]}"#,
+        r#"exit 78"#
+    );
+
+    create_hook!(tempdir, "trap-term.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"sleep 10 > /dev/null 2>&1 &"#,
+        r#"cleanup() {"#,
+        r#"    kill $!"#,
+        r#"    echo "cleaned up" > "${FISHER_TESTING_ENV}""#,
+        r#"    exit 1"#,
+        r#"}"#,
+        r#"trap cleanup TERM"#,
+        r#"wait"#
+    );
+
+    create_hook!(tempdir, "trap-term-success.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"sleep 10 > /dev/null 2>&1 &"#,
+        r#"trap 'kill $!; exit 0' TERM"#,
+        r#"wait"#
+    );
+    create_hook!(tempdir, "grandchild.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"sleep 30 > /dev/null 2>&1 &"#,
+        r#"echo $! > "${FISHER_TESTING_ENV}""#,
+        r#"wait"#
+    );
+    create_hook!(tempdir, "grandchild-output.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"echo started"#,
+        r#"sleep 30 &"#,
+        r#"echo $! > "${FISHER_TESTING_ENV}""#,
+        r#"wait"#
+    );
+    create_hook!(tempdir, "kill-signal.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Kill: {"signal": "SIGTERM", "grace": 5}"#,
+        r#"echo "Hello world""#
+    );
+    create_hook!(tempdir, "sandbox.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"echo "$(nice) $(ulimit -t) $(ulimit -v)""#
+    );
+    create_hook!(tempdir, "sandbox-user.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"touch created"#,
+        r#"echo "$(id -u) $(id -g) $(cat "${FISHER_REQUEST_BODY}")""#
+    );
+    create_hook!(tempdir, "no-timeout.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Timeout: {"seconds": 0}"#,
+        r#"sleep 0.5"#
+    );
+
+    create_hook!(tempdir, "job-log.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Response: {"template": "{log_id}"}"#,
+        r#"echo "Hello stdout""#,
+        r#"echo "Hello stderr" >&2"#
+    );
+
+    create_hook!(tempdir, "response.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        concat!(
+            r#"## Fisher-Response: "#,
+            r#"{"template": "Queued {hook} from {param:ref}"}"#
+        ),
+        r#"echo "Hello world""#
+    );
+    create_hook!(tempdir, "strict-response.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        concat!(
+            r#"## Fisher-Response: "#,
+            r#"{"template": "Queued {hook} from {param:ref}", "#,
+            r#""strict": true, "content_type": "text/markdown"}"#
+        ),
+        r#"echo "Hello world""#
+    );
+
+    create_hook!(tempdir, "memory-hog.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Limits: {"memory": "16M"}"#,
+        r#"hog="$(head -c 33554432 /dev/zero | tr '\0' a)""#
+    );
+
+    create_hook!(tempdir, "path.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Path: {"pattern": "^repo/(?P<name>[a-z]+)$"}"#,
+        r#"echo "${FISHER_PATH_NAME}""#
+    );
+
+    create_hook!(tempdir, "body-size.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Body-Size: {"min": 1, "max": 10}"#,
+        r#"echo "Hello world""#
+    );
+
+    create_hook!(tempdir, "rate-limit.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-RateLimit: {"per_minute": 2}"#,
+        r#"echo "Hello world""#
+    );
+
+    create_hook!(tempdir, "env-prefix.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"env > "${MYAPP_TESTING_ENV}/env""#
+    );
+
+    create_hook!(tempdir, "accepted.sh",
+        r#"#!/bin/bash"#,
+        concat!(
+            r#"## Fisher-Standalone: {"secret": "testing", "#,
+            r#""accepted_status": 202}"#,
+        ),
+        r#"echo "Hello world""#
+    );
+
+    create_hook!(tempdir, "configured.sh",
+        r#"#!/bin/bash"#,
+        concat!(
+            r#"## Fisher-Standalone: {"secret": "testing", "cwd": "/", "#,
+            r#""env": {"CONFIGURED": "yes", "FISHER_REQUEST_IP": "none"}}"#,
+        ),
+        r#"b="$(cat "${FISHER_REQUEST_BODY}")""#,
+        r#"env > "${b}/env""#,
+        r#"pwd > "${b}/pwd""#
+    );
+
     fs::create_dir(&tempdir.join("sub")).unwrap();
     create_hook!(tempdir.join("sub"), "hook.sh",
         r#"#!/bin/bash"#,
@@ -182,6 +406,8 @@ pub fn sample_hooks() -> PathBuf {
 pub enum ProcessorApiCall {
     Queue(Job, isize),
     HealthDetails,
+    LabeledHealthDetails(String),
+    History(String),
     Cleanup,
     Lock,
     Unlock,
@@ -199,15 +425,44 @@ impl ProcessorApiTrait<Hooks> for FakeProcessorApi {
         Ok(())
     }
 
+    fn queue_wait(&self, job: Job, priority: isize)
+                  -> Result<mpsc::Receiver<WaitResult<JobOutput>>> {
+        self.sender.send(ProcessorApiCall::Queue(job, priority))?;
+
+        // The fake jobs are never executed, so there is no output to send
+        let (_, output_recv) = mpsc::channel();
+        Ok(output_recv)
+    }
+
     fn health_details(&self) -> Result<HealthDetails> {
         self.sender.send(ProcessorApiCall::HealthDetails)?;
         Ok(HealthDetails {
             queued_jobs: 1,
             busy_threads: 2,
             max_threads: 3,
+            throughput: 0.5,
+            hooks: HashMap::new(),
+        })
+    }
+
+    fn labeled_health_details(&self, label: &str) -> Result<HealthDetails> {
+        self.sender.send(
+            ProcessorApiCall::LabeledHealthDetails(label.into())
+        )?;
+        Ok(HealthDetails {
+            queued_jobs: 0,
+            busy_threads: 1,
+            max_threads: 3,
+            throughput: 0.5,
+            hooks: HashMap::new(),
         })
     }
 
+    fn history(&self, script: &str) -> Result<Vec<HistoryEntry>> {
+        self.sender.send(ProcessorApiCall::History(script.into()))?;
+        Ok(Vec::new())
+    }
+
     fn cleanup(&self) -> Result<()> {
         self.sender.send(ProcessorApiCall::Cleanup)?;
         Ok(())
@@ -229,6 +484,7 @@ pub struct WebAppInstance {
     inst: WebApp<FakeProcessorApi>,
 
     url: String,
+    admin_url: Option<String>,
     client: hyper::Client,
 
     processor_api_call: mpsc::Receiver<ProcessorApiCall>,
@@ -236,8 +492,7 @@ pub struct WebAppInstance {
 
 impl WebAppInstance {
 
-    pub fn new(hooks: Arc<Hooks>, health: bool, behind_proxies: u8)
-               -> Self {
+    pub fn new(hooks: Arc<Hooks>, config: &WebConfig) -> Self {
         let (chan_send, chan_recv) = mpsc::channel();
         let fake_processor = FakeProcessorApi {
             sender: chan_send,
@@ -245,18 +500,20 @@ impl WebAppInstance {
 
         // Start the web server
         // Create a new instance of WebApp
-        let inst = WebApp::new(
-            hooks, health, behind_proxies, "127.0.0.1:0", fake_processor,
-        ).unwrap();
+        let inst = WebApp::new(hooks, config, fake_processor).unwrap();
 
         // Create the HTTP client
         let url = format!("http://{}", inst.addr());
+        let admin_url = inst.admin_addr().map(|addr| {
+            format!("http://{}", addr)
+        });
         let client = hyper::Client::new();
 
         WebAppInstance {
             inst: inst,
 
             url: url,
+            admin_url: admin_url,
             client: client,
             processor_api_call: chan_recv,
         }
@@ -268,6 +525,20 @@ impl WebAppInstance {
         self.client.request(method, &format!("{}{}", self.url, url))
     }
 
+    pub fn admin_request(&mut self, method: Method, url: &str)
+                         -> hyper::RequestBuilder {
+        let admin_url = self.admin_url.as_ref().expect("no admin server");
+        self.client.request(method, &format!("{}{}", admin_url, url))
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        self.inst.addr()
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.inst.addrs()
+    }
+
     pub fn processor_input(&self) -> Option<ProcessorApiCall> {
         if let Ok(result) = self.processor_api_call.try_recv() {
             Some(result)
@@ -276,6 +547,13 @@ impl WebAppInstance {
         }
     }
 
+    /// Wait for the processor to receive an input, for calls done in the
+    /// background.
+    pub fn processor_input_timeout(&self, timeout: Duration)
+                                   -> Option<ProcessorApiCall> {
+        self.processor_api_call.recv_timeout(timeout).ok()
+    }
+
     pub fn lock(&self) {
         self.inst.lock();
     }
@@ -290,7 +568,86 @@ impl WebAppInstance {
 }
 
 
+/// A running instance of both the Web API and the processor, connected
+/// together like in the real application
+pub struct FullInstance {
+    processor: Processor<Hooks>,
+    web: WebApp<ProcessorApi<Hooks>>,
+
+    url: String,
+    client: hyper::Client,
+}
+
+impl FullInstance {
+
+    pub fn request(&mut self, method: Method, url: &str)
+                   -> hyper::RequestBuilder {
+        self.client.request(method, &format!("{}{}", self.url, url))
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn health_details(&self) -> HealthDetails {
+        self.processor.api().health_details().unwrap()
+    }
+
+    /// Wait until all the queued jobs are processed
+    pub fn wait_idle(&self) {
+        if ! self.wait_idle_timeout(Duration::from_secs(10)) {
+            panic!("the jobs weren't processed in time");
+        }
+    }
+
+    /// Wait until all the queued jobs are processed, returning false if
+    /// they weren't processed before the timeout
+    pub fn wait_idle_timeout(&self, timeout: Duration) -> bool {
+        let started_at = Instant::now();
+        loop {
+            let details = self.health_details();
+            if details.queued_jobs == 0 && details.busy_threads == 0 {
+                return true;
+            }
+
+            if started_at.elapsed() > timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Wait until a job writes something in the file, returning its
+    /// content, or None if nothing was written before the timeout
+    pub fn wait_file(&self, path: &Path, timeout: Duration)
+                     -> Option<String> {
+        let started_at = Instant::now();
+        loop {
+            let mut content = String::new();
+            if let Ok(mut file) = fs::File::open(path) {
+                if file.read_to_string(&mut content).is_ok()
+                   && ! content.is_empty() {
+                    return Some(content);
+                }
+            }
+
+            if started_at.elapsed() > timeout {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    pub fn stop(self) {
+        self.web.lock();
+        self.processor.stop().unwrap();
+        self.web.stop();
+    }
+}
+
+
 pub struct TestingEnv {
+    state: Arc<State>,
     hooks: Arc<Hooks>,
     remove_dirs: Vec<String>,
 }
@@ -306,6 +663,7 @@ impl TestingEnv {
         hooks_blueprint.collect_path(&hooks_dir, true).unwrap();
 
         TestingEnv {
+            state: state,
             hooks: Arc::new(hooks_blueprint.hooks()),
             remove_dirs: vec![hooks_dir],
         }
@@ -328,15 +686,59 @@ impl TestingEnv {
 
     pub fn create_job(&self, hook_name: &str, req: Request) -> Job {
         let hook = self.hooks.get_by_name(&hook_name.to_string()).unwrap();
-        let (_, provider) = hook.validate(&req);
+        let (_, provider) = hook.validate(&req, Default::default());
 
-        Job::new(hook.clone(), provider, req)
+        Job::new(self.hooks.next_job_id(), hook.clone(), provider, req)
     }
 
     // WEB TESTING
 
     pub fn start_web(&self, health: bool, behind_proxies: u8)
                      -> WebAppInstance {
-        WebAppInstance::new(self.hooks.clone(), health, behind_proxies)
+        self.start_web_with(WebConfig {
+            enable_health: health,
+            behind_proxies: behind_proxies,
+            .. WebConfig::default()
+        })
+    }
+
+    pub fn start_web_with(&self, config: WebConfig) -> WebAppInstance {
+        WebAppInstance::new(self.hooks.clone(), &WebConfig {
+            bind: "127.0.0.1:0".into(),
+            .. config
+        })
+    }
+
+    // FULL APP TESTING
+
+    pub fn start_full(&self, max_threads: u16) -> FullInstance {
+        self.start_full_with(
+            max_threads, Context::default(), WebConfig::default(),
+        )
+    }
+
+    pub fn start_full_with(&self, max_threads: u16, ctx: Context,
+                           config: WebConfig) -> FullInstance {
+        let processor = Processor::new(
+            self.hooks.clone(), Arc::new(ctx), self.state.clone(),
+            ProcessorConfig {
+                max_threads: max_threads,
+                .. ProcessorConfig::default()
+            },
+        ).unwrap();
+
+        let web = WebApp::new(self.hooks.clone(), &WebConfig {
+            bind: "127.0.0.1:0".into(),
+            .. config
+        }, processor.api()).unwrap();
+        let url = format!("http://{}", web.addr());
+
+        FullInstance {
+            processor: processor,
+            web: web,
+
+            url: url,
+            client: hyper::Client::new(),
+        }
     }
 }
@@ -0,0 +1,126 @@
+// Copyright (C) 2016-2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use rand::{self, Rng, XorShiftRng};
+use tiny_http::Method;
+
+use utils::Redactor;
+
+
+pub struct AccessLog {
+    sample_rate: f64,
+    rng: XorShiftRng,
+    redactor: Redactor,
+}
+
+impl AccessLog {
+
+    pub fn new(sample_rate: f64, redactor: Redactor) -> Self {
+        AccessLog {
+            sample_rate: sample_rate,
+            rng: rand::weak_rng(),
+            redactor: redactor,
+        }
+    }
+
+    fn should_log(&mut self, status: u16) -> bool {
+        // Errors and rejections are always logged
+        if status >= 400 || self.sample_rate >= 1.0 {
+            true
+        } else if self.sample_rate <= 0.0 {
+            false
+        } else {
+            self.rng.next_f64() < self.sample_rate
+        }
+    }
+
+    pub fn log(&mut self, source: &IpAddr, method: &Method, url: &str,
+               status: u16, duration: Duration) {
+        if ! self.should_log(status) {
+            return;
+        }
+
+        println!("{}", self.line(source, method, url, status, duration));
+    }
+
+    fn line(&self, source: &IpAddr, method: &Method, url: &str,
+            status: u16, duration: Duration) -> String {
+        let millis = duration.as_secs() * 1000
+                   + (duration.subsec_nanos() / 1_000_000) as u64;
+
+        // The query string might contain secrets
+        format!("{} \"{} {}\" {} {}ms",
+            source, method, self.redactor.url(url), status, millis,
+        )
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tiny_http::Method;
+
+    use utils::Redactor;
+
+    use super::AccessLog;
+
+
+    #[test]
+    fn test_sampling() {
+        const REQUESTS: usize = 10000;
+
+        macro_rules! logged {
+            ($log:expr, $status:expr) => {{
+                let mut log = $log;
+                (0..REQUESTS).filter(|_| log.should_log($status)).count()
+            }};
+        }
+
+        let new = |rate| AccessLog::new(rate, Redactor::default());
+
+        // Everything is logged with a sample rate of 1
+        assert_eq!(logged!(new(1.0), 200), REQUESTS);
+
+        // Only a fraction of the requests is logged with a lower rate
+        let count = logged!(new(0.1), 200);
+        assert!(count > 800 && count < 1200, "{} requests logged", count);
+
+        // Errors and rejections are always logged
+        assert_eq!(logged!(new(0.1), 403), REQUESTS);
+        assert_eq!(logged!(new(0.0), 400), REQUESTS);
+        assert_eq!(logged!(new(0.0), 200), 0);
+    }
+
+
+    #[test]
+    fn test_redaction() {
+        let log = AccessLog::new(1.0, Redactor::new(&["user"]));
+
+        // Sensitive params are masked, while the others are logged
+        assert_eq!(log.line(
+            &"127.0.0.1".parse().unwrap(), &Method::Post,
+            "/hook/a.sh?secret=abc&user=pietro&ref=master", 200,
+            Duration::from_millis(42),
+        ), concat!(
+            r#"127.0.0.1 "POST /hook/a.sh?secret=[redacted]&user=[redacted]"#,
+            r#"&ref=master" 200 42ms"#,
+        ));
+    }
+}
@@ -13,82 +13,649 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ansi_term::Colour;
 
 use fisher_common::prelude::*;
+use fisher_common::auth::{constant_time_eq, Authorization};
 
 use requests::{Request, RequestType};
-use hooks::Hooks;
+use hooks::{Hook, Hooks};
 use jobs::Job;
-use web::responses::Response;
+use job_logs;
+use event_log;
+use providers::ProviderErrorPolicy;
+use utils::Redactor;
+use web::responses::{JobResult, Response};
+use web::app::WebConfig;
+use web::rate_limit::RateLimiter;
+use web::request_log::RequestLog;
+
+
+// Clients can ask to wait for the output of the job with the `wait` param
+fn wants_output(req: &Request) -> bool {
+    match req.web() {
+        Ok(web) => match web.params.get("wait").map(|wait| wait.as_str()) {
+            Some("1") | Some("true") => true,
+            _ => false,
+        },
+        Err(..) => false,
+    }
+}
+
+
+fn log_request(log: &Option<Arc<Mutex<RequestLog>>>, source: Option<IpAddr>,
+               hook: &str, decision: Option<RequestType>,
+               response: &Response) {
+    if let Some(ref log) = *log {
+        log.lock().unwrap().log(source, hook, decision, response.status());
+    }
+}
+
+
+const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const GIT_HASH: &'static str = env!("FISHER_GIT_HASH");
+
+
+// Clients are told to retry after this many seconds when there is no
+// estimate of how long the queue takes to drain
+const DEFAULT_RETRY_AFTER: u64 = 60;
+const MAX_RETRY_AFTER: u64 = 3600;
+
+// How many acknowledged requests can wait to be validated
+const ACK_QUEUE_SIZE: usize = 1024;
+
+
+type AckedRequest = (Arc<Hook>, Request, HashMap<String, String>);
+
+
+// Releases the slot of a client waiting for the output of a job
+struct Waiter(Arc<AtomicUsize>);
+
+impl Drop for Waiter {
+
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+fn queue_error(error: Error, drain_time: Option<Duration>) -> Response {
+    // Round up, so clients don't retry before the queue drained
+    let retry_after = drain_time.map(|time| {
+        time.as_secs() + if time.subsec_nanos() > 0 { 1 } else { 0 }
+    }).map(|secs| secs.max(1).min(MAX_RETRY_AFTER))
+      .unwrap_or(DEFAULT_RETRY_AFTER);
+
+    match *error.kind() {
+        // The processor is shedding the low-priority jobs
+        ErrorKind::Overloaded => Response::RetryLater(
+            "overloaded", retry_after,
+        ),
+        ErrorKind::QueueFull => Response::RetryLater(
+            "the queue is full", retry_after,
+        ),
+        _ => panic!("failed to queue the job: {}", error),
+    }
+}
 
 
-#[derive(Clone)]
 pub struct WebApi<A: ProcessorApiTrait<Hooks>> {
     processor: Arc<Mutex<A>>,
     hooks: Arc<Hooks>,
     locked: Arc<AtomicBool>,
+    last_request: Arc<Mutex<Instant>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
 
     health_enabled: bool,
+    health_secret: Option<String>,
+    provider_errors: ProviderErrorPolicy,
+    open_hooks: Vec<String>,
+    ack_queue: Option<mpsc::SyncSender<AckedRequest>>,
+    logs_dir: Option<PathBuf>,
+    logs_token: Option<String>,
+    wait_timeout: Duration,
+    waiters: Arc<AtomicUsize>,
+    max_waiters: usize,
+    redactor: Redactor,
+    environment: HashMap<String, String>,
+    request_log: Option<Arc<Mutex<RequestLog>>>,
 }
 
-impl<A: ProcessorApiTrait<Hooks>> WebApi<A> {
+// This is implemented manually, since the derive would require the processor
+// API to be cloneable too
+impl<A: ProcessorApiTrait<Hooks>> Clone for WebApi<A> {
 
-    pub fn new(processor: A, hooks: Arc<Hooks>, locked: Arc<AtomicBool>,
-               health_enabled: bool) -> Self {
+    fn clone(&self) -> Self {
         WebApi {
+            processor: self.processor.clone(),
+            hooks: self.hooks.clone(),
+            locked: self.locked.clone(),
+            last_request: self.last_request.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            health_enabled: self.health_enabled,
+            health_secret: self.health_secret.clone(),
+            provider_errors: self.provider_errors,
+            open_hooks: self.open_hooks.clone(),
+            ack_queue: self.ack_queue.clone(),
+            logs_dir: self.logs_dir.clone(),
+            logs_token: self.logs_token.clone(),
+            wait_timeout: self.wait_timeout,
+            waiters: self.waiters.clone(),
+            max_waiters: self.max_waiters,
+            redactor: self.redactor.clone(),
+            environment: self.environment.clone(),
+            request_log: self.request_log.clone(),
+        }
+    }
+}
+
+impl<A: ProcessorApiTrait<Hooks> + 'static> WebApi<A> {
+
+    pub fn new(processor: A, hooks: Arc<Hooks>, locked: Arc<AtomicBool>,
+               last_request: Arc<Mutex<Instant>>, config: &WebConfig,
+               request_log: Option<Arc<Mutex<RequestLog>>>) -> Self {
+        let mut api = WebApi {
             processor: Arc::new(Mutex::new(processor)),
             hooks: hooks,
             locked: locked,
-            health_enabled: health_enabled,
+            last_request: last_request,
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::new())),
+            health_enabled: config.enable_health,
+            health_secret: config.health_secret.clone(),
+            provider_errors: config.provider_errors,
+            open_hooks: config.open_hooks.clone(),
+            ack_queue: None,
+            logs_dir: config.logs_dir.clone(),
+            logs_token: config.logs_token.clone(),
+            wait_timeout: config.wait_timeout,
+            waiters: Arc::new(AtomicUsize::new(0)),
+            max_waiters: config.max_waiters,
+            redactor: config.redactor.clone(),
+            environment: config.environment.clone(),
+            request_log: request_log,
+        };
+
+        // The acknowledged requests are validated by a single worker, which
+        // exits once all the clones of the API are dropped. Its own clone
+        // doesn't have the queue, so it doesn't keep itself alive.
+        if config.ack_fast {
+            let (queue_send, queue_recv) =
+                mpsc::sync_channel::<AckedRequest>(ACK_QUEUE_SIZE);
+            let worker = api.clone();
+            thread::spawn(move || {
+                for (hook, req, path_params) in queue_recv.iter() {
+                    worker.validate_acked(&hook, &req, path_params);
+                }
+            });
+            api.ack_queue = Some(queue_send);
         }
+
+        api
     }
 
     pub fn process_hook(&self, req: &Request, args: Vec<String>) -> Response {
         let hook_name = &args[0];
 
+        // Keep track of the activity, so idle instances can be shut down
+        *self.last_request.lock().unwrap() = Instant::now();
+
         // Don't process hooks if the web api is locked
         if self.locked.load(Ordering::Relaxed) {
-            return Response::Unavailable;
+            return self.rejected(
+                req, hook_name, Response::Unavailable("fisher is locked"),
+            );
+        }
+
+        // Check if the hook exists, falling back to the hooks which claim
+        // the path with a pattern
+        let (hook, path_params) = if let Some(found) =
+            self.hooks.get_by_name(hook_name)
+        {
+            (found, HashMap::new())
+        } else if let Some(found) = self.hooks.get_by_path(hook_name) {
+            found
+        } else {
+            return self.rejected(
+                req, hook_name, Response::NotFound("unknown hook"),
+            );
+        };
+
+        // Reject the requests over the rate limit of the hook, before doing
+        // any work on them
+        if let (Some(limit), Ok(web)) = (hook.rate_limit(), req.web()) {
+            let retry_after = {
+                let mut limiter = self.rate_limiter.lock().unwrap();
+                if limiter.allow(hook.name(), web.source, &limit) {
+                    None
+                } else {
+                    Some(limiter.retry_after(hook.name(), web.source, &limit))
+                }
+            };
+            if let Some(secs) = retry_after {
+                return self.rejected(
+                    req, hook.name(), Response::TooManyRequests(secs),
+                );
+            }
+        }
+
+        // Reply right away, and validate the request in the background,
+        // unless the client wants to wait for the output of the job
+        if let Some(ref queue) = self.ack_queue {
+            if ! wants_output(req) {
+                let name = hook.name().to_string();
+                let acked = (hook, req.clone(), path_params);
+                return match queue.try_send(acked) {
+                    Ok(()) => Response::Ok,
+                    Err(..) => self.rejected(
+                        req, &name, Response::RetryLater(
+                            "too many requests are waiting",
+                            DEFAULT_RETRY_AFTER,
+                        ),
+                    ),
+                };
+            }
+        }
+
+        let (decision, response) = self.execute_hook(&hook, req, path_params);
+        self.log_request(req, hook.name(), Some(decision), &response);
+        response
+    }
+
+    // Validate and queue a request the client already received the
+    // acknowledgement for
+    fn validate_acked(&self, hook: &Arc<Hook>, req: &Request,
+                      path_params: HashMap<String, String>) {
+        // Fisher could have been locked while the request was waiting
+        let (decision, response) = if self.locked.load(Ordering::Relaxed) {
+            (None, Response::Unavailable("fisher is locked"))
+        } else {
+            let (decision, response) = self.execute_hook(
+                hook, req, path_params,
+            );
+            (Some(decision), response)
+        };
+
+        if let Some((_, reason)) = response.error() {
+            if event_log::json_output() {
+                event_log::log_message("rejected", json!({
+                    "hook": hook.name(),
+                    "reason": reason,
+                }));
+            } else {
+                println!("{} request to {}: {}",
+                    Colour::Yellow.bold().paint("Rejected"), hook.name(),
+                    reason,
+                );
+            }
         }
 
-        // Check if the hook exists
-        let hook;
-        if let Some(found) = self.hooks.get_by_name(hook_name) {
-            hook = found;
+        // The client already received the acknowledgement
+        self.log_request(req, hook.name(), decision, &Response::Ok);
+    }
+
+    /// Check if the hook wants the request body streamed to disk. This is
+    /// called before the body is read.
+    pub fn streams_body(&self, args: Vec<String>) -> bool {
+        let hook_name = &args[0];
+        if let Some(hook) = self.hooks.get_by_name(hook_name) {
+            hook.streaming_body()
+        } else if let Some((hook, _)) = self.hooks.get_by_path(hook_name) {
+            hook.streaming_body()
         } else {
-            return Response::NotFound;
+            false
         }
+    }
 
-        // Validate the hook
-        let (request_type, provider) = hook.validate(req);
+    // Log the requests rejected before the providers validated them
+    fn rejected(&self, req: &Request, hook_name: &str, response: Response)
+                -> Response {
+        self.log_request(req, hook_name, None, &response);
+        response
+    }
+
+    fn log_request(&self, req: &Request, hook_name: &str,
+                   decision: Option<RequestType>, response: &Response) {
+        // The deferred responses are logged by wait_job once they're ready
+        if let Response::Deferred(..) = *response {
+            return;
+        }
+
+        let source = req.web().ok().map(|web| web.source);
+        log_request(&self.request_log, source, hook_name, decision, response);
+    }
+
+    fn execute_hook(&self, hook: &Arc<Hook>, req: &Request,
+                    path_params: HashMap<String, String>)
+                    -> (RequestType, Response) {
+        let hook_name = hook.name();
+
+        // Validate the hook, unless it's explicitly allowed to be called
+        // without going through its providers
+        let open = self.open_hooks.iter().any(|name| name == hook_name);
+        let (request_type, provider, reason) = if open {
+            (RequestType::ExecuteHook, None, None)
+        } else {
+            hook.validate_explained(req, self.provider_errors)
+        };
 
         // Change behavior based on the request type
-        match request_type {
-            // Don't do anything if it's only a ping
-            RequestType::Ping => Response::Ok,
+        let response = match request_type {
+            // Don't do anything if it's only a ping, but tell the client
+            // which hook and provider answered it
+            RequestType::Ping => Response::Ping(
+                hook_name.to_string(),
+                provider.map(|provider| provider.name().to_string())
+                        .unwrap_or_default(),
+            ),
 
             // Queue a job if the hook should be executed
             RequestType::ExecuteHook => {
-                let job = Job::new(hook.clone(), provider, req.clone());
-                self.processor.lock().unwrap()
-                              .queue(job, hook.priority()).unwrap();
+                // Reject bodies the hook doesn't expect
+                if let Ok(web) = req.web() {
+                    if ! hook.accepts_body_size(web.body_size()) {
+                        return (request_type, Response::BadRequest(
+                            ErrorKind::InvalidInput(format!(
+                                "the hook doesn't accept a body of {} bytes",
+                                web.body_size(),
+                            )).into()
+                        ));
+                    }
+                }
+
+                let job_id = self.hooks.next_job_id();
+
+                // Render the response before queueing the job, so it's not
+                // queued if the response can't be rendered
+                let response = if let Some(template) = hook.response() {
+                    let rendered = req.web().and_then(|web| {
+                        template.render(hook, job_id, web)
+                    });
+                    match rendered {
+                        Ok(body) => Response::Text(
+                            body, template.content_type().map(String::from),
+                        ),
+                        Err(error) => return (
+                            request_type, Response::BadRequest(error),
+                        ),
+                    }
+                } else {
+                    Response::Ok
+                };
+
+                let accepted_status = provider.as_ref()
+                    .and_then(|provider| provider.accepted_status());
+                let mut job = Job::new(
+                    job_id, hook.clone(), provider, req.clone(),
+                );
+                job.set_path_params(path_params);
 
-                Response::Ok
+                if wants_output(req) {
+                    return (request_type, self.wait_job(job, hook, req));
+                }
+
+                let queued = self.processor.lock().unwrap()
+                                 .queue(job, hook.priority());
+                match queued {
+                    // The provider can tell the client the job was queued
+                    // with a different status
+                    Ok(()) => match accepted_status {
+                        Some(status) => Response::WithStatus(
+                            status, Box::new(response),
+                        ),
+                        None => response,
+                    },
+                    Err(error) => queue_error(error, self.drain_time()),
+                }
             },
 
-            RequestType::Invalid => Response::Forbidden,
+            RequestType::Invalid => Response::Rejected(
+                reason.unwrap_or_else(|| "rejected by the providers".into()),
+            ),
+
+            // The request is fine, it just shouldn't execute the hook
+            RequestType::Skip => Response::Ok,
+        };
+
+        (request_type, response)
+    }
+
+    // Estimate how long the queue of the processor takes to drain
+    fn drain_time(&self) -> Option<Duration> {
+        self.processor.lock().unwrap().health_details().ok()
+            .and_then(|details| details.drain_time())
+    }
+
+    // Queue the job and reply with its output once it's executed. The wait
+    // happens in another thread, so the other requests aren't blocked.
+    fn wait_job(&self, job: Job, hook: &Hook, req: &Request) -> Response {
+        // Every waiting client costs a thread, so only a few of them are
+        // allowed at the same time
+        let waiter = Waiter(self.waiters.clone());
+        if self.waiters.fetch_add(1, Ordering::SeqCst) >= self.max_waiters {
+            return Response::RetryLater(
+                "too many clients are waiting",
+                self.wait_timeout.as_secs().max(1),
+            );
+        }
+
+        let queued = self.processor.lock().unwrap()
+                         .queue_wait(job, hook.priority());
+        let output_recv = match queued {
+            Ok(recv) => recv,
+            Err(error) => return queue_error(error, self.drain_time()),
+        };
+
+        // The secrets in the output are redacted like in the logs
+        let redactor = self.redactor.clone();
+        let mut values = self.environment.clone();
+        if let Ok(web) = req.web() {
+            values.extend(web.params.clone());
+            values.extend(web.headers.clone());
+        }
+
+        let request_log = self.request_log.clone();
+        let source = req.web().ok().map(|web| web.source);
+        let hook_name = hook.name().to_string();
+
+        let timeout = self.wait_timeout;
+        let (response_send, response_recv) = mpsc::channel();
+        thread::spawn(move || {
+            let _waiter = waiter;
+
+            let response = match output_recv.recv_timeout(timeout) {
+                Ok(Ok(output)) => Response::JobOutput(JobResult {
+                    success: output.success,
+                    exit_code: output.exit_code,
+                    signal: output.signal,
+                    timed_out: output.timed_out,
+                    stdout: redactor.text(&output.stdout, &values),
+                    error: None,
+                }),
+                // The client isn't kept waiting through the retries
+                Ok(Err(error)) => Response::JobOutput(JobResult {
+                    success: false,
+                    exit_code: None,
+                    signal: None,
+                    timed_out: false,
+                    stdout: String::new(),
+                    error: Some(redactor.text(&error, &values)),
+                }),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    Response::Timeout("the job is still running")
+                },
+                // The job was dropped or it failed to start
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    Response::Unavailable("the job wasn't executed")
+                },
+            };
+
+            log_request(
+                &request_log, source, &hook_name,
+                Some(RequestType::ExecuteHook), &response,
+            );
+            let _ = response_send.send(response);
+        });
+
+        Response::Deferred(response_recv)
+    }
+
+    // The health details are public unless a secret is configured, which
+    // must be provided either as a query param or as a Bearer token. The
+    // response to return is provided if the request isn't authorized.
+    fn health_rejection(&self, req: &Request) -> Option<Response> {
+        if ! self.health_enabled {
+            return Some(Response::Forbidden("health endpoints disabled"));
+        }
+
+        let secret = match self.health_secret {
+            Some(ref secret) => secret,
+            None => return None,
+        };
+
+        let req = match req.web() {
+            Ok(req) => req,
+            Err(..) => return Some(Response::Forbidden("missing secret")),
+        };
+        let provided = req.params.get("secret").cloned().or_else(|| {
+            let header = req.headers.get("Authorization");
+            match header.and_then(|header| Authorization::parse(header)) {
+                Some(Authorization::Bearer(token)) => Some(token),
+                _ => None,
+            }
+        });
+
+        match provided {
+            Some(ref found) if constant_time_eq(
+                found.as_bytes(), secret.as_bytes(),
+            ) => None,
+            Some(..) => Some(Response::Forbidden("invalid secret")),
+            None => Some(Response::Forbidden("missing secret")),
         }
     }
 
-    pub fn get_health(&self, _req: &Request, _args: Vec<String>) -> Response {
-        if self.health_enabled {
-            Response::HealthStatus(
-                self.processor.lock().unwrap().health_details().unwrap()
-            )
-        } else {
-            Response::Forbidden
+    pub fn get_health(&self, req: &Request, _args: Vec<String>) -> Response {
+        if let Some(rejection) = self.health_rejection(req) {
+            return rejection;
+        }
+
+        // Only the hooks with the label are reported, if one is requested
+        let label = req.web().ok().and_then(|web| web.params.get("label"));
+        let included = |hook: &Hook| {
+            label.map(|label| hook.has_label(label)).unwrap_or(true)
+        };
+
+        let mut rate_limits = HashMap::new();
+        {
+            let mut limiter = self.rate_limiter.lock().unwrap();
+            for hook in self.hooks.iter().filter(|hook| included(hook)) {
+                if let Some(limit) = hook.rate_limit() {
+                    rate_limits.insert(
+                        hook.name().to_string(),
+                        limiter.status(hook.name(), &limit),
+                    );
+                }
+            }
+        }
+
+        let processor = self.processor.lock().unwrap();
+        let mut details = match label {
+            Some(label) => processor.labeled_health_details(label).unwrap(),
+            None => processor.health_details().unwrap(),
+        };
+        if label.is_some() {
+            let hooks = &self.hooks;
+            details.hooks.retain(|name, _| {
+                hooks.get_by_name(name).map_or(false, |hook| included(&hook))
+            });
+        }
+
+        Response::HealthStatus(details, rate_limits)
+    }
+
+    pub fn get_hook_history(&self, req: &Request, args: Vec<String>)
+                            -> Response {
+        if let Some(rejection) = self.health_rejection(req) {
+            return rejection;
+        }
+
+        let hook_name = &args[0];
+        if self.hooks.get_by_name(hook_name).is_none() {
+            return Response::NotFound("unknown hook");
+        }
+
+        Response::HookHistory(
+            self.processor.lock().unwrap().history(hook_name).unwrap()
+        )
+    }
+
+    pub fn get_hooks(&self, req: &Request, _args: Vec<String>) -> Response {
+        if let Some(rejection) = self.health_rejection(req) {
+            return rejection;
+        }
+
+        // The paths of the hooks are shown only if the secret was required
+        // and provided
+        let with_exec = self.health_secret.is_some();
+
+        let mut hooks = self.hooks.iter()
+            .map(|hook| hook.summary(with_exec))
+            .collect::<Vec<_>>();
+        hooks.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Response::HooksList(hooks)
+    }
+
+    // The version isn't a secret, so it's served even without the health
+    // secret
+    pub fn get_version(&self, _req: &Request, _args: Vec<String>)
+                       -> Response {
+        Response::Version(VERSION, GIT_HASH)
+    }
+
+    pub fn get_metrics(&self, req: &Request, _args: Vec<String>) -> Response {
+        if let Some(rejection) = self.health_rejection(req) {
+            return rejection;
+        }
+
+        Response::Metrics(
+            self.processor.lock().unwrap().health_details().unwrap()
+        )
+    }
+
+    pub fn get_job_log(&self, req: &Request, args: Vec<String>) -> Response {
+        // The logs are served only if both a directory and a token are set
+        let (dir, token) = match (&self.logs_dir, &self.logs_token) {
+            (&Some(ref dir), &Some(ref token)) => (dir, token),
+            _ => return Response::NotFound("job logs disabled"),
+        };
+
+        let req = match req.web() {
+            Ok(req) => req,
+            Err(..) => return Response::Forbidden("missing token"),
+        };
+        let authorized = req.params.get("token")
+            .or_else(|| req.headers.get("X-Fisher-Token"))
+            .map(|found| constant_time_eq(found.as_bytes(), token.as_bytes()))
+            .unwrap_or(false);
+        if ! authorized {
+            return Response::Forbidden("invalid token");
+        }
+
+        match job_logs::read(dir, &args[0]) {
+            Ok(Some(log)) => Response::Text(log, None),
+            Ok(None) => Response::NotFound("unknown job"),
+            Err(error) => {
+                error.pretty_print();
+                Response::Unavailable("failed to read the job log")
+            },
         }
     }
 }
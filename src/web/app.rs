@@ -13,63 +13,297 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use tiny_http::Method;
 
 use fisher_common::prelude::*;
 
 use hooks::Hooks;
-use web::http::HttpServer;
+use providers::ProviderErrorPolicy;
+use web::http::{HttpServer, TlsConfig};
 use web::api::WebApi;
+use web::access_log::AccessLog;
+use web::request_log::{RequestLog, DEFAULT_REQUEST_LOG_MAX_SIZE};
+use web::proxies::DEFAULT_PROXY_HEADERS;
+use utils::{IpNetwork, Redactor};
+
+
+/// The default maximum size of the request bodies, in bytes.
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The default maximum number of headers of a request.
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+/// The default maximum size of the headers of a request, in bytes.
+pub const DEFAULT_MAX_HEADERS_SIZE: usize = 32 * 1024;
+
+/// How long the clients can wait for the output of a job by default, in
+/// seconds.
+pub const DEFAULT_WAIT_TIMEOUT: u64 = 60;
+
+/// How many clients can wait for the output of a job at the same time by
+/// default.
+pub const DEFAULT_MAX_WAITERS: usize = 64;
+
+
+#[derive(Debug, Clone)]
+pub struct WebConfig {
+    pub bind: String,
+    pub extra_binds: Vec<String>,
+    pub admin_bind: Option<String>,
+    pub enable_health: bool,
+    pub health_secret: Option<String>,
+    pub behind_proxies: u8,
+    pub proxy_headers: Vec<String>,
+    pub trusted_proxies: Vec<IpNetwork>,
+    pub access_log: Option<f64>,
+    pub request_log: Option<PathBuf>,
+    pub request_log_max_size: u64,
+    pub max_body_memory: Option<usize>,
+    pub max_body_size: Option<u64>,
+    pub max_headers: Option<usize>,
+    pub max_headers_size: Option<usize>,
+    pub tls: Option<TlsConfig>,
+    pub provider_errors: ProviderErrorPolicy,
+    pub open_hooks: Vec<String>,
+    pub redactor: Redactor,
+    pub ack_fast: bool,
+    pub logs_dir: Option<PathBuf>,
+    pub logs_token: Option<String>,
+    pub url_prefix: String,
+    pub wait_timeout: Duration,
+    pub max_waiters: usize,
+    pub environment: HashMap<String, String>,
+}
+
+impl Default for WebConfig {
+
+    fn default() -> Self {
+        WebConfig {
+            bind: "127.0.0.1:8000".into(),
+            extra_binds: Vec::new(),
+            admin_bind: None,
+            enable_health: true,
+            health_secret: None,
+            behind_proxies: 0,
+            proxy_headers: DEFAULT_PROXY_HEADERS.iter()
+                .map(|name| name.to_string()).collect(),
+            trusted_proxies: Vec::new(),
+            access_log: None,
+            request_log: None,
+            request_log_max_size: DEFAULT_REQUEST_LOG_MAX_SIZE,
+            max_body_memory: None,
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            max_headers: Some(DEFAULT_MAX_HEADERS),
+            max_headers_size: Some(DEFAULT_MAX_HEADERS_SIZE),
+            tls: None,
+            provider_errors: ProviderErrorPolicy::default(),
+            open_hooks: Vec::new(),
+            redactor: Redactor::default(),
+            ack_fast: false,
+            logs_dir: None,
+            logs_token: None,
+            url_prefix: String::new(),
+            wait_timeout: Duration::from_secs(DEFAULT_WAIT_TIMEOUT),
+            max_waiters: DEFAULT_MAX_WAITERS,
+            environment: HashMap::new(),
+        }
+    }
+}
 
 
 pub struct WebApp<A: ProcessorApiTrait<Hooks> + 'static> {
     server: HttpServer<WebApi<A>>,
     addr: SocketAddr,
+    extra_servers: Vec<HttpServer<WebApi<A>>>,
+    extra_addrs: Vec<SocketAddr>,
+    admin_server: Option<HttpServer<WebApi<A>>>,
+    admin_addr: Option<SocketAddr>,
     locked: Arc<AtomicBool>,
+    last_request: Arc<Mutex<Instant>>,
 }
 
 impl<A: ProcessorApiTrait<Hooks>> WebApp<A> {
 
-    pub fn new(hooks: Arc<Hooks>, enable_health: bool, behind_proxies: u8,
-               bind: &str, processor: A)
+    pub fn new(hooks: Arc<Hooks>, config: &WebConfig, processor: A)
                -> Result<Self> {
         let locked = Arc::new(AtomicBool::new(false));
+        let last_request = Arc::new(Mutex::new(Instant::now()));
+
+        let request_log = match config.request_log {
+            Some(ref path) => Some(Arc::new(Mutex::new(
+                RequestLog::open(path, config.request_log_max_size)?
+            ))),
+            None => None,
+        };
 
         // Create the web api
-        let api = WebApi::new(processor, hooks, locked.clone(), enable_health);
+        let api = Arc::new(WebApi::new(
+            processor, hooks, locked.clone(), last_request.clone(), config,
+            request_log,
+        ));
 
         // Create the HTTP server
-        let mut server = HttpServer::new(api, behind_proxies);
+        let mut server = Self::create_public_server(api.clone(), config);
+        let socket = server.listen(&config.bind)?;
+
+        // Every additional address is served by its own HTTP server, sharing
+        // the same API
+        let mut extra_servers = Vec::with_capacity(config.extra_binds.len());
+        let mut extra_sockets = Vec::with_capacity(config.extra_binds.len());
+        for bind in &config.extra_binds {
+            let mut extra_server = Self::create_public_server(
+                api.clone(), config,
+            );
+            match extra_server.listen(bind) {
+                Ok(socket) => {
+                    extra_servers.push(extra_server);
+                    extra_sockets.push(socket);
+                },
+                Err(error) => {
+                    // Don't leave the other servers running
+                    server.stop();
+                    for mut extra_server in extra_servers {
+                        extra_server.stop();
+                    }
+                    return Err(error);
+                },
+            }
+        }
+
+        // The administrative endpoints are served by a separate HTTP server
+        // only if the user asked for it
+        let (admin_server, admin_socket) = match config.admin_bind {
+            Some(ref admin_bind) => {
+                let mut admin_server = Self::create_server(
+                    api.clone(), config,
+                );
+                Self::add_admin_routes(&mut admin_server);
+
+                match admin_server.listen(admin_bind) {
+                    Ok(socket) => (Some(admin_server), Some(socket)),
+                    Err(error) => {
+                        // Don't leave the other servers running
+                        server.stop();
+                        for mut extra_server in extra_servers {
+                            extra_server.stop();
+                        }
+                        return Err(error);
+                    },
+                }
+            },
+            None => (None, None),
+        };
+
+        Ok(WebApp {
+            server: server,
+            addr: socket,
+            extra_servers: extra_servers,
+            extra_addrs: extra_sockets,
+            admin_server: admin_server,
+            admin_addr: admin_socket,
+            locked: locked,
+            last_request: last_request,
+        })
+    }
+
+    fn create_server(api: Arc<WebApi<A>>, config: &WebConfig)
+                     -> HttpServer<WebApi<A>> {
+        let mut server = HttpServer::new(api, config.behind_proxies);
+        if let Some(sample_rate) = config.access_log {
+            server.set_access_log(
+                AccessLog::new(sample_rate, config.redactor.clone()),
+            );
+        }
+        server.set_max_body_memory(config.max_body_memory);
+        server.set_max_body_size(config.max_body_size);
+        server.set_max_headers(config.max_headers, config.max_headers_size);
+        server.set_tls(config.tls.clone());
+        server.set_proxy_headers(config.proxy_headers.clone());
+        server.set_trusted_proxies(config.trusted_proxies.clone());
+        server.set_url_prefix(&config.url_prefix);
+        server
+    }
+
+    // The servers receiving the hooks also serve the administrative
+    // endpoints, unless a separate server does
+    fn create_public_server(api: Arc<WebApi<A>>, config: &WebConfig)
+                            -> HttpServer<WebApi<A>> {
+        let mut server = Self::create_server(api, config);
+        if config.admin_bind.is_none() {
+            Self::add_admin_routes(&mut server);
+        }
+        Self::add_hooks_routes(&mut server);
+        server
+    }
+
+    fn add_hooks_routes(server: &mut HttpServer<WebApi<A>>) {
+        server.add_streaming_route(
+            Method::Get, "/hook/?",
+            Box::new(WebApi::process_hook),
+            Box::new(WebApi::streams_body),
+        );
+        server.add_streaming_route(
+            Method::Post, "/hook/?",
+            Box::new(WebApi::process_hook),
+            Box::new(WebApi::streams_body),
+        );
+    }
+
+    fn add_admin_routes(server: &mut HttpServer<WebApi<A>>) {
         server.add_route(
             Method::Get, "/health",
             Box::new(WebApi::get_health)
         );
         server.add_route(
-            Method::Get, "/hook/?",
-            Box::new(WebApi::process_hook)
+            Method::Get, "/metrics",
+            Box::new(WebApi::get_metrics)
         );
         server.add_route(
-            Method::Post, "/hook/?",
-            Box::new(WebApi::process_hook)
+            Method::Get, "/jobs/?/log",
+            Box::new(WebApi::get_job_log)
+        );
+        server.add_route(
+            Method::Get, "/history/?",
+            Box::new(WebApi::get_hook_history)
+        );
+        server.add_route(
+            Method::Get, "/hooks",
+            Box::new(WebApi::get_hooks)
+        );
+        server.add_route(
+            Method::Get, "/version",
+            Box::new(WebApi::get_version)
         );
-
-        let socket = server.listen(bind)?;
-
-        Ok(WebApp {
-            server: server,
-            addr: socket,
-            locked: locked,
-        })
     }
 
     pub fn addr(&self) -> &SocketAddr {
         &self.addr
     }
 
+    /// Return all the addresses the hooks are served on, starting with the
+    /// main one.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = vec![self.addr];
+        addrs.extend(self.extra_addrs.iter().cloned());
+        addrs
+    }
+
+    pub fn admin_addr(&self) -> Option<&SocketAddr> {
+        self.admin_addr.as_ref()
+    }
+
+    /// Return when the last request to a hook was received.
+    pub fn last_request(&self) -> Instant {
+        *self.last_request.lock().unwrap()
+    }
+
     pub fn lock(&self) {
         self.locked.store(true, Ordering::SeqCst);
     }
@@ -80,22 +314,37 @@ impl<A: ProcessorApiTrait<Hooks>> WebApp<A> {
 
     pub fn stop(mut self) {
         self.server.stop();
+        for mut extra_server in self.extra_servers {
+            extra_server.stop();
+        }
+        if let Some(mut admin_server) = self.admin_server {
+            admin_server.stop();
+        }
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use std::io::Read;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
 
     use serde_json;
+    use hyper::client::{Body, Client};
     use hyper::status::StatusCode;
     use hyper::method::Method;
     use hyper::header::Headers;
 
     use fisher_common::prelude::*;
 
+    use jobs::Context;
+    use providers::ProviderErrorPolicy;
     use utils::testing::*;
+    use utils;
+    use web::WebConfig;
 
 
     #[test]
@@ -144,10 +393,24 @@ mod tests {
         }
 
         // Call the example hook simulating a Ping
-        let res = inst.request(Method::Get, "/hook/example.sh?request_type=ping")
-                      .send().unwrap();
+        let mut res = inst.request(
+            Method::Get, "/hook/example.sh?request_type=ping",
+        ).send().unwrap();
         assert_eq!(res.status, StatusCode::Ok);
 
+        // The response tells which hook and provider answered the ping
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&content).unwrap(),
+            json!({
+                "status": "ok",
+                "ping": true,
+                "hook": "example.sh",
+                "provider": "Testing",
+            })
+        );
+
         // Even if the last request succeded, there shouldn't be any job
         assert!(inst.processor_input().is_none());
 
@@ -204,6 +467,30 @@ mod tests {
         // It shouldn't be possible to get the health status
         let res = inst.request(Method::Get, "/health").send().unwrap();
         assert_eq!(res.status, StatusCode::Forbidden);
+        let res = inst.request(Method::Get, "/metrics").send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        let res = inst.request(Method::Get, "/hooks").send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_version() {
+        // The version is served even with the health status disabled
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(false, 0);
+
+        let mut res = inst.request(Method::Get, "/version").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content)
+            .unwrap();
+        assert_eq!(data["version"], json!(env!("CARGO_PKG_VERSION")));
+        assert!(! data["git"].as_str().unwrap().is_empty());
 
         inst.stop();
         testing_env.cleanup();
@@ -218,6 +505,10 @@ mod tests {
         // Assert the request is OK
         let mut res = inst.request(Method::Get, "/health").send().unwrap();
         assert_eq!(res.status, StatusCode::Ok);
+        assert_eq!(
+            res.headers.get_raw("Content-Type"),
+            Some(&[b"application/json; charset=utf-8".to_vec()][..])
+        );
 
         // Decode the output
         let mut content = String::new();
@@ -242,6 +533,82 @@ mod tests {
             3 as u64
         );
 
+        // The same details are available in the Prometheus format
+        let mut res = inst.request(Method::Get, "/metrics").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert!(content.contains("\nfisher_queue_size 1\n"));
+        assert!(content.contains("\nfisher_active_jobs 2\n"));
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_health_label() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Only the jobs of the hooks with the label are counted
+        let mut res = inst.request(Method::Get, "/health?label=critical")
+            .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::LabeledHealthDetails(label)) => {
+                assert_eq!(label, "critical");
+            },
+            _ => panic!("the labeled health details weren't requested"),
+        }
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content)
+            .unwrap();
+        assert_eq!(data["result"]["queued_jobs"].as_u64(), Some(0));
+        assert_eq!(data["result"]["busy_threads"].as_u64(), Some(1));
+
+        // Without the label every job is counted
+        let res = inst.request(Method::Get, "/health").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::HealthDetails) => {},
+            _ => panic!("the health details weren't requested"),
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_hooks_list() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        let mut res = inst.request(Method::Get, "/hooks").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content)
+            .unwrap();
+
+        // The hooks are sorted by name, and their paths aren't shown
+        // without a secret
+        let hooks = data["result"].as_array().unwrap();
+        let names = hooks.iter()
+            .map(|hook| hook["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+
+        let example = hooks.iter()
+            .find(|hook| hook["name"].as_str() == Some("example.sh"))
+            .unwrap();
+        assert_eq!(example["providers"].as_array().unwrap().len(), 1);
+        assert_eq!(example["providers"][0].as_str(), Some("Testing"));
+        assert!(example.get("exec").is_none());
+
         inst.stop();
         testing_env.cleanup();
     }
@@ -273,4 +640,913 @@ mod tests {
         inst.stop();
         testing_env.cleanup();
     }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let testing_env = TestingEnv::new();
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Real-IP", vec![b"127.1.1.1".to_vec()]);
+
+        for &(network, status) in &[
+            ("127.0.0.0/8", StatusCode::Ok),
+            ("10.0.0.0/8", StatusCode::BadRequest),
+        ] {
+            let mut inst = testing_env.start_web_with(WebConfig {
+                behind_proxies: 1,
+                proxy_headers: vec!["X-Real-IP".into()],
+                trusted_proxies: vec![network.parse().unwrap()],
+                .. WebConfig::default()
+            });
+
+            // The header is only believed if the request comes from a
+            // trusted proxy
+            let res = inst.request(
+                Method::Get, "/hook/example.sh?ip=127.1.1.1",
+            ).headers(headers.clone()).send().unwrap();
+            assert_eq!(res.status, status);
+
+            inst.stop();
+        }
+
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_accepted_status() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // The provider changes the status of the queued requests
+        let mut res = inst.request(
+            Method::Get, "/hook/accepted.sh?secret=testing",
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::Accepted);
+        assert!(inst.processor_input().is_some());
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content)
+            .unwrap();
+        assert_eq!(data["status"].as_str(), Some("ok"));
+
+        // The rejected requests keep their status
+        let res = inst.request(Method::Get, "/hook/accepted.sh?secret=wrong")
+            .send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_response_template() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        macro_rules! assert_response {
+            ($url:expr, $status:expr, $body:expr, $type:expr) => {{
+                let mut res = inst.request(Method::Get, $url).send().unwrap();
+                assert_eq!(res.status, $status);
+                assert_eq!(
+                    res.headers.get_raw("Content-Type"),
+                    Some(&[$type.to_vec()][..])
+                );
+
+                let mut content = String::new();
+                res.read_to_string(&mut content).unwrap();
+                assert_eq!(content, $body.to_string());
+            }};
+        }
+
+        // The template is rendered with the request params
+        assert_response!(
+            "/hook/response.sh?ref=master", StatusCode::Ok,
+            "Queued response.sh from master", b"text/plain; charset=utf-8"
+        );
+        assert!(inst.processor_input().is_some());
+
+        // Missing keys are rendered empty by default
+        assert_response!(
+            "/hook/response.sh", StatusCode::Ok, "Queued response.sh from ",
+            b"text/plain; charset=utf-8"
+        );
+        assert!(inst.processor_input().is_some());
+
+        // Strict templates reject requests with missing keys, and the
+        // content type of the response can be overridden
+        assert_response!(
+            "/hook/strict-response.sh?ref=master", StatusCode::Ok,
+            "Queued strict-response.sh from master", b"text/markdown"
+        );
+        assert!(inst.processor_input().is_some());
+
+        let res = inst.request(Method::Get, "/hook/strict-response.sh")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::BadRequest);
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_body_size() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        macro_rules! assert_body {
+            ($body:expr, $status:expr) => {{
+                let res = inst.request(Method::Post, "/hook/body-size.sh")
+                              .body($body).send().unwrap();
+                assert_eq!(res.status, $status);
+            }};
+        }
+
+        // Bodies inside the range are accepted
+        assert_body!("a", StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+        assert_body!("aaaaaaaaaa", StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // Bodies under the minimum are rejected
+        assert_body!("", StatusCode::BadRequest);
+        assert!(inst.processor_input().is_none());
+
+        // Bodies over the maximum are rejected
+        assert_body!("aaaaaaaaaaa", StatusCode::BadRequest);
+        assert!(inst.processor_input().is_none());
+
+        // Hooks without a range accept every body
+        let res = inst.request(Method::Post, "/hook/example.sh")
+                      .body("").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Requests over the limit are rejected without queueing a job
+        for status in &[StatusCode::Ok, StatusCode::Ok,
+                        StatusCode::TooManyRequests] {
+            let res = inst.request(Method::Get, "/hook/rate-limit.sh")
+                          .send().unwrap();
+            assert_eq!(res.status, *status);
+            assert_eq!(
+                inst.processor_input().is_some(), *status == StatusCode::Ok
+            );
+
+            // Clients are told when the next token is earned
+            if *status == StatusCode::TooManyRequests {
+                assert_eq!(
+                    res.headers.get_raw("Retry-After"),
+                    Some(&[b"30".to_vec()][..])
+                );
+            } else {
+                assert!(res.headers.get_raw("Retry-After").is_none());
+            }
+        }
+
+        // Other hooks are not affected
+        let res = inst.request(Method::Get, "/hook/example.sh")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // The status of the limit is in the health details
+        let mut res = inst.request(Method::Get, "/health").send().unwrap();
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content)
+            .unwrap();
+        let limit = &data["result"]["rate_limits"]["rate-limit.sh"];
+        assert_eq!(limit["per_minute"].as_u64(), Some(2));
+        assert_eq!(limit["available"].as_u64(), Some(0));
+        assert!(data["result"]["rate_limits"].get("example.sh").is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_provider_errors() {
+        let testing_env = TestingEnv::new();
+        let url = "/hook/example.sh?provider_error=yes";
+
+        // By default requests which errored out are rejected
+        let mut inst = testing_env.start_web(true, 0);
+        let res = inst.request(Method::Get, url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+        inst.stop();
+
+        // With the open policy they're accepted instead
+        let mut inst = testing_env.start_web_with(WebConfig {
+            provider_errors: ProviderErrorPolicy::Open,
+            .. WebConfig::default()
+        });
+        let res = inst.request(Method::Get, url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+        inst.stop();
+
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_open_hooks() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            open_hooks: vec!["example.sh".into()],
+            .. WebConfig::default()
+        });
+
+        // Open hooks are queued even without a valid secret
+        let res = inst.request(Method::Get, "/hook/example.sh?secret=invalid")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        if let Some(ProcessorApiCall::Queue(job, _)) = inst.processor_input() {
+            assert_eq!(job.script_name(), "example.sh");
+        } else {
+            panic!("the open hook wasn't queued");
+        }
+
+        // Other hooks still require their providers to validate the request
+        let res = inst.request(Method::Get, "/hook/response.sh?secret=invalid")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_path_hooks() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Paths which are not hook names are matched with the patterns
+        let res = inst.request(Method::Get, "/hook/repo/fisher")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        // The captured variables are exported to the job
+        if let Some(ProcessorApiCall::Queue(job, _)) = inst.processor_input() {
+            assert_eq!(job.script_name(), "path.sh");
+
+            let output = job.execute(&Context::default()).unwrap();
+            assert_eq!(output.stdout, "fisher\n");
+        } else {
+            panic!("Wrong processor input received");
+        }
+
+        // Paths not matching any pattern are still missing
+        let res = inst.request(Method::Get, "/hook/repo/Fisher")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_ack_fast() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            ack_fast: true,
+            .. WebConfig::default()
+        });
+        let timeout = Duration::from_millis(500);
+
+        // Invalid requests are acknowledged, but they're still not queued
+        let res = inst.request(Method::Get, "/hook/example.sh?secret=invalid")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input_timeout(timeout).is_none());
+
+        // Valid requests are queued in the background
+        let res = inst.request(Method::Get, "/hook/example.sh?secret=testing")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input_timeout(timeout).is_some());
+
+        // Missing hooks are still rejected right away
+        let res = inst.request(Method::Get, "/hook/missing.sh")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_request_log() {
+        let mut testing_env = TestingEnv::new();
+
+        let dir = utils::create_temp_dir().unwrap();
+        testing_env.delete_also(dir.to_str().unwrap());
+        let path = dir.join("requests.log");
+
+        let mut inst = testing_env.start_web_with(WebConfig {
+            request_log: Some(path.clone()),
+            .. WebConfig::default()
+        });
+
+        for &(url, status) in &[
+            ("/hook/example.sh?secret=testing", StatusCode::Ok),
+            ("/hook/example.sh?secret=invalid", StatusCode::Forbidden),
+            ("/hook/example.sh?request_type=ping", StatusCode::Ok),
+            ("/hook/missing.sh", StatusCode::NotFound),
+            ("/hook/example.sh?wait=1", StatusCode::ServiceUnavailable),
+        ] {
+            let res = inst.request(Method::Get, url).send().unwrap();
+            assert_eq!(res.status, status);
+        }
+
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        let lines = content.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(lines.len(), 5);
+
+        // Every request is logged with the decision of the providers
+        for (line, &(hook, decision, status)) in lines.iter().zip(&[
+            ("example.sh", Some("accepted"), 200),
+            ("example.sh", Some("invalid"), 403),
+            ("example.sh", Some("ping"), 200),
+            ("missing.sh", None, 404),
+            ("example.sh", Some("accepted"), 503),
+        ]) {
+            assert_eq!(line["hook"].as_str(), Some(hook));
+            assert_eq!(line["decision"].as_str(), decision);
+            assert_eq!(line["status"].as_u64(), Some(status));
+            assert_eq!(line["source"].as_str(), Some("127.0.0.1"));
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_full_request() {
+        let mut testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_full(1);
+
+        // Create a directory which will contain the hook output
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap().to_string();
+        testing_env.delete_also(&output);
+
+        let res = inst.request(
+            Method::Post, &format!("/hook/jobs-details.sh?env={}", output),
+        ).body("a body!").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        inst.wait_idle();
+
+        // The hook was executed with the request body
+        let mut content = String::new();
+        fs::File::open(output_path.join("request_body")).unwrap()
+            .read_to_string(&mut content).unwrap();
+        assert_eq!(content, "a body!\n");
+
+        // The execution is reported in the health details
+        let details = inst.health_details();
+        assert_eq!(details.queued_jobs, 0);
+        assert_eq!(details.busy_threads, 0);
+        assert_eq!(details.max_threads, 1);
+
+        let stats = &details.hooks["jobs-details.sh"];
+        assert_eq!(stats.jobs.executed, 1);
+        assert_eq!(stats.jobs.failed, 0);
+
+        // The execution is also in the history of the hook
+        let mut res = inst.request(
+            Method::Get, "/history/jobs-details.sh",
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content)
+            .unwrap();
+        let history = data["result"].as_array().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0]["exit_code"].as_i64(), Some(0));
+        assert_eq!(history[0]["successful"].as_bool(), Some(true));
+        assert_eq!(history[0]["retry"].as_bool(), Some(false));
+
+        let res = inst.request(Method::Get, "/history/missing.sh")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        // The old path is handled as a hook, which doesn't exist
+        let res = inst.request(
+            Method::Get, "/hook/jobs-details.sh/history",
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_full_wait() {
+        let mut testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_full(1);
+
+        let output_path = utils::create_temp_dir().unwrap();
+        testing_env.delete_also(output_path.to_str().unwrap());
+        let output = output_path.join("output");
+
+        // The hook writes the file after half a second
+        let res = inst.request(
+            Method::Post, &format!("/hook/long.sh?env={}", output.display()),
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        assert!(! inst.wait_idle_timeout(Duration::from_millis(10)));
+        assert_eq!(inst.wait_file(&output, Duration::from_millis(10)), None);
+
+        assert_eq!(
+            inst.wait_file(&output, Duration::from_secs(10)),
+            Some("ok\n".into())
+        );
+        assert!(inst.wait_idle_timeout(Duration::from_secs(10)));
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_wait_output() {
+        let mut testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_full_with(1, Context::default(),
+            WebConfig {
+                wait_timeout: Duration::from_millis(100),
+                .. WebConfig::default()
+            },
+        );
+
+        // The closure borrows the instance until the end of the block
+        {
+            let mut wait = |url: &str| {
+                let mut res = inst.request(Method::Get, url).send().unwrap();
+                let mut content = String::new();
+                res.read_to_string(&mut content).unwrap();
+                let data = serde_json::from_str::<serde_json::Value>(
+                    &content,
+                ).unwrap();
+                (res.status, data)
+            };
+
+            // The output of the job is returned, without the secrets
+            let (status, data) = wait("/hook/example.sh?wait=1&token=world");
+            assert_eq!(status, StatusCode::Ok);
+            assert_eq!(data["result"]["success"].as_bool(), Some(true));
+            assert_eq!(data["result"]["exit_code"].as_i64(), Some(0));
+            assert_eq!(
+                data["result"]["stdout"].as_str(), Some("Hello [redacted]\n"),
+            );
+
+            // Failed jobs are reported in the result
+            let (status, data) = wait("/hook/failing.sh?wait=1");
+            assert_eq!(status, StatusCode::Ok);
+            assert_eq!(data["result"]["success"].as_bool(), Some(false));
+            assert_eq!(data["result"]["exit_code"].as_i64(), Some(1));
+            assert!(data["result"]["error"].is_null());
+
+            // So are the jobs which couldn't be executed at all
+            let (status, data) = wait("/hook/missing-interpreter.sh?wait=1");
+            assert_eq!(status, StatusCode::Ok);
+            assert_eq!(data["result"]["success"].as_bool(), Some(false));
+            assert!(data["result"]["exit_code"].is_null());
+            assert!(data["result"]["error"].is_string());
+
+            // The client stops waiting after the timeout
+            let (status, data) = wait("/hook/long.sh?wait=1");
+            assert_eq!(status, StatusCode::GatewayTimeout);
+            assert_eq!(data["code"].as_str(), Some("timeout"));
+        }
+
+        inst.wait_idle();
+        inst.stop();
+
+        // Without a processor executing them no output is ever sent
+        let mut inst = testing_env.start_web(true, 0);
+        let res = inst.request(Method::Get, "/hook/example.sh?wait=1")
+            .send().unwrap();
+        assert_eq!(res.status, StatusCode::ServiceUnavailable);
+        assert!(inst.processor_input().is_some());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_max_waiters() {
+        let mut testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_full_with(1, Context::default(),
+            WebConfig {
+                max_waiters: 1,
+                .. WebConfig::default()
+            },
+        );
+
+        // Keep the only slot busy from another client
+        let url = format!("{}/hook/long.sh?wait=1", inst.url());
+        let waiting = thread::spawn(move || {
+            Client::new().get(&url).send().unwrap().status
+        });
+        thread::sleep(Duration::from_millis(200));
+
+        // The other clients can't wait, and their jobs aren't queued
+        let res = inst.request(Method::Get, "/hook/example.sh?wait=1")
+            .send().unwrap();
+        assert_eq!(res.status, StatusCode::ServiceUnavailable);
+        assert_eq!(waiting.join().unwrap(), StatusCode::Ok);
+
+        // The slot is released once the output is sent
+        let res = inst.request(Method::Get, "/hook/example.sh?wait=1")
+            .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        inst.wait_idle();
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_job_log() {
+        let mut testing_env = TestingEnv::new();
+
+        let logs = utils::create_temp_dir().unwrap();
+        testing_env.delete_also(logs.to_str().unwrap());
+
+        let mut inst = testing_env.start_full_with(1, Context {
+            logs_dir: Some(logs.clone()),
+            .. Context::default()
+        }, WebConfig {
+            logs_dir: Some(logs.clone()),
+            logs_token: Some("hunter2".into()),
+            .. WebConfig::default()
+        });
+
+        // The hook replies with the ID of the log of its job
+        let mut res = inst.request(Method::Get, "/hook/job-log.sh")
+            .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut log_id = String::new();
+        res.read_to_string(&mut log_id).unwrap();
+        inst.wait_idle();
+
+        // The output of the job can be fetched back
+        let mut res = inst.request(
+            Method::Get, &format!("/jobs/{}/log?token=hunter2", log_id),
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert_eq!(content, concat!(
+            "--- stdout ---\nHello stdout\n",
+            "--- stderr ---\nHello stderr\n",
+        ));
+
+        // The token can also be provided in a header
+        let mut headers = Headers::new();
+        headers.set_raw("X-Fisher-Token", vec![b"hunter2".to_vec()]);
+        let res = inst.request(Method::Get, &format!("/jobs/{}/log", log_id))
+            .headers(headers).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        // The token is required
+        for url in &[
+            format!("/jobs/{}/log", log_id),
+            format!("/jobs/{}/log?token=wrong", log_id),
+        ] {
+            let res = inst.request(Method::Get, url).send().unwrap();
+            assert_eq!(res.status, StatusCode::Forbidden);
+        }
+
+        // Unknown jobs don't have any log
+        let res = inst.request(Method::Get, "/jobs/1000/log?token=hunter2")
+            .send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        inst.stop();
+
+        // Without a token the logs aren't served at all
+        let mut inst = testing_env.start_full_with(1, Context::default(),
+            WebConfig {
+                logs_dir: Some(logs.clone()),
+                .. WebConfig::default()
+            },
+        );
+        let res = inst.request(
+            Method::Get, &format!("/jobs/{}/log?token=hunter2", log_id),
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_large_body() {
+        let mut testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            max_body_memory: Some(1024),
+            .. WebConfig::default()
+        });
+
+        // Create a directory which will contain the hook output
+        let output_path = utils::create_temp_dir().unwrap();
+        let output = output_path.to_str().unwrap().to_string();
+        testing_env.delete_also(&output);
+
+        // Send a body bigger than the limit
+        let body = "a".repeat(64 * 1024);
+        let res = inst.request(
+            Method::Post, &format!("/hook/jobs-details.sh?env={}", output),
+        ).body(&*body).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let job = match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(job, _)) => job,
+            _ => panic!("the job wasn't queued"),
+        };
+
+        // The hook must receive the whole body
+        job.execute(&Context::default()).unwrap();
+        let mut content = String::new();
+        fs::File::open(output_path.join("request_body")).unwrap()
+            .read_to_string(&mut content).unwrap();
+        assert_eq!(content, format!("{}\n", body));
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_max_body_size() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            max_body_size: Some(1024),
+            .. WebConfig::default()
+        });
+
+        // Bodies up to the limit are accepted
+        let body = "a".repeat(1024);
+        let res = inst.request(Method::Post, "/hook/example.sh")
+                      .body(&*body).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // Bigger bodies are rejected, even without a Content-Length
+        let body = "a".repeat(2048);
+        let res = inst.request(Method::Post, "/hook/example.sh")
+                      .body(&*body).send().unwrap();
+        assert_eq!(res.status, StatusCode::PayloadTooLarge);
+
+        let mut reader = body.as_bytes();
+        let res = inst.request(Method::Post, "/hook/example.sh")
+                      .body(Body::ChunkedBody(&mut reader)).send().unwrap();
+        assert_eq!(res.status, StatusCode::PayloadTooLarge);
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_max_headers() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            max_headers: Some(10),
+            max_headers_size: Some(1024),
+            .. WebConfig::default()
+        });
+
+        // Requests within the limits are accepted
+        let res = inst.request(Method::Get, "/hook/example.sh")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // Requests with too many headers are rejected
+        let mut headers = Headers::new();
+        for i in 0..10 {
+            headers.set_raw(format!("X-Test-{}", i), vec![b"a".to_vec()]);
+        }
+        let res = inst.request(Method::Get, "/hook/example.sh")
+                      .headers(headers).send().unwrap();
+        assert_eq!(res.status, StatusCode::RequestHeaderFieldsTooLarge);
+
+        // Requests with headers too big are rejected
+        let mut headers = Headers::new();
+        headers.set_raw("X-Test", vec!["a".repeat(1024).into_bytes()]);
+        let res = inst.request(Method::Get, "/hook/example.sh")
+                      .headers(headers).send().unwrap();
+        assert_eq!(res.status, StatusCode::RequestHeaderFieldsTooLarge);
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_health_secret() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            health_secret: Some("hunter2".into()),
+            .. WebConfig::default()
+        });
+
+        for url in &["/health", "/metrics", "/hooks"] {
+            // The secret is required
+            let res = inst.request(Method::Get, url).send().unwrap();
+            assert_eq!(res.status, StatusCode::Forbidden);
+            let mut res = inst.request(
+                Method::Get, &format!("{}?secret=a", url),
+            ).send().unwrap();
+            assert_eq!(res.status, StatusCode::Forbidden);
+
+            // The reason of the rejection is in the body
+            let mut content = String::new();
+            res.read_to_string(&mut content).unwrap();
+            let data = serde_json::from_str::<serde_json::Value>(&content)
+                .unwrap();
+            assert_eq!(data["status"].as_str(), Some("error"));
+            assert_eq!(data["error"].as_str(), Some("invalid secret"));
+
+            // It can be provided as a query param or as a Bearer token
+            let res = inst.request(
+                Method::Get, &format!("{}?secret=hunter2", url),
+            ).send().unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+
+            let mut headers = Headers::new();
+            headers.set_raw("Authorization", vec![b"Bearer hunter2".to_vec()]);
+            let res = inst.request(Method::Get, url)
+                          .headers(headers).send().unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+
+            let mut headers = Headers::new();
+            headers.set_raw("Authorization", vec![b"Bearer wrong".to_vec()]);
+            let res = inst.request(Method::Get, url)
+                          .headers(headers).send().unwrap();
+            assert_eq!(res.status, StatusCode::Forbidden);
+        }
+
+        // The history of the hooks is protected too
+        let res = inst.request(Method::Get, "/history/example.sh")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        let res = inst.request(
+            Method::Get, "/history/example.sh?secret=hunter2",
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        // The paths of the hooks are shown to who provided the secret
+        let mut res = inst.request(Method::Get, "/hooks?secret=hunter2")
+                          .send().unwrap();
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content)
+            .unwrap();
+        for hook in data["result"].as_array().unwrap() {
+            assert!(hook["exec"].as_str().unwrap().ends_with(
+                hook["name"].as_str().unwrap()
+            ));
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_extra_binds() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            extra_binds: vec!["127.0.0.1:0".into(), "127.0.0.1:0".into()],
+            .. WebConfig::default()
+        });
+
+        // Every address serves the hooks, queueing the jobs in the same
+        // processor
+        let addrs = inst.addrs();
+        assert_eq!(addrs.len(), 3);
+        assert_eq!(addrs[0], *inst.addr());
+        let client = ::hyper::Client::new();
+        for addr in &addrs {
+            let res = client.get(&format!(
+                "http://{}/hook/example.sh?secret=testing", addr,
+            )).send().unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+            assert!(inst.processor_input().is_some());
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_admin_bind() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            admin_bind: Some("127.0.0.1:0".into()),
+            .. WebConfig::default()
+        });
+
+        // The health endpoint is served only on the admin port
+        let res = inst.request(Method::Get, "/health").send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+        let res = inst.admin_request(Method::Get, "/health").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // The hooks are served only on the main port
+        let res = inst.admin_request(Method::Get, "/hook/example.sh")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(inst.processor_input().is_none());
+
+        let res = inst.request(Method::Get, "/hook/example.sh?secret=testing")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_url_prefix() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(WebConfig {
+            url_prefix: "/webhooks/".into(),
+            .. WebConfig::default()
+        });
+
+        // Every endpoint is served under the prefix
+        let res = inst.request(
+            Method::Get, "/webhooks/hook/example.sh?secret=testing",
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        let res = inst.request(Method::Get, "/webhooks/health")
+                      .send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // Nothing is served outside of it
+        for url in &["/hook/example.sh?secret=testing", "/health",
+                     "/webhooks", "/webhookshook/example.sh",
+                     "/other/hook/example.sh?secret=testing"] {
+            let res = inst.request(Method::Get, url).send().unwrap();
+            assert_eq!(res.status, StatusCode::NotFound);
+        }
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let testing_env = TestingEnv::new();
+        let dir = utils::create_temp_dir().unwrap();
+        let socket = dir.join("fisher.sock");
+
+        // Stale sockets are replaced
+        drop(::std::os::unix::net::UnixListener::bind(&socket).unwrap());
+
+        let mut inst = testing_env.start_web_with(WebConfig {
+            admin_bind: Some(format!("unix:{}", socket.to_str().unwrap())),
+            .. WebConfig::default()
+        });
+
+        let mut conn = UnixStream::connect(&socket).unwrap();
+        write!(conn, "GET /health HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200"), response);
+        assert!(inst.processor_input().is_some());
+
+        // The socket is removed when the server stops
+        inst.stop();
+        assert!(! socket.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        testing_env.cleanup();
+    }
 }
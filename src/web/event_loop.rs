@@ -0,0 +1,1420 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use httparse;
+use mio::{Evented, Events, Poll, PollOpt, Ready, Registration, SetReadiness,
+          Token};
+use mio::net::{TcpListener, TcpStream};
+use mio::unix::EventedFd;
+use tiny_http::{self, Method};
+
+use fisher_common::prelude::*;
+use requests::Request;
+use web::http::{self, ServerState, SERVER_NAME};
+use web::requests::{self as web_requests, BodyWriter, WebRequest};
+use web::responses::Response;
+use web::unix;
+
+
+const LISTENER: Token = Token(0);
+const WAKEUP: Token = Token(1);
+
+/// How many bytes are read from a connection at once.
+const READ_SIZE: usize = 8192;
+
+/// The request line and the size of a chunk must fit in this many bytes.
+const MAX_LINE_SIZE: usize = 8192;
+
+/// How many headers are parsed when their number is not limited.
+const UNLIMITED_HEADERS: usize = 1024;
+
+/// How many bytes of headers are received when their size is not limited.
+const UNLIMITED_HEADERS_SIZE: usize = 1024 * 1024;
+
+/// How many milliseconds the new connections aren't accepted after an
+/// error, for example when no file descriptors are available.
+const ACCEPT_BACKOFF_MS: u64 = 100;
+
+/// How many milliseconds the event loop waits for events at most.
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// How many seconds a connection can stay idle before it's closed.
+const IDLE_TIMEOUT: u64 = 30;
+
+/// How many seconds a client has to send the whole head of a request.
+const HEAD_TIMEOUT: u64 = 30;
+
+
+/// A request received from a connection, ready to be handled.
+struct Incoming {
+    method: Method,
+    url: String,
+    request: WebRequest,
+    version: u8,
+    keep_alive: bool,
+    started_at: Instant,
+}
+
+
+/// What a connection needs after processing the data it received.
+enum Step {
+    /// More data is needed to continue.
+    Wait,
+    /// A whole request was received.
+    Request(Incoming),
+    /// The request can't be handled, and the connection must be closed
+    /// after sending the response.
+    Reject(Response),
+}
+
+
+/// How the end of a request body is found.
+#[derive(Debug, PartialEq)]
+enum Framing {
+    /// The body is as long as the number of bytes.
+    Length(u64),
+    /// The body is chunked, and the next chunk starts with its size.
+    ChunkSize,
+    /// The body is chunked, and the number of bytes of the current chunk
+    /// is still missing.
+    ChunkData(u64),
+    /// The body is chunked, and the current chunk ended.
+    ChunkEnd,
+    /// The body is chunked, and the trailers are being received.
+    Trailers,
+}
+
+impl Framing {
+
+    /// Move the body data in the input to the writer, returning if the
+    /// whole body was received.
+    fn receive(&mut self, input: &mut Vec<u8>, body: &mut BodyWriter)
+               -> Result<bool> {
+        loop {
+            let next = match *self {
+                Framing::Length(0) => return Ok(true),
+                Framing::Length(remaining) => {
+                    if input.is_empty() {
+                        return Ok(false);
+                    }
+                    let size = received(input, remaining, body)?;
+                    Framing::Length(remaining - size)
+                },
+                Framing::ChunkSize => {
+                    let line = match take_line(input)? {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+
+                    // Chunk extensions are ignored
+                    let size = line.split(';').next().unwrap().trim();
+                    match u64::from_str_radix(size, 16) {
+                        Ok(0) => Framing::Trailers,
+                        Ok(size) => Framing::ChunkData(size),
+                        Err(..) => return Err(ErrorKind::InvalidInput(
+                            "invalid chunk size".into()
+                        ).into()),
+                    }
+                },
+                Framing::ChunkData(remaining) => {
+                    if input.is_empty() {
+                        return Ok(false);
+                    }
+                    let size = received(input, remaining, body)?;
+                    if size == remaining {
+                        Framing::ChunkEnd
+                    } else {
+                        Framing::ChunkData(remaining - size)
+                    }
+                },
+                Framing::ChunkEnd => {
+                    match take_line(input)? {
+                        Some(ref line) if line.is_empty() => {
+                            Framing::ChunkSize
+                        },
+                        Some(..) => return Err(ErrorKind::InvalidInput(
+                            "the chunk is longer than its size".into()
+                        ).into()),
+                        None => return Ok(false),
+                    }
+                },
+                Framing::Trailers => {
+                    // The trailers end with an empty line
+                    match take_line(input)? {
+                        Some(ref line) if line.is_empty() => {
+                            return Ok(true);
+                        },
+                        Some(..) => Framing::Trailers,
+                        None => return Ok(false),
+                    }
+                },
+            };
+
+            *self = next;
+        }
+    }
+}
+
+
+/// Move up to `max` bytes of the input to the body, returning how many
+/// were moved.
+fn received(input: &mut Vec<u8>, max: u64, body: &mut BodyWriter)
+            -> Result<u64> {
+    let size = if (input.len() as u64) < max {
+        input.len()
+    } else {
+        max as usize
+    };
+
+    body.write(&input[..size])?;
+    input.drain(..size);
+
+    Ok(size as u64)
+}
+
+
+/// Remove a line ending with CRLF from the start of the input, if it was
+/// received in full.
+fn take_line(input: &mut Vec<u8>) -> Result<Option<String>> {
+    let end = input.windows(2).position(|bytes| bytes == b"\r\n");
+
+    let end = match end {
+        Some(end) => end,
+        None if input.len() > MAX_LINE_SIZE => {
+            return Err(ErrorKind::InvalidInput("line too long".into()).into());
+        },
+        None => return Ok(None),
+    };
+
+    let line = String::from_utf8_lossy(&input[..end]).into_owned();
+    input.drain(..end + 2);
+
+    Ok(Some(line))
+}
+
+
+/// The socket the connections are accepted from. Without the asynchronous
+/// server, the event loop only serves the Unix sockets.
+#[cfg_attr(not(feature = "async-server"), allow(dead_code))]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[cfg_attr(not(feature = "async-server"), allow(dead_code))]
+impl Listener {
+
+    pub fn tcp(addr: &SocketAddr) -> Result<Self> {
+        Ok(Listener::Tcp(TcpListener::bind(addr)?))
+    }
+
+    pub fn unix(path: &Path) -> Result<Self> {
+        let listener = unix::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Return the address of the TCP socket, if the listener is one.
+    pub fn local_addr(&self) -> Result<Option<SocketAddr>> {
+        Ok(match *self {
+            Listener::Tcp(ref listener) => Some(listener.local_addr()?),
+            Listener::Unix(..) => None,
+        })
+    }
+
+    fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), addr))
+            },
+            Listener::Unix(ref listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok((Stream::Unix(stream), unix::peer_address()))
+            },
+        }
+    }
+}
+
+impl Evented for Listener {
+
+    fn register(&self, poll: &Poll, token: Token, interest: Ready,
+                opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                listener.register(poll, token, interest, opts)
+            },
+            Listener::Unix(ref listener) => {
+                EventedFd(&listener.as_raw_fd())
+                    .register(poll, token, interest, opts)
+            },
+        }
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready,
+                  opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                listener.reregister(poll, token, interest, opts)
+            },
+            Listener::Unix(ref listener) => {
+                EventedFd(&listener.as_raw_fd())
+                    .reregister(poll, token, interest, opts)
+            },
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.deregister(poll),
+            Listener::Unix(ref listener) => {
+                EventedFd(&listener.as_raw_fd()).deregister(poll)
+            },
+        }
+    }
+}
+
+
+/// A connection accepted by a `Listener`.
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl Evented for Stream {
+
+    fn register(&self, poll: &Poll, token: Token, interest: Ready,
+                opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => {
+                stream.register(poll, token, interest, opts)
+            },
+            Stream::Unix(ref stream) => {
+                EventedFd(&stream.as_raw_fd())
+                    .register(poll, token, interest, opts)
+            },
+        }
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready,
+                  opts: PollOpt) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => {
+                stream.reregister(poll, token, interest, opts)
+            },
+            Stream::Unix(ref stream) => {
+                EventedFd(&stream.as_raw_fd())
+                    .reregister(poll, token, interest, opts)
+            },
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.deregister(poll),
+            Stream::Unix(ref stream) => {
+                EventedFd(&stream.as_raw_fd()).deregister(poll)
+            },
+        }
+    }
+}
+
+
+/// A request whose body is being received.
+struct PendingRequest {
+    method: Method,
+    url: String,
+    headers: Vec<(String, String)>,
+    version: u8,
+    keep_alive: bool,
+    started_at: Instant,
+    framing: Framing,
+    body: BodyWriter,
+}
+
+
+struct Connection {
+    stream: Stream,
+    peer: SocketAddr,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    pending: Option<PendingRequest>,
+    /// A request was received, and its response wasn't sent yet.
+    waiting: bool,
+    /// The connection is closed once all the output is sent.
+    closing: bool,
+    /// When some data was last received or sent.
+    last_activity: Instant,
+    /// When the first byte of the head of the next request was received.
+    head_started: Option<Instant>,
+}
+
+impl Connection {
+
+    fn new(stream: Stream, peer: SocketAddr) -> Self {
+        Connection {
+            stream: stream,
+            peer: peer,
+            input: Vec::new(),
+            output: Vec::new(),
+            pending: None,
+            waiting: false,
+            closing: false,
+            last_activity: Instant::now(),
+            head_started: None,
+        }
+    }
+
+    /// Check if the connection must be closed because the client is too
+    /// slow. Connections waiting for the response are never stale.
+    fn is_stale(&self, now: Instant, limits: &Limits) -> bool {
+        if self.waiting {
+            return false;
+        }
+
+        if now.duration_since(self.last_activity) > limits.idle_timeout {
+            return true;
+        }
+
+        self.head_started.map_or(false, |started| {
+            now.duration_since(started) > limits.head_timeout
+        })
+    }
+
+    fn process<F>(&mut self, limits: &Limits, wants_streaming: F)
+                  -> Result<Step> where F: Fn(&Method, &str) -> bool {
+        if self.pending.is_none() {
+            if self.input.is_empty() {
+                return Ok(Step::Wait);
+            }
+            if self.head_started.is_none() {
+                self.head_started = Some(Instant::now());
+            }
+
+            match self.parse_head(limits, wants_streaming)? {
+                Some(pending) => {
+                    self.pending = Some(pending);
+                    self.head_started = None;
+                },
+                None => return Ok(Step::Wait),
+            }
+        }
+
+        let done = {
+            let pending = self.pending.as_mut().unwrap();
+            pending.framing.receive(&mut self.input, &mut pending.body)?
+        };
+        if ! done {
+            return Ok(Step::Wait);
+        }
+
+        let pending = self.pending.take().unwrap();
+        let (body, body_file) = pending.body.finish()?;
+        Ok(Step::Request(Incoming {
+            request: WebRequest::from_parts(
+                self.peer.ip(), pending.method.as_str(), &pending.url,
+                pending.headers, body, body_file,
+            ),
+            method: pending.method,
+            url: pending.url,
+            version: pending.version,
+            keep_alive: pending.keep_alive,
+            started_at: pending.started_at,
+        }))
+    }
+
+    fn parse_head<F>(&mut self, limits: &Limits, wants_streaming: F)
+                     -> Result<Option<PendingRequest>>
+                     where F: Fn(&Method, &str) -> bool {
+        let (size, method, url, version, headers) = {
+            let mut parsed_headers = vec![
+                httparse::EMPTY_HEADER;
+                limits.max_headers.map_or(UNLIMITED_HEADERS, |max| max + 1)
+            ];
+            let mut parsed = httparse::Request::new(&mut parsed_headers);
+
+            let size = match parsed.parse(&self.input) {
+                Ok(httparse::Status::Complete(size)) => size,
+                Ok(httparse::Status::Partial) => {
+                    let max_size = MAX_LINE_SIZE + limits.max_headers_size
+                        .unwrap_or(UNLIMITED_HEADERS_SIZE);
+                    if self.input.len() > max_size {
+                        return Err(ErrorKind::HeadersTooLarge.into());
+                    }
+                    return Ok(None);
+                },
+                Err(httparse::Error::TooManyHeaders) => {
+                    return Err(ErrorKind::HeadersTooLarge.into());
+                },
+                Err(..) => return Err(ErrorKind::InvalidInput(
+                    "invalid HTTP request".into()
+                ).into()),
+            };
+
+            let headers = parsed.headers.iter().map(|header| (
+                header.name.to_string(),
+                String::from_utf8_lossy(header.value).into_owned(),
+            )).collect::<Vec<_>>();
+
+            (
+                size, parsed.method.unwrap().to_string(),
+                parsed.path.unwrap().to_string(), parsed.version.unwrap(),
+                headers,
+            )
+        };
+        self.input.drain(..size);
+        let started_at = Instant::now();
+
+        web_requests::check_headers(
+            &headers, limits.max_headers, limits.max_headers_size,
+        )?;
+
+        let method = match method.parse::<Method>() {
+            Ok(method) => method,
+            Err(..) => return Err(ErrorKind::InvalidInput(
+                "invalid HTTP method".into()
+            ).into()),
+        };
+
+        let (keep_alive, framing, expect_continue) = {
+            let header = |name: &str| {
+                headers.iter()
+                    .find(|&&(ref key, _)| key.to_lowercase() == name)
+                    .map(|&(_, ref value)| value.trim().to_lowercase())
+            };
+
+            // HTTP/1.1 connections are kept alive by default
+            let keep_alive = match header("connection") {
+                Some(ref value) if value == "close" => false,
+                Some(ref value) if value == "keep-alive" => true,
+                _ => version == 1,
+            };
+
+            let framing = request_framing(&headers, limits)?;
+
+            let expect = header("expect");
+            let expect_continue = expect.map_or(false, |value| {
+                value == "100-continue"
+            });
+
+            (keep_alive, framing, expect_continue)
+        };
+
+        // Clients sending big bodies might wait for the permission to do so
+        if expect_continue {
+            self.output.extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+        }
+
+        let max_body_memory = if wants_streaming(&method, &url) {
+            Some(0)
+        } else {
+            limits.max_body_memory
+        };
+
+        Ok(Some(PendingRequest {
+            method: method,
+            url: url,
+            headers: headers,
+            version: version,
+            keep_alive: keep_alive,
+            started_at: started_at,
+            framing: framing,
+            body: BodyWriter::new(max_body_memory, limits.max_body_size),
+        }))
+    }
+}
+
+
+/// Return all the values of the header, even if it was repeated.
+fn header_values(headers: &[(String, String)], name: &str) -> Vec<String> {
+    headers.iter()
+        .filter(|&&(ref key, _)| key.to_lowercase() == name)
+        .flat_map(|&(_, ref value)| value.split(','))
+        .map(|value| value.trim().to_lowercase())
+        .collect()
+}
+
+
+/// Find out how the end of the body is found. A request which could be
+/// understood differently by a proxy in front of Fisher is rejected, so no
+/// other request can be hidden in its body.
+fn request_framing(headers: &[(String, String)], limits: &Limits)
+                   -> Result<Framing> {
+    let codings = header_values(headers, "transfer-encoding");
+    let lengths = header_values(headers, "content-length");
+
+    if ! codings.is_empty() {
+        if codings != ["chunked"] {
+            return Err(ErrorKind::InvalidInput(
+                "unsupported transfer encoding".into()
+            ).into());
+        }
+        if ! lengths.is_empty() {
+            return Err(ErrorKind::InvalidInput(
+                "both Transfer-Encoding and Content-Length are present".into()
+            ).into());
+        }
+
+        return Ok(Framing::ChunkSize);
+    }
+
+    let length = match lengths.first() {
+        Some(length) => length,
+        None => return Ok(Framing::Length(0)),
+    };
+
+    // Repeating the same length is allowed, but not conflicting ones
+    if lengths.iter().any(|other| other != length) {
+        return Err(ErrorKind::InvalidInput(
+            "conflicting Content-Length headers".into()
+        ).into());
+    }
+    let length = match length.parse::<u64>() {
+        Ok(length) => length,
+        Err(..) => return Err(ErrorKind::InvalidInput(
+            "invalid Content-Length header".into()
+        ).into()),
+    };
+
+    // Reject the bodies declared too big before reading them
+    web_requests::check_body_size(length, limits.max_body_size)?;
+    Ok(Framing::Length(length))
+}
+
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    max_body_memory: Option<usize>,
+    max_body_size: Option<u64>,
+    max_headers: Option<usize>,
+    max_headers_size: Option<usize>,
+    idle_timeout: Duration,
+    head_timeout: Duration,
+}
+
+impl Limits {
+
+    /// How many bytes are buffered for a connection before reading from it
+    /// is stopped. This is enough to parse the head of a request, and the
+    /// bodies are moved out of the buffer while they're received.
+    fn max_buffered(&self) -> usize {
+        MAX_LINE_SIZE + READ_SIZE
+            + self.max_headers_size.unwrap_or(UNLIMITED_HEADERS_SIZE)
+    }
+}
+
+
+/// Convert the response to bytes, using the same HTTP version as the
+/// request (the minor version, either 0 or 1).
+fn serialize(response: &Response, version: u8, keep_alive: bool)
+             -> Vec<u8> {
+    let status = response.status();
+    let body = response.body();
+
+    let mut head = format!(
+        "HTTP/1.{} {} {}\r\nServer: {}\r\nContent-Type: {}\r\n",
+        version, status, tiny_http::StatusCode(status).default_reason_phrase(),
+        SERVER_NAME, response.content_type(),
+    );
+    // Responses without content can't have a length either
+    if status != 204 {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    if let Some(secs) = response.retry_after() {
+        head.push_str(&format!("Retry-After: {}\r\n", secs));
+    }
+    head.push_str(if keep_alive {
+        "Connection: keep-alive\r\n\r\n"
+    } else {
+        "Connection: close\r\n\r\n"
+    });
+
+    let mut result = head.into_bytes();
+    result.extend_from_slice(body.as_bytes());
+    result
+}
+
+
+/// Where the responses are sent once they're ready.
+#[derive(Clone)]
+struct Responder {
+    responses: mpsc::Sender<(Token, Vec<u8>)>,
+    wakeup: SetReadiness,
+}
+
+impl Responder {
+
+    fn send(&self, token: Token, response: Vec<u8>) {
+        // The event loop could be stopped already
+        if self.responses.send((token, response)).is_ok() {
+            let _ = self.wakeup.set_readiness(Ready::readable());
+        }
+    }
+}
+
+
+/// Handle the requests one at a time, outside of the event loop.
+fn dispatcher<App>(state: Arc<ServerState<App>>,
+                   requests: mpsc::Receiver<(Token, Incoming)>,
+                   responder: Responder)
+                   where App: Send + Sync + 'static {
+    for (token, incoming) in requests.iter() {
+        let Incoming {
+            method, url, request, version, keep_alive, started_at,
+        } = incoming;
+
+        let mut req = Request::Web(request);
+        let response = {
+            let handlers = state.handlers.lock().unwrap();
+            http::dispatch(
+                &*state.app, &handlers[..], &state.proxy_support,
+                &method, &url, &mut req,
+            )
+        };
+
+        // The proxy support could have changed the source
+        let source = req.web().unwrap().source;
+
+        let respond = {
+            let state = state.clone();
+            let responder = responder.clone();
+            move |response: Response| {
+                if let Some(ref access_log) = state.access_log {
+                    access_log.lock().unwrap().log(
+                        &source, &method, &url, response.status(),
+                        started_at.elapsed(),
+                    );
+                }
+                responder.send(token, serialize(&response, version, keep_alive));
+            }
+        };
+
+        match response {
+            // Deferred responses are sent by another thread once they're
+            // ready, so they don't block the other requests
+            Response::Deferred(recv) => {
+                thread::spawn(move || {
+                    respond(recv.recv().unwrap_or(
+                        Response::Unavailable("no response available")
+                    ));
+                });
+            },
+            response => respond(response),
+        }
+    }
+}
+
+
+struct EventLoop<App: Send + Sync + 'static> {
+    poll: Poll,
+    listener: Listener,
+    wakeup: SetReadiness,
+    // The registration must live as long as the event loop
+    _registration: Registration,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    state: Arc<ServerState<App>>,
+    limits: Limits,
+    requests: mpsc::Sender<(Token, Incoming)>,
+    responses: mpsc::Receiver<(Token, Vec<u8>)>,
+    /// No connection is accepted until then, after an error.
+    accept_paused_until: Option<Instant>,
+}
+
+impl<App: Send + Sync + 'static> EventLoop<App> {
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+        let mut last_cleanup = Instant::now();
+
+        loop {
+            // The request sent to stop the server wakes the loop up, but
+            // it's also checked periodically
+            let mut timeout = poll_interval;
+            if let Some(until) = self.accept_paused_until {
+                let now = Instant::now();
+                timeout = if until > now {
+                    ::std::cmp::min(timeout, until - now)
+                } else {
+                    Duration::from_millis(0)
+                };
+            }
+            match self.poll.poll(&mut events, Some(timeout)) {
+                Ok(..) => {},
+                Err(ref error)
+                    if error.kind() == io::ErrorKind::Interrupted =>
+                {
+                    continue;
+                },
+                Err(error) => return Err(error),
+            }
+
+            // Don't accept any request anymore
+            if self.state.should_stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+
+            if last_cleanup.elapsed() >= poll_interval {
+                self.close_stale();
+                last_cleanup = Instant::now();
+            }
+
+            // The listener is edge-triggered, so the connections queued
+            // while accepting was paused don't generate a new event
+            let resume = self.accept_paused_until.map_or(false, |until| {
+                Instant::now() >= until
+            });
+            if resume {
+                self.accept_paused_until = None;
+                self.accept();
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => {
+                        if self.accept_paused_until.is_none() {
+                            self.accept();
+                        }
+                    },
+                    WAKEUP => self.send_responses(),
+                    token => {
+                        if event.readiness().is_readable() {
+                            self.read(token);
+                        }
+                        if event.readiness().is_writable() {
+                            self.write(token);
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let (stream, addr) = match self.listener.accept() {
+                Ok(result) => result,
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    return;
+                },
+                // Only this connection failed
+                Err(ref error)
+                    if error.kind() == io::ErrorKind::Interrupted ||
+                       error.kind() == io::ErrorKind::ConnectionAborted =>
+                {
+                    continue;
+                },
+                // The errors here are usually caused by the lack of
+                // resources (like EMFILE when too many connections are
+                // open), so the server waits a bit instead of stopping
+                Err(error) => {
+                    Error::from(error).pretty_print();
+                    let backoff = Duration::from_millis(ACCEPT_BACKOFF_MS);
+                    self.accept_paused_until = Some(Instant::now() + backoff);
+                    return;
+                },
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            let registered = self.poll.register(
+                &stream, token, Ready::readable() | Ready::writable(),
+                PollOpt::edge(),
+            );
+            if let Err(error) = registered {
+                Error::from(error).pretty_print();
+                continue;
+            }
+            self.connections.insert(token, Connection::new(stream, addr));
+        }
+    }
+
+    fn read(&mut self, token: Token) {
+        let max_buffered = self.limits.max_buffered();
+
+        loop {
+            // The data isn't read anymore once the buffer is full, and
+            // since the connection is edge-triggered reading is resumed
+            // after the buffer is processed
+            let mut full = false;
+            if let Some(conn) = self.connections.get_mut(&token) {
+                let mut closed = false;
+                let mut buffer = [0; READ_SIZE];
+                while conn.input.len() < max_buffered {
+                    match conn.stream.read(&mut buffer) {
+                        Ok(0) => {
+                            closed = true;
+                            break;
+                        },
+                        Ok(size) => {
+                            conn.input.extend_from_slice(&buffer[..size]);
+                            conn.last_activity = Instant::now();
+                        },
+                        Err(ref error)
+                            if error.kind() == io::ErrorKind::WouldBlock =>
+                        {
+                            break;
+                        },
+                        Err(ref error)
+                            if error.kind() == io::ErrorKind::Interrupted =>
+                        {},
+                        Err(..) => {
+                            closed = true;
+                            break;
+                        },
+                    }
+                }
+                full = conn.input.len() >= max_buffered;
+
+                // Responses can still be sent to half-closed connections
+                if closed {
+                    conn.closing = true;
+                }
+            }
+
+            self.advance(token);
+
+            // While a request is handled the data isn't read, so clients
+            // pipelining requests can't fill the memory: the reading is
+            // resumed when the response is sent
+            let resume = self.connections.get(&token).map_or(false, |conn| {
+                full && ! conn.waiting && ! conn.closing
+            });
+            if ! resume {
+                return;
+            }
+        }
+    }
+
+    /// Close the connections of the clients which are too slow, so idle
+    /// connections can't use all the file descriptors.
+    fn close_stale(&mut self) {
+        let now = Instant::now();
+        let stale = self.connections.iter()
+            .filter(|&(_, conn)| conn.is_stale(now, &self.limits))
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+
+        for token in stale {
+            if let Some(conn) = self.connections.remove(&token) {
+                let _ = self.poll.deregister(&conn.stream);
+            }
+        }
+    }
+
+    /// Process the data received by the connection, until a request
+    /// can't be handled yet.
+    fn advance(&mut self, token: Token) {
+        let state = self.state.clone();
+        let wants_streaming = |method: &Method, url: &str| {
+            let handlers = state.handlers.lock().unwrap();
+            http::wants_streaming(&*state.app, &handlers[..], method, url)
+        };
+
+        let step = match self.connections.get_mut(&token) {
+            Some(conn) => {
+                // Only a request at a time is handled for each connection
+                if conn.waiting {
+                    return;
+                }
+
+                match conn.process(&self.limits, wants_streaming) {
+                    Ok(step) => step,
+                    Err(error) => Step::Reject(
+                        http::read_error_response(error)
+                    ),
+                }
+            },
+            None => return,
+        };
+
+        match step {
+            Step::Wait => {},
+            Step::Request(incoming) => {
+                if let Some(conn) = self.connections.get_mut(&token) {
+                    conn.waiting = true;
+                    if ! incoming.keep_alive {
+                        conn.closing = true;
+                    }
+                }
+                // The dispatcher only stops after the event loop
+                self.requests.send((token, incoming)).unwrap();
+            },
+            Step::Reject(response) => {
+                if let Some(conn) = self.connections.get_mut(&token) {
+                    conn.output.extend(serialize(&response, 1, false));
+                    conn.closing = true;
+                }
+            },
+        }
+
+        self.write(token);
+    }
+
+    fn write(&mut self, token: Token) {
+        let mut close = false;
+        if let Some(conn) = self.connections.get_mut(&token) {
+            while ! conn.output.is_empty() {
+                match conn.stream.write(&conn.output) {
+                    Ok(0) => {
+                        close = true;
+                        break;
+                    },
+                    Ok(size) => {
+                        conn.output.drain(..size);
+                        conn.last_activity = Instant::now();
+                    },
+                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    },
+                    Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {},
+                    Err(..) => {
+                        close = true;
+                        break;
+                    },
+                }
+            }
+
+            if conn.output.is_empty() && conn.closing && ! conn.waiting {
+                close = true;
+            }
+        }
+
+        if close {
+            if let Some(conn) = self.connections.remove(&token) {
+                let _ = self.poll.deregister(&conn.stream);
+            }
+        }
+    }
+
+    fn send_responses(&mut self) {
+        // The readiness is reset before receiving, so no response is missed
+        let _ = self.wakeup.set_readiness(Ready::empty());
+
+        while let Ok((token, response)) = self.responses.try_recv() {
+            if let Some(conn) = self.connections.get_mut(&token) {
+                conn.output.extend(response);
+                conn.waiting = false;
+            } else {
+                // The connection was closed in the meantime
+                continue;
+            }
+
+            self.write(token);
+
+            // Handle the next request, if the client sent it already, and
+            // resume reading from the connection
+            self.read(token);
+        }
+    }
+}
+
+
+/// Start serving the requests received by the listener. All the
+/// connections are handled by a single thread, so the idle keep-alive ones
+/// don't need a thread each, while the requests are handled one at a time
+/// by another thread. The channel is notified when the server stops.
+pub fn serve<App>(listener: Listener, state: ServerState<App>,
+                  stop_send: mpsc::Sender<()>) -> Result<()>
+                  where App: Send + Sync + 'static {
+    let poll = Poll::new()?;
+    poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge())?;
+    let (registration, wakeup) = Registration::new2();
+    poll.register(&registration, WAKEUP, Ready::readable(), PollOpt::edge())?;
+
+    let limits = Limits {
+        max_body_memory: state.max_body_memory,
+        max_body_size: state.max_body_size,
+        max_headers: state.max_headers,
+        max_headers_size: state.max_headers_size,
+        idle_timeout: Duration::from_secs(IDLE_TIMEOUT),
+        head_timeout: Duration::from_secs(HEAD_TIMEOUT),
+    };
+    let state = Arc::new(state);
+
+    let (requests_send, requests_recv) = mpsc::channel();
+    let (responses_send, responses_recv) = mpsc::channel();
+
+    let responder = Responder {
+        responses: responses_send,
+        wakeup: wakeup.clone(),
+    };
+    let dispatcher_state = state.clone();
+    thread::spawn(move || {
+        dispatcher(dispatcher_state, requests_recv, responder);
+    });
+
+    let mut event_loop = EventLoop {
+        poll: poll,
+        listener: listener,
+        wakeup: wakeup,
+        _registration: registration,
+        connections: HashMap::new(),
+        next_token: 2,
+        state: state,
+        limits: limits,
+        requests: requests_send,
+        responses: responses_recv,
+        accept_paused_until: None,
+    };
+    thread::spawn(move || {
+        if let Err(error) = event_loop.run() {
+            Error::from(error).pretty_print();
+        }
+
+        // Stop the dispatcher too
+        drop(event_loop);
+        stop_send.send(()).unwrap();
+    });
+
+    Ok(())
+}
+
+
+#[cfg(all(test, feature = "async-server"))]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{self, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use mio;
+    use tiny_http::Method;
+
+    use requests::Request;
+    use web::http::HttpServer;
+    use web::requests::BodyWriter;
+    use web::responses::Response;
+    use web::http::read_error_response;
+    use super::{Connection, Framing, Limits, Stream};
+    use super::{request_framing, serialize};
+
+
+    fn body_handler(expected: &String, req: &Request, _args: Vec<String>)
+                    -> Response {
+        if req.web().unwrap().body == *expected {
+            Response::Ok
+        } else {
+            Response::Forbidden("wrong body")
+        }
+    }
+
+    fn start_server(body: &str, max_body_size: Option<u64>)
+                    -> (HttpServer<String>, TcpStream) {
+        let mut server = HttpServer::new(Arc::new(body.to_string()), 0);
+        server.set_max_body_size(max_body_size);
+        server.add_route(Method::Post, "/?", Box::new(body_handler));
+        let addr = server.listen("127.0.0.1:0").unwrap();
+
+        let conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        (server, conn)
+    }
+
+    fn read_until(conn: &mut TcpStream, content: &str, count: usize)
+                  -> String {
+        let mut result = String::new();
+        let mut buffer = [0; 1024];
+        while result.matches(content).count() < count {
+            let size = conn.read(&mut buffer).unwrap();
+            if size == 0 {
+                break;
+            }
+            result.push_str(&String::from_utf8_lossy(&buffer[..size]));
+        }
+        result
+    }
+
+
+    #[test]
+    fn test_chunked_body() {
+        let mut body = BodyWriter::new(None, None);
+        let mut framing = Framing::ChunkSize;
+
+        // The chunks can be split everywhere
+        let mut input = b"5;ext=1\r\nhel".to_vec();
+        assert!(! framing.receive(&mut input, &mut body).unwrap());
+        assert_eq!(framing, Framing::ChunkData(2));
+        assert!(input.is_empty());
+
+        input.extend_from_slice(b"lo\r\n6\r\n world\r\n0\r\n");
+        assert!(! framing.receive(&mut input, &mut body).unwrap());
+        assert_eq!(framing, Framing::Trailers);
+
+        // The trailers are ignored, and what's after the body is kept
+        input.extend_from_slice(b"Trailer: yes\r\n\r\nGET");
+        assert!(framing.receive(&mut input, &mut body).unwrap());
+        assert_eq!(input, b"GET".to_vec());
+        assert_eq!(body.finish().unwrap().0, "hello world");
+
+        // Chunks must match their size
+        let mut framing = Framing::ChunkSize;
+        let mut input = b"2\r\nabc\r\n".to_vec();
+        let mut body = BodyWriter::new(None, None);
+        assert!(framing.receive(&mut input, &mut body).is_err());
+
+        // The size must be hexadecimal
+        let mut framing = Framing::ChunkSize;
+        let mut input = b"zz\r\n".to_vec();
+        assert!(framing.receive(&mut input, &mut body).is_err());
+    }
+
+    #[test]
+    fn test_request_framing() {
+        let limits = Limits {
+            max_body_memory: None,
+            max_body_size: Some(100),
+            max_headers: None,
+            max_headers_size: None,
+            idle_timeout: Duration::from_secs(30),
+            head_timeout: Duration::from_secs(10),
+        };
+        let framing = |headers: &[(&str, &str)]| {
+            let headers = headers.iter()
+                .map(|&(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Vec<_>>();
+            request_framing(&headers, &limits)
+        };
+
+        assert_eq!(framing(&[]).unwrap(), Framing::Length(0));
+        assert_eq!(
+            framing(&[("Content-Length", "10")]).unwrap(), Framing::Length(10)
+        );
+        assert_eq!(
+            framing(&[("transfer-encoding", "Chunked")]).unwrap(),
+            Framing::ChunkSize
+        );
+
+        // The same length can be repeated
+        assert_eq!(framing(&[
+            ("Content-Length", "10, 10"), ("content-length", "10"),
+        ]).unwrap(), Framing::Length(10));
+
+        for headers in &[
+            // Conflicting or invalid lengths
+            &[("Content-Length", "10"), ("Content-Length", "11")][..],
+            &[("Content-Length", "10, 11")][..],
+            &[("Content-Length", "-1")][..],
+            &[("Content-Length", "")][..],
+            // Only the chunked encoding is supported
+            &[("Transfer-Encoding", "gzip")][..],
+            &[("Transfer-Encoding", "gzip, chunked")][..],
+            &[
+                ("Transfer-Encoding", "chunked"),
+                ("Transfer-Encoding", "chunked"),
+            ][..],
+            // The body can't be framed in two ways
+            &[("Transfer-Encoding", "chunked"), ("Content-Length", "10")][..],
+        ] {
+            let error = framing(headers).unwrap_err();
+            assert_eq!(
+                read_error_response(error).status(), 400, "{:?}", headers
+            );
+        }
+
+        // The bodies declared too big are rejected
+        let error = framing(&[("Content-Length", "101")]).unwrap_err();
+        assert_eq!(read_error_response(error).status(), 413);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let response = String::from_utf8(serialize(
+            &Response::WithStatus(202, Box::new(Response::Ok)), 1, true,
+        )).unwrap();
+        assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"));
+        assert!(response.contains(&format!(
+            "\r\nContent-Length: {}\r\n", Response::Ok.body().len()
+        )));
+
+        // No length is sent along the responses without content
+        let response = String::from_utf8(serialize(
+            &Response::WithStatus(204, Box::new(Response::Ok)), 1, false,
+        )).unwrap();
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(! response.contains("Content-Length"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_stale_connections() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .unwrap();
+        let peer = stream.local_addr().unwrap();
+        let mut conn = Connection::new(
+            Stream::Tcp(mio::net::TcpStream::from_stream(stream).unwrap()),
+            peer,
+        );
+
+        let limits = Limits {
+            max_body_memory: None,
+            max_body_size: None,
+            max_headers: None,
+            max_headers_size: None,
+            idle_timeout: Duration::from_secs(30),
+            head_timeout: Duration::from_secs(10),
+        };
+        let now = conn.last_activity;
+        assert!(! conn.is_stale(now + Duration::from_secs(30), &limits));
+        assert!(conn.is_stale(now + Duration::from_secs(31), &limits));
+
+        // The whole head must be received in time, even if the client is
+        // sending some data
+        conn.head_started = Some(now);
+        conn.last_activity = now + Duration::from_secs(10);
+        assert!(! conn.is_stale(now + Duration::from_secs(10), &limits));
+        assert!(conn.is_stale(now + Duration::from_secs(11), &limits));
+
+        // The connections waiting for a response are never closed
+        conn.waiting = true;
+        assert!(! conn.is_stale(now + Duration::from_secs(60), &limits));
+    }
+
+    #[test]
+    fn test_pipelined_big_requests() {
+        let body = "a".repeat(20000);
+        let mut server = HttpServer::new(Arc::new(body.clone()), 0);
+        server.set_max_headers(None, Some(1024));
+        server.add_route(Method::Post, "/?", Box::new(body_handler));
+        let addr = server.listen("127.0.0.1:0").unwrap();
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // The requests are bigger than what's buffered for the connection,
+        // so the server needs to resume reading after each response
+        let mut requests = String::new();
+        for _ in 0..4 {
+            requests.push_str(&format!(
+                "POST /a HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(), body,
+            ));
+        }
+        let mut writer = conn.try_clone().unwrap();
+        let sender = thread::spawn(move || {
+            writer.write_all(requests.as_bytes()).unwrap();
+        });
+
+        let responses = read_until(&mut conn, "HTTP/1.1 ", 4);
+        assert_eq!(responses.matches("HTTP/1.1 200 OK").count(), 4);
+        sender.join().unwrap();
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let (mut server, mut conn) = start_server("hi", None);
+
+        // Pipelined requests are answered in order on the same connection
+        write!(conn,
+            "POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi\
+             POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             1\r\nh\r\n1\r\ni\r\n0\r\n\r\n\
+             POST /c HTTP/1.1\r\nContent-Length: 3\r\n\r\nbye"
+        ).unwrap();
+        let responses = read_until(&mut conn, "HTTP/1.1 ", 3);
+        assert_eq!(responses.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(responses.matches("HTTP/1.1 403 Forbidden").count(), 1);
+        assert!(
+            responses.rfind("200 OK").unwrap() <
+            responses.find("403 Forbidden").unwrap()
+        );
+        assert!(! responses.contains("Connection: close"));
+
+        // The connection is closed when the client asks for it
+        write!(conn,
+            "POST /d HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\
+             \r\n\r\nhi"
+        ).unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), response);
+        assert!(response.contains("Connection: close"), response);
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let (mut server, mut conn) = start_server("hello", Some(10));
+
+        write!(conn,
+            "POST /a HTTP/1.1\r\nContent-Length: 5\r\n\
+             Expect: 100-continue\r\n\r\n"
+        ).unwrap();
+        let response = read_until(&mut conn, "\r\n\r\n", 1);
+        assert_eq!(response, "HTTP/1.1 100 Continue\r\n\r\n");
+
+        write!(conn, "hello").unwrap();
+        let response = read_until(&mut conn, "HTTP/1.1 200 OK", 1);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), response);
+
+        // Bodies declared too big are rejected before being sent
+        write!(conn,
+            "POST /a HTTP/1.1\r\nContent-Length: 11\r\n\
+             Expect: 100-continue\r\n\r\n"
+        ).unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 "), response);
+
+        server.stop();
+    }
+}
@@ -13,26 +13,42 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::net::{SocketAddr, TcpStream, Shutdown};
-use std::io::Write;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, Shutdown};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
 
 use regex::{self, Regex};
-use tiny_http::{self, Method};
+use tiny_http::Method;
 
 use fisher_common::prelude::*;
 use requests::Request;
 use web::responses::Response;
 use web::proxies::ProxySupport;
+use web::access_log::AccessLog;
+use web::event_loop::{self, Listener};
+use web::unix;
+use utils::IpNetwork;
+
+#[cfg(not(feature = "async-server"))] use std::thread;
+#[cfg(not(feature = "async-server"))] use std::time::{Duration, Instant};
+#[cfg(not(feature = "async-server"))] use tiny_http;
+#[cfg(not(feature = "async-server"))] use web::requests::WebRequest;
 
 
 pub type RequestHandler<App> = Box<
     fn(&App, &Request, Vec<String>) -> Response
 >;
 
+/// Decide, before the request body is read, if it should be streamed to
+/// disk instead of being kept in memory.
+pub type StreamingCheck<App> = Box<fn(&App, Vec<String>) -> bool>;
+
 
 struct Route {
     method: Method,
@@ -94,17 +110,20 @@ impl Route {
 }
 
 
-struct Handler<App: Send + Sync + 'static> {
+pub struct Handler<App: Send + Sync + 'static> {
     handler: RequestHandler<App>,
     route: Route,
+    streaming: Option<StreamingCheck<App>>,
 }
 
 impl<App: Send + Sync + 'static> Handler<App> {
 
-    fn new(handler: RequestHandler<App>, route: Route) -> Self {
+    fn new(handler: RequestHandler<App>, route: Route,
+           streaming: Option<StreamingCheck<App>>) -> Self {
         Handler {
             handler: handler,
             route: route,
+            streaming: streaming,
         }
     }
 
@@ -112,126 +131,441 @@ impl<App: Send + Sync + 'static> Handler<App> {
         self.route.matches(method, url)
     }
 
+    fn wants_streaming(&self, app: &App, args: Vec<String>) -> bool {
+        match self.streaming {
+            Some(ref streaming) => streaming(app, args),
+            None => false,
+        }
+    }
+
     fn call(&self, app: &App, req: &Request, args: Vec<String>) -> Response {
         (self.handler)(app, req, args)
     }
 }
 
 
+/// The certificate and the private key used to serve HTTPS, PEM-encoded.
+#[derive(Clone)]
+#[cfg_attr(
+    any(not(feature = "tls"), feature = "async-server"), allow(dead_code)
+)]
+pub struct TlsConfig {
+    certificate: Vec<u8>,
+    private_key: Vec<u8>,
+}
+
+impl fmt::Debug for TlsConfig {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The private key shouldn't end up in the logs
+        write!(f, "TlsConfig {{ .. }}")
+    }
+}
+
+impl TlsConfig {
+
+    pub fn load(certificate: &Path, private_key: &Path) -> Result<Self> {
+        let read = |path: &Path| -> Result<Vec<u8>> {
+            let mut content = Vec::new();
+            fs::File::open(path)?.read_to_end(&mut content)?;
+            Ok(content)
+        };
+
+        Ok(TlsConfig {
+            certificate: read(certificate)?,
+            private_key: read(private_key)?,
+        })
+    }
+}
+
+
+#[cfg(all(feature = "tls", not(feature = "async-server")))]
+fn create_server(addr: SocketAddr, tls: Option<TlsConfig>)
+                 -> Result<tiny_http::Server> {
+    Ok(match tls {
+        Some(tls) => tiny_http::Server::https(addr, tiny_http::SslConfig {
+            certificate: tls.certificate,
+            private_key: tls.private_key,
+        })?,
+        None => tiny_http::Server::http(addr)?,
+    })
+}
+
+
+#[cfg(all(not(feature = "tls"), not(feature = "async-server")))]
+fn create_server(addr: SocketAddr, tls: Option<TlsConfig>)
+                 -> Result<tiny_http::Server> {
+    if tls.is_some() {
+        return Err(ErrorKind::InvalidInput(
+            "Fisher was built without TLS support".into()
+        ).into());
+    }
+
+    Ok(tiny_http::Server::http(addr)?)
+}
+
+
+/// The value of the `Server` header of the responses.
+pub const SERVER_NAME: &'static str = concat!(
+    "Fisher/", env!("CARGO_PKG_VERSION")
+);
+
+/// The non-standard method of the request sent to shut the server down.
+/// No client should be using it.
+const STOP_METHOD: &'static str = "X_FISHER_IGNORE_THIS";
+
+
+/// What the thread serving the requests needs to handle them.
+pub struct ServerState<App: Send + Sync + 'static> {
+    pub app: Arc<App>,
+    pub handlers: Arc<Mutex<Vec<Handler<App>>>>,
+    pub proxy_support: ProxySupport,
+    pub access_log: Option<Arc<Mutex<AccessLog>>>,
+    pub max_body_memory: Option<usize>,
+    pub max_body_size: Option<u64>,
+    pub max_headers: Option<usize>,
+    pub max_headers_size: Option<usize>,
+    pub should_stop: Arc<AtomicBool>,
+}
+
+
+/// Check if the first route matching the request wants its body to be
+/// streamed to disk.
+pub fn wants_streaming<App>(app: &App, handlers: &[Handler<App>],
+                            method: &Method, url: &str) -> bool
+                            where App: Send + Sync + 'static {
+    handlers.iter().filter_map(|handler| {
+        handler.matches(method, url).map(|args| (handler, args))
+    }).next().map(|(handler, args)| {
+        handler.wants_streaming(app, args)
+    }).unwrap_or(false)
+}
+
+
+/// Call the handler of the first route matching the request.
+pub fn dispatch<App>(app: &App, handlers: &[Handler<App>],
+                     proxy_support: &ProxySupport, method: &Method,
+                     url: &str, req: &mut Request) -> Response
+                     where App: Send + Sync + 'static {
+    if *method == STOP_METHOD.parse::<Method>().unwrap() {
+        return Response::Forbidden("method not allowed");
+    }
+
+    if let Err(e) = proxy_support.fix_request(req) {
+        return Response::BadRequest(e);
+    }
+
+    for handler in handlers {
+        if let Some(args) = handler.matches(method, url) {
+            return handler.call(app, req, args);
+        }
+    }
+
+    Response::NotFound("unknown endpoint")
+}
+
+
+pub fn read_error_response(error: Error) -> Response {
+    // Requests too big are rejected with specific status codes
+    match *error.kind() {
+        ErrorKind::BodyTooLarge(..) => Response::PayloadTooLarge,
+        ErrorKind::HeadersTooLarge => Response::HeadersTooLarge,
+        _ => Response::BadRequest(error),
+    }
+}
+
+
+#[cfg(not(feature = "async-server"))]
+fn respond(request: tiny_http::Request, response: &Response,
+           server_header: tiny_http::Header,
+           access_log: &Option<Arc<Mutex<AccessLog>>>,
+           source: Option<IpAddr>, started_at: Instant) {
+    let mut tiny_response = tiny_http::Response::from_data(
+        response.body().into_bytes()
+    ).with_status_code(response.status());
+
+    tiny_response.add_header(server_header);
+    tiny_response.add_header(
+        format!("Content-Type: {}", response.content_type())
+            .parse::<tiny_http::Header>().unwrap()
+    );
+    if let Some(secs) = response.retry_after() {
+        tiny_response.add_header(
+            format!("Retry-After: {}", secs)
+                .parse::<tiny_http::Header>().unwrap()
+        );
+    }
+
+    if let (&Some(ref access_log), Some(source)) = (access_log, source) {
+        access_log.lock().unwrap().log(
+            &source, request.method(), request.url(),
+            response.status(), started_at.elapsed(),
+        );
+    }
+
+    let _ = request.respond(tiny_response);
+}
+
+
 pub struct HttpServer<App: Send + Sync + 'static> {
     app: Arc<App>,
     handlers: Arc<Mutex<Vec<Handler<App>>>>,
-    proxy_support: Arc<ProxySupport>,
+    proxy_support: ProxySupport,
+    access_log: Option<AccessLog>,
+    max_body_memory: Option<usize>,
+    max_body_size: Option<u64>,
+    max_headers: Option<usize>,
+    max_headers_size: Option<usize>,
+    tls: Option<TlsConfig>,
+    url_prefix: String,
 
     should_stop: Arc<AtomicBool>,
 
     listening_to: Option<SocketAddr>,
     stop_wait: Option<mpsc::Receiver<()>>,
+    unix_socket: Option<PathBuf>,
 }
 
 impl<App: Send + Sync + 'static> HttpServer<App> {
 
-    pub fn new(app: App, proxies_count: u8) -> Self {
+    pub fn new(app: Arc<App>, proxies_count: u8) -> Self {
         HttpServer {
-            app: Arc::new(app),
+            app: app,
             handlers: Arc::new(Mutex::new(Vec::new())),
-            proxy_support: Arc::new(ProxySupport::new(proxies_count)),
+            proxy_support: ProxySupport::new(proxies_count),
+            access_log: None,
+            max_body_memory: None,
+            max_body_size: None,
+            max_headers: None,
+            max_headers_size: None,
+            tls: None,
+            url_prefix: String::new(),
 
             should_stop: Arc::new(AtomicBool::new(false)),
 
             listening_to: None,
             stop_wait: None,
+            unix_socket: None,
         }
     }
 
     pub fn add_route(&mut self, method: Method, url: &str,
                      handler: RequestHandler<App>) {
-        let route = Route::new(method, url);
+        let route = Route::new(method, &format!("{}{}", self.url_prefix, url));
         self.handlers.try_lock().unwrap().push(
-            Handler::new(handler, route)
+            Handler::new(handler, route, None)
         );
     }
 
-    pub fn listen(&mut self, bind: &str) -> Result<SocketAddr> {
-        macro_rules! header {
-            ($value:expr) => {
-                $value.parse::<tiny_http::Header>().unwrap()
-            };
-        }
+    /// Add a route which can ask to stream the request body to disk, even
+    /// if it's small enough to be kept in memory.
+    pub fn add_streaming_route(&mut self, method: Method, url: &str,
+                               handler: RequestHandler<App>,
+                               streaming: StreamingCheck<App>) {
+        let route = Route::new(method, &format!("{}{}", self.url_prefix, url));
+        self.handlers.try_lock().unwrap().push(
+            Handler::new(handler, route, Some(streaming))
+        );
+    }
 
-        // This will move to the thread, and the server will be stopped when
-        // the thread exits
-        let server = tiny_http::Server::http(bind.parse::<SocketAddr>()?)?;
+    pub fn set_access_log(&mut self, access_log: AccessLog) {
+        self.access_log = Some(access_log);
+    }
 
-        // Store the server address into the struct
-        self.listening_to = Some(server.server_addr());
+    pub fn set_max_body_memory(&mut self, max_body_memory: Option<usize>) {
+        self.max_body_memory = max_body_memory;
+    }
+
+    pub fn set_max_body_size(&mut self, max_body_size: Option<u64>) {
+        self.max_body_size = max_body_size;
+    }
+
+    pub fn set_max_headers(&mut self, max_headers: Option<usize>,
+                           max_headers_size: Option<usize>) {
+        self.max_headers = max_headers;
+        self.max_headers_size = max_headers_size;
+    }
+
+    pub fn set_tls(&mut self, tls: Option<TlsConfig>) {
+        self.tls = tls;
+    }
+
+    pub fn set_proxy_headers(&mut self, headers: Vec<String>) {
+        self.proxy_support.set_headers(headers);
+    }
+
+    pub fn set_trusted_proxies(&mut self, networks: Vec<IpNetwork>) {
+        self.proxy_support.set_trusted(networks);
+    }
 
+    /// Serve the routes added after this under the prefix. The prefix always
+    /// starts with a slash and never ends with one, whatever was provided.
+    pub fn set_url_prefix(&mut self, prefix: &str) {
+        let prefix = prefix.trim_matches('/');
+        self.url_prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", prefix)
+        };
+    }
+
+    /// Start serving the requests, returning the address the server is
+    /// listening to. Unix sockets don't have one, so the unspecified
+    /// address is returned for them.
+    pub fn listen(&mut self, bind: &str) -> Result<SocketAddr> {
         let (stop_send, stop_recv) = mpsc::channel();
+        let addr = if let Some(path) = unix::socket_path(bind) {
+            self.serve_unix(path, stop_send)?;
+            self.unix_socket = Some(path.to_path_buf());
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
+        } else {
+            self.serve(bind.parse::<SocketAddr>()?, stop_send)?
+        };
+
+        // Store the server address into the struct
+        self.listening_to = Some(addr);
         self.stop_wait = Some(stop_recv);
 
-        let app = self.app.clone();
-        let handlers_arc = self.handlers.clone();
-        let proxy_support = self.proxy_support.clone();
-        let should_stop = self.should_stop.clone();
+        Ok(addr)
+    }
+
+    fn server_state(&mut self) -> ServerState<App> {
+        ServerState {
+            app: self.app.clone(),
+            handlers: self.handlers.clone(),
+            proxy_support: self.proxy_support.clone(),
+            access_log: self.access_log.take()
+                .map(|access_log| Arc::new(Mutex::new(access_log))),
+            max_body_memory: self.max_body_memory,
+            max_body_size: self.max_body_size,
+            max_headers: self.max_headers,
+            max_headers_size: self.max_headers_size,
+            should_stop: self.should_stop.clone(),
+        }
+    }
+
+    /// Serve the requests received by the Unix socket. tiny_http can only
+    /// listen on TCP sockets, so they're always served by the event loop.
+    fn serve_unix(&mut self, path: &Path, stop_send: mpsc::Sender<()>)
+                  -> Result<()> {
+        if self.tls.is_some() {
+            return Err(ErrorKind::InvalidInput(
+                "HTTPS isn't supported on Unix sockets".into()
+            ).into());
+        }
+
+        let listener = Listener::unix(path)?;
+        event_loop::serve(listener, self.server_state(), stop_send)
+    }
+
+    #[cfg(feature = "async-server")]
+    fn serve(&mut self, addr: SocketAddr, stop_send: mpsc::Sender<()>)
+             -> Result<SocketAddr> {
+        if self.tls.is_some() {
+            return Err(ErrorKind::InvalidInput(
+                "HTTPS isn't supported by the asynchronous server".into()
+            ).into());
+        }
+
+        let listener = Listener::tcp(&addr)?;
+        let addr = listener.local_addr()?.unwrap();
+        event_loop::serve(listener, self.server_state(), stop_send)?;
+
+        Ok(addr)
+    }
+
+    #[cfg(not(feature = "async-server"))]
+    fn serve(&mut self, addr: SocketAddr, stop_send: mpsc::Sender<()>)
+             -> Result<SocketAddr> {
+        // This will move to the thread, and the server will be stopped when
+        // the thread exits
+        let server = create_server(addr, self.tls.clone())?;
+        let addr = server.server_addr();
+
+        let state = self.server_state();
         thread::spawn(move || {
             // Get a reference to the handlers
-            let handlers = &*handlers_arc.lock().unwrap();
+            let handlers = &*state.handlers.lock().unwrap();
 
-            // Prepare some headers which will be sent everytime
-            let server_header = header!(
-                format!("Server: Fisher/{}", env!("CARGO_PKG_VERSION"))
-            );
-            let content_type = header!("Content-Type: application/json");
+            let server_header = format!("Server: {}", SERVER_NAME)
+                .parse::<tiny_http::Header>().unwrap();
 
-            let ignored_method = Method::NonStandard(
-                "X_FISHER_IGNORE_THIS".parse().unwrap()
-            );
+            loop {
+                // The request sent to stop the server can't get through TLS,
+                // so the server also checks periodically if it should stop
+                let request = server.recv_timeout(Duration::from_millis(500));
 
-            for mut request in server.incoming_requests() {
                 // Don't accept any request anymore
-                if should_stop.load(Ordering::Relaxed) {
+                if state.should_stop.load(Ordering::Relaxed) {
                     break;
                 }
 
+                let mut request = match request {
+                    Ok(Some(request)) => request,
+                    Ok(None) => continue,
+                    Err(..) => break,
+                };
+
+                let started_at = Instant::now();
+
+                // The request is dispatched to the first matching route,
+                // which can ask to keep none of the body in memory
+                let streaming = wants_streaming(
+                    &*state.app, &handlers[..], request.method(),
+                    request.url(),
+                );
+
                 // Convert the request to a Fisher request
-                let mut req = Request::Web((&mut request).into());
-
-                let response = (|| {
-                    if *request.method() == ignored_method {
-                        // This request comes with the non-standard method used
-                        // to shut the server down -- no client should be using
-                        // it
-                        Response::Forbidden
-                    } else if let Err(e) = proxy_support.fix_request(&mut req) {
-                        Response::BadRequest(e)
-                    } else {
-                        let method = request.method();
-                        let url = request.url();
-
-                        for handler in handlers {
-                            if let Some(args) = handler.matches(method, url) {
-                                return handler.call(&app, &req, args);
-                            }
-                        }
-
-                        Response::NotFound
-                    }
-                })();
-
-                let mut tiny_response = tiny_http::Response::from_data(
-                    response.json().into_bytes()
-                ).with_status_code(response.status());
-
-                tiny_response.add_header(server_header.clone());
-                tiny_response.add_header(content_type.clone());
-
-                let _ = request.respond(tiny_response);
+                let (mut req, read_error) = match WebRequest::read(
+                    &mut request,
+                    if streaming { Some(0) } else { state.max_body_memory },
+                    state.max_body_size, state.max_headers,
+                    state.max_headers_size,
+                ) {
+                    Ok(web) => (Some(Request::Web(web)), None),
+                    Err(error) => (None, Some(error)),
+                };
+
+                let response = match req {
+                    Some(ref mut req) => dispatch(
+                        &*state.app, &handlers[..], &state.proxy_support,
+                        request.method(), request.url(), req,
+                    ),
+                    // The request body couldn't be read
+                    None => read_error_response(read_error.unwrap()),
+                };
+
+                let source = req.as_ref()
+                    .and_then(|req| req.web().ok())
+                    .map(|web| web.source);
+
+                match response {
+                    // Deferred responses are sent by another thread once
+                    // they're ready, so they don't block the other requests
+                    Response::Deferred(recv) => {
+                        let server_header = server_header.clone();
+                        let access_log = state.access_log.clone();
+                        thread::spawn(move || {
+                            let response = recv.recv().unwrap_or(
+                                Response::Unavailable("no response available")
+                            );
+                            respond(
+                                request, &response, server_header,
+                                &access_log, source, started_at,
+                            );
+                        });
+                    },
+                    response => respond(
+                        request, &response, server_header.clone(),
+                        &state.access_log, source, started_at,
+                    ),
+                }
             }
 
             stop_send.send(()).unwrap();
         });
 
-        Ok(self.listening_to.unwrap())
+        Ok(addr)
     }
 
     pub fn stop(&mut self) -> bool {
@@ -239,17 +573,27 @@ impl<App: Send + Sync + 'static> HttpServer<App> {
             // Tell the server to stop
             self.should_stop.store(true, Ordering::Relaxed);
 
-            // Send an HTTP request to force stopping the server
-            match TcpStream::connect(self.listening_to.unwrap()) {
-                Ok(mut conn) => {
-                    (writeln!(conn,
-                        "X_FISHER_IGNORE_THIS / HTTP/1.0\r\n\r\n"
-                    )).unwrap();
-                    conn.shutdown(Shutdown::Both).unwrap();
-                },
-                Err(..) => {
-                    return false;
-                },
+            // Unix sockets are served by the event loop, which is woken up
+            // by any connection
+            if let Some(ref path) = self.unix_socket {
+                if let Ok(conn) = UnixStream::connect(path) {
+                    let _ = conn.shutdown(Shutdown::Both);
+                }
+            } else {
+                // Send an HTTP request to force stopping the server
+                match TcpStream::connect(self.listening_to.unwrap()) {
+                    Ok(mut conn) => {
+                        // The server might close the connection as soon as
+                        // it notices it should stop
+                        let _ = writeln!(conn,
+                            "{} / HTTP/1.0\r\n\r\n", STOP_METHOD
+                        );
+                        let _ = conn.shutdown(Shutdown::Both);
+                    },
+                    Err(..) => {
+                        return false;
+                    },
+                }
             }
 
             if let Some(ref stop_wait) = self.stop_wait {
@@ -259,6 +603,11 @@ impl<App: Send + Sync + 'static> HttpServer<App> {
                 unreachable!();
             }
 
+            // The socket file isn't removed by closing the listener
+            if let Some(path) = self.unix_socket.take() {
+                let _ = fs::remove_file(&path);
+            }
+
             self.stop_wait = None;
             self.listening_to = None;
 
@@ -272,6 +621,9 @@ impl<App: Send + Sync + 'static> HttpServer<App> {
 
 #[cfg(test)]
 mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
     use std::time::Duration;
 
     use tiny_http::Method;
@@ -281,6 +633,7 @@ mod tests {
     use requests::Request;
     use web::responses::Response;
     use utils::testing::*;
+    use utils;
     use super::{Route, Handler, HttpServer};
 
 
@@ -291,13 +644,13 @@ mod tests {
         if data.0 == args {
            Response::Ok
         } else {
-           Response::Forbidden
+           Response::Forbidden("wrong arguments")
         }
     }
 
     fn dummy_handler() -> Handler<DummyData> {
         let route = Route::new(Method::Get, "/?");
-        Handler::new(Box::new(dummy_handler_fn), route)
+        Handler::new(Box::new(dummy_handler_fn), route, None)
     }
 
 
@@ -378,7 +731,9 @@ mod tests {
         }
 
         // Create the server instance
-        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        let mut server = HttpServer::new(
+            Arc::new(DummyData(vec!["test".into()])), 0,
+        );
         server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
 
         // Start the server
@@ -406,4 +761,111 @@ mod tests {
             .is_err()
         );
     }
+
+    #[test]
+    fn test_unix_socket() {
+        let dir = utils::create_temp_dir().unwrap();
+        let socket = dir.join("fisher.sock");
+
+        let mut server = HttpServer::new(
+            Arc::new(DummyData(vec!["test".into()])), 0,
+        );
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        let addr = server.listen(
+            &format!("unix:{}", socket.to_str().unwrap())
+        ).unwrap();
+
+        // No TCP port is opened
+        assert_eq!(addr.port(), 0);
+
+        let request = |conn: &mut UnixStream| {
+            write!(conn, "GET /test HTTP/1.0\r\n\r\n").unwrap();
+            let mut response = String::new();
+            conn.read_to_string(&mut response).unwrap();
+            assert_eq!(
+                response.split(' ').nth(1), Some("200"), "{}", response
+            );
+        };
+
+        // The idle connections don't prevent the other ones from being
+        // served, however many they are
+        let mut conns = (0..200).map(|_| {
+            let conn = UnixStream::connect(&socket).unwrap();
+            conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            conn
+        }).collect::<Vec<_>>();
+        request(conns.last_mut().unwrap());
+        request(&mut conns[0]);
+
+        // The socket is removed when the server stops
+        server.stop();
+        assert!(! socket.exists());
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_route() {
+        fn streamed(_data: &DummyData, req: &Request, _args: Vec<String>)
+                    -> Response {
+            let web = req.web().unwrap();
+            if web.body_file.is_some() && web.body.is_empty() {
+                Response::Ok
+            } else {
+                Response::Forbidden("not streamed")
+            }
+        }
+
+        fn wants_streaming(_data: &DummyData, args: Vec<String>) -> bool {
+            args[0] == "yes"
+        }
+
+        let mut server = HttpServer::new(
+            Arc::new(DummyData(Vec::new())), 0,
+        );
+        server.add_streaming_route(
+            Method::Post, "/?", Box::new(streamed),
+            Box::new(wants_streaming),
+        );
+        let addr = server.listen("127.0.0.1:0").unwrap();
+
+        // Only the requests the route asks for are streamed, even if they
+        // would fit in memory
+        let client = hyper::Client::new();
+        for &(url, status) in &[
+            ("yes", StatusCode::Ok), ("no", StatusCode::Forbidden),
+        ] {
+            let res = client.post(&format!("http://{}/{}", addr, url))
+                .body("small body").send().unwrap();
+            assert_eq!(res.status, status);
+        }
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_url_prefix() {
+        let mut server = HttpServer::new(
+            Arc::new(DummyData(vec!["test".into()])), 0,
+        );
+
+        for &(prefix, expected) in &[
+            ("", ""), ("/", ""), ("webhooks", "/webhooks"),
+            ("/webhooks/", "/webhooks"), ("//a/b//", "/a/b"),
+        ] {
+            server.set_url_prefix(prefix);
+            assert_eq!(server.url_prefix, expected);
+        }
+
+        // The prefix is applied to the routes added after it
+        server.set_url_prefix("webhooks/");
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+
+        let handlers = server.handlers.lock().unwrap();
+        assert_eq!(
+            handlers[0].matches(&Method::Get, "/webhooks/test"),
+            Some(vec!["test".into()])
+        );
+        assert_eq!(handlers[0].matches(&Method::Get, "/test"), None);
+    }
 }
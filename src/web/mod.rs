@@ -18,10 +18,20 @@ mod app;
 mod requests;
 mod responses;
 mod proxies;
+mod access_log;
+mod unix;
+mod rate_limit;
+mod request_log;
+mod event_loop;
 
 // Parts of the webapp
 mod api;
 
-pub use self::http::HttpServer;
-pub use self::app::WebApp;
+pub use self::http::{HttpServer, TlsConfig};
+pub use self::app::{WebApp, WebConfig, DEFAULT_MAX_BODY_SIZE};
+pub use self::app::{DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEADERS_SIZE};
+pub use self::app::{DEFAULT_WAIT_TIMEOUT, DEFAULT_MAX_WAITERS};
+pub use self::request_log::DEFAULT_REQUEST_LOG_MAX_SIZE;
 pub use self::requests::WebRequest;
+pub use self::proxies::DEFAULT_PROXY_HEADERS;
+pub use self::unix::socket_path as unix_socket_path;
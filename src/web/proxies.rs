@@ -17,12 +17,19 @@ use std::net::IpAddr;
 
 use requests::Request;
 use fisher_common::prelude::*;
-use utils;
+use utils::{self, IpNetwork};
+
+
+pub const DEFAULT_PROXY_HEADERS: &'static [&'static str] = &[
+    "X-Forwarded-For",
+];
 
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ProxySupport {
     behind: u8,
+    headers: Vec<String>,
+    trusted: Vec<IpNetwork>,
 }
 
 impl ProxySupport {
@@ -30,9 +37,24 @@ impl ProxySupport {
     pub fn new(behind: u8) -> Self {
         ProxySupport {
             behind: behind,
+            headers: DEFAULT_PROXY_HEADERS.iter()
+                .map(|name| name.to_string()).collect(),
+            trusted: Vec::new(),
         }
     }
 
+    /// Set the headers containing the IP of the client. The first one
+    /// present in the request is used.
+    pub fn set_headers(&mut self, headers: Vec<String>) {
+        self.headers = headers;
+    }
+
+    /// Only believe the headers of the requests coming from one of these
+    /// networks. Every request is believed if no network is set.
+    pub fn set_trusted(&mut self, networks: Vec<IpNetwork>) {
+        self.trusted = networks;
+    }
+
     pub fn source_ip(&self, req: &Request) -> Result<IpAddr> {
         let req = req.web()?;
         let original = req.source;
@@ -42,8 +64,21 @@ impl ProxySupport {
             return Ok(original);
         }
 
-        // Parse the X-Forwarded-For header
-        let mut forwarded_ips = utils::parse_forwarded_for(&req.headers)?;
+        // Anyone can send those headers, so the request must come from one
+        // of the proxies to believe them
+        let trusted = self.trusted.is_empty()
+            || self.trusted.iter().any(|network| network.contains(&original));
+        if ! trusted {
+            return Err(ErrorKind::NotBehindProxy.into());
+        }
+
+        let mut forwarded_ips = Vec::new();
+        for header in &self.headers {
+            forwarded_ips = utils::parse_ip_header(&req.headers, header)?;
+            if ! forwarded_ips.is_empty() {
+                break;
+            }
+        }
 
         // Return an error if there was no header
         if forwarded_ips.is_empty() {
@@ -164,6 +199,62 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_proxy_headers() {
+        let mut p = ProxySupport::new(1);
+        p.set_headers(vec!["X-Real-IP".into(), "X-Forwarded-For".into()]);
+
+        // The first header present in the request is used
+        let mut req = req!("127.2.2.2");
+        assert_eq!(
+            p.source_ip(&req).unwrap(),
+            IpAddr::from_str("127.2.2.2").unwrap()
+        );
+        if let Request::Web(ref mut inner) = req {
+            inner.headers.insert("x-real-ip".into(), "127.3.3.3".into());
+        }
+        assert_eq!(
+            p.source_ip(&req).unwrap(),
+            IpAddr::from_str("127.3.3.3").unwrap()
+        );
+
+        // Headers which aren't configured are ignored
+        p.set_headers(vec!["X-Real-IP".into()]);
+        assert_err!(
+            p.source_ip(&req!("127.2.2.2")), ErrorKind::NotBehindProxy
+        );
+    }
+
+
+    #[test]
+    fn test_trusted_proxies() {
+        let mut p = ProxySupport::new(1);
+        p.set_trusted(vec![
+            "127.1.0.0/16".parse().unwrap(), "10.0.0.1".parse().unwrap(),
+        ]);
+
+        // The request comes from 127.1.1.1
+        assert_eq!(
+            p.source_ip(&req!("127.2.2.2")).unwrap(),
+            IpAddr::from_str("127.2.2.2").unwrap()
+        );
+
+        // The headers sent by anyone else aren't believed
+        p.set_trusted(vec!["10.0.0.1".parse().unwrap()]);
+        assert_err!(
+            p.source_ip(&req!("127.2.2.2")), ErrorKind::NotBehindProxy
+        );
+
+        // Nothing is checked if the proxy support is disabled
+        let mut p = ProxySupport::new(0);
+        p.set_trusted(vec!["10.0.0.1".parse().unwrap()]);
+        assert_eq!(
+            p.source_ip(&req!("127.2.2.2")).unwrap(),
+            IpAddr::from_str("127.1.1.1").unwrap()
+        );
+    }
+
+
     #[test]
     fn test_fix_request() {
         let proxy = ProxySupport::new(1);
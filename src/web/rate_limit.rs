@@ -0,0 +1,370 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use hooks::RateLimit;
+
+
+// How many sources of each hook can have their own bucket, so the sources
+// which stopped sending requests don't use memory forever. The other ones
+// share a single bucket, until some of the buckets are full again and can
+// be forgotten.
+const MAX_SOURCES: usize = 1024;
+
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Bucket {
+            tokens: limit.per_minute as f64,
+            updated: now,
+        }
+    }
+
+    // Add the tokens earned since the last update, up to the limit
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64
+                    + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+        let capacity = limit.per_minute as f64;
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated = now;
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= limit.per_minute as f64
+    }
+
+    // When the bucket will be full if no more tokens are taken, rounded up
+    // to the next millisecond
+    fn full_at(&self, limit: &RateLimit) -> Instant {
+        let missing = limit.per_minute as f64 - self.tokens;
+        let millis = missing * 60_000.0 / limit.per_minute as f64;
+        self.updated + Duration::from_millis(millis.ceil() as u64 + 1)
+    }
+
+    // How many seconds are needed to earn the next token, rounded up
+    fn next_token(&self, limit: &RateLimit) -> u64 {
+        if self.tokens >= 1.0 {
+            return 0;
+        }
+
+        let missing = 1.0 - self.tokens;
+        (missing * 60.0 / limit.per_minute as f64).ceil() as u64
+    }
+}
+
+
+/// The buckets of a single hook.
+#[derive(Debug)]
+struct HookBuckets {
+    limit: RateLimit,
+    /// The bucket of all the requests, or of the sources without a bucket
+    /// of their own if the limit is per source.
+    shared: Bucket,
+    sources: HashMap<IpAddr, Bucket>,
+    /// None of the buckets of the sources is full before then.
+    next_full: Option<Instant>,
+}
+
+impl HookBuckets {
+
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        HookBuckets {
+            limit: *limit,
+            shared: Bucket::new(limit, now),
+            sources: HashMap::new(),
+            next_full: None,
+        }
+    }
+
+    fn set_limit(&mut self, limit: &RateLimit) {
+        // The limit could have changed since the hook was reloaded
+        if self.limit != *limit {
+            self.limit = *limit;
+            self.next_full = None;
+        }
+    }
+
+    /// Return the bucket of the source, if there's one or there is room to
+    /// create it, or the shared one.
+    fn bucket(&mut self, source: IpAddr, now: Instant) -> &mut Bucket {
+        if ! self.limit.per_source {
+            return &mut self.shared;
+        }
+
+        if ! self.sources.contains_key(&source)
+           && self.sources.len() >= MAX_SOURCES {
+            self.forget_full(now);
+        }
+
+        if self.sources.len() < MAX_SOURCES {
+            let limit = self.limit;
+            self.sources.entry(source)
+                .or_insert_with(|| Bucket::new(&limit, now))
+        } else {
+            match self.sources.get_mut(&source) {
+                Some(bucket) => bucket,
+                None => &mut self.shared,
+            }
+        }
+    }
+
+    /// Return the bucket the requests of the source would use, without
+    /// creating it.
+    fn find(&mut self, source: IpAddr) -> Option<&mut Bucket> {
+        let shared = self.sources.len() >= MAX_SOURCES
+                  && ! self.sources.contains_key(&source);
+        if ! self.limit.per_source || shared {
+            Some(&mut self.shared)
+        } else {
+            self.sources.get_mut(&source)
+        }
+    }
+
+    // Forget the full buckets, which would be recreated the same. The ones
+    // still being refilled are never forgotten, or a source could empty its
+    // bucket and get a full one back. The buckets are scanned again only
+    // once at least one of them could be full.
+    fn forget_full(&mut self, now: Instant) {
+        if self.next_full.map_or(false, |next_full| now < next_full) {
+            return;
+        }
+
+        let limit = self.limit;
+        let mut next_full: Option<Instant> = None;
+        self.sources.retain(|_, bucket| {
+            bucket.refill(&limit, now);
+            if bucket.is_full(&limit) {
+                return false;
+            }
+
+            let full_at = bucket.full_at(&limit);
+            next_full = Some(next_full.map_or(full_at, |next_full| {
+                cmp::min(next_full, full_at)
+            }));
+            true
+        });
+        self.next_full = next_full;
+    }
+}
+
+
+/// The current state of the rate limit of an hook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RateLimitStatus {
+    pub per_minute: u32,
+    pub per_source: bool,
+    /// How many requests can be accepted right now. If the limit is per
+    /// source, this is the number for the most limited source.
+    pub available: u32,
+}
+
+
+/// A token bucket rate limiter, with a bucket for each hook or for each
+/// source of each hook.
+#[derive(Debug)]
+pub struct RateLimiter {
+    hooks: HashMap<String, HookBuckets>,
+}
+
+impl RateLimiter {
+
+    pub fn new() -> Self {
+        RateLimiter {
+            hooks: HashMap::new(),
+        }
+    }
+
+    /// Take a token from the bucket of the request, returning false if the
+    /// bucket is empty and the request should be rejected.
+    pub fn allow(&mut self, hook: &str, source: IpAddr, limit: &RateLimit)
+                 -> bool {
+        self.allow_at(hook, source, limit, Instant::now())
+    }
+
+    fn allow_at(&mut self, hook: &str, source: IpAddr, limit: &RateLimit,
+                now: Instant) -> bool {
+        let buckets = self.hooks.entry(hook.to_string())
+            .or_insert_with(|| HookBuckets::new(limit, now));
+        buckets.set_limit(limit);
+
+        let bucket = buckets.bucket(source, now);
+        bucket.refill(limit, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get after how many seconds the bucket of the request will allow it
+    /// again. This is zero if the request would be allowed right now.
+    pub fn retry_after(&mut self, hook: &str, source: IpAddr,
+                       limit: &RateLimit) -> u64 {
+        let bucket = self.hooks.get_mut(hook)
+            .and_then(|buckets| buckets.find(source));
+        match bucket {
+            Some(bucket) => {
+                bucket.refill(limit, Instant::now());
+                bucket.next_token(limit)
+            },
+            None => 0,
+        }
+    }
+
+    /// Get the current state of the rate limit of an hook.
+    pub fn status(&mut self, hook: &str, limit: &RateLimit)
+                  -> RateLimitStatus {
+        let now = Instant::now();
+
+        let mut available = limit.per_minute as f64;
+        if let Some(buckets) = self.hooks.get_mut(hook) {
+            buckets.shared.refill(limit, now);
+            available = available.min(buckets.shared.tokens);
+
+            for bucket in buckets.sources.values_mut() {
+                bucket.refill(limit, now);
+                available = available.min(bucket.tokens);
+            }
+        }
+
+        RateLimitStatus {
+            per_minute: limit.per_minute,
+            per_source: limit.per_source,
+            available: available.floor() as u32,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use hooks::RateLimit;
+
+    use super::{Bucket, RateLimiter, MAX_SOURCES};
+
+
+    #[test]
+    fn test_bucket_refill() {
+        let limit = RateLimit { per_minute: 60, per_source: false };
+        let start = Instant::now();
+
+        let mut bucket = Bucket::new(&limit, start);
+        bucket.tokens = 0.0;
+
+        // A token is earned every second, up to the limit
+        bucket.refill(&limit, start + Duration::from_millis(2500));
+        assert_eq!(bucket.tokens, 2.5);
+        bucket.refill(&limit, start + Duration::from_secs(3600));
+        assert_eq!(bucket.tokens, 60.0);
+    }
+
+
+    #[test]
+    fn test_bucket_next_token() {
+        let limit = RateLimit { per_minute: 4, per_source: false };
+        let mut bucket = Bucket::new(&limit, Instant::now());
+        assert_eq!(bucket.next_token(&limit), 0);
+
+        // A token is earned every 15 seconds, rounded up
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.next_token(&limit), 15);
+        bucket.tokens = 0.5;
+        assert_eq!(bucket.next_token(&limit), 8);
+    }
+
+
+    #[test]
+    fn test_allow() {
+        let first = IpAddr::from_str("127.0.0.1").unwrap();
+        let second = IpAddr::from_str("127.0.0.2").unwrap();
+
+        let mut limiter = RateLimiter::new();
+        let limit = RateLimit { per_minute: 2, per_source: false };
+        assert!(limiter.allow("a", first, &limit));
+        assert!(limiter.allow("a", second, &limit));
+        assert!(! limiter.allow("a", first, &limit));
+        assert_eq!(limiter.status("a", &limit).available, 0);
+        assert!(limiter.retry_after("a", first, &limit) > 0);
+        assert!(limiter.retry_after("a", first, &limit) <= 30);
+
+        // Every hook has its own bucket
+        assert!(limiter.allow("b", first, &limit));
+        assert_eq!(limiter.status("b", &limit).available, 1);
+
+        // With per-source limits every source has its own bucket
+        let limit = RateLimit { per_minute: 1, per_source: true };
+        assert!(limiter.allow("c", first, &limit));
+        assert!(! limiter.allow("c", first, &limit));
+        assert!(limiter.allow("c", second, &limit));
+        assert_eq!(limiter.status("c", &limit).available, 0);
+
+        // Hooks without requests have all the tokens available
+        assert_eq!(limiter.status("d", &limit).available, 1);
+        assert_eq!(limiter.retry_after("d", first, &limit), 0);
+    }
+
+
+    #[test]
+    fn test_max_sources() {
+        let source = |i: usize| IpAddr::from(Ipv4Addr::from(i as u32));
+        let limit = RateLimit { per_minute: 1, per_source: true };
+        let start = Instant::now();
+
+        // Fill the limiter with empty buckets
+        let mut limiter = RateLimiter::new();
+        for i in 0..MAX_SOURCES {
+            assert!(limiter.allow_at("a", source(i), &limit, start));
+        }
+        assert_eq!(limiter.hooks["a"].sources.len(), MAX_SOURCES);
+
+        // The other sources share a single bucket
+        assert!(limiter.allow_at("a", source(MAX_SOURCES), &limit, start));
+        let new = source(MAX_SOURCES + 1);
+        assert!(! limiter.allow_at("a", new, &limit, start));
+        assert!(limiter.retry_after("a", new, &limit) > 0);
+
+        // The buckets being refilled are never forgotten
+        assert!(! limiter.allow_at("a", source(0), &limit, start));
+        assert_eq!(limiter.hooks["a"].sources.len(), MAX_SOURCES);
+
+        // Every hook has its own sources
+        assert!(limiter.allow_at("b", new, &limit, start));
+
+        // The full buckets are forgotten to make room for the new sources
+        let later = start + Duration::from_secs(61);
+        assert!(limiter.allow_at("a", new, &limit, later));
+        assert_eq!(limiter.hooks["a"].sources.len(), 1);
+        assert!(! limiter.allow_at("a", new, &limit, later));
+    }
+}
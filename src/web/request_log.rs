@@ -0,0 +1,222 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fisher_common::prelude::*;
+
+use requests::RequestType;
+
+
+/// The default size the request log can grow to before being rotated, in
+/// bytes.
+pub const DEFAULT_REQUEST_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+// How many rotated files are kept, besides the current one
+const ROTATED_FILES: usize = 5;
+
+
+fn open(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+
+fn json_line(timestamp: u64, source: Option<IpAddr>, hook: &str,
+             decision: Option<RequestType>, status: u16) -> String {
+    json!({
+        "timestamp": timestamp,
+        "source": source.map(|source| source.to_string()),
+        "hook": hook,
+        // Requests rejected before reaching the providers have no decision
+        "decision": decision.map(|decision| match decision {
+            RequestType::ExecuteHook => "accepted",
+            RequestType::Invalid => "invalid",
+            RequestType::Ping => "ping",
+            RequestType::Skip => "skipped",
+        }),
+        "status": status,
+    }).to_string()
+}
+
+
+/// A file containing every request received by the hooks, as a JSON object
+/// per line. When the file grows over the maximum size it's renamed to
+/// `FILE.1` (shifting the older ones up to `FILE.5`), and a new one is
+/// started.
+#[derive(Debug)]
+pub struct RequestLog {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl RequestLog {
+
+    pub fn open(path: &Path, max_size: u64) -> Result<Self> {
+        let file = open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RequestLog {
+            path: path.to_path_buf(),
+            max_size: max_size,
+            file: file,
+            size: size,
+        })
+    }
+
+    pub fn log(&mut self, source: Option<IpAddr>, hook: &str,
+               decision: Option<RequestType>, status: u16) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        let line = json_line(timestamp, source, hook, decision, status);
+
+        // Failing to log a request shouldn't fail the request itself
+        if let Err(mut error) = self.write(&line) {
+            error.set_location(ErrorLocation::File(
+                self.path.to_string_lossy().into(), None,
+            ));
+            error.pretty_print();
+        }
+    }
+
+    fn write(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        // The oldest file is replaced by the one before it
+        for index in (1..ROTATED_FILES).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+
+        self.file = open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    use serde_json::{self, Value};
+
+    use requests::RequestType;
+    use utils;
+
+    use super::{json_line, RequestLog};
+
+
+    fn read(path: &str) -> String {
+        let mut content = String::new();
+        File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+
+    #[test]
+    fn test_json_line() {
+        let parse = |line: String| -> Value {
+            serde_json::from_str(&line).unwrap()
+        };
+        let source = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(parse(json_line(
+            42, Some(source), "a.sh", Some(RequestType::ExecuteHook), 200,
+        )), json!({
+            "timestamp": 42, "source": "127.0.0.1", "hook": "a.sh",
+            "decision": "accepted", "status": 200,
+        }));
+        assert_eq!(parse(json_line(
+            42, Some(source), "a.sh", Some(RequestType::Invalid), 403,
+        ))["decision"], json!("invalid"));
+        assert_eq!(parse(json_line(
+            42, Some(source), "a.sh", Some(RequestType::Ping), 200,
+        ))["decision"], json!("ping"));
+        assert_eq!(parse(json_line(
+            42, Some(source), "a.sh", Some(RequestType::Skip), 200,
+        ))["decision"], json!("skipped"));
+
+        // Rejections before the validation don't have a decision
+        assert_eq!(parse(json_line(
+            42, None, "missing.sh", None, 404,
+        )), json!({
+            "timestamp": 42, "source": null, "hook": "missing.sh",
+            "decision": null, "status": 404,
+        }));
+    }
+
+
+    #[test]
+    fn test_rotation() {
+        let dir = utils::create_temp_dir().unwrap();
+        let path = dir.join("requests.log");
+        let file = path.to_str().unwrap();
+
+        // Every line is about 80 bytes long, so only two fit in the file
+        let mut log = RequestLog::open(&path, 200).unwrap();
+        for hook in &["a.sh", "b.sh", "c.sh", "d.sh", "e.sh"] {
+            log.log(None, hook, None, 404);
+        }
+
+        assert!(read(file).contains("e.sh"));
+        assert!(read(&format!("{}.1", file)).contains("c.sh"));
+        assert!(read(&format!("{}.1", file)).contains("d.sh"));
+        assert!(read(&format!("{}.2", file)).contains("a.sh"));
+
+        // The size of the existing file is taken into account
+        let mut log = RequestLog::open(&path, 200).unwrap();
+        log.log(None, "f.sh", None, 404);
+        log.log(None, "g.sh", None, 404);
+        assert!(read(&format!("{}.1", file)).contains("f.sh"));
+        assert!(read(&format!("{}.3", file)).contains("a.sh"));
+
+        // Only a few rotated files are kept
+        for _ in 0..20 {
+            log.log(None, "h.sh", None, 404);
+        }
+        assert!(fs::metadata(format!("{}.5", file)).is_ok());
+        assert!(fs::metadata(format!("{}.6", file)).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
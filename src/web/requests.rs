@@ -15,53 +15,298 @@
 
 use std::net::IpAddr;
 use std::collections::HashMap;
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use serde_json;
 use tiny_http;
 use url::form_urlencoded;
 
+use fisher_common::prelude::*;
+use utils;
+
+
+/// A request body too big to be kept in memory, stored in a temporary
+/// directory. The directory is removed when the last reference is dropped.
+
+#[derive(Debug)]
+pub struct BodyFile {
+    directory: PathBuf,
+    path: PathBuf,
+    size: u64,
+}
+
+impl BodyFile {
+
+    fn create() -> Result<(Self, fs::File)> {
+        let directory = utils::create_temp_dir()?;
+        let mut path = directory.clone();
+        path.push("request_body");
+
+        let file = fs::File::create(&path)?;
+        Ok((BodyFile {
+            directory: directory,
+            path: path,
+            size: 0,
+        }, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for BodyFile {
+
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
 
 #[derive(Debug, Clone)]
 pub struct WebRequest {
     pub source: IpAddr,
+    pub method: String,
+    pub url: String,
     pub headers: HashMap<String, String>,
     pub params: HashMap<String, String>,
     pub body: String,
+    pub body_file: Option<Arc<BodyFile>>,
 }
 
+impl WebRequest {
 
-impl<'a> From<&'a mut tiny_http::Request> for WebRequest {
-
-    fn from(origin: &'a mut tiny_http::Request) -> WebRequest {
+    pub fn read(origin: &mut tiny_http::Request,
+                max_body_memory: Option<usize>, max_body_size: Option<u64>,
+                max_headers: Option<usize>, max_headers_size: Option<usize>)
+                -> Result<WebRequest> {
         // Get the source IP
         let source = origin.remote_addr().ip();
 
-        // Get the headers
-        let mut headers = HashMap::new();
-        for header in origin.headers() {
-            headers.insert(
-                header.field.as_str().as_str().to_string(),
-                header.value.as_str().to_string(),
-            );
+        // tiny_http already received and buffered all the headers, so the
+        // limits only keep the rejected ones away from the hooks. The
+        // async server checks them while reading from the socket instead
+        let headers = origin.headers().iter().map(|header| (
+            header.field.as_str().as_str().to_string(),
+            header.value.as_str().to_string(),
+        )).collect::<Vec<_>>();
+        check_headers(&headers, max_headers, max_headers_size)?;
+
+        // Reject the bodies declared too big before reading them
+        if let Some(length) = origin.body_length() {
+            check_body_size(length as u64, max_body_size)?;
         }
 
-        // Get the body
-        let mut body = String::new();
-        origin.as_reader().read_to_string(&mut body).unwrap();
+        // Get the body, storing it on disk if it's too big
+        let (body, body_file) = read_body(
+            origin.as_reader(), max_body_memory, max_body_size,
+        )?;
+
+        Ok(WebRequest::from_parts(
+            source, origin.method().as_str(), origin.url(), headers, body,
+            body_file,
+        ))
+    }
+
+    /// Build the request from its parts, once the whole body was received.
+    pub fn from_parts(source: IpAddr, method: &str, url: &str,
+                      headers: Vec<(String, String)>, body: String,
+                      body_file: Option<Arc<BodyFile>>) -> WebRequest {
+        let headers = headers.into_iter().collect::<HashMap<_, _>>();
 
         // Get the querystring
-        let url = origin.url();
-        let params = if url.contains('?') {
+        let mut params = if url.contains('?') {
             let query = url.rsplitn(2, '?').nth(0).unwrap();
             params_from_query(query)
         } else {
             HashMap::new()
         };
 
+        // Bodies stored on disk are too big to be forms
+        if body_file.is_none() {
+            add_form_params(&headers, &body, &mut params);
+        }
+
         WebRequest {
             source: source,
+            method: method.to_string(),
+            url: url.to_string(),
             headers: headers,
             params: params,
             body: body,
+            body_file: body_file,
+        }
+    }
+
+    /// Get the size of the request body in bytes, even if it's stored on
+    /// disk.
+    pub fn body_size(&self) -> u64 {
+        if let Some(ref file) = self.body_file {
+            file.size
+        } else {
+            self.body.len() as u64
+        }
+    }
+
+    /// Get the request body, reading it from disk if it was too big to be
+    /// kept in memory.
+    pub fn read_body(&self) -> Result<Cow<str>> {
+        if let Some(ref file) = self.body_file {
+            let mut body = String::new();
+            fs::File::open(file.path())?.read_to_string(&mut body)?;
+            Ok(Cow::Owned(body))
+        } else {
+            Ok(Cow::Borrowed(&self.body))
+        }
+    }
+
+    /// Get the request body parsed as JSON, failing if it's not valid JSON.
+    pub fn json_body(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::from_str(&self.read_body()?)?)
+    }
+}
+
+
+/// Check the headers don't exceed the limits, counting the size of both
+/// their names and their values. With tiny_http this only happens after
+/// all the headers were received, so it doesn't bound the memory used
+/// while reading them.
+pub fn check_headers(headers: &[(String, String)], max_count: Option<usize>,
+                     max_size: Option<usize>) -> Result<()> {
+    if let Some(max_count) = max_count {
+        if headers.len() > max_count {
+            return Err(ErrorKind::HeadersTooLarge.into());
+        }
+    }
+
+    if let Some(max_size) = max_size {
+        let size = headers.iter().fold(0, |size, &(ref name, ref value)| {
+            size + name.len() + value.len()
+        });
+        if size > max_size {
+            return Err(ErrorKind::HeadersTooLarge.into());
+        }
+    }
+
+    Ok(())
+}
+
+
+pub fn check_body_size(size: u64, max_size: Option<u64>) -> Result<()> {
+    match max_size {
+        Some(max_size) if size > max_size => {
+            Err(ErrorKind::BodyTooLarge(max_size).into())
+        },
+        _ => Ok(()),
+    }
+}
+
+
+/// Receive the request body in chunks, moving it to disk as soon as it
+/// doesn't fit in memory anymore.
+pub struct BodyWriter {
+    buffer: Vec<u8>,
+    file: Option<(BodyFile, fs::File)>,
+    max_memory: Option<usize>,
+    max_size: Option<u64>,
+}
+
+impl BodyWriter {
+
+    pub fn new(max_memory: Option<usize>, max_size: Option<u64>) -> Self {
+        BodyWriter {
+            buffer: Vec::new(),
+            file: None,
+            max_memory: max_memory,
+            max_size: max_size,
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        check_body_size(self.size() + data.len() as u64, self.max_size)?;
+
+        let exceeds_memory = match self.max_memory {
+            Some(max_memory) => self.buffer.len() + data.len() > max_memory,
+            None => false,
+        };
+        if self.file.is_none() && exceeds_memory {
+            // Move what was received so far to the file
+            let (mut body_file, mut file) = BodyFile::create()?;
+            file.write_all(&self.buffer)?;
+            body_file.size = self.buffer.len() as u64;
+            self.buffer = Vec::new();
+            self.file = Some((body_file, file));
+        }
+
+        if let Some((ref mut body_file, ref mut file)) = self.file {
+            file.write_all(data)?;
+            body_file.size += data.len() as u64;
+        } else {
+            self.buffer.extend_from_slice(data);
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(String, Option<Arc<BodyFile>>)> {
+        if let Some((body_file, _)) = self.file {
+            return Ok((String::new(), Some(Arc::new(body_file))));
+        }
+
+        match String::from_utf8(self.buffer) {
+            Ok(body) => Ok((body, None)),
+            Err(..) => Err(ErrorKind::InvalidInput(
+                "the request body is not valid UTF-8".into()
+            ).into()),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self.file {
+            Some((ref body_file, _)) => body_file.size,
+            None => self.buffer.len() as u64,
+        }
+    }
+}
+
+
+fn read_body<R: Read + ?Sized>(reader: &mut R, max_memory: Option<usize>,
+                               max_size: Option<u64>)
+                               -> Result<(String, Option<Arc<BodyFile>>)> {
+    let mut writer = BodyWriter::new(max_memory, max_size);
+
+    // Bodies without a Content-Length are cut while they're read, as soon
+    // as they exceed the limit
+    let mut buffer = [0; 8192];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => writer.write(&buffer[..read])?,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {},
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    writer.finish()
+}
+
+
+// Add the fields of form-encoded bodies to the params, without replacing
+// the ones in the querystring
+fn add_form_params(headers: &HashMap<String, String>, body: &str,
+                   params: &mut HashMap<String, String>) {
+    let is_form = headers.iter().any(|(name, value)| {
+        name.to_lowercase() == "content-type" && value.to_lowercase()
+            .starts_with("application/x-www-form-urlencoded")
+    });
+
+    if is_form {
+        for (key, value) in params_from_query(body) {
+            params.entry(key).or_insert(value);
         }
     }
 }
@@ -74,3 +319,138 @@ pub fn params_from_query(query: &str) -> HashMap<String, String> {
     }
     hashmap
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Cursor, Read};
+
+    use std::collections::HashMap;
+
+    use fisher_common::prelude::*;
+    use utils::testing::*;
+
+    use super::{read_body, add_form_params, check_headers};
+
+
+    #[test]
+    fn test_read_body() {
+        let content = "a".repeat(1024);
+
+        // Without a limit the body is always kept in memory
+        let (body, file) = read_body(
+            &mut Cursor::new(content.clone()), None, None,
+        ).unwrap();
+        assert_eq!(body, content);
+        assert!(file.is_none());
+
+        // Bodies under the limit are kept in memory
+        let (body, file) = read_body(
+            &mut Cursor::new(content.clone()), Some(1024), None,
+        ).unwrap();
+        assert_eq!(body, content);
+        assert!(file.is_none());
+
+        // Bodies over the limit are stored on disk
+        let (body, file) = read_body(
+            &mut Cursor::new(content.clone()), Some(100), None,
+        ).unwrap();
+        assert_eq!(body, "");
+        let file = file.unwrap();
+
+        let mut on_disk = String::new();
+        fs::File::open(file.path()).unwrap()
+            .read_to_string(&mut on_disk).unwrap();
+        assert_eq!(on_disk, content);
+        assert_eq!(file.size, 1024);
+
+        // The file is removed when the body is dropped
+        let path = file.path().to_path_buf();
+        drop(file);
+        assert!(! path.exists());
+    }
+
+
+    #[test]
+    fn test_read_body_max_size() {
+        let content = "a".repeat(1024);
+
+        // Bodies up to the maximum size are accepted
+        for max_memory in &[None, Some(100)] {
+            let (body, file) = read_body(
+                &mut Cursor::new(content.clone()), *max_memory, Some(1024),
+            ).unwrap();
+            assert_eq!(body.len() as u64 + file.map_or(0, |f| f.size), 1024);
+        }
+
+        // Bigger bodies are rejected, even if they would be stored on disk
+        for max_memory in &[None, Some(100)] {
+            let error = read_body(
+                &mut Cursor::new(content.clone()), *max_memory, Some(1023),
+            ).unwrap_err();
+            match *error.kind() {
+                ErrorKind::BodyTooLarge(1023) => {},
+                ref other => panic!("unexpected error: {:?}", other),
+            }
+        }
+    }
+
+
+    #[test]
+    fn test_check_headers() {
+        let headers = vec![
+            ("Host".to_string(), "example.com".to_string()),
+            ("X-Test".to_string(), "abcde".to_string()),
+        ];
+
+        // Both the names and the values count towards the size
+        assert!(check_headers(&headers, None, None).is_ok());
+        assert!(check_headers(&headers, Some(2), Some(26)).is_ok());
+
+        for &(max_count, max_size) in &[(Some(1), None), (None, Some(25))] {
+            let error = check_headers(&headers, max_count, max_size);
+            match *error.unwrap_err().kind() {
+                ErrorKind::HeadersTooLarge => {},
+                ref other => panic!("unexpected error: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_form_params() {
+        let body = "secret=from+body&event=push";
+
+        // Bodies aren't parsed without the right content type
+        let mut headers = HashMap::new();
+        let mut params = HashMap::new();
+        add_form_params(&headers, body, &mut params);
+        assert!(params.is_empty());
+
+        // The querystring takes precedence over the body
+        headers.insert(
+            "Content-Type".into(),
+            "application/x-www-form-urlencoded; charset=utf-8".into(),
+        );
+        params.insert("secret".into(), "from query".into());
+        add_form_params(&headers, body, &mut params);
+        assert_eq!(params.len(), 2);
+        assert_eq!(params["secret"], "from query");
+        assert_eq!(params["event"], "push");
+    }
+
+
+    #[test]
+    fn test_json_body() {
+        let mut req = dummy_web_request();
+        req.body = r#"{"repository": {"full_name": "a/b"}}"#.into();
+        let json = req.json_body().unwrap();
+        assert_eq!(
+            json.pointer("/repository/full_name").unwrap().as_str(),
+            Some("a/b")
+        );
+
+        req.body = "not json".into();
+        assert!(req.json_body().is_err());
+    }
+}
@@ -13,72 +13,285 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::mpsc;
+
 use serde_json;
 
 use fisher_common::prelude::*;
-use fisher_common::structs::HealthDetails;
+use fisher_common::structs::{HealthDetails, HistoryEntry};
+
+use hooks::HookSummary;
+use web::rate_limit::RateLimitStatus;
+
+
+fn metrics(details: &HealthDetails) -> String {
+    let (mut processed, mut failed) = (0, 0);
+    for stats in details.hooks.values() {
+        processed += stats.jobs.executed + stats.status_jobs.executed;
+        failed += stats.jobs.failed + stats.status_jobs.failed;
+    }
+
+    let mut result = String::new();
+    for &(name, kind, help, value) in &[
+        (
+            "fisher_queue_size", "gauge",
+            "Number of jobs waiting in the queue.",
+            details.queued_jobs as u64,
+        ),
+        (
+            "fisher_active_jobs", "gauge",
+            "Number of jobs being executed.",
+            details.busy_threads as u64,
+        ),
+        (
+            "fisher_jobs_processed_total", "counter",
+            "Number of executed jobs, including the retries.",
+            processed,
+        ),
+        (
+            "fisher_jobs_failed_total", "counter",
+            "Number of executed jobs which failed.",
+            failed,
+        ),
+    ] {
+        // Writing to a String can't fail
+        let _ = write!(
+            result, "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}\n",
+            name, help, kind, value,
+        );
+    }
+
+    result
+}
+
+
+/// The outcome of a job, returned to the clients waiting for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobResult {
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: String,
+    /// Why the job didn't return an output, if it didn't.
+    pub error: Option<String>,
+}
 
 
 #[derive(Debug)]
 pub enum Response {
-    NotFound,
-    Forbidden,
+    NotFound(&'static str),
+    Forbidden(&'static str),
+    /// The providers of the hook rejected the request, for the reason.
+    Rejected(String),
     BadRequest(Error),
-    Unavailable,
+    PayloadTooLarge,
+    HeadersTooLarge,
+    /// The client can retry after the number of seconds.
+    TooManyRequests(u64),
+    Unavailable(&'static str),
+    /// Like `Unavailable`, but the client can retry after the number of
+    /// seconds (second parameter).
+    RetryLater(&'static str, u64),
+    Timeout(&'static str),
     Ok,
-    HealthStatus(HealthDetails),
+    /// A ping was received by the hook (first parameter), and detected by
+    /// the provider (second parameter).
+    Ping(String, String),
+    Text(String, Option<String>),
+    HealthStatus(HealthDetails, HashMap<String, RateLimitStatus>),
+    HookHistory(Vec<HistoryEntry>),
+    HooksList(Vec<HookSummary>),
+    Metrics(HealthDetails),
+    /// The version of Fisher (first parameter) and the git commit it was
+    /// built from (second parameter).
+    Version(&'static str, &'static str),
+    JobOutput(JobResult),
+    /// The response will be sent through the channel once it's ready.
+    Deferred(mpsc::Receiver<Response>),
+    /// A successful response (second parameter) sent with a different
+    /// status (first parameter).
+    WithStatus(u16, Box<Response>),
 }
 
 impl Response {
 
     pub fn status(&self) -> u16 {
         match *self {
-            Response::NotFound => 404,
-            Response::Forbidden => 403,
+            Response::NotFound(..) => 404,
+            Response::Forbidden(..) | Response::Rejected(..) => 403,
             Response::BadRequest(..) => 400,
-            Response::Unavailable => 503,
+            Response::PayloadTooLarge => 413,
+            Response::HeadersTooLarge => 431,
+            Response::TooManyRequests(..) => 429,
+            Response::Unavailable(..) | Response::RetryLater(..) => 503,
+            Response::Deferred(..) => 503,
+            Response::Timeout(..) => 504,
+            Response::WithStatus(status, _) => status,
             _ => 200,
         }
     }
 
+    /// Return after how many seconds the client should retry the request,
+    /// if it's worth retrying it.
+    pub fn retry_after(&self) -> Option<u64> {
+        match *self {
+            Response::TooManyRequests(secs) => Some(secs),
+            Response::RetryLater(_, secs) => Some(secs),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        match *self {
+            Response::WithStatus(_, ref inner) => inner.content_type(),
+            Response::Text(_, Some(ref content_type)) => content_type,
+            Response::Text(_, None) => "text/plain; charset=utf-8",
+            Response::Metrics(..) => "text/plain; version=0.0.4",
+            _ => "application/json; charset=utf-8",
+        }
+    }
+
+    pub fn body(&self) -> String {
+        match *self {
+            // No content can be sent along with this status
+            Response::WithStatus(204, _) => String::new(),
+            Response::WithStatus(_, ref inner) => inner.body(),
+            Response::Text(ref text, _) => text.clone(),
+            Response::Metrics(ref details) => metrics(details),
+            _ => self.json(),
+        }
+    }
+
     pub fn json(&self) -> String {
+        if let Response::WithStatus(_, ref inner) = *self {
+            return inner.json();
+        }
+
         serde_json::to_string(&match *self {
-            Response::HealthStatus(ref details) => {
+            Response::HealthStatus(ref details, ref rate_limits) => {
+                let mut result = json!(details);
+                if let serde_json::Value::Object(ref mut result) = result {
+                    result.insert("rate_limits".into(), json!(rate_limits));
+                }
+
                 json!({
                     "status": "ok",
-                    "result": details,
+                    "result": result,
                 })
             },
-            Response::BadRequest(ref error) => {
+            Response::HookHistory(ref history) => {
                 json!({
-                    "status": "bad_request",
-                    "error_msg": format!("{}", error),
+                    "status": "ok",
+                    "result": history,
+                })
+            },
+            Response::HooksList(ref hooks) => {
+                json!({
+                    "status": "ok",
+                    "result": hooks,
+                })
+            },
+            Response::JobOutput(ref result) => {
+                json!({
+                    "status": "ok",
+                    "result": result,
+                })
+            },
+            // The other successful responses of the hooks don't have the
+            // ping field, so clients can tell them apart
+            Response::Ping(ref hook, ref provider) => {
+                json!({
+                    "status": "ok",
+                    "ping": true,
+                    "hook": hook,
+                    "provider": provider,
+                })
+            },
+            Response::Version(version, git) => {
+                json!({
+                    "status": "ok",
+                    "version": version,
+                    "git": git,
+                })
+            },
+            Response::Ok | Response::Text(..) | Response::Metrics(..) => {
+                json!({
+                    "status": "ok",
                 })
             },
             _ => {
+                // The reason is also in error_msg, where older versions of
+                // Fisher put it
+                let (code, reason) = self.error().unwrap();
                 json!({
-                    "status": match *self {
-                        Response::NotFound => "not_found",
-                        Response::Forbidden => "forbidden",
-                        Response::BadRequest(..) => "bad_request",
-                        Response::Unavailable => "unavailable",
-                        Response::Ok | Response::HealthStatus(..) => "ok",
-                    },
+                    "status": "error",
+                    "code": code,
+                    "error": reason,
+                    "error_msg": reason,
                 })
             }
         }).unwrap()
     }
+
+    /// Return the machine-readable code and the reason of the error, if this
+    /// response is an error.
+    pub fn error(&self) -> Option<(&'static str, String)> {
+        match *self {
+            Response::NotFound(reason) => {
+                Some(("not_found", reason.to_string()))
+            },
+            Response::Forbidden(reason) => {
+                Some(("forbidden", reason.to_string()))
+            },
+            Response::Rejected(ref reason) => {
+                Some(("forbidden", reason.clone()))
+            },
+            Response::BadRequest(ref error) => {
+                Some(("bad_request", error.to_string()))
+            },
+            Response::PayloadTooLarge => {
+                Some(("payload_too_large", "request body too large".into()))
+            },
+            Response::HeadersTooLarge => {
+                Some((
+                    "headers_too_large", "request headers too large".into(),
+                ))
+            },
+            Response::TooManyRequests(..) => {
+                Some(("too_many_requests", "rate limit exceeded".into()))
+            },
+            Response::Unavailable(reason) |
+            Response::RetryLater(reason, _) => {
+                Some(("unavailable", reason.to_string()))
+            },
+            Response::Timeout(reason) => {
+                Some(("timeout", reason.to_string()))
+            },
+            // Deferred responses should never be sent as they are
+            Response::Deferred(..) => {
+                Some(("unavailable", "the response isn't ready".into()))
+            },
+            _ => None,
+        }
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use serde_json;
 
     use fisher_common::prelude::*;
-    use fisher_common::structs::HealthDetails;
+    use fisher_common::structs::{HealthDetails, JobCounters, Percentiles};
+    use fisher_common::structs::ScriptStats;
+    use web::rate_limit::RateLimitStatus;
 
-    use super::Response;
+    use super::{JobResult, Response};
 
 
     #[inline]
@@ -87,36 +300,43 @@ mod tests {
     }
 
 
-    #[test]
-    fn test_not_found() {
-        let response = Response::NotFound;
-        assert_eq!(response.status(), 404);
-
+    fn assert_error(response: Response, code: &str, reason: &str) {
         // The result must be an object
         let json = j(response.json());
         let obj = json.as_object().unwrap();
 
-        // The status must be "not_found"
-        assert_eq!(
-            obj.get("status").unwrap().as_str().unwrap(),
-            "not_found"
-        );
+        // The status must be "error", with the code and the reason
+        assert_eq!(obj.get("status").unwrap().as_str().unwrap(), "error");
+        assert_eq!(obj.get("code").unwrap().as_str().unwrap(), code);
+        assert_eq!(obj.get("error").unwrap().as_str().unwrap(), reason);
+        assert_eq!(obj.get("error_msg").unwrap().as_str().unwrap(), reason);
+    }
+
+
+    #[test]
+    fn test_not_found() {
+        let response = Response::NotFound("unknown hook");
+        assert_eq!(response.status(), 404);
+        assert_error(response, "not_found", "unknown hook");
     }
 
 
     #[test]
     fn test_forbidden() {
-        let response = Response::Forbidden;
+        let response = Response::Forbidden("invalid secret");
         assert_eq!(response.status(), 403);
+        assert_error(response, "forbidden", "invalid secret");
+    }
 
-        // The result must be an object
-        let json = j(response.json());
-        let obj = json.as_object().unwrap();
 
-        // The status must be "forbidden"
-        assert_eq!(
-            obj.get("status").unwrap().as_str().unwrap(),
-            "forbidden"
+    #[test]
+    fn test_rejected() {
+        let response = Response::Rejected(
+            "rejected by the Standalone provider".into(),
+        );
+        assert_eq!(response.status(), 403);
+        assert_error(
+            response, "forbidden", "rejected by the Standalone provider",
         );
     }
 
@@ -130,38 +350,63 @@ mod tests {
         let response = Response::BadRequest(error);
         assert_eq!(response.status(), 400);
 
-        // The result must be an object
-        let json = j(response.json());
-        let obj = json.as_object().unwrap();
+        // The reason must be the error's message
+        assert_error(response, "bad_request", &error_msg);
+    }
 
-        // The status must be "forbidden"
-        assert_eq!(
-            obj.get("status").unwrap().as_str().unwrap(),
-            "bad_request"
-        );
 
-        // The error_msg must be the error's message
-        assert_eq!(
-            obj.get("error_msg").unwrap().as_str().unwrap(),
-            error_msg.as_str()
+    #[test]
+    fn test_payload_too_large() {
+        let response = Response::PayloadTooLarge;
+        assert_eq!(response.status(), 413);
+        assert_error(response, "payload_too_large", "request body too large");
+    }
+
+
+    #[test]
+    fn test_headers_too_large() {
+        let response = Response::HeadersTooLarge;
+        assert_eq!(response.status(), 431);
+        assert_error(
+            response, "headers_too_large", "request headers too large",
         );
     }
 
 
+    #[test]
+    fn test_too_many_requests() {
+        let response = Response::TooManyRequests(30);
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.retry_after(), Some(30));
+        assert_error(response, "too_many_requests", "rate limit exceeded");
+    }
+
+
+    #[test]
+    fn test_retry_later() {
+        let response = Response::RetryLater("the queue is full", 60);
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.retry_after(), Some(60));
+        assert_error(response, "unavailable", "the queue is full");
+
+        // Other errors aren't worth retrying
+        assert_eq!(Response::Unavailable("locked").retry_after(), None);
+    }
+
+
     #[test]
     fn test_unavailable() {
-        let response = Response::Unavailable;
+        let response = Response::Unavailable("fisher is locked");
         assert_eq!(response.status(), 503);
+        assert_error(response, "unavailable", "fisher is locked");
+    }
 
-        // The result must be an object
-        let json = j(response.json());
-        let obj = json.as_object().unwrap();
 
-        // The status must be "unavailable"
-        assert_eq!(
-            obj.get("status").unwrap().as_str().unwrap(),
-            "unavailable"
-        );
+    #[test]
+    fn test_timeout() {
+        let response = Response::Timeout("the job is still running");
+        assert_eq!(response.status(), 504);
+        assert_error(response, "timeout", "the job is still running");
     }
 
 
@@ -182,18 +427,66 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_text() {
+        let response = Response::Text("Hello world".into(), None);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.content_type(), "text/plain; charset=utf-8");
+        assert_eq!(response.body(), "Hello world".to_string());
+
+        // The content type can be overridden
+        let response = Response::Text("<p>Hi</p>".into(), Some(
+            "text/html".into()
+        ));
+        assert_eq!(response.content_type(), "text/html");
+        assert_eq!(response.body(), "<p>Hi</p>".to_string());
+    }
+
+
+    #[test]
+    fn test_with_status() {
+        // Everything except the status comes from the inner response
+        let response = Response::WithStatus(202, Box::new(Response::Ok));
+        assert_eq!(response.status(), 202);
+        assert_eq!(response.content_type(), "application/json; charset=utf-8");
+        assert_eq!(response.body(), Response::Ok.body());
+        assert!(response.error().is_none());
+
+        let response = Response::WithStatus(202, Box::new(Response::Text(
+            "Queued".into(), None,
+        )));
+        assert_eq!(response.content_type(), "text/plain; charset=utf-8");
+        assert_eq!(response.body(), "Queued".to_string());
+
+        // No content is sent with the 204 status
+        let response = Response::WithStatus(204, Box::new(Response::Ok));
+        assert_eq!(response.body(), String::new());
+    }
+
+
     #[test]
     fn test_health_status() {
+        let mut rate_limits = HashMap::new();
+        rate_limits.insert("example.sh".to_string(), RateLimitStatus {
+            per_minute: 30,
+            per_source: false,
+            available: 12,
+        });
         let response = Response::HealthStatus(HealthDetails {
             queued_jobs: 1,
             busy_threads: 2,
             max_threads: 3,
-        });
+            throughput: 0.5,
+            hooks: HashMap::new(),
+        }, rate_limits);
 
         // The result must be an object
         let json = j(response.json());
         let obj = json.as_object().unwrap();
         assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.content_type(), "application/json; charset=utf-8"
+        );
 
 
         // The status must be "ok"
@@ -218,6 +511,126 @@ mod tests {
         assert_eq!(
             result.get("max_threads").unwrap().as_u64().unwrap(),
             3 as u64
-        )
+        );
+        assert_eq!(result["throughput"].as_f64(), Some(0.5));
+
+        // The status of the rate limits is merged in the result
+        let limit = &result["rate_limits"]["example.sh"];
+        assert_eq!(limit["per_minute"].as_u64(), Some(30));
+        assert_eq!(limit["available"].as_u64(), Some(12));
+    }
+
+
+    #[test]
+    fn test_version() {
+        let response = Response::Version("1.0.0", "abcdef");
+        assert_eq!(response.status(), 200);
+        assert_eq!(j(response.json()), json!({
+            "status": "ok",
+            "version": "1.0.0",
+            "git": "abcdef",
+        }));
+    }
+
+
+    #[test]
+    fn test_metrics() {
+        let percentiles = Percentiles { p50: 0.0, p95: 0.0, p99: 0.0 };
+        let stats = |executed, failed| ScriptStats {
+            queue_time: percentiles,
+            execution_time: percentiles,
+            jobs: JobCounters {
+                executed: executed,
+                failed: failed,
+                .. JobCounters::default()
+            },
+            status_jobs: JobCounters {
+                executed: 1,
+                .. JobCounters::default()
+            },
+        };
+
+        let mut hooks = HashMap::new();
+        hooks.insert("a".to_string(), stats(5, 2));
+        hooks.insert("b".to_string(), stats(3, 1));
+
+        let response = Response::Metrics(HealthDetails {
+            queued_jobs: 4,
+            busy_threads: 2,
+            max_threads: 3,
+            throughput: 0.0,
+            hooks: hooks,
+        });
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.content_type(), "text/plain; version=0.0.4");
+
+        // The totals include the status jobs of every hook
+        let body = response.body();
+        let samples = body.lines()
+            .filter(|line| ! line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(samples, vec![
+            "fisher_queue_size 4",
+            "fisher_active_jobs 2",
+            "fisher_jobs_processed_total 10",
+            "fisher_jobs_failed_total 3",
+        ]);
+        assert!(body.contains("# TYPE fisher_jobs_failed_total counter\n"));
+    }
+
+
+    #[test]
+    fn test_job_output() {
+        let response = Response::JobOutput(JobResult {
+            success: false,
+            exit_code: Some(2),
+            signal: None,
+            timed_out: false,
+            stdout: "hello\n".into(),
+            error: None,
+        });
+        assert_eq!(response.status(), 200);
+
+        let json = j(response.json());
+        assert_eq!(json["status"].as_str(), Some("ok"));
+        assert_eq!(json["result"]["success"].as_bool(), Some(false));
+        assert_eq!(json["result"]["exit_code"].as_i64(), Some(2));
+        assert!(json["result"]["signal"].is_null());
+        assert_eq!(json["result"]["stdout"].as_str(), Some("hello\n"));
+        assert!(json["result"]["error"].is_null());
+
+        // The jobs which returned an error tell why
+        let response = Response::JobOutput(JobResult {
+            success: false,
+            exit_code: None,
+            signal: None,
+            timed_out: false,
+            stdout: String::new(),
+            error: Some("the interpreter is missing".into()),
+        });
+        let json = j(response.json());
+        assert_eq!(json["result"]["success"].as_bool(), Some(false));
+        assert_eq!(
+            json["result"]["error"].as_str(),
+            Some("the interpreter is missing"),
+        );
+    }
+
+
+    #[test]
+    fn test_ping() {
+        let response = Response::Ping("example.sh".into(), "GitHub".into());
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.error(), None);
+
+        assert_eq!(j(response.json()), json!({
+            "status": "ok",
+            "ping": true,
+            "hook": "example.sh",
+            "provider": "GitHub",
+        }));
+
+        // Other successful responses aren't pings
+        assert!(j(Response::Ok.json()).get("ping").is_none());
     }
 }
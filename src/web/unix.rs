@@ -0,0 +1,59 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+use fisher_common::prelude::*;
+
+
+/// The prefix of the addresses which are Unix sockets.
+pub const PREFIX: &'static str = "unix:";
+
+
+/// Return the path of the Unix socket, if the address is one.
+pub fn socket_path(bind: &str) -> Option<&Path> {
+    if bind.starts_with(PREFIX) {
+        Some(Path::new(&bind[PREFIX.len()..]))
+    } else {
+        None
+    }
+}
+
+
+/// The clients of a Unix socket don't have an address, but they're on the
+/// same host, so they're treated as if they connected to the loopback
+/// interface.
+pub fn peer_address() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)
+}
+
+
+/// Start listening on the Unix socket. The socket isn't removed when the
+/// listener is dropped, so it must be removed afterwards.
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    // A socket left behind by a previous run prevents binding, but other
+    // kinds of files are never removed
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(UnixListener::bind(path)?)
+}